@@ -0,0 +1,212 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde_json::Value;
+use tokio::sync::RwLock;
+
+use crate::calc_hash;
+use crate::cluster::ClusterMap;
+use crate::custom_error::{common_err, CustomError, CustomResult};
+use crate::ds::{DataSet, DsUpdateResult, FeatureUpdateResult};
+use crate::ds::column::get_value_as_int;
+use crate::feature::{FeatureQuery, FeatureQueryResult};
+use crate::rpc::{call, Request, Response, unexpected_response_err};
+
+const KEY_DS: &str = "ds";
+
+struct ClientState {
+    cluster: ClusterMap,
+    datasets: HashMap<i64, DataSet>,
+}
+
+/// 客户端，从meta server获取集群信息，按key的hash把读写直接发给owner node
+pub struct FeatureClient {
+    meta_addr: String,
+    state: RwLock<ClientState>,
+}
+
+impl FeatureClient {
+    pub async fn connect(meta_addr: &str) -> CustomResult<FeatureClient> {
+        let client = FeatureClient {
+            meta_addr: meta_addr.to_string(),
+            state: RwLock::new(ClientState {
+                cluster: fetch_cluster_map(meta_addr).await?,
+                datasets: fetch_datasets(meta_addr).await?,
+            }),
+        };
+        Ok(client)
+    }
+
+    /// 重新获取集群信息和数据集
+    pub async fn refresh(&self) -> CustomResult<()> {
+        let cluster = fetch_cluster_map(&self.meta_addr).await?;
+        let datasets = fetch_datasets(&self.meta_addr).await?;
+        let mut state = self.state.write().await;
+        state.cluster = cluster;
+        state.datasets = datasets;
+        Ok(())
+    }
+
+    pub async fn cluster_version(&self) -> u64 {
+        self.state.read().await.cluster.version
+    }
+
+    /// 更新事件关联的指标，集群信息过期时刷新后重试一次
+    ///
+    /// 每个owner单独提交，重试时只提交返回可重试错误的owner负责的指标，已经更新的指标不会重复计算。
+    /// 所有owner都没有更新时返回错误，调用方可以重新提交整个事件；否则失败的指标记录在结果中
+    pub async fn update(&self, event: Value) -> CustomResult<DsUpdateResult> {
+        let mut update = self.try_update(&event, None).await?;
+        let retry_ids: Vec<u64> = update.failed.iter()
+            .filter(|(_, e)| e.is_retriable())
+            .flat_map(|(feature_ids, _)| feature_ids.iter().cloned())
+            .collect();
+        if !retry_ids.is_empty() {
+            match self.refresh().await {
+                Ok(()) => {
+                    update.failed.retain(|(_, e)| !e.is_retriable());
+                    match self.try_update(&event, Some(&retry_ids)).await {
+                        Ok(retry) => update.merge(retry),
+                        Err(e) => update.failed.push((retry_ids, e)),
+                    }
+                }
+                Err(e) => update.failed.push((retry_ids, e)),
+            }
+        }
+        update.finish()
+    }
+
+    /// 按owner分组提交，feature_ids为空时更新数据集的所有指标
+    async fn try_update(&self, event: &Value, feature_ids: Option<&[u64]>) -> CustomResult<PartialUpdate> {
+        let ds_id = get_value_as_int(event, KEY_DS)?;
+        let mut result_map = HashMap::new();
+
+        // 按owner对指标分组
+        let mut owner_features: BTreeMap<String, Vec<u64>> = BTreeMap::new();
+        let owner_addrs = {
+            let state = self.state.read().await;
+            let ds = state.datasets.get(&ds_id)
+                .ok_or(common_err(format!("找不到对应的ds:{}", ds_id)))?;
+            // 派生指标没有存储，不需要更新
+            let features = ds.features.iter()
+                .filter(|f| f.derived().is_none())
+                .filter(|f| match feature_ids {
+                    Some(ids) => ids.contains(&f.id),
+                    None => true,
+                });
+            for feature in features {
+                match feature.build_key(event, &ds.column_type_map) {
+                    Ok(key) => {
+                        let owner = owner_addr(&state.cluster, calc_hash(key.as_bytes()))?;
+                        owner_features.entry(owner).or_insert(vec![]).push(feature.id);
+                    }
+                    Err(e) => {
+                        result_map.insert(feature.id, FeatureUpdateResult::failed(&e));
+                    }
+                }
+            }
+            owner_features
+        };
+
+        let mut update = PartialUpdate { result: DsUpdateResult { id: ds_id, feature_result_map: result_map }, failed: vec![], applied: false };
+        for (addr, feature_ids) in owner_addrs {
+            let request = Request::Update { event: event.clone(), feature_ids: Some(feature_ids.clone()), forwarded: false };
+            match call(&addr, &request).await {
+                Ok(Response::Update(res)) => {
+                    update.result.feature_result_map.extend(res.feature_result_map);
+                    update.applied = true;
+                }
+                Ok(resp) => update.failed.push((feature_ids, unexpected_response_err(&resp))),
+                Err(e) => update.failed.push((feature_ids, e)),
+            }
+        }
+        Ok(update)
+    }
+
+    /// 查询指标，集群信息过期时刷新后重试一次
+    pub async fn query(&self, query: FeatureQuery) -> CustomResult<FeatureQueryResult> {
+        match self.try_query(&query).await {
+            Err(e) if e.is_retriable() => {
+                self.refresh().await?;
+                self.try_query(&query).await
+            }
+            res => res
+        }
+    }
+
+    async fn try_query(&self, query: &FeatureQuery) -> CustomResult<FeatureQueryResult> {
+        let addr = {
+            let state = self.state.read().await;
+            let ds = state.datasets.get(&query.ds)
+                .ok_or(common_err(format!("找不到对应的ds:{}", query.ds)))?;
+            let feature = ds.features.iter().find(|f| f.id == query.feature_id)
+                .ok_or(common_err(format!("找不到对应的feature:{}", query.feature_id)))?;
+            let key = feature.build_key(&query.keys, &ds.column_type_map)?;
+            owner_addr(&state.cluster, calc_hash(key.as_bytes()))?
+        };
+        match call(&addr, &Request::Query { query: query.clone(), forwarded: false }).await? {
+            Response::Query(res) => Ok(res),
+            resp => Err(unexpected_response_err(&resp)),
+        }
+    }
+}
+
+/// 一次提交的结果，部分owner可能失败
+struct PartialUpdate {
+    result: DsUpdateResult,
+    // 失败的owner负责的指标和错误，这些指标都没有更新
+    failed: Vec<(Vec<u64>, CustomError)>,
+    // 是否有owner已经更新
+    applied: bool,
+}
+
+impl PartialUpdate {
+    fn merge(&mut self, other: PartialUpdate) {
+        self.result.feature_result_map.extend(other.result.feature_result_map);
+        self.failed.extend(other.failed);
+        self.applied |= other.applied;
+    }
+
+    fn finish(mut self) -> CustomResult<DsUpdateResult> {
+        if !self.applied {
+            if let Some((_, e)) = self.failed.into_iter().next() {
+                return Err(e);
+            }
+            return Ok(self.result);
+        }
+        for (feature_ids, e) in self.failed {
+            for feature_id in feature_ids {
+                self.result.feature_result_map.insert(feature_id, FeatureUpdateResult::failed(&e));
+            }
+        }
+        Ok(self.result)
+    }
+}
+
+fn owner_addr(cluster: &ClusterMap, key_hash: u64) -> CustomResult<String> {
+    cluster.owner_of_hash(key_hash)
+        .and_then(|id| cluster.addr_of(id))
+        .cloned()
+        .ok_or(common_err(format!("找不到hash:{} 对应的node", key_hash)))
+}
+
+pub async fn fetch_cluster_map(meta_addr: &str) -> CustomResult<ClusterMap> {
+    match call(meta_addr, &Request::GetClusterMap).await? {
+        Response::ClusterMap(map) => Ok(map),
+        resp => Err(unexpected_response_err(&resp)),
+    }
+}
+
+pub async fn fetch_datasets(meta_addr: &str) -> CustomResult<HashMap<i64, DataSet>> {
+    match call(meta_addr, &Request::GetDataSets).await? {
+        Response::DataSets(datasets) => Ok(datasets.into_iter().map(|ds| (ds.id, ds)).collect()),
+        resp => Err(unexpected_response_err(&resp)),
+    }
+}
+
+/// 触发meta server重新分配slot，返回迁移完成后的集群信息
+pub async fn rebalance(meta_addr: &str) -> CustomResult<ClusterMap> {
+    match call(meta_addr, &Request::Rebalance).await? {
+        Response::ClusterMap(map) => Ok(map),
+        resp => Err(unexpected_response_err(&resp)),
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::calc_hash;
+use crate::store::slot::{slot_id_of_hash, SLOT_NUM_BY_BIT};
+
+/// 每个节点在hash环上的虚拟节点数
+pub const VIRTUAL_NODE_NUM: u32 = 128;
+
+/// 节点信息
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NodeInfo {
+    pub id: String,
+    pub addr: String,
+}
+
+/// 集群的slot分配，由meta server维护，每次变更版本号加一
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClusterMap {
+    pub version: u64,
+    // node id -> 地址
+    pub nodes: BTreeMap<String, String>,
+    // 下标为slot id，值为node id
+    pub slots: Vec<String>,
+}
+
+impl ClusterMap {
+    /// 单节点，拥有所有slot
+    pub fn single(node: &NodeInfo) -> ClusterMap {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(node.id.clone(), node.addr.clone());
+        ClusterMap {
+            version: 0,
+            nodes,
+            slots: vec![node.id.clone(); 1 << SLOT_NUM_BY_BIT],
+        }
+    }
+
+    /// 按一致性hash把slot分配给节点：slot的起始hash顺时针找到的第一个虚拟节点即为owner
+    pub fn assign(version: u64, nodes: BTreeMap<String, String>) -> ClusterMap {
+        let mut ring = BTreeMap::new();
+        for id in nodes.keys() {
+            for i in 0..VIRTUAL_NODE_NUM {
+                ring.insert(calc_hash(format!("{}#{}", id, i).as_bytes()), id.clone());
+            }
+        }
+
+        let mut slots = Vec::with_capacity(1 << SLOT_NUM_BY_BIT);
+        for slot_id in 0..(1u64 << SLOT_NUM_BY_BIT) {
+            let point = slot_id << (64 - SLOT_NUM_BY_BIT);
+            let owner = ring.range(point..).next()
+                .or(ring.iter().next())
+                .map(|(_, id)| id.clone())
+                .unwrap_or_default();
+            slots.push(owner);
+        }
+        ClusterMap { version, nodes, slots }
+    }
+
+    /// 没有分配任何slot，如还没有node注册
+    pub fn is_unassigned(&self) -> bool {
+        self.slots.iter().all(|owner| owner.is_empty())
+    }
+
+    /// 与按一致性hash分配的结果对比，返回需要迁移的slot，按 (from, to) 分组
+    pub fn rebalance_plan(&self) -> BTreeMap<(String, String), Vec<u16>> {
+        let expected = ClusterMap::assign(self.version, self.nodes.clone());
+        let mut plan: BTreeMap<(String, String), Vec<u16>> = BTreeMap::new();
+        for (slot_id, (from, to)) in self.slots.iter().zip(expected.slots.iter()).enumerate() {
+            if from != to && !from.is_empty() {
+                plan.entry((from.clone(), to.clone())).or_insert(vec![]).push(slot_id as u16);
+            }
+        }
+        plan
+    }
+
+    pub fn owner_of_slot(&self, slot_id: u16) -> Option<&String> {
+        self.slots.get(slot_id as usize)
+    }
+
+    pub fn owner_of_hash(&self, key_hash: u64) -> Option<&String> {
+        self.owner_of_slot(slot_id_of_hash(key_hash))
+    }
+
+    pub fn addr_of(&self, node_id: &str) -> Option<&String> {
+        self.nodes.get(node_id)
+    }
+
+    /// 节点拥有的slot
+    pub fn slots_of(&self, node_id: &str) -> Vec<u16> {
+        self.slots.iter().enumerate()
+            .filter(|(_, owner)| owner.as_str() == node_id)
+            .map(|(slot_id, _)| slot_id as u16)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::cluster::ClusterMap;
+    use crate::store::slot::SLOT_NUM_BY_BIT;
+
+    fn nodes(n: usize) -> BTreeMap<String, String> {
+        (0..n).map(|i| (format!("node_{}", i), format!("127.0.0.1:{}", 7000 + i))).collect()
+    }
+
+    #[test]
+    pub fn test_assign_balance() {
+        let map = ClusterMap::assign(1, nodes(3));
+        assert_eq!(map.slots.len(), 1 << SLOT_NUM_BY_BIT);
+        for id in map.nodes.keys() {
+            let n = map.slots_of(id).len();
+            // 虚拟节点保证分布大致均匀
+            assert!(n > (1 << SLOT_NUM_BY_BIT) / 6, "{}:{}", id, n);
+        }
+    }
+
+    #[test]
+    pub fn test_assign_minimal_move() {
+        let before = ClusterMap::assign(1, nodes(3));
+        let after = ClusterMap::assign(2, nodes(4));
+
+        let moved: Vec<usize> = (0..before.slots.len())
+            .filter(|i| before.slots[*i] != after.slots[*i])
+            .collect();
+        // 只有分给新节点的slot发生移动
+        assert!(moved.iter().all(|i| after.slots[*i] == "node_3"));
+        assert!(moved.len() < before.slots.len() / 2);
+    }
+}
@@ -1,3 +1,443 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::custom_error::{config_invalid_err, CustomResult};
+
+/// 环境变量覆盖配置时使用的前缀，如 FEATURE_DB_DATA_DIR
+pub const ENV_PREFIX: &str = "FEATURE_DB_";
+
+/// wal刷盘策略
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum WalSyncPolicy {
+    /// 每条日志都刷盘
+    Always,
+    /// 只在事务提交时刷盘
+    Commit,
+    /// 不主动刷盘，由操作系统决定，只用于导入历史数据等可以重做的场景
+    Never,
+}
+
+impl FromStr for WalSyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(WalSyncPolicy::Always),
+            "commit" => Ok(WalSyncPolicy::Commit),
+            "never" => Ok(WalSyncPolicy::Never),
+            _ => Err(format!("不识别的wal刷盘策略:{}", s)),
+        }
+    }
+}
+
+/// 数据源配置，按kind区分
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum SourceConfig {
+    Kafka(KafkaSourceConfig),
+    File(FileSourceConfig),
+}
+
+/// Kafka协议的数据源，消费指定的分区，位置提交到消费组
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct KafkaSourceConfig {
+    // broker地址，需要是所有分区的leader和消费组的coordinator
+    pub broker: String,
+    pub topic: String,
+    pub partitions: Vec<i32>,
+    // 提交位置使用的消费组
+    pub group: String,
+    pub client_id: String,
+    // 消费组没有已提交的位置时，从最早(earliest)还是最新(latest)的消息开始
+    pub start_from: String,
+    // 没有新消息时broker最长等待时间，毫秒
+    pub max_wait_ms: u64,
+    // 每次拉取的最大字节数
+    pub max_bytes: usize,
+}
+
+impl Default for KafkaSourceConfig {
+    fn default() -> Self {
+        KafkaSourceConfig {
+            broker: "".to_string(),
+            topic: "".to_string(),
+            partitions: vec![0],
+            group: "feature_db".to_string(),
+            client_id: "feature_db".to_string(),
+            start_from: "earliest".to_string(),
+            max_wait_ms: 500,
+            max_bytes: 1 << 20,
+        }
+    }
+}
+
+/// 跟踪追加写入的JSONL文件，每行一个事件
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileSourceConfig {
+    pub path: String,
+    // 保存已提交位置的文件，为空时为 {path}.offset
+    pub offset_path: String,
+    // 没有新数据时的检查间隔，毫秒
+    pub poll_interval_ms: u64,
+    // 每次读取的最大行数
+    pub max_lines: usize,
+}
+
+impl Default for FileSourceConfig {
+    fn default() -> Self {
+        FileSourceConfig {
+            path: "".to_string(),
+            offset_path: "".to_string(),
+            poll_interval_ms: 1000,
+            max_lines: 1000,
+        }
+    }
+}
+
+/// 节点配置，优先级：命令行 > 环境变量 > 配置文件 > 默认值
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
+    // node id，集群内唯一
+    pub node_id: String,
+    // 数据目录
     pub data_dir: String,
-}
\ No newline at end of file
+    // 对外服务地址
+    pub listen_addr: String,
+    // meta server 地址
+    pub meta_addr: String,
+    // prometheus metrics 地址
+    pub metrics_addr: String,
+    // wal刷盘策略
+    pub wal_sync: WalSyncPolicy,
+    // 检查点间隔，秒
+    pub checkpoint_interval_secs: u64,
+    // 从meta server刷新集群信息的间隔，秒
+    pub cluster_refresh_secs: u64,
+    // 获取page写锁的最长等待时间，毫秒
+    pub page_lock_timeout_ms: u64,
+    // log4rs配置文件路径，为空时使用默认配置
+    pub log_config: String,
+    // 同时处理的事件数上限
+    pub max_in_flight: usize,
+    // wal队列长度，队列满时拒绝新的事件
+    pub wal_queue_size: usize,
+    // 每个数据集每秒处理的事件数上限，0表示不限制
+    pub dataset_rate_limit: u64,
+    // 按数据集id单独设置的速率上限，只能在配置文件中设置
+    pub dataset_rate_limits: HashMap<String, u64>,
+    // leader的地址，不为空时作为follower启动，只复制leader的数据；node_id应与leader相同，提升后接替leader
+    pub replicate_from: String,
+    // leader保留在内存中供follower拉取的wal日志条数
+    pub replication_log_size: usize,
+    // 提交时是否等待follower确认
+    pub replication_sync_ack: bool,
+    // 等待follower确认的最长时间，毫秒，超时后只在本地提交
+    pub replication_ack_timeout_ms: u64,
+    // 数据源，只能在配置文件中设置
+    pub sources: Vec<SourceConfig>,
+    // 变更订阅服务地址（SSE），为空时不启动
+    pub changes_addr: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            node_id: "node_1".to_string(),
+            data_dir: "./data".to_string(),
+            listen_addr: "127.0.0.1:6600".to_string(),
+            meta_addr: "127.0.0.1:6500".to_string(),
+            metrics_addr: "127.0.0.1:6601".to_string(),
+            wal_sync: WalSyncPolicy::Always,
+            checkpoint_interval_secs: 5,
+            cluster_refresh_secs: 5,
+            page_lock_timeout_ms: 3000,
+            log_config: "".to_string(),
+            max_in_flight: 1000,
+            wal_queue_size: 100,
+            dataset_rate_limit: 0,
+            dataset_rate_limits: HashMap::new(),
+            replicate_from: "".to_string(),
+            replication_log_size: 100000,
+            replication_sync_ack: false,
+            replication_ack_timeout_ms: 1000,
+            sources: vec![],
+            changes_addr: "".to_string(),
+        }
+    }
+}
+
+impl Config {
+    /// 从TOML文本解析
+    pub fn from_toml(content: &str) -> CustomResult<Config> {
+        toml::from_str(content).map_err(|e| config_invalid_err(format!("解析配置失败:{}", e)))
+    }
+
+    /// 加载配置：命令行参数中 --config 指定的文件，再依次应用环境变量和命令行参数，最后校验
+    pub fn load(args: &[String], envs: &HashMap<String, String>) -> CustomResult<Config> {
+        let cli = parse_args(args)?;
+
+        let mut config = match cli.get("config") {
+            Some(path) => {
+                let content = std::fs::read_to_string(path)
+                    .map_err(|e| config_invalid_err(format!("读取配置文件{}失败:{}", path, e)))?;
+                Config::from_toml(&content)?
+            }
+            None => Config::default(),
+        };
+
+        // 环境变量中可能有其他程序使用的同前缀变量，未知的直接跳过，由 ignored_env_keys 告警
+        for (k, v) in envs {
+            if let Some(key) = k.strip_prefix(ENV_PREFIX) {
+                config.apply(&key.to_lowercase(), v)?;
+            }
+        }
+        for (k, v) in &cli {
+            if k != "config" {
+                config.set(k, v)?;
+            }
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// 按名称覆盖单个配置项
+    pub fn set(&mut self, key: &str, value: &str) -> CustomResult<()> {
+        if !self.apply(key, value)? {
+            return Err(config_invalid_err(format!("未知的配置项:{}", key)));
+        }
+        Ok(())
+    }
+
+    /// 环境变量中带 FEATURE_DB_ 前缀但不是配置项的变量名，加载时被忽略
+    pub fn ignored_env_keys(envs: &HashMap<String, String>) -> Vec<String> {
+        let mut keys: Vec<String> = envs.keys()
+            .filter(|k| match k.strip_prefix(ENV_PREFIX) {
+                Some(key) => matches!(Config::default().apply(&key.to_lowercase(), ""), Ok(false)),
+                None => false,
+            })
+            .cloned()
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    /// 覆盖单个配置项，未知的配置项返回false
+    fn apply(&mut self, key: &str, value: &str) -> CustomResult<bool> {
+        match key {
+            "node_id" => self.node_id = value.to_string(),
+            "data_dir" => self.data_dir = value.to_string(),
+            "listen_addr" => self.listen_addr = value.to_string(),
+            "meta_addr" => self.meta_addr = value.to_string(),
+            "metrics_addr" => self.metrics_addr = value.to_string(),
+            "wal_sync" => self.wal_sync = parse_value(key, value)?,
+            "checkpoint_interval_secs" => self.checkpoint_interval_secs = parse_value(key, value)?,
+            "cluster_refresh_secs" => self.cluster_refresh_secs = parse_value(key, value)?,
+            "page_lock_timeout_ms" => self.page_lock_timeout_ms = parse_value(key, value)?,
+            "log_config" => self.log_config = value.to_string(),
+            "max_in_flight" => self.max_in_flight = parse_value(key, value)?,
+            "wal_queue_size" => self.wal_queue_size = parse_value(key, value)?,
+            "dataset_rate_limit" => self.dataset_rate_limit = parse_value(key, value)?,
+            "replicate_from" => self.replicate_from = value.to_string(),
+            "replication_log_size" => self.replication_log_size = parse_value(key, value)?,
+            "replication_sync_ack" => self.replication_sync_ack = parse_value(key, value)?,
+            "replication_ack_timeout_ms" => self.replication_ack_timeout_ms = parse_value(key, value)?,
+            "changes_addr" => self.changes_addr = value.to_string(),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// 启动前校验
+    pub fn validate(&self) -> CustomResult<()> {
+        if self.node_id.is_empty() {
+            return Err(config_invalid_err("node_id 不能为空".to_string()));
+        }
+        if self.data_dir.is_empty() {
+            return Err(config_invalid_err("data_dir 不能为空".to_string()));
+        }
+        let data_dir = Path::new(&self.data_dir);
+        if data_dir.exists() && !data_dir.is_dir() {
+            return Err(config_invalid_err(format!("data_dir:{} 不是目录", self.data_dir)));
+        }
+        for (key, addr) in [("listen_addr", &self.listen_addr), ("meta_addr", &self.meta_addr),
+            ("metrics_addr", &self.metrics_addr)] {
+            addr.parse::<SocketAddr>()
+                .map_err(|e| config_invalid_err(format!("{}:{} 格式错误:{}", key, addr, e)))?;
+        }
+        if self.checkpoint_interval_secs == 0 {
+            return Err(config_invalid_err("checkpoint_interval_secs 必须大于0".to_string()));
+        }
+        if self.cluster_refresh_secs == 0 {
+            return Err(config_invalid_err("cluster_refresh_secs 必须大于0".to_string()));
+        }
+        if self.page_lock_timeout_ms == 0 {
+            return Err(config_invalid_err("page_lock_timeout_ms 必须大于0".to_string()));
+        }
+        if self.max_in_flight == 0 {
+            return Err(config_invalid_err("max_in_flight 必须大于0".to_string()));
+        }
+        if self.wal_queue_size == 0 {
+            return Err(config_invalid_err("wal_queue_size 必须大于0".to_string()));
+        }
+        for k in self.dataset_rate_limits.keys() {
+            k.parse::<i64>()
+                .map_err(|_| config_invalid_err(format!("dataset_rate_limits 的key必须是数据集id:{}", k)))?;
+        }
+        if !self.replicate_from.is_empty() {
+            self.replicate_from.parse::<SocketAddr>()
+                .map_err(|e| config_invalid_err(format!("replicate_from:{} 格式错误:{}", self.replicate_from, e)))?;
+        }
+        if !self.changes_addr.is_empty() {
+            self.changes_addr.parse::<SocketAddr>()
+                .map_err(|e| config_invalid_err(format!("changes_addr:{} 格式错误:{}", self.changes_addr, e)))?;
+        }
+        if self.replication_log_size == 0 {
+            return Err(config_invalid_err("replication_log_size 必须大于0".to_string()));
+        }
+        if self.replication_sync_ack && self.replication_ack_timeout_ms == 0 {
+            return Err(config_invalid_err("replication_ack_timeout_ms 必须大于0".to_string()));
+        }
+        for source in &self.sources {
+            match source {
+                SourceConfig::Kafka(kafka) => {
+                    kafka.broker.parse::<SocketAddr>()
+                        .map_err(|e| config_invalid_err(format!("数据源broker:{} 格式错误:{}", kafka.broker, e)))?;
+                    if kafka.topic.is_empty() || kafka.group.is_empty() || kafka.partitions.is_empty() {
+                        return Err(config_invalid_err("kafka数据源的topic、group、partitions不能为空".to_string()));
+                    }
+                    if kafka.start_from != "earliest" && kafka.start_from != "latest" {
+                        return Err(config_invalid_err(format!("start_from:{} 非法，可选earliest/latest", kafka.start_from)));
+                    }
+                }
+                SourceConfig::File(file) => {
+                    if file.path.is_empty() {
+                        return Err(config_invalid_err("文件数据源的path不能为空".to_string()));
+                    }
+                    if file.poll_interval_ms == 0 || file.max_lines == 0 {
+                        return Err(config_invalid_err("文件数据源的poll_interval_ms、max_lines必须大于0".to_string()));
+                    }
+                }
+            }
+        }
+        if !self.log_config.is_empty() && !Path::new(&self.log_config).is_file() {
+            return Err(config_invalid_err(format!("log_config:{} 文件不存在", self.log_config)));
+        }
+        Ok(())
+    }
+}
+
+fn parse_value<T: FromStr>(key: &str, value: &str) -> CustomResult<T> where T::Err: std::fmt::Display {
+    value.parse::<T>().map_err(|e| config_invalid_err(format!("配置项{}的值{}非法:{}", key, value, e)))
+}
+
+/// 解析命令行参数，支持 --data-dir /x 和 --data-dir=/x 两种写法
+pub fn parse_args(args: &[String]) -> CustomResult<HashMap<String, String>> {
+    let mut res = HashMap::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let arg = arg.strip_prefix("--")
+            .ok_or(config_invalid_err(format!("无法识别的参数:{}", arg)))?;
+        let (key, value) = match arg.split_once('=') {
+            Some((k, v)) => (k.to_string(), v.to_string()),
+            None => {
+                let v = iter.next()
+                    .ok_or(config_invalid_err(format!("参数--{}缺少值", arg)))?;
+                (arg.to_string(), v.clone())
+            }
+        };
+        res.insert(key.replace('-', "_"), value);
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::config::{Config, SourceConfig, WalSyncPolicy};
+
+    #[test]
+    pub fn test_load_with_override() {
+        let dir = std::env::temp_dir().join(format!("feature_db_config_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("node.toml");
+        std::fs::write(&path, r#"
+            data_dir = "/tmp/from_file"
+            listen_addr = "0.0.0.0:7000"
+            wal_sync = "commit"
+            checkpoint_interval_secs = 10
+        "#).unwrap();
+
+        let args: Vec<String> = vec!["--config".to_string(), path.to_str().unwrap().to_string(),
+                                     "--checkpoint-interval-secs=30".to_string()];
+        let mut envs = HashMap::new();
+        envs.insert("FEATURE_DB_DATA_DIR".to_string(), "/tmp/from_env".to_string());
+        envs.insert("FEATURE_DB_CHECKPOINT_INTERVAL_SECS".to_string(), "20".to_string());
+        envs.insert("PATH".to_string(), "/usr/bin".to_string());
+        envs.insert("FEATURE_DB_HOME".to_string(), "/opt/feature_db".to_string());
+
+        let config = Config::load(&args, &envs).unwrap();
+        assert_eq!(config.data_dir, "/tmp/from_env");
+        assert_eq!(config.listen_addr, "0.0.0.0:7000");
+        assert_eq!(config.wal_sync, WalSyncPolicy::Commit);
+        assert_eq!(config.checkpoint_interval_secs, 30);
+        assert_eq!(config.page_lock_timeout_ms, Config::default().page_lock_timeout_ms);
+        assert_eq!(Config::ignored_env_keys(&envs), vec!["FEATURE_DB_HOME".to_string()]);
+
+        envs.insert("FEATURE_DB_MAX_IN_FLIGHT".to_string(), "many".to_string());
+        assert!(Config::load(&args, &envs).is_err());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    pub fn test_invalid_config() {
+        let envs = HashMap::new();
+        let args = |a: &[&str]| -> Vec<String> { a.iter().map(|s| s.to_string()).collect() };
+
+        assert!(Config::load(&args(&["--listen-addr", "not_an_addr"]), &envs).is_err());
+        assert!(Config::load(&args(&["--checkpoint-interval-secs", "0"]), &envs).is_err());
+        assert!(Config::load(&args(&["--wal-sync", "sometimes"]), &envs).is_err());
+        assert!(Config::load(&args(&["--changes-addr", "8080"]), &envs).is_err());
+        assert!(Config::load(&args(&["--unknown", "1"]), &envs).is_err());
+        assert!(Config::load(&args(&["--data-dir"]), &envs).is_err());
+        assert!(Config::from_toml("data_dirr = \"/x\"").is_err());
+    }
+
+    #[test]
+    pub fn test_sources() {
+        let config = Config::from_toml(r#"
+            [[sources]]
+            kind = "kafka"
+            broker = "127.0.0.1:9092"
+            topic = "orders"
+            partitions = [0, 1]
+
+            [[sources]]
+            kind = "file"
+            path = "/data/events.jsonl"
+        "#).unwrap();
+        config.validate().unwrap();
+        match &config.sources[..] {
+            [SourceConfig::Kafka(kafka), SourceConfig::File(file)] => {
+                assert_eq!((kafka.topic.as_str(), kafka.partitions.clone(), kafka.group.as_str()), ("orders", vec![0, 1], "feature_db"));
+                assert_eq!((file.path.as_str(), file.max_lines), ("/data/events.jsonl", 1000));
+            }
+            sources => panic!("数据源解析错误:{:?}", sources),
+        }
+
+        assert!(Config::from_toml("[[sources]]\nkind = \"redis\"").is_err());
+        assert!(Config::from_toml("[[sources]]\nkind = \"file\"\npath = \"/x\"\ntopic = \"y\"").is_err());
+        let config = Config::from_toml("[[sources]]\nkind = \"kafka\"\nbroker = \"127.0.0.1:9092\"").unwrap();
+        assert!(config.validate().is_err());
+    }
+}
@@ -6,6 +6,7 @@ use std::string::FromUtf8Error;
 use num_enum::TryFromPrimitiveError;
 use serde_json::Value;
 
+
 // 为 `Box<error::Error>` 取别名。
 //pub type BoxErr = Box<dyn error::Error + Send + Sync>;
 pub type CustomResult<T> = std::result::Result<T, CustomError>;
@@ -20,6 +21,11 @@ impl CustomError {
     pub fn new(e: Box<dyn Error>) -> CustomError {
         common_err(e.to_string())
     }
+
+    /// 是否可以重试，调用方稍后重新提交即可
+    pub fn is_retriable(&self) -> bool {
+        is_retriable_code(self.code)
+    }
 }
 
 impl From<std::io::Error> for CustomError {
@@ -95,11 +101,115 @@ pub fn column_not_found_in_ds_err(key: &str) -> CustomError {
     }
 }
 
+/// 参数或配置错误
+pub static CONFIG_INVALID_CODE: usize = 10004;
+pub fn config_invalid_err(msg: String) -> CustomError {
+    CustomError {
+        code: CONFIG_INVALID_CODE,
+        message: format!("配置错误:{}", msg),
+    }
+}
+
+/// 数据集或指标的定义错误
+pub static FEATURE_INVALID_CODE: usize = 10005;
+pub fn feature_invalid_err(feature_id: u64, msg: String) -> CustomError {
+    CustomError {
+        code: FEATURE_INVALID_CODE,
+        message: format!("指标:{} 定义错误:{}", feature_id, msg),
+    }
+}
+
 /// 因为数据不足导致的失败，错误码
 pub static DECODE_FAILED_BY_INSUFFICIENT_DATA_CODE: usize = 20001;
 pub fn decode_failed_by_insufficient_data_err() -> CustomError {
     CustomError {
         code: DECODE_FAILED_BY_INSUFFICIENT_DATA_CODE,
-        message: format!("解析失败，数据长度不足"),
+        message: "解析失败，数据长度不足".to_string(),
+    }
+}
+
+/// 获取page锁超时，可重试
+pub static PAGE_LOCK_TIMEOUT_CODE: usize = 30001;
+pub fn page_lock_timeout_err(slot_id: u16, min_pk: u64) -> CustomError {
+    CustomError {
+        code: PAGE_LOCK_TIMEOUT_CODE,
+        message: format!("获取page锁超时，slot:{} page:{}，请稍后重试", slot_id, min_pk),
+    }
+}
+
+/// node正在停机，不再接收数据
+pub static NODE_SHUTTING_DOWN_CODE: usize = 30002;
+pub fn node_shutting_down_err() -> CustomError {
+    CustomError {
+        code: NODE_SHUTTING_DOWN_CODE,
+        message: "node正在停机，不再接收数据".to_string(),
+    }
+}
+
+/// 负载过高，拒绝处理，可重试
+pub static OVERLOADED_CODE: usize = 30003;
+pub fn overloaded_err(msg: String) -> CustomError {
+    CustomError {
+        code: OVERLOADED_CODE,
+        message: format!("负载过高，请稍后重试:{}", msg),
+    }
+}
+
+/// slot不属于当前node，刷新集群信息后重试
+pub static NOT_OWNER_CODE: usize = 30004;
+pub fn not_owner_err(slot_id: u16, owner: Option<&String>) -> CustomError {
+    CustomError {
+        code: NOT_OWNER_CODE,
+        message: format!("slot:{} 不属于当前node，owner:{:?}", slot_id, owner),
     }
-}
\ No newline at end of file
+}
+
+/// 当前node是follower，只接收leader复制的数据
+pub static READ_ONLY_CODE: usize = 30005;
+pub fn read_only_err() -> CustomError {
+    CustomError {
+        code: READ_ONLY_CODE,
+        message: "当前node是follower，不能直接写入".to_string(),
+    }
+}
+
+/// 转发给owner失败，owner上的指标没有更新，可以重试
+pub static FORWARD_FAILED_CODE: usize = 30007;
+pub fn forward_failed_err(addr: &str, msg: String) -> CustomError {
+    CustomError {
+        code: FORWARD_FAILED_CODE,
+        message: format!("转发到{}失败:{}", addr, msg),
+    }
+}
+
+/// wal写入失败，内存中的数据与wal不一致，node不再接收写入，需要重启
+pub static NODE_FAILED_CODE: usize = 30008;
+pub fn node_failed_err() -> CustomError {
+    CustomError {
+        code: NODE_FAILED_CODE,
+        message: "wal写入失败，node已停止接收写入，请重启".to_string(),
+    }
+}
+
+/// 错误码对应的错误是否可以重试
+pub fn is_retriable_code(code: usize) -> bool {
+    code == PAGE_LOCK_TIMEOUT_CODE || code == OVERLOADED_CODE || code == NOT_OWNER_CODE || code == FORWARD_FAILED_CODE
+}
+
+/// follower需要的wal日志已不在leader的复制缓冲区中，需要从备份重新同步
+pub static REPLICATION_GAP_CODE: usize = 30006;
+pub fn replication_gap_err(from: u64, floor: u64) -> CustomError {
+    CustomError {
+        code: REPLICATION_GAP_CODE,
+        message: format!("follower落后太多，需要的日志:{} 早于leader保留的最早日志:{}", from, floor),
+    }
+}
+
+/// 数据目录的元数据与当前程序不一致
+pub static STORE_META_MISMATCH_CODE: usize = 20002;
+pub fn store_meta_mismatch_err(found: &str, expected: &str) -> CustomError {
+    CustomError {
+        code: STORE_META_MISMATCH_CODE,
+        message: format!("数据目录的元数据不匹配，拒绝打开，数据:{}，当前:{}", found, expected),
+    }
+}
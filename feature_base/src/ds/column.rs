@@ -1,10 +1,11 @@
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-use crate::custom_error::{value_not_found_err, value_type_not_match_err, CustomResult};
+use crate::custom_error::{common_err, value_not_found_err, value_type_not_match_err, CustomResult};
+use crate::feature::key::KeyPart;
 
 /// 字段类型
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ColumnType {
     // 文本
     TEXT,
@@ -29,27 +30,39 @@ pub fn get_value_as_u64(data: &Value, column: &str) -> CustomResult<u64> {
         .as_u64().ok_or(value_type_not_match_err(&data, column))
 }
 
-pub fn get_value_to_str(event: &Value, column: &str, column_type: &ColumnType) -> CustomResult<String> {
+pub fn get_value_as_key_part(event: &Value, column: &str, column_type: &ColumnType) -> CustomResult<KeyPart> {
     let value = event.get(column)
         .ok_or(value_not_found_err(&event, column))?;
     let value = match column_type {
         ColumnType::TEXT => {
-            value.as_str().ok_or(value_type_not_match_err(&event, column))?.to_string()
+            KeyPart::Text(value.as_str().ok_or(value_type_not_match_err(event, column))?.to_string())
         }
         ColumnType::INT => {
-            value.as_i64().ok_or(value_type_not_match_err(&event, column))?.to_string()
+            KeyPart::Int(value.as_i64().ok_or(value_type_not_match_err(event, column))?)
         }
         ColumnType::FLOAT => {
-            value.as_f64().ok_or(value_type_not_match_err(&event, column))?.to_string()
+            KeyPart::Float(value.as_f64().ok_or(value_type_not_match_err(event, column))?)
         }
         ColumnType::DATETIME => {
-            value.as_u64().ok_or(value_type_not_match_err(&event, column))?.to_string()
+            KeyPart::DateTime(value.as_u64().ok_or(value_type_not_match_err(event, column))?)
         }
     };
 
     Ok(value)
 }
 
+/// 把文本（如CSV中的字段）按字段类型转换为json值
+pub fn parse_column_value(text: &str, column: &str, column_type: &ColumnType) -> CustomResult<Value> {
+    let invalid = || common_err(format!("字段:{} 的值:{} 不是{:?}类型", column, text, column_type));
+    let value = match column_type {
+        ColumnType::TEXT => Value::from(text),
+        ColumnType::INT => Value::from(text.trim().parse::<i64>().map_err(|_| invalid())?),
+        ColumnType::FLOAT => Value::from(text.trim().parse::<f64>().map_err(|_| invalid())?),
+        ColumnType::DATETIME => Value::from(text.trim().parse::<u64>().map_err(|_| invalid())?),
+    };
+    Ok(value)
+}
+
 pub fn check_value_and_type_match(event: &Value, column: &str, column_type: &ColumnType) -> CustomResult<()> {
     let value = event.get(column)
         .ok_or(value_not_found_err(&event, column))?;
@@ -6,12 +6,15 @@ use serde::{Deserialize, Serialize};
 
 use column::ColumnType;
 
+use crate::custom_error::{config_invalid_err, CustomError, CustomResult, feature_invalid_err, is_retriable_code};
 use crate::feature::Feature;
+use crate::feature::FeatureTemplate::{LAST_N, STDDEV};
+use crate::feature::last_n_feature::MAX_LAST_N;
 
 pub mod column;
 
 /// 命名空间,
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DataSet {
     pub id: i64,
     // 名称
@@ -24,26 +27,159 @@ pub struct DataSet {
     pub features: Vec<Feature>,
 }
 
+impl DataSet {
+    /// 定义时校验：指标用到的字段都在数据集中，过滤条件与字段类型一致；
+    /// 派生指标引用的指标在同一数据集中、值为数字、分组字段包含在派生指标中，且引用不能成环
+    pub fn validate(&self) -> CustomResult<()> {
+        let features: HashMap<u64, &Feature> = self.features.iter().map(|f| (f.id, f)).collect();
+        if features.len() != self.features.len() {
+            return Err(config_invalid_err(format!("数据集:{} 中的指标id重复", self.id)));
+        }
+        for feature in &self.features {
+            for column in feature.group_keys().iter().map(|k| k.as_str()).chain(feature.time_key()) {
+                if !self.column_type_map.contains_key(column) {
+                    return Err(feature_invalid_err(feature.id, format!("字段:{} 不在数据集:{} 中", column, self.id)));
+                }
+            }
+            if let Some(filter) = feature.filter() {
+                filter.validate(feature.id, &self.column_type_map)?;
+            }
+            if let LAST_N(lf) = &feature.template {
+                if !self.column_type_map.contains_key(&lf.value_key) {
+                    return Err(feature_invalid_err(feature.id, format!("字段:{} 不在数据集:{} 中", lf.value_key, self.id)));
+                }
+                if lf.n == 0 || lf.n > MAX_LAST_N {
+                    return Err(feature_invalid_err(feature.id, format!("n:{} 必须在1到{}之间", lf.n, MAX_LAST_N)));
+                }
+            }
+            if let STDDEV(vf) = &feature.template {
+                match self.column_type_map.get(&vf.value_key) {
+                    None => return Err(feature_invalid_err(feature.id, format!("字段:{} 不在数据集:{} 中", vf.value_key, self.id))),
+                    Some(ColumnType::INT | ColumnType::FLOAT) => {}
+                    Some(t) => return Err(feature_invalid_err(feature.id, format!("字段:{} 的类型:{:?} 不是数字", vf.value_key, t))),
+                }
+            }
+            if let Some(derived) = feature.derived() {
+                for id in derived.feature_ids() {
+                    let referenced = features.get(&id)
+                        .ok_or(feature_invalid_err(feature.id, format!("引用的指标:{} 不在数据集:{} 中", id, self.id)))?;
+                    if !matches!(referenced.value_type(), ColumnType::INT | ColumnType::FLOAT) {
+                        return Err(feature_invalid_err(feature.id, format!("引用的指标:{} 的值不是数字", id)));
+                    }
+                    if let Some(k) = referenced.group_keys().iter().find(|k| !feature.group_keys().contains(k)) {
+                        return Err(feature_invalid_err(feature.id, format!("引用的指标:{} 的分组字段:{} 不在派生指标的分组字段中", id, k)));
+                    }
+                }
+            }
+        }
+        // 沿派生指标的引用深度优先遍历，遇到正在访问的指标说明成环
+        let mut visited = HashMap::new();
+        for feature in &self.features {
+            check_cycle(feature.id, &features, &mut visited)?;
+        }
+        Ok(())
+    }
+}
+
+/// visited: 指标id -> 是否已访问完成，false表示正在访问
+fn check_cycle(feature_id: u64, features: &HashMap<u64, &Feature>, visited: &mut HashMap<u64, bool>) -> CustomResult<()> {
+    match visited.get(&feature_id) {
+        Some(true) => return Ok(()),
+        Some(false) => return Err(feature_invalid_err(feature_id, "派生指标的引用成环".to_string())),
+        None => {}
+    }
+    visited.insert(feature_id, false);
+    if let Some(derived) = features.get(&feature_id).and_then(|f| f.derived()) {
+        for id in derived.feature_ids() {
+            check_cycle(id, features, visited)?;
+        }
+    }
+    visited.insert(feature_id, true);
+    Ok(())
+}
+
 /// 每个指标更新的结果
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FeatureUpdateResult {
     pub success: bool,
     pub msg: String,
+    // 失败时的错误码
+    #[serde(default)]
+    pub code: usize,
 }
 
 impl FeatureUpdateResult {
-    pub fn failed(msg: String) -> FeatureUpdateResult {
+    pub fn failed(e: &CustomError) -> FeatureUpdateResult {
         FeatureUpdateResult {
             success: false,
-            msg,
+            msg: e.to_string(),
+            code: e.code,
         }
     }
+
+    /// 失败是否可以重试，如转发失败、owner变化，指标没有更新
+    pub fn is_retriable(&self) -> bool {
+        !self.success && is_retriable_code(self.code)
+    }
 }
 
 /// 数据集更新结果
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DsUpdateResult {
     pub id: i64,
     pub feature_result_map: HashMap<u64, FeatureUpdateResult>,
 }
 
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::custom_error::FEATURE_INVALID_CODE;
+    use crate::ds::DataSet;
+
+    fn dataset(features: serde_json::Value) -> DataSet {
+        serde_json::from_value(json!({
+            "id": 101,
+            "name": "ds_user_order",
+            "desc": "",
+            "column_type_map": {"user_id": "INT", "merchant_id": "INT", "ts": "DATETIME"},
+            "features": features
+        })).unwrap()
+    }
+
+    fn count(id: u64, group_key: &str) -> serde_json::Value {
+        json!({"id": id, "name": "", "template": {"COUNT": {
+            "group_keys": [group_key], "time_key": "ts", "window_unit": "DAY", "window_size": 30}}})
+    }
+
+    fn derived(id: u64, expr: serde_json::Value) -> serde_json::Value {
+        json!({"id": id, "name": "", "template": {"DERIVED": {"group_keys": ["user_id"], "expr": expr}}})
+    }
+
+    fn stddev(id: u64, value_key: &str) -> serde_json::Value {
+        json!({"id": id, "name": "", "template": {"STDDEV": {
+            "group_keys": ["user_id"], "time_key": "ts", "value_key": value_key,
+            "window_unit": "DAY", "window_size": 30, "output": "STDDEV"}}})
+    }
+
+    #[test]
+    pub fn test_validate_derived() {
+        let ratio = json!({"DIV": [{"FEATURE": 2}, {"FEATURE": 1}]});
+        dataset(json!([count(1, "user_id"), count(2, "user_id"), derived(3, ratio)])).validate().unwrap();
+
+        let invalid = |features| dataset(features).validate().unwrap_err().code == FEATURE_INVALID_CODE;
+        // 引用的指标不存在
+        assert!(invalid(json!([count(1, "user_id"), derived(3, json!({"FEATURE": 9}))])));
+        // 引用的指标按商户分组，无法用用户查询
+        assert!(invalid(json!([count(1, "merchant_id"), derived(3, json!({"FEATURE": 1}))])));
+        // 引用成环
+        assert!(invalid(json!([count(1, "user_id"),
+            derived(3, json!({"ADD": [{"FEATURE": 1}, {"FEATURE": 4}]})),
+            derived(4, json!({"MUL": [{"FEATURE": 3}, {"CONST": 2}]}))])));
+        assert!(invalid(json!([derived(3, json!({"FEATURE": 3}))])));
+        // 标准差的值是数字，可以被引用；统计的字段必须是数字
+        dataset(json!([count(1, "user_id"), stddev(2, "merchant_id"), derived(3, json!({"DIV": [{"FEATURE": 2}, {"FEATURE": 1}]}))])).validate().unwrap();
+        assert!(invalid(json!([stddev(2, "ts")])));
+        assert!(invalid(json!([stddev(2, "amount")])));
+    }
+}
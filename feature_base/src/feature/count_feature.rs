@@ -3,19 +3,21 @@ use std::collections::HashMap;
 use log::info;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use string_builder::Builder;
 use tokio::sync::RwLockWriteGuard;
 
-use crate::custom_error::{column_not_found_in_ds_err, CustomError, CustomResult};
-use crate::ds::column::{ColumnType, get_value_as_u64, get_value_to_str};
-use crate::feature::value::FeatureValue;
+use crate::custom_error::CustomResult;
+use crate::ds::column::{ColumnType, get_value_as_u64};
+use crate::feature::build_group_key;
+use crate::feature::filter::Filter;
+use crate::feature::key::FeatureKey;
+use crate::feature::value::{FeatureValue, ValueKind};
 
 use crate::store::page::Page;
 use crate::store::wal::{Wal, WalFeatureUpdateValue};
 use crate::WindowUnit;
 
 /// 累加类型的指标模板
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CountFeatureTemplate {
     // 分组字段
     pub group_keys: Vec<String>,
@@ -25,47 +27,54 @@ pub struct CountFeatureTemplate {
     pub window_unit: WindowUnit,
     // 窗口大小
     pub window_size: u64,
+    // 过滤条件，为空时所有事件都参与计算
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<Filter>,
 }
 
 impl CountFeatureTemplate {
     pub fn build_key(&self, event: &Value,
                      feature_id: u64,
-                     column_type_map: &HashMap<String, ColumnType>) -> CustomResult<String> {
-        // 拼接主键
-        let mut builder = Builder::default();
-        for k in &self.group_keys {
-            let column_type = column_type_map.get(k)
-                .ok_or(column_not_found_in_ds_err(k))?;
-
-            builder.append(get_value_to_str(event, k, column_type)?);
-        }
-        builder.append(feature_id.to_string());
-        builder.string().map_err(|e| -> CustomError { e.into() })
+                     column_type_map: &HashMap<String, ColumnType>) -> CustomResult<FeatureKey> {
+        build_group_key(feature_id, &self.group_keys, event, column_type_map)
     }
 
 
     pub async fn calc_and_update<'a>(&self, event: &Value,
                                      column_type_map: &HashMap<String, ColumnType>,
-                                     key: &String,
+                                     key: &FeatureKey,
                                      page: &mut RwLockWriteGuard<'_, Page>,
                                      wal: &Wal) -> CustomResult<WalFeatureUpdateValue> {
 
-        // 事件时间
-        let time = get_value_as_u64(event, &self.time_key)?;
-
-        let old_value = page.get(key).await;
-        //
-        let update_res = match old_value {
+        // page的写锁由调用方持有，这里直接修改
+        let update_res = match page.get_mut(key).await {
             None => {
-                let  sv = FeatureValue::new();
-                let update_res = sv.add_int(key, time, self.window_unit.to_millis(self.window_size), 1)?;
+                let mut sv = FeatureValue::new();
+                let update_res = self.calc(event, key, &mut sv)?;
                 page.put(key.clone(), sv).await?;
                 update_res
             }
             Some(sv) => {
-                sv.add_int(key, time, self.window_unit.to_millis(self.window_size), 1)?
+                self.calc(event, key, sv)?
             }
         };
         Ok(update_res)
     }
-}
\ No newline at end of file
+
+    /// 事件时间
+    pub fn event_time(&self, event: &Value) -> CustomResult<u64> {
+        get_value_as_u64(event, &self.time_key)
+    }
+
+    /// 把事件累加到value中
+    pub fn calc(&self, event: &Value, key: &FeatureKey, value: &mut FeatureValue) -> CustomResult<WalFeatureUpdateValue> {
+        let time = self.event_time(event)?;
+        let window_size = self.window_unit.to_millis(self.window_size);
+        value.add_int(key, time, window_size, 1)
+    }
+
+    pub fn query(&self, value: Option<&FeatureValue>, time: u64) -> Option<ValueKind> {
+        let window_size = self.window_unit.to_millis(self.window_size);
+        value.and_then(|v| v.sum_window(time, window_size))
+    }
+}
@@ -0,0 +1,110 @@
+use std::collections::{BTreeSet, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::feature::value::ValueKind;
+
+/// 派生指标的表达式，如 {"DIV":[{"FEATURE":10002},{"FEATURE":10001}]}
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Expr {
+    // 同一数据集中的指标
+    Feature(u64),
+    Const(f64),
+    Add(Box<Expr>, Box<Expr>),
+    Sub(Box<Expr>, Box<Expr>),
+    Mul(Box<Expr>, Box<Expr>),
+    Div(Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn collect_feature_ids(&self, ids: &mut BTreeSet<u64>) {
+        match self {
+            Expr::Feature(id) => {
+                ids.insert(*id);
+            }
+            Expr::Const(_) => {}
+            Expr::Add(a, b) | Expr::Sub(a, b) | Expr::Mul(a, b) | Expr::Div(a, b) => {
+                a.collect_feature_ids(ids);
+                b.collect_feature_ids(ids);
+            }
+        }
+    }
+
+    /// 没有数据的指标按0计算，除数为0时结果为空
+    fn eval(&self, values: &HashMap<u64, Option<ValueKind>>) -> Option<f64> {
+        match self {
+            Expr::Feature(id) => match values.get(id).cloned().flatten() {
+                Some(ValueKind::Int(v)) => Some(v as f64),
+                Some(ValueKind::Float(v)) => Some(v),
+                // 定义时已校验引用的指标是数字
+                Some(ValueKind::List(_) | ValueKind::Seen { .. } | ValueKind::Stats { .. }) => None,
+                None => Some(0.0),
+            },
+            Expr::Const(v) => Some(*v),
+            Expr::Add(a, b) => Some(a.eval(values)? + b.eval(values)?),
+            Expr::Sub(a, b) => Some(a.eval(values)? - b.eval(values)?),
+            Expr::Mul(a, b) => Some(a.eval(values)? * b.eval(values)?),
+            Expr::Div(a, b) => {
+                let (a, b) = (a.eval(values)?, b.eval(values)?);
+                if b == 0.0 { None } else { Some(a / b) }
+            }
+        }
+    }
+}
+
+/// 派生指标模板：由同一数据集中其它指标的值计算，查询时计算，没有自己的存储。
+///
+/// 引用的指标用同样的分组字段值查询，分组字段必须包含在派生指标的分组字段中
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DerivedFeatureTemplate {
+    // 分组字段
+    pub group_keys: Vec<String>,
+    // 表达式
+    pub expr: Expr,
+}
+
+impl DerivedFeatureTemplate {
+    /// 表达式直接引用的指标
+    pub fn feature_ids(&self) -> Vec<u64> {
+        let mut ids = BTreeSet::new();
+        self.expr.collect_feature_ids(&mut ids);
+        ids.into_iter().collect()
+    }
+
+    /// 由引用的指标在同一时刻的值计算
+    pub fn eval(&self, values: &HashMap<u64, Option<ValueKind>>) -> Option<ValueKind> {
+        self.expr.eval(values)
+            .filter(|v| v.is_finite())
+            .map(ValueKind::Float)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::feature::derived_feature::DerivedFeatureTemplate;
+    use crate::feature::value::ValueKind;
+
+    #[test]
+    pub fn test_derived_eval() {
+        let template: DerivedFeatureTemplate = serde_json::from_str(r#"{
+            "group_keys":["user_id"],
+            "expr":{"DIV":[{"FEATURE":10002},{"ADD":[{"FEATURE":10001},{"CONST":1}]}]}
+        }"#).unwrap();
+        assert_eq!(template.feature_ids(), vec![10001, 10002]);
+
+        let values = HashMap::from([(10001, Some(ValueKind::Int(3))), (10002, Some(ValueKind::Float(2.0)))]);
+        assert_eq!(template.eval(&values), Some(ValueKind::Float(0.5)));
+        // 没有数据的指标按0计算
+        let values = HashMap::from([(10001, None), (10002, Some(ValueKind::Int(3)))]);
+        assert_eq!(template.eval(&values), Some(ValueKind::Float(3.0)));
+
+        let template: DerivedFeatureTemplate = serde_json::from_str(r#"{
+            "group_keys":["user_id"],
+            "expr":{"DIV":[{"FEATURE":10002},{"FEATURE":10001}]}
+        }"#).unwrap();
+        assert_eq!(template.eval(&HashMap::from([(10001, None), (10002, Some(ValueKind::Int(1)))])), None);
+    }
+}
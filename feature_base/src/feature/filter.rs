@@ -0,0 +1,169 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::custom_error::{column_not_found_in_ds_err, CustomResult, feature_invalid_err, value_type_not_match_err};
+use crate::ds::column::{ColumnType, get_value_as_key_part};
+use crate::feature::key::KeyPart;
+
+/// 指标的过滤条件，事件满足条件时才更新指标。
+///
+/// 按数据集中字段的类型比较，如 {"AND":[{"GT":["amount",100]},{"IN":["status",["paid","done"]]}]}。
+/// 字段不存在或为null时比较和IN都不成立，用IS_NULL/NOT_NULL判断
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Filter {
+    Eq(String, Value),
+    Ne(String, Value),
+    Gt(String, Value),
+    Ge(String, Value),
+    Lt(String, Value),
+    Le(String, Value),
+    In(String, Vec<Value>),
+    IsNull(String),
+    NotNull(String),
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+}
+
+impl Filter {
+    /// 定义时校验：字段必须在数据集中，常量与字段类型一致
+    pub fn validate(&self, feature_id: u64, column_type_map: &HashMap<String, ColumnType>) -> CustomResult<()> {
+        let column_type = |column: &String| column_type_map.get(column)
+            .ok_or(feature_invalid_err(feature_id, format!("过滤条件中的字段:{} 不在数据集中", column)));
+        let check_literal = |column: &String, literal: &Value| -> CustomResult<()> {
+            let column_type = column_type(column)?;
+            literal_as_key_part(literal, column_type).map(|_| ())
+                .ok_or(feature_invalid_err(feature_id, format!("过滤条件中字段:{} 的值:{} 不是{:?}类型", column, literal, column_type)))
+        };
+        match self {
+            Filter::Eq(column, literal) | Filter::Ne(column, literal) | Filter::Gt(column, literal)
+            | Filter::Ge(column, literal) | Filter::Lt(column, literal) | Filter::Le(column, literal) => check_literal(column, literal),
+            Filter::In(column, literals) => literals.iter().try_for_each(|literal| check_literal(column, literal)),
+            Filter::IsNull(column) | Filter::NotNull(column) => column_type(column).map(|_| ()),
+            Filter::And(filters) | Filter::Or(filters) => filters.iter().try_for_each(|f| f.validate(feature_id, column_type_map)),
+            Filter::Not(filter) => filter.validate(feature_id, column_type_map),
+        }
+    }
+
+    /// 事件是否满足条件，字段的值与类型不一致时返回错误
+    pub fn matches(&self, event: &Value, column_type_map: &HashMap<String, ColumnType>) -> CustomResult<bool> {
+        let res = match self {
+            Filter::Eq(column, literal) => compare(event, column, literal, column_type_map)? == Some(Ordering::Equal),
+            Filter::Ne(column, literal) => matches!(compare(event, column, literal, column_type_map)?, Some(o) if o != Ordering::Equal),
+            Filter::Gt(column, literal) => compare(event, column, literal, column_type_map)? == Some(Ordering::Greater),
+            Filter::Ge(column, literal) => matches!(compare(event, column, literal, column_type_map)?, Some(Ordering::Greater | Ordering::Equal)),
+            Filter::Lt(column, literal) => compare(event, column, literal, column_type_map)? == Some(Ordering::Less),
+            Filter::Le(column, literal) => matches!(compare(event, column, literal, column_type_map)?, Some(Ordering::Less | Ordering::Equal)),
+            Filter::In(column, literals) => {
+                let mut res = false;
+                for literal in literals {
+                    if compare(event, column, literal, column_type_map)? == Some(Ordering::Equal) {
+                        res = true;
+                        break;
+                    }
+                }
+                res
+            }
+            Filter::IsNull(column) => is_null(event, column),
+            Filter::NotNull(column) => !is_null(event, column),
+            Filter::And(filters) => {
+                for f in filters {
+                    if !f.matches(event, column_type_map)? {
+                        return Ok(false);
+                    }
+                }
+                true
+            }
+            Filter::Or(filters) => {
+                for f in filters {
+                    if f.matches(event, column_type_map)? {
+                        return Ok(true);
+                    }
+                }
+                false
+            }
+            Filter::Not(filter) => !filter.matches(event, column_type_map)?,
+        };
+        Ok(res)
+    }
+}
+
+fn is_null(event: &Value, column: &str) -> bool {
+    event.get(column).unwrap_or(&Value::Null).is_null()
+}
+
+/// 按字段类型比较事件中的值和常量，字段为null时返回None
+fn compare(event: &Value, column: &str, literal: &Value, column_type_map: &HashMap<String, ColumnType>) -> CustomResult<Option<Ordering>> {
+    if is_null(event, column) {
+        return Ok(None);
+    }
+    let column_type = column_type_map.get(column).ok_or(column_not_found_in_ds_err(column))?;
+    let value = get_value_as_key_part(event, column, column_type)?;
+    let literal = literal_as_key_part(literal, column_type).ok_or(value_type_not_match_err(literal, column))?;
+    let res = match (&value, &literal) {
+        (KeyPart::Text(a), KeyPart::Text(b)) => a.partial_cmp(b),
+        (KeyPart::Int(a), KeyPart::Int(b)) => a.partial_cmp(b),
+        (KeyPart::Float(a), KeyPart::Float(b)) => a.partial_cmp(b),
+        (KeyPart::DateTime(a), KeyPart::DateTime(b)) => a.partial_cmp(b),
+        _ => None,
+    };
+    Ok(res)
+}
+
+/// 按字段类型转换过滤条件中的常量
+fn literal_as_key_part(literal: &Value, column_type: &ColumnType) -> Option<KeyPart> {
+    match column_type {
+        ColumnType::TEXT => literal.as_str().map(|v| KeyPart::Text(v.to_string())),
+        ColumnType::INT => literal.as_i64().map(KeyPart::Int),
+        ColumnType::FLOAT => literal.as_f64().map(KeyPart::Float),
+        ColumnType::DATETIME => literal.as_u64().map(KeyPart::DateTime),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+
+    use crate::ds::column::ColumnType;
+    use crate::feature::filter::Filter;
+
+    #[test]
+    pub fn test_filter() {
+        let column_type_map = HashMap::from([
+            ("amount".to_string(), ColumnType::FLOAT),
+            ("status".to_string(), ColumnType::TEXT),
+            ("retries".to_string(), ColumnType::INT),
+        ]);
+        let filter: Filter = serde_json::from_value(json!({"AND": [
+            {"GT": ["amount", 100]},
+            {"OR": [{"IN": ["status", ["paid", "done"]]}, {"IS_NULL": "status"}]},
+            {"NOT": {"EQ": ["retries", 3]}}
+        ]})).unwrap();
+        filter.validate(1, &column_type_map).unwrap();
+        assert_eq!(serde_json::from_value::<Filter>(serde_json::to_value(&filter).unwrap()).unwrap(), filter);
+
+        let matches = |event| filter.matches(&event, &column_type_map).unwrap();
+        assert!(matches(json!({"amount": 100.5, "status": "paid", "retries": 1})));
+        assert!(matches(json!({"amount": 200, "status": null})));
+        assert!(!matches(json!({"amount": 100, "status": "paid"})));
+        assert!(!matches(json!({"amount": 150.0, "status": "failed"})));
+        assert!(!matches(json!({"amount": 150.0, "status": "done", "retries": 3})));
+        // 字段不存在时比较不成立，NOT取反后成立
+        assert!(!matches(json!({"status": "paid"})));
+        assert!(matches(json!({"amount": 101, "status": "paid"})));
+        assert!(filter.matches(&json!({"amount": "x"}), &column_type_map).is_err());
+
+        let invalid = |v| serde_json::from_value::<Filter>(v).unwrap().validate(1, &column_type_map).is_err();
+        assert!(invalid(json!({"GT": ["missing", 1]})));
+        assert!(invalid(json!({"EQ": ["retries", "3"]})));
+        assert!(invalid(json!({"IN": ["status", ["paid", 1]]})));
+        assert!(invalid(json!({"NOT": {"NOT_NULL": "missing"}})));
+        assert!(!invalid(json!({"LE": ["retries", -1]})));
+    }
+}
@@ -0,0 +1,289 @@
+use std::fmt;
+use std::io::Cursor;
+
+use bytes::{Buf, BufMut, BytesMut};
+use serde::{Serialize, Serializer};
+
+use crate::custom_error::{common_err, CustomResult, decode_failed_by_insufficient_data_err};
+use crate::store::Storable;
+
+/// 分组字段的值，带类型
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyPart {
+    Text(String),
+    Int(i64),
+    Float(f64),
+    DateTime(u64),
+}
+
+/// KeyPart序列化的类型标记
+const KEY_PART_TEXT: u8 = 1;
+const KEY_PART_INT: u8 = 2;
+const KEY_PART_FLOAT: u8 = 3;
+const KEY_PART_DATETIME: u8 = 4;
+
+impl KeyPart {
+    /// 保序编码：相同类型的值，字节序与值的大小顺序一致；文本按内容的字节序排序
+    ///
+    /// 文本中的0x00转义为0x00 0xFF，以0x00 0x00结尾，较短的前缀排在前面
+    fn encode_to(&self, buf: &mut Vec<u8>) -> CustomResult<()> {
+        match self {
+            KeyPart::Text(v) => {
+                buf.put_u8(KEY_PART_TEXT);
+                for b in v.as_bytes() {
+                    buf.put_u8(*b);
+                    if *b == 0 {
+                        buf.put_u8(0xFF);
+                    }
+                }
+                buf.put_u16(0);
+            }
+            KeyPart::Int(v) => {
+                buf.put_u8(KEY_PART_INT);
+                // 翻转符号位，负数排在正数前面
+                buf.put_u64((*v as u64) ^ (1 << 63));
+            }
+            KeyPart::Float(v) => {
+                buf.put_u8(KEY_PART_FLOAT);
+                let bits = v.to_bits();
+                let bits = if bits >> 63 == 0 { bits ^ (1 << 63) } else { !bits };
+                buf.put_u64(bits);
+            }
+            KeyPart::DateTime(v) => {
+                buf.put_u8(KEY_PART_DATETIME);
+                buf.put_u64(*v);
+            }
+        }
+        Ok(())
+    }
+
+    fn decode_from(buf: &mut Cursor<&[u8]>) -> CustomResult<KeyPart> {
+        if buf.remaining() < 1 {
+            return Err(decode_failed_by_insufficient_data_err());
+        }
+        let tag = buf.get_u8();
+        if tag == KEY_PART_TEXT {
+            return decode_text(buf);
+        }
+        if buf.remaining() < 8 {
+            return Err(decode_failed_by_insufficient_data_err());
+        }
+        match tag {
+            KEY_PART_INT => Ok(KeyPart::Int((buf.get_u64() ^ (1 << 63)) as i64)),
+            KEY_PART_FLOAT => {
+                let bits = buf.get_u64();
+                let bits = if bits >> 63 == 1 { bits ^ (1 << 63) } else { !bits };
+                Ok(KeyPart::Float(f64::from_bits(bits)))
+            }
+            KEY_PART_DATETIME => Ok(KeyPart::DateTime(buf.get_u64())),
+            _ => Err(common_err(format!("反序列化失败，不识别的key类型：{}", tag)))
+        }
+    }
+}
+
+/// 解析转义后的文本，直到结束标记
+fn decode_text(buf: &mut Cursor<&[u8]>) -> CustomResult<KeyPart> {
+    let mut bytes = vec![];
+    loop {
+        if buf.remaining() < 1 {
+            return Err(decode_failed_by_insufficient_data_err());
+        }
+        let b = buf.get_u8();
+        if b != 0 {
+            bytes.push(b);
+            continue;
+        }
+        if buf.remaining() < 1 {
+            return Err(decode_failed_by_insufficient_data_err());
+        }
+        match buf.get_u8() {
+            0 => return Ok(KeyPart::Text(String::from_utf8(bytes)?)),
+            0xFF => bytes.push(0),
+            b => return Err(common_err(format!("反序列化失败，文本中非法的转义：{}", b))),
+        }
+    }
+}
+
+impl fmt::Display for KeyPart {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyPart::Text(v) => write!(f, "{:?}", v),
+            KeyPart::Int(v) => write!(f, "{}", v),
+            KeyPart::Float(v) => write!(f, "{}", v),
+            KeyPart::DateTime(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+/// 指标主键，feature id + 分组字段值，以编码后的字节保存
+///
+/// 编码格式：feature_id(u64) + [类型标记(u8) + 值]...，字节序即主键顺序
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FeatureKey(Vec<u8>);
+
+impl FeatureKey {
+    pub fn new(feature_id: u64, parts: &[KeyPart]) -> CustomResult<FeatureKey> {
+        let mut buf = Vec::with_capacity(8 + parts.len() * 9);
+        buf.put_u64(feature_id);
+        for part in parts {
+            part.encode_to(&mut buf)?;
+        }
+        if buf.len() > u16::MAX as usize {
+            return Err(common_err(format!("主键过长:{}", buf.len())));
+        }
+        Ok(FeatureKey(buf))
+    }
+
+    /// 从编码后的字节构建，会校验格式
+    pub fn from_bytes(bytes: Vec<u8>) -> CustomResult<FeatureKey> {
+        let key = FeatureKey(bytes);
+        key.parts()?;
+        Ok(key)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn feature_id(&self) -> u64 {
+        let mut id = [0u8; 8];
+        id.copy_from_slice(&self.0[..8]);
+        u64::from_be_bytes(id)
+    }
+
+    /// 解析分组字段值
+    pub fn parts(&self) -> CustomResult<Vec<KeyPart>> {
+        if self.0.len() < 8 {
+            return Err(decode_failed_by_insufficient_data_err());
+        }
+        let mut cursor: Cursor<&[u8]> = Cursor::new(&self.0[8..]);
+        let mut parts = vec![];
+        while cursor.remaining() > 0 {
+            parts.push(KeyPart::decode_from(&mut cursor)?);
+        }
+        Ok(parts)
+    }
+}
+
+impl fmt::Display for FeatureKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.parts() {
+            Ok(parts) => {
+                write!(f, "{}", self.feature_id())?;
+                for p in parts {
+                    write!(f, ":{}", p)?;
+                }
+                Ok(())
+            }
+            Err(_) => write!(f, "{:?}", self.0),
+        }
+    }
+}
+
+impl fmt::Debug for FeatureKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "FeatureKey({})", self)
+    }
+}
+
+impl Serialize for FeatureKey {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        serializer.collect_str(self)
+    }
+}
+
+impl Storable for FeatureKey {
+    fn encode(&self, buf: &mut BytesMut) -> CustomResult<()> {
+        buf.put_u16(self.0.len() as u16);
+        buf.put(self.0.as_slice());
+        Ok(())
+    }
+
+    fn decode(buf: &mut Cursor<&[u8]>) -> CustomResult<Self> where Self: Sized {
+        if buf.remaining() < 2 {
+            return Err(decode_failed_by_insufficient_data_err());
+        }
+        let len = buf.get_u16() as usize;
+        if buf.remaining() < len {
+            return Err(decode_failed_by_insufficient_data_err());
+        }
+        FeatureKey::from_bytes(buf.copy_to_bytes(len).to_vec())
+    }
+
+    fn need_space(&self) -> usize {
+        2 + self.0.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use bytes::BytesMut;
+
+    use crate::feature::key::{FeatureKey, KeyPart};
+    use crate::store::Storable;
+
+    #[test]
+    pub fn test_no_collision() {
+        // 字符串拼接时 "1"+"23" 与 "12"+"3" 相同
+        let k1 = FeatureKey::new(23, &[KeyPart::Text("1".to_string())]).unwrap();
+        let k2 = FeatureKey::new(3, &[KeyPart::Text("12".to_string())]).unwrap();
+        assert_ne!(k1, k2);
+
+        let k3 = FeatureKey::new(1, &[KeyPart::Int(1), KeyPart::Int(23)]).unwrap();
+        let k4 = FeatureKey::new(1, &[KeyPart::Int(12), KeyPart::Int(3)]).unwrap();
+        assert_ne!(k3, k4);
+
+        let k5 = FeatureKey::new(1, &[KeyPart::Int(1)]).unwrap();
+        let k6 = FeatureKey::new(1, &[KeyPart::Text("1".to_string())]).unwrap();
+        assert_ne!(k5, k6);
+    }
+
+    #[test]
+    pub fn test_order_preserving() {
+        let ints = [i64::MIN, -100, -1, 0, 1, 100, i64::MAX];
+        let keys: Vec<FeatureKey> = ints.iter()
+            .map(|v| FeatureKey::new(1, &[KeyPart::Int(*v)]).unwrap())
+            .collect();
+        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+
+        let floats = [f64::NEG_INFINITY, -10.5, -0.1, 0.0, 0.1, 10.5, f64::INFINITY];
+        let keys: Vec<FeatureKey> = floats.iter()
+            .map(|v| FeatureKey::new(1, &[KeyPart::Float(*v)]).unwrap())
+            .collect();
+        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+
+        // 文本按字节序排序，与长度无关；后面还有其它字段时同样保序
+        let texts = ["", "\0", "\0a", "a", "a\0", "ab", "b"];
+        let keys: Vec<FeatureKey> = texts.iter()
+            .map(|v| FeatureKey::new(1, &[KeyPart::Text(v.to_string()), KeyPart::Int(i64::MIN)]).unwrap())
+            .collect();
+        assert!(keys.windows(2).all(|w| w[0] < w[1]), "{:?}", keys);
+
+        let k1 = FeatureKey::new(1, &[KeyPart::Int(i64::MAX)]).unwrap();
+        let k2 = FeatureKey::new(2, &[KeyPart::Int(i64::MIN)]).unwrap();
+        assert!(k1 < k2);
+    }
+
+    #[test]
+    pub fn test_encode_decode() {
+        let parts = vec![
+            KeyPart::Text("杨".to_string()),
+            KeyPart::Text("a\0b".to_string()),
+            KeyPart::Text(String::new()),
+            KeyPart::Int(-42),
+            KeyPart::Float(-3.5),
+            KeyPart::DateTime(1650000000000),
+        ];
+        let key = FeatureKey::new(10001, &parts).unwrap();
+        assert_eq!(key.feature_id(), 10001);
+        assert_eq!(key.parts().unwrap(), parts);
+
+        let mut buf = BytesMut::new();
+        key.encode(&mut buf).unwrap();
+        assert_eq!(buf.len(), key.need_space());
+        let mut cursor: Cursor<&[u8]> = Cursor::new(&*buf);
+        assert_eq!(FeatureKey::decode(&mut cursor).unwrap(), key);
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::RwLockWriteGuard;
+
+use crate::custom_error::{column_not_found_in_ds_err, CustomResult};
+use crate::ds::column::{ColumnType, get_value_as_key_part, get_value_as_u64};
+use crate::feature::build_group_key;
+use crate::feature::filter::Filter;
+use crate::feature::key::{FeatureKey, KeyPart};
+use crate::feature::value::{FeatureValue, Scalar, ValueKind};
+use crate::store::page::Page;
+use crate::store::slot::PAGE_SIZE;
+use crate::store::wal::WalFeatureUpdateValue;
+
+/// LAST_N最多保留的值个数
+pub const MAX_LAST_N: usize = 1000;
+
+/// 每个key的列表编码后的大小上限，超过时去掉最早的值；
+/// page按key拆分，单个key必须远小于PAGE_SIZE，且每次更新wal会记录整个列表两次（undo_v和redo_v）
+pub const MAX_LAST_N_BYTES: usize = (PAGE_SIZE / 8) as usize;
+
+/// 保留每个key最近n个值的指标模板，如最近10笔订单金额、最近5个登录ip
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LastNFeatureTemplate {
+    // 分组字段
+    pub group_keys: Vec<String>,
+    // 时间字段
+    pub time_key: String,
+    // 保留值的字段
+    pub value_key: String,
+    // 保留的个数
+    pub n: usize,
+    // 过滤条件，为空时所有事件都参与计算
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<Filter>,
+}
+
+impl LastNFeatureTemplate {
+    pub fn build_key(&self, event: &Value,
+                     feature_id: u64,
+                     column_type_map: &HashMap<String, ColumnType>) -> CustomResult<FeatureKey> {
+        build_group_key(feature_id, &self.group_keys, event, column_type_map)
+    }
+
+    pub async fn calc_and_update(&self, event: &Value,
+                                 column_type_map: &HashMap<String, ColumnType>,
+                                 key: &FeatureKey,
+                                 page: &mut RwLockWriteGuard<'_, Page>) -> CustomResult<WalFeatureUpdateValue> {
+        // page的写锁由调用方持有，这里直接修改
+        let update_res = match page.get_mut(key).await {
+            None => {
+                let mut sv = FeatureValue::new();
+                let update_res = self.calc(event, column_type_map, key, &mut sv)?;
+                page.put(key.clone(), sv).await?;
+                update_res
+            }
+            Some(sv) => {
+                self.calc(event, column_type_map, key, sv)?
+            }
+        };
+        Ok(update_res)
+    }
+
+    /// 事件时间
+    pub fn event_time(&self, event: &Value) -> CustomResult<u64> {
+        get_value_as_u64(event, &self.time_key)
+    }
+
+    /// 把事件中的值按事件时间插入列表
+    pub fn calc(&self, event: &Value, column_type_map: &HashMap<String, ColumnType>,
+                key: &FeatureKey, value: &mut FeatureValue) -> CustomResult<WalFeatureUpdateValue> {
+        let time = self.event_time(event)?;
+        let column_type = column_type_map.get(&self.value_key)
+            .ok_or(column_not_found_in_ds_err(&self.value_key))?;
+        let v = match get_value_as_key_part(event, &self.value_key, column_type)? {
+            KeyPart::Text(v) => Scalar::Text(v),
+            KeyPart::Int(v) => Scalar::Int(v),
+            KeyPart::Float(v) => Scalar::Float(v),
+            KeyPart::DateTime(v) => Scalar::DateTime(v),
+        };
+        value.push_last(key, time, v, self.n, MAX_LAST_N_BYTES)
+    }
+
+    /// 查询时间之前（含）的值，按时间从早到晚排列，没有时返回None
+    pub fn query(&self, value: Option<&FeatureValue>, time: u64) -> Option<ValueKind> {
+        match value.and_then(|v| v.get(0)) {
+            Some(ValueKind::List(items)) => {
+                let items: Vec<_> = items.iter().filter(|i| i.time <= time).cloned().collect();
+                if items.is_empty() { None } else { Some(ValueKind::List(items)) }
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use bytes::BytesMut;
+    use serde_json::json;
+    use std::io::Cursor;
+
+    use crate::ds::column::ColumnType;
+    use crate::feature::last_n_feature::{LastNFeatureTemplate, MAX_LAST_N_BYTES};
+    use crate::feature::value::{FeatureValue, Scalar, TimedValue, ValueKind};
+    use crate::store::Storable;
+    use crate::store::wal::WalFeatureUpdateValue;
+
+    #[test]
+    pub fn test_last_n() {
+        let template: LastNFeatureTemplate = serde_json::from_value(json!({
+            "group_keys": ["user_id"], "time_key": "ts", "value_key": "ip", "n": 3
+        })).unwrap();
+        let column_type_map = HashMap::from([
+            ("user_id".to_string(), ColumnType::INT),
+            ("ip".to_string(), ColumnType::TEXT),
+            ("ts".to_string(), ColumnType::DATETIME),
+        ]);
+        let event = |ip: &str, ts: u64| json!({"user_id": 1, "ip": ip, "ts": ts});
+        let key = template.build_key(&event("", 0), 1, &column_type_map).unwrap();
+        let ips = |v: &ValueKind| match v {
+            ValueKind::List(items) => items.iter().map(|i| match &i.value {
+                Scalar::Text(ip) => ip.clone(),
+                v => panic!("{:?}", v),
+            }).collect::<Vec<_>>(),
+            v => panic!("{:?}", v),
+        };
+
+        let mut value = FeatureValue::new();
+        let update = template.calc(&event("a", 10), &column_type_map, &key, &mut value).unwrap();
+        assert_eq!(update.undo_v, None);
+        template.calc(&event("b", 30), &column_type_map, &key, &mut value).unwrap();
+        // 乱序到达的事件按时间插入
+        template.calc(&event("c", 20), &column_type_map, &key, &mut value).unwrap();
+        let update = template.calc(&event("d", 40), &column_type_map, &key, &mut value).unwrap();
+        assert_eq!(ips(update.undo_v.as_ref().unwrap()), vec!["a", "c", "b"]);
+        assert_eq!(ips(&update.redo_v), vec!["c", "b", "d"]);
+        assert_eq!(ips(&template.query(Some(&value), 35).unwrap()), vec!["c", "b"]);
+        assert_eq!(template.query(Some(&value), 5), None);
+        assert!(template.calc(&json!({"user_id": 1, "ip": 1, "ts": 50}), &column_type_map, &key, &mut value).is_err());
+
+        // wal日志编码后还原
+        let mut buf = BytesMut::new();
+        update.encode(&mut buf).unwrap();
+        assert_eq!(buf.len(), update.need_space());
+        let decoded = WalFeatureUpdateValue::decode(&mut Cursor::new(&buf[..])).unwrap();
+        assert_eq!((decoded.undo_v, decoded.redo_v), (update.undo_v, update.redo_v));
+
+        let list = ValueKind::List(vec![
+            TimedValue { time: 1, value: Scalar::Int(-5) },
+            TimedValue { time: 2, value: Scalar::Float(1.5) },
+            TimedValue { time: 3, value: Scalar::DateTime(7) },
+        ]);
+        let mut buf = BytesMut::new();
+        list.encode(&mut buf).unwrap();
+        assert_eq!(buf.len(), list.need_space());
+        assert_eq!(ValueKind::decode(&mut Cursor::new(&buf[..])).unwrap(), list);
+        assert!(ValueKind::decode(&mut Cursor::new(&buf[..buf.len() - 1])).is_err());
+    }
+
+    #[test]
+    pub fn test_last_n_size_limit() {
+        let template: LastNFeatureTemplate = serde_json::from_value(json!({
+            "group_keys": ["user_id"], "time_key": "ts", "value_key": "ua", "n": 1000
+        })).unwrap();
+        let column_type_map = HashMap::from([
+            ("user_id".to_string(), ColumnType::INT),
+            ("ua".to_string(), ColumnType::TEXT),
+            ("ts".to_string(), ColumnType::DATETIME),
+        ]);
+        let event = |ua: String, ts: u64| json!({"user_id": 1, "ua": ua, "ts": ts});
+        let key = template.build_key(&event(String::new(), 0), 1, &column_type_map).unwrap();
+
+        // 1000个70字节的值超过上限，只保留最近的
+        let mut value = FeatureValue::new();
+        for ts in 0..1000 {
+            template.calc(&event(format!("{:070}", ts), ts), &column_type_map, &key, &mut value).unwrap();
+        }
+        let items = match value.get(0) {
+            Some(ValueKind::List(items)) => items.clone(),
+            v => panic!("{:?}", v),
+        };
+        assert!(items.len() < 1000);
+        assert!(value.get(0).unwrap().need_space() <= MAX_LAST_N_BYTES);
+        assert_eq!(items.last().unwrap().value, Scalar::Text(format!("{:070}", 999)));
+        assert_eq!(items.first().unwrap().time, 1000 - items.len() as u64);
+
+        // 单个值超过上限时拒绝，列表不变
+        let before = value.get(0).cloned();
+        assert!(template.calc(&event("x".repeat(MAX_LAST_N_BYTES), 1000), &column_type_map, &key, &mut value).is_err());
+        assert_eq!(value.get(0).cloned(), before);
+    }
+}
@@ -3,26 +3,44 @@ use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-use crate::custom_error::{CustomResult};
-use crate::ds::column::{ ColumnType};
+use crate::custom_error::{column_not_found_in_ds_err, common_err, CustomResult};
+use crate::ds::column::{ColumnType, get_value_as_key_part};
 use crate::feature::count_feature::CountFeatureTemplate;
+use crate::feature::derived_feature::DerivedFeatureTemplate;
+use crate::feature::filter::Filter;
+use crate::feature::last_n_feature::LastNFeatureTemplate;
+use crate::feature::seen_feature::SeenFeatureTemplate;
+use crate::feature::stddev_feature::StddevFeatureTemplate;
+use crate::feature::key::FeatureKey;
+use crate::feature::value::{FeatureValue, ValueKind};
 
 use crate::store::wal::{Wal, WalFeatureUpdateValue};
-use crate::feature::FeatureTemplate::COUNT;
+use crate::feature::FeatureTemplate::{COUNT, DERIVED, LAST_N, SEEN, STDDEV};
 
 use crate::store::page::Page;
 use tokio::sync::RwLockWriteGuard;
 
 pub mod count_feature;
+pub mod derived_feature;
+pub mod filter;
+pub mod key;
+pub mod last_n_feature;
+pub mod seen_feature;
+pub mod stddev_feature;
 pub mod value;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[allow(non_camel_case_types)]
 pub enum FeatureTemplate {
     COUNT(CountFeatureTemplate),
+    DERIVED(DerivedFeatureTemplate),
+    LAST_N(LastNFeatureTemplate),
+    SEEN(SeenFeatureTemplate),
+    STDDEV(StddevFeatureTemplate),
 }
 
 /// 指标实例
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Feature {
     pub id: u64,
     pub name: String,
@@ -31,19 +49,158 @@ pub struct Feature {
 
 impl Feature {
 
-    pub fn build_key(&self, event: &Value, column_type_map: &HashMap<String, ColumnType>) -> CustomResult<String>{
+    pub fn build_key(&self, event: &Value, column_type_map: &HashMap<String, ColumnType>) -> CustomResult<FeatureKey>{
         match &self.template {
-            COUNT(cf) => cf.build_key(event, self.id, column_type_map)
+            COUNT(cf) => cf.build_key(event, self.id, column_type_map),
+            DERIVED(df) => build_group_key(self.id, &df.group_keys, event, column_type_map),
+            LAST_N(lf) => lf.build_key(event, self.id, column_type_map),
+            SEEN(sf) => sf.build_key(event, self.id, column_type_map),
+            STDDEV(vf) => vf.build_key(event, self.id, column_type_map),
         }
     }
 
+    /// 计算并更新page中的指标值，事件不满足过滤条件时不更新，返回None；派生指标没有存储，不更新
     pub async fn calc_and_update(&self, event: &Value,
                                  column_type_map: &HashMap<String, ColumnType>,
-                                 key:&String,
+                                 key:&FeatureKey,
                                  page:&mut RwLockWriteGuard<'_,Page>,
-                                 wal: &Wal) -> CustomResult<WalFeatureUpdateValue> {
+                                 wal: &Wal) -> CustomResult<Option<WalFeatureUpdateValue>> {
+        if !self.matches(event, column_type_map)? {
+            return Ok(None);
+        }
+        let update = match &self.template {
+            COUNT(cf) => cf.calc_and_update(event, column_type_map,key, page, wal).await?,
+            LAST_N(lf) => lf.calc_and_update(event, column_type_map, key, page).await?,
+            SEEN(sf) => sf.calc_and_update(event, key, page).await?,
+            STDDEV(vf) => vf.calc_and_update(event, column_type_map, key, page).await?,
+            DERIVED(_) => return Ok(None),
+        };
+        Ok(Some(update))
+    }
+
+    /// 不依赖page计算指标，用于离线重放事件；事件不满足过滤条件时返回None
+    pub fn calc(&self, event: &Value, column_type_map: &HashMap<String, ColumnType>,
+                key: &FeatureKey, value: &mut FeatureValue) -> CustomResult<Option<WalFeatureUpdateValue>> {
+        if !self.matches(event, column_type_map)? {
+            return Ok(None);
+        }
+        let update = match &self.template {
+            COUNT(cf) => cf.calc(event, key, value)?,
+            LAST_N(lf) => lf.calc(event, column_type_map, key, value)?,
+            SEEN(sf) => sf.calc(event, key, value)?,
+            STDDEV(vf) => vf.calc(event, column_type_map, key, value)?,
+            DERIVED(_) => return Ok(None),
+        };
+        Ok(Some(update))
+    }
+
+    /// 事件是否满足指标的过滤条件，没有过滤条件时都满足
+    pub fn matches(&self, event: &Value, column_type_map: &HashMap<String, ColumnType>) -> CustomResult<bool> {
+        match self.filter() {
+            Some(filter) => filter.matches(event, column_type_map),
+            None => Ok(true),
+        }
+    }
+
+    /// 过滤条件
+    pub fn filter(&self) -> Option<&Filter> {
+        match &self.template {
+            COUNT(cf) => cf.filter.as_ref(),
+            LAST_N(lf) => lf.filter.as_ref(),
+            SEEN(sf) => sf.filter.as_ref(),
+            STDDEV(vf) => vf.filter.as_ref(),
+            DERIVED(_) => None,
+        }
+    }
+
+    /// 时间字段，派生指标没有
+    pub fn time_key(&self) -> Option<&str> {
+        match &self.template {
+            COUNT(cf) => Some(&cf.time_key),
+            LAST_N(lf) => Some(&lf.time_key),
+            SEEN(sf) => Some(&sf.time_key),
+            STDDEV(vf) => Some(&vf.time_key),
+            DERIVED(_) => None,
+        }
+    }
+
+    /// 事件发生的时间，毫秒
+    pub fn event_time(&self, event: &Value) -> CustomResult<u64> {
+        match &self.template {
+            COUNT(cf) => cf.event_time(event),
+            LAST_N(lf) => lf.event_time(event),
+            SEEN(sf) => sf.event_time(event),
+            STDDEV(vf) => vf.event_time(event),
+            DERIVED(_) => Err(common_err(format!("派生指标:{} 没有时间字段", self.id))),
+        }
+    }
+
+    /// 分组字段，与key中的值一一对应
+    pub fn group_keys(&self) -> &[String] {
         match &self.template {
-            COUNT(cf) => cf.calc_and_update(event, column_type_map,key, page, wal).await
+            COUNT(cf) => &cf.group_keys,
+            DERIVED(df) => &df.group_keys,
+            LAST_N(lf) => &lf.group_keys,
+            SEEN(sf) => &sf.group_keys,
+            STDDEV(vf) => &vf.group_keys,
         }
     }
+
+    /// 指标值的类型，列表按JSON文本输出
+    pub fn value_type(&self) -> ColumnType {
+        match &self.template {
+            COUNT(_) | SEEN(_) => ColumnType::INT,
+            DERIVED(_) | STDDEV(_) => ColumnType::FLOAT,
+            LAST_N(_) => ColumnType::TEXT,
+        }
+    }
+
+    /// 派生指标的模板，不是派生指标时返回None
+    pub fn derived(&self) -> Option<&DerivedFeatureTemplate> {
+        match &self.template {
+            DERIVED(df) => Some(df),
+            _ => None,
+        }
+    }
+
+    /// 查询指标在指定时间的值，value为空表示该key还没有数据；派生指标需要先查询引用的指标，再调用DerivedFeatureTemplate::eval
+    pub fn query(&self, value: Option<&FeatureValue>, time: u64) -> CustomResult<Option<ValueKind>> {
+        match &self.template {
+            COUNT(cf) => Ok(cf.query(value, time)),
+            LAST_N(lf) => Ok(lf.query(value, time)),
+            SEEN(sf) => Ok(sf.query(value, time)),
+            STDDEV(vf) => Ok(vf.query(value, time)),
+            DERIVED(_) => Err(common_err(format!("派生指标:{} 没有存储，需要由引用的指标计算", self.id))),
+        }
+    }
+}
+
+/// 按分组字段拼接主键
+pub fn build_group_key(feature_id: u64, group_keys: &[String], event: &Value,
+                       column_type_map: &HashMap<String, ColumnType>) -> CustomResult<FeatureKey> {
+    let mut parts = Vec::with_capacity(group_keys.len());
+    for k in group_keys {
+        let column_type = column_type_map.get(k)
+            .ok_or(column_not_found_in_ds_err(k))?;
+
+        parts.push(get_value_as_key_part(event, k, column_type)?);
+    }
+    FeatureKey::new(feature_id, &parts)
+}
+
+/// 查询请求，keys中包含指标分组字段的值
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FeatureQuery {
+    pub ds: i64,
+    pub feature_id: u64,
+    pub keys: Value,
+    // 查询时间，毫秒
+    pub time: u64,
+}
+
+/// 查询结果
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FeatureQueryResult {
+    pub feature_id: u64,
+    pub value: Option<ValueKind>,
 }
\ No newline at end of file
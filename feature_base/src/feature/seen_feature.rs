@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::RwLockWriteGuard;
+
+use crate::custom_error::CustomResult;
+use crate::ds::column::{ColumnType, get_value_as_u64};
+use crate::feature::build_group_key;
+use crate::feature::filter::Filter;
+use crate::feature::key::FeatureKey;
+use crate::feature::value::{FeatureValue, ValueKind};
+use crate::store::page::Page;
+use crate::store::wal::WalFeatureUpdateValue;
+use crate::WindowUnit;
+
+/// 查询返回的值
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum SeenOutput {
+    // 首次出现的时间，毫秒
+    FirstTime,
+    // 最近一次出现的时间，毫秒
+    LastTime,
+    // 查询时间距首次出现的间隔
+    SinceFirst,
+    // 查询时间距最近一次出现的间隔
+    SinceLast,
+}
+
+/// 记录每个key首次和最近一次出现时间的指标模板，如用户首单距今天数、距上次登录的秒数。
+///
+/// 间隔相对查询请求中的时间计算，按unit向下取整，不指定unit时为毫秒
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SeenFeatureTemplate {
+    // 分组字段
+    pub group_keys: Vec<String>,
+    // 时间字段
+    pub time_key: String,
+    // 查询返回的值
+    pub output: SeenOutput,
+    // 间隔的单位
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit: Option<WindowUnit>,
+    // 过滤条件，为空时所有事件都参与计算
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<Filter>,
+}
+
+impl SeenFeatureTemplate {
+    pub fn build_key(&self, event: &Value,
+                     feature_id: u64,
+                     column_type_map: &HashMap<String, ColumnType>) -> CustomResult<FeatureKey> {
+        build_group_key(feature_id, &self.group_keys, event, column_type_map)
+    }
+
+    pub async fn calc_and_update(&self, event: &Value,
+                                 key: &FeatureKey,
+                                 page: &mut RwLockWriteGuard<'_, Page>) -> CustomResult<WalFeatureUpdateValue> {
+        // page的写锁由调用方持有，这里直接修改
+        let update_res = match page.get_mut(key).await {
+            None => {
+                let mut sv = FeatureValue::new();
+                let update_res = self.calc(event, key, &mut sv)?;
+                page.put(key.clone(), sv).await?;
+                update_res
+            }
+            Some(sv) => {
+                self.calc(event, key, sv)?
+            }
+        };
+        Ok(update_res)
+    }
+
+    /// 事件时间
+    pub fn event_time(&self, event: &Value) -> CustomResult<u64> {
+        get_value_as_u64(event, &self.time_key)
+    }
+
+    /// 用事件时间更新首次和最近一次出现的时间
+    pub fn calc(&self, event: &Value, key: &FeatureKey, value: &mut FeatureValue) -> CustomResult<WalFeatureUpdateValue> {
+        let time = self.event_time(event)?;
+        value.update_seen(key, time)
+    }
+
+    /// 首次出现晚于查询时间时返回None；最近一次出现晚于查询时间时间隔为0
+    pub fn query(&self, value: Option<&FeatureValue>, time: u64) -> Option<ValueKind> {
+        let (first, last) = match value.and_then(|v| v.get(0)) {
+            Some(ValueKind::Seen { first, last }) if *first <= time => (*first, *last),
+            _ => return None,
+        };
+        let unit = self.unit.as_ref().map_or(1, |u| u.to_millis(1));
+        let res = match self.output {
+            SeenOutput::FirstTime => first,
+            SeenOutput::LastTime => last,
+            SeenOutput::SinceFirst => (time - first) / unit,
+            SeenOutput::SinceLast => time.saturating_sub(last) / unit,
+        };
+        Some(ValueKind::Int(res))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::Cursor;
+
+    use bytes::BytesMut;
+    use serde_json::json;
+
+    use crate::ds::column::ColumnType;
+    use crate::feature::seen_feature::{SeenFeatureTemplate, SeenOutput};
+    use crate::feature::value::{FeatureValue, ValueKind};
+    use crate::store::Storable;
+    use crate::store::wal::WalFeatureUpdateValue;
+
+    #[test]
+    pub fn test_seen() {
+        let mut template: SeenFeatureTemplate = serde_json::from_value(json!({
+            "group_keys": ["user_id"], "time_key": "ts", "output": "SINCE_FIRST", "unit": "DAY"
+        })).unwrap();
+        let column_type_map = HashMap::from([("user_id".to_string(), ColumnType::INT), ("ts".to_string(), ColumnType::DATETIME)]);
+        let day = 24 * 3600 * 1000u64;
+        let t0 = 1650000000000u64;
+        let event = |ts: u64| json!({"user_id": 1, "ts": ts});
+        let key = template.build_key(&event(t0), 1, &column_type_map).unwrap();
+
+        let mut value = FeatureValue::new();
+        let update = template.calc(&event(t0 + 2 * day), &key, &mut value).unwrap();
+        assert_eq!((update.undo_v, update.redo_v), (None, ValueKind::Seen { first: t0 + 2 * day, last: t0 + 2 * day }));
+        template.calc(&event(t0 + 5 * day), &key, &mut value).unwrap();
+        // 乱序到达的更早的事件
+        let update = template.calc(&event(t0), &key, &mut value).unwrap();
+        assert_eq!(update.undo_v, Some(ValueKind::Seen { first: t0 + 2 * day, last: t0 + 5 * day }));
+        assert_eq!(update.redo_v, ValueKind::Seen { first: t0, last: t0 + 5 * day });
+
+        let now = t0 + 10 * day + 1;
+        assert_eq!(template.query(Some(&value), now), Some(ValueKind::Int(10)));
+        assert_eq!(template.query(Some(&value), t0 - 1), None);
+        template.output = SeenOutput::SinceLast;
+        assert_eq!(template.query(Some(&value), now), Some(ValueKind::Int(5)));
+        assert_eq!(template.query(Some(&value), t0 + day), Some(ValueKind::Int(0)));
+        template.output = SeenOutput::LastTime;
+        assert_eq!(template.query(Some(&value), now), Some(ValueKind::Int(t0 + 5 * day)));
+        template.unit = None;
+        template.output = SeenOutput::SinceFirst;
+        assert_eq!(template.query(Some(&value), now), Some(ValueKind::Int(10 * day + 1)));
+
+        let mut buf = BytesMut::new();
+        update.encode(&mut buf).unwrap();
+        assert_eq!(buf.len(), update.need_space());
+        let decoded = WalFeatureUpdateValue::decode(&mut Cursor::new(&buf[..])).unwrap();
+        assert_eq!((decoded.undo_v, decoded.redo_v), (update.undo_v, update.redo_v));
+    }
+}
@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::RwLockWriteGuard;
+
+use crate::custom_error::{column_not_found_in_ds_err, common_err, CustomResult};
+use crate::ds::column::{ColumnType, get_value_as_key_part, get_value_as_u64};
+use crate::feature::build_group_key;
+use crate::feature::filter::Filter;
+use crate::feature::key::{FeatureKey, KeyPart};
+use crate::feature::value::{FeatureValue, ValueKind};
+use crate::store::page::Page;
+use crate::store::wal::WalFeatureUpdateValue;
+use crate::WindowUnit;
+
+/// 查询返回的值
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum StddevOutput {
+    // 方差
+    Variance,
+    // 标准差
+    Stddev,
+}
+
+/// 窗口内数值的方差或标准差指标模板，如用户30天内交易金额的标准差。
+///
+/// 按一个window_unit分片，每个分片保存个数、均值和离差平方和，查询时合并窗口内的分片，按总体方差计算
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StddevFeatureTemplate {
+    // 分组字段
+    pub group_keys: Vec<String>,
+    // 时间字段
+    pub time_key: String,
+    // 统计的数值字段，INT或FLOAT
+    pub value_key: String,
+    // 时间单位
+    pub window_unit: WindowUnit,
+    // 窗口大小
+    pub window_size: u64,
+    // 查询返回的值
+    pub output: StddevOutput,
+    // 过滤条件，为空时所有事件都参与计算
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<Filter>,
+}
+
+impl StddevFeatureTemplate {
+    pub fn build_key(&self, event: &Value,
+                     feature_id: u64,
+                     column_type_map: &HashMap<String, ColumnType>) -> CustomResult<FeatureKey> {
+        build_group_key(feature_id, &self.group_keys, event, column_type_map)
+    }
+
+    pub async fn calc_and_update(&self, event: &Value,
+                                 column_type_map: &HashMap<String, ColumnType>,
+                                 key: &FeatureKey,
+                                 page: &mut RwLockWriteGuard<'_, Page>) -> CustomResult<WalFeatureUpdateValue> {
+        // page的写锁由调用方持有，这里直接修改
+        let update_res = match page.get_mut(key).await {
+            None => {
+                let mut sv = FeatureValue::new();
+                let update_res = self.calc(event, column_type_map, key, &mut sv)?;
+                page.put(key.clone(), sv).await?;
+                update_res
+            }
+            Some(sv) => {
+                self.calc(event, column_type_map, key, sv)?
+            }
+        };
+        Ok(update_res)
+    }
+
+    /// 事件时间
+    pub fn event_time(&self, event: &Value) -> CustomResult<u64> {
+        get_value_as_u64(event, &self.time_key)
+    }
+
+    /// 把事件中的数值合并到所在时间分片
+    pub fn calc(&self, event: &Value, column_type_map: &HashMap<String, ColumnType>,
+                key: &FeatureKey, value: &mut FeatureValue) -> CustomResult<WalFeatureUpdateValue> {
+        let time = self.event_time(event)?;
+        let column_type = column_type_map.get(&self.value_key)
+            .ok_or(column_not_found_in_ds_err(&self.value_key))?;
+        let v = match get_value_as_key_part(event, &self.value_key, column_type)? {
+            KeyPart::Int(v) => v as f64,
+            KeyPart::Float(v) => v,
+            v => return Err(common_err(format!("字段:{} 的值:{:?} 不是数字", self.value_key, v))),
+        };
+        value.add_stats(key, time, self.window_unit.to_millis(1), v)
+    }
+
+    /// 合并窗口内的分片，没有数据时返回None
+    pub fn query(&self, value: Option<&FeatureValue>, time: u64) -> Option<ValueKind> {
+        let window_size = self.window_unit.to_millis(self.window_size);
+        match value.and_then(|v| v.sum_window(time, window_size)) {
+            Some(ValueKind::Stats { count, m2, .. }) if count > 0 => {
+                // 浮点误差可能使离差平方和略小于0
+                let variance = m2.max(0.0) / count as f64;
+                Some(ValueKind::Float(match self.output {
+                    StddevOutput::Variance => variance,
+                    StddevOutput::Stddev => variance.sqrt(),
+                }))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::Cursor;
+
+    use bytes::BytesMut;
+    use serde_json::json;
+
+    use crate::ds::column::ColumnType;
+    use crate::feature::stddev_feature::{StddevFeatureTemplate, StddevOutput};
+    use crate::feature::value::{FeatureValue, ValueKind};
+    use crate::store::Storable;
+    use crate::store::wal::WalFeatureUpdateValue;
+
+    #[test]
+    pub fn test_stddev() {
+        let mut template: StddevFeatureTemplate = serde_json::from_value(json!({
+            "group_keys": ["user_id"], "time_key": "ts", "value_key": "amount",
+            "window_unit": "DAY", "window_size": 30, "output": "VARIANCE"
+        })).unwrap();
+        let column_type_map = HashMap::from([
+            ("user_id".to_string(), ColumnType::INT),
+            ("amount".to_string(), ColumnType::FLOAT),
+            ("ts".to_string(), ColumnType::DATETIME),
+        ]);
+        let day = 24 * 3600 * 1000u64;
+        let t0 = 1650000000000u64 - 1650000000000u64 % day;
+        let event = |amount: f64, ts: u64| json!({"user_id": 1, "amount": amount, "ts": ts});
+        let key = template.build_key(&event(0.0, t0), 1, &column_type_map).unwrap();
+        let approx = |res: Option<ValueKind>, expected: f64| match res {
+            Some(ValueKind::Float(v)) => assert!((v - expected).abs() < 1e-9, "{} != {}", v, expected),
+            v => panic!("{:?}", v),
+        };
+
+        // 分布在三个分片中的 2,4,4,4,5,5,7,9，总体方差为4
+        let mut value = FeatureValue::new();
+        let mut updates = vec![];
+        for (amount, d) in [(2.0, 0), (4.0, 0), (4.0, 1), (4.0, 1), (5.0, 1), (5.0, 2), (7.0, 2), (9.0, 2)] {
+            updates.push(template.calc(&event(amount, t0 + d * day), &column_type_map, &key, &mut value).unwrap());
+        }
+        let now = t0 + 2 * day;
+        approx(template.query(Some(&value), now), 4.0);
+        template.output = StddevOutput::Stddev;
+        approx(template.query(Some(&value), now), 2.0);
+        // 只包含第一个分片：2,4
+        approx(template.query(Some(&value), t0), 1.0);
+        assert_eq!(template.query(Some(&value), t0 - 1), None);
+        // 窗口滑过前两个分片后只剩 5,7,9
+        template.output = StddevOutput::Variance;
+        approx(template.query(Some(&value), t0 + 31 * day), 8.0 / 3.0);
+        assert!(template.calc(&json!({"user_id": 1, "amount": "x", "ts": t0}), &column_type_map, &key, &mut value).is_err());
+
+        // 按undo日志逆序回滚最后两次更新，再按redo日志重做
+        let mut rolled = value.clone();
+        for update in updates.iter().rev().take(2) {
+            match &update.undo_v {
+                Some(v) => rolled.set(update.tk, v.clone()),
+                None => panic!("{:?}", update),
+            }
+        }
+        // 剩下 2,4,4,4,5,5
+        approx(template.query(Some(&rolled), now), 1.0);
+        for update in updates.iter().skip(6) {
+            rolled.set(update.tk, update.redo_v.clone());
+        }
+        approx(template.query(Some(&rolled), now), 4.0);
+
+        let update = updates.last().unwrap();
+        let mut buf = BytesMut::new();
+        update.encode(&mut buf).unwrap();
+        assert_eq!(buf.len(), update.need_space());
+        let decoded = WalFeatureUpdateValue::decode(&mut Cursor::new(&buf[..])).unwrap();
+        assert_eq!((decoded.undo_v, decoded.redo_v), (update.undo_v.clone(), update.redo_v.clone()));
+
+        let mut buf = BytesMut::new();
+        update.redo_v.encode(&mut buf).unwrap();
+        assert_eq!(buf.len(), update.redo_v.need_space());
+        assert!(ValueKind::decode(&mut Cursor::new(&buf[..buf.len() - 1])).is_err());
+    }
+}
@@ -1,24 +1,117 @@
 
 use std::collections::BTreeMap;
+use std::ops::Bound;
 
 use serde::{Deserialize, Serialize};
 
 
-use crate::custom_error::{common_err, CustomResult};
+use crate::custom_error::{common_err, CustomResult, decode_failed_by_insufficient_data_err};
+use crate::feature::key::FeatureKey;
 use crate::store::Storable;
 use crate::store::wal::{ WalFeatureUpdateValue};
 use bytes::{BytesMut, BufMut, Buf};
 use std::io::Cursor;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum ValueKind {
     Int(u64),
     Float(f64),
+    // 按时间排序的最近几个值，LAST_N使用
+    List(Vec<TimedValue>),
+    // 首次和最近一次出现的时间，SEEN使用
+    Seen { first: u64, last: u64 },
+    // 分片内数值的个数、均值和离差平方和（Welford），STDDEV使用
+    Stats { count: u64, mean: f64, m2: f64 },
+}
+
+/// 带类型的单个字段值
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum Scalar {
+    Text(String),
+    Int(i64),
+    Float(f64),
+    DateTime(u64),
+}
+
+/// 带事件时间的字段值
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TimedValue {
+    pub time: u64,
+    pub value: Scalar,
 }
 
 /// ValueKind序列号的代码
 const VALUE_KIND_INT: u8 = 1;
 const VALUE_KIND_FLOAT: u8 = 2;
+const VALUE_KIND_LIST: u8 = 3;
+const VALUE_KIND_SEEN: u8 = 4;
+const VALUE_KIND_STATS: u8 = 5;
+
+/// Scalar序列化的代码
+const SCALAR_TEXT: u8 = 1;
+const SCALAR_INT: u8 = 2;
+const SCALAR_FLOAT: u8 = 3;
+const SCALAR_DATETIME: u8 = 4;
+
+impl Storable for Scalar {
+    fn encode(&self, buf: &mut BytesMut) -> CustomResult<()> {
+        match self {
+            Scalar::Text(v) => {
+                if v.len() > u16::MAX as usize {
+                    return Err(common_err(format!("字段值过长:{}", v.len())));
+                }
+                buf.put_u8(SCALAR_TEXT);
+                buf.put_u16(v.len() as u16);
+                buf.put(v.as_bytes());
+            }
+            Scalar::Int(v) => {
+                buf.put_u8(SCALAR_INT);
+                buf.put_i64(*v);
+            }
+            Scalar::Float(v) => {
+                buf.put_u8(SCALAR_FLOAT);
+                buf.put_f64(*v);
+            }
+            Scalar::DateTime(v) => {
+                buf.put_u8(SCALAR_DATETIME);
+                buf.put_u64(*v);
+            }
+        }
+        Ok(())
+    }
+
+    fn decode(buf: &mut Cursor<&[u8]>) -> CustomResult<Scalar> {
+        if buf.remaining() < 1 {
+            return Err(decode_failed_by_insufficient_data_err());
+        }
+        let tag = buf.get_u8();
+        let need = if tag == SCALAR_TEXT { 2 } else { 8 };
+        if buf.remaining() < need {
+            return Err(decode_failed_by_insufficient_data_err());
+        }
+        match tag {
+            SCALAR_TEXT => {
+                let len = buf.get_u16() as usize;
+                if buf.remaining() < len {
+                    return Err(decode_failed_by_insufficient_data_err());
+                }
+                let bytes = buf.copy_to_bytes(len);
+                Ok(Scalar::Text(String::from_utf8(bytes.to_vec())?))
+            }
+            SCALAR_INT => Ok(Scalar::Int(buf.get_i64())),
+            SCALAR_FLOAT => Ok(Scalar::Float(buf.get_f64())),
+            SCALAR_DATETIME => Ok(Scalar::DateTime(buf.get_u64())),
+            _ => Err(common_err(format!("反序列化失败，不识别的值类型：{}", tag)))
+        }
+    }
+
+    fn need_space(&self) -> usize {
+        match self {
+            Scalar::Text(v) => 1 + 2 + v.len(),
+            _ => 1 + 8,
+        }
+    }
+}
 
 impl Storable for ValueKind {
     fn encode(&self, buf: &mut BytesMut) -> CustomResult<()> {
@@ -31,20 +124,73 @@ impl Storable for ValueKind {
                 buf.put_u8(VALUE_KIND_FLOAT);
                 buf.put_f64(*v);
             }
+            ValueKind::List(items) => {
+                if items.len() > u16::MAX as usize {
+                    return Err(common_err(format!("列表过长:{}", items.len())));
+                }
+                buf.put_u8(VALUE_KIND_LIST);
+                buf.put_u16(items.len() as u16);
+                for item in items {
+                    buf.put_u64(item.time);
+                    item.value.encode(buf)?;
+                }
+            }
+            ValueKind::Seen { first, last } => {
+                buf.put_u8(VALUE_KIND_SEEN);
+                buf.put_u64(*first);
+                buf.put_u64(*last);
+            }
+            ValueKind::Stats { count, mean, m2 } => {
+                buf.put_u8(VALUE_KIND_STATS);
+                buf.put_u64(*count);
+                buf.put_f64(*mean);
+                buf.put_f64(*m2);
+            }
         };
         Ok(())
     }
     fn decode(buf: &mut Cursor<&[u8]>) -> CustomResult<ValueKind> {
+        if buf.remaining() < 1 {
+            return Err(decode_failed_by_insufficient_data_err());
+        }
         let kind_num = buf.get_u8();
+        let need = match kind_num {
+            VALUE_KIND_LIST => 2,
+            VALUE_KIND_SEEN => 16,
+            VALUE_KIND_STATS => 24,
+            _ => 8,
+        };
+        if buf.remaining() < need {
+            return Err(decode_failed_by_insufficient_data_err());
+        }
         match kind_num {
             VALUE_KIND_INT => Ok(ValueKind::Int(buf.get_u64())),
             VALUE_KIND_FLOAT => Ok(ValueKind::Float(buf.get_f64())),
+            VALUE_KIND_LIST => {
+                let len = buf.get_u16() as usize;
+                let mut items = Vec::with_capacity(len);
+                for _ in 0..len {
+                    if buf.remaining() < 8 {
+                        return Err(decode_failed_by_insufficient_data_err());
+                    }
+                    let time = buf.get_u64();
+                    items.push(TimedValue { time, value: Scalar::decode(buf)? });
+                }
+                Ok(ValueKind::List(items))
+            }
+            VALUE_KIND_SEEN => Ok(ValueKind::Seen { first: buf.get_u64(), last: buf.get_u64() }),
+            VALUE_KIND_STATS => Ok(ValueKind::Stats { count: buf.get_u64(), mean: buf.get_f64(), m2: buf.get_f64() }),
             _ => Err(common_err(format!("反序列化失败，不识别的kind_num：{}", kind_num)))
         }
     }
 
     fn need_space(&self) -> usize {
-        9 as usize
+        match self {
+            ValueKind::List(items) => 1 + 2 + items.iter().map(|i| 8 + i.value.need_space()).sum::<usize>(),
+            ValueKind::Seen { .. } => 1 + 16,
+            ValueKind::Stats { .. } => 1 + 24,
+            _ => 9,
+        }
     }
 }
 
@@ -56,59 +202,185 @@ impl FeatureValue {
         FeatureValue(BTreeMap::new())
     }
 
-    pub fn add_int(&self, key: &String, time: u64, window_size: u64, value: u64) ->CustomResult<WalFeatureUpdateValue> {
+    pub fn add_int(&mut self, key: &FeatureKey, time: u64, window_size: u64, value: u64) -> CustomResult<WalFeatureUpdateValue> {
         let t = time - time % window_size;
 
         let res = match self.0.get(&t) {
-            None => unsafe {
-                let mutable_t: &mut FeatureValue = &mut *(self as *const Self as *mut Self);
+            None => {
                 let new_value = ValueKind::Int(value);
-                mutable_t.0.insert(t, new_value.clone());
+                self.0.insert(t, new_value.clone());
 
-                Ok(WalFeatureUpdateValue{
-                    fk:key.clone(),
-                    tk:t,
+                Ok(WalFeatureUpdateValue {
+                    fk: key.clone(),
+                    tk: t,
                     undo_v: None,
-                    redo_v: new_value
+                    redo_v: new_value,
                 })
             }
-            Some(value_kind) => unsafe {
-                let mutable_t: &mut FeatureValue = &mut *(self as *const Self as *mut Self);
-                if let ValueKind::Int(v) = value_kind {
-                    let new_value = ValueKind::Int(v + value);
-                    mutable_t.0.insert(t, new_value.clone());
-                    Ok(WalFeatureUpdateValue{
-                        fk:key.clone(),
-                        tk:t,
-                        undo_v: Some(ValueKind::Int(*v)),
-                        redo_v: new_value
-                    })
-                } else {
-                    Err(common_err(format!("value_kind 类型不匹配！")))
-                }
+            Some(ValueKind::Int(v)) => {
+                let old_v = *v;
+                let new_value = ValueKind::Int(old_v + value);
+                self.0.insert(t, new_value.clone());
+                Ok(WalFeatureUpdateValue {
+                    fk: key.clone(),
+                    tk: t,
+                    undo_v: Some(ValueKind::Int(old_v)),
+                    redo_v: new_value,
+                })
             }
+            Some(_) => Err(common_err("value_kind 类型不匹配！".to_string()))
         };
         res
     }
 
-    pub fn add_float(&self, time: u64, window_size: u64, value: f64) {
+    /// 把带时间的值插入列表，保持按时间排序，超过n个或编码后超过max_bytes时去掉最早的；列表保存在时间分片0中
+    pub fn push_last(&mut self, key: &FeatureKey, time: u64, value: Scalar, n: usize, max_bytes: usize) -> CustomResult<WalFeatureUpdateValue> {
+        let undo_v = self.0.get(&0).cloned();
+        let mut items = match &undo_v {
+            None => vec![],
+            Some(ValueKind::List(items)) => items.clone(),
+            Some(_) => return Err(common_err("value_kind 类型不匹配！".to_string())),
+        };
+        // 时间相同的值排在之前的值后面
+        let index = items.partition_point(|i| i.time <= time);
+        items.insert(index, TimedValue { time, value });
+        if items.len() > n {
+            items.drain(..items.len() - n);
+        }
+        let mut size = ValueKind::List(vec![]).need_space() + items.iter().map(|i| 8 + i.value.need_space()).sum::<usize>();
+        let mut evict = 0;
+        while size > max_bytes && evict + 1 < items.len() {
+            size -= 8 + items[evict].value.need_space();
+            evict += 1;
+        }
+        if size > max_bytes {
+            return Err(common_err(format!("值编码后的大小:{} 超过上限:{}", size, max_bytes)));
+        }
+        items.drain(..evict);
+        let redo_v = ValueKind::List(items);
+        self.0.insert(0, redo_v.clone());
+        Ok(WalFeatureUpdateValue {
+            fk: key.clone(),
+            tk: 0,
+            undo_v,
+            redo_v,
+        })
+    }
+
+    /// 记录一次出现，更新首次和最近一次出现的时间，保存在时间分片0中
+    pub fn update_seen(&mut self, key: &FeatureKey, time: u64) -> CustomResult<WalFeatureUpdateValue> {
+        let undo_v = self.0.get(&0).cloned();
+        let redo_v = match &undo_v {
+            None => ValueKind::Seen { first: time, last: time },
+            Some(ValueKind::Seen { first, last }) => ValueKind::Seen { first: time.min(*first), last: time.max(*last) },
+            Some(_) => return Err(common_err("value_kind 类型不匹配！".to_string())),
+        };
+        self.0.insert(0, redo_v.clone());
+        Ok(WalFeatureUpdateValue {
+            fk: key.clone(),
+            tk: 0,
+            undo_v,
+            redo_v,
+        })
+    }
+
+    /// 把一个数值合并到所在时间分片的统计值中
+    pub fn add_stats(&mut self, key: &FeatureKey, time: u64, bucket_size: u64, value: f64) -> CustomResult<WalFeatureUpdateValue> {
+        let t = time - time % bucket_size;
+        let undo_v = self.0.get(&t).cloned();
+        let single = ValueKind::Stats { count: 1, mean: value, m2: 0.0 };
+        let redo_v = match &undo_v {
+            None => single,
+            Some(old @ ValueKind::Stats { .. }) => merge_stats(old, &single),
+            Some(_) => return Err(common_err("value_kind 类型不匹配！".to_string())),
+        };
+        self.0.insert(t, redo_v.clone());
+        Ok(WalFeatureUpdateValue {
+            fk: key.clone(),
+            tk: t,
+            undo_v,
+            redo_v,
+        })
+    }
+
+    pub fn add_float(&mut self, time: u64, window_size: u64, value: f64) {
         let t = time - time % window_size;
 
         match self.0.get(&t) {
-            None => unsafe {
-                let mutable_t: &mut FeatureValue = &mut *(self as *const Self as *mut Self);
-                mutable_t.0.insert(t, ValueKind::Float(value));
-            }
-            Some(value_kind) => unsafe {
-                let mutable_t: &mut FeatureValue = &mut *(self as *const Self as *mut Self);
-                if let ValueKind::Float(v) = value_kind {
-                    mutable_t.0.insert(t, ValueKind::Float(v + value));
-                }
+            None => {
+                self.0.insert(t, ValueKind::Float(value));
             }
+            Some(ValueKind::Float(v)) => {
+                let new_value = ValueKind::Float(v + value);
+                self.0.insert(t, new_value);
+            }
+            Some(_) => {}
         };
     }
 }
 
+impl FeatureValue {
+    /// 直接设置分片的值，用于按redo日志恢复或复制
+    pub fn set(&mut self, tk: u64, value: ValueKind) {
+        self.0.insert(tk, value);
+    }
+
+    /// 删除分片，返回删除前的值
+    pub fn remove(&mut self, tk: u64) -> Option<ValueKind> {
+        self.0.remove(&tk)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// 获取指定时间分片的值
+    pub fn get(&self, tk: u64) -> Option<&ValueKind> {
+        self.0.get(&tk)
+    }
+
+    /// 按时间顺序遍历所有分片
+    pub fn iter(&self) -> impl Iterator<Item=(&u64, &ValueKind)> {
+        self.0.iter()
+    }
+
+    /// 汇总窗口内的分片，分片起始时间在 (time - window_size, time] 之间
+    pub fn sum_window(&self, time: u64, window_size: u64) -> Option<ValueKind> {
+        let lower = if time >= window_size { Bound::Excluded(time - window_size) } else { Bound::Unbounded };
+        let mut res: Option<ValueKind> = None;
+        for (_, v) in self.0.range((lower, Bound::Included(time))) {
+            res = Some(match (res, v) {
+                (None, v) => v.clone(),
+                (Some(ValueKind::Int(a)), ValueKind::Int(b)) => ValueKind::Int(a + b),
+                (Some(ValueKind::Float(a)), ValueKind::Float(b)) => ValueKind::Float(a + b),
+                (Some(a @ ValueKind::Stats { .. }), b @ ValueKind::Stats { .. }) => merge_stats(&a, b),
+                (Some(a), _) => a,
+            });
+        }
+        res
+    }
+}
+
+/// 按Chan的并行算法合并两组统计值，不是Stats时返回a
+fn merge_stats(a: &ValueKind, b: &ValueKind) -> ValueKind {
+    match (a, b) {
+        (ValueKind::Stats { count: na, mean: ma, m2: m2a }, ValueKind::Stats { count: nb, mean: mb, m2: m2b }) => {
+            let count = na + nb;
+            if count == 0 {
+                return a.clone();
+            }
+            let (na, nb) = (*na as f64, *nb as f64);
+            let delta = mb - ma;
+            ValueKind::Stats {
+                count,
+                mean: ma + delta * nb / count as f64,
+                m2: m2a + m2b + delta * delta * na * nb / count as f64,
+            }
+        }
+        _ => a.clone(),
+    }
+}
+
 impl Storable for FeatureValue {
     fn encode(&self, buf: &mut BytesMut) -> CustomResult<()> {
         buf.put_u32(self.0.len() as u32);
@@ -120,9 +392,15 @@ impl Storable for FeatureValue {
     }
 
     fn decode(buf: &mut Cursor<&[u8]>) -> CustomResult<Self> where Self: Sized {
+        if buf.remaining() < 4 {
+            return Err(decode_failed_by_insufficient_data_err());
+        }
         let len = buf.get_u32();
         let mut tree = BTreeMap::new();
         for _ in 0..len {
+            if buf.remaining() < 8 {
+                return Err(decode_failed_by_insufficient_data_err());
+            }
             let key = buf.get_u64();
             let v = ValueKind::decode(buf)?;
             tree.insert(key, v);
@@ -131,8 +409,8 @@ impl Storable for FeatureValue {
     }
 
     fn need_space(&self) -> usize {
-        // key = 8, value = 9
-        4 + self.0.len() * 17
+        // key = 8
+        4 + self.0.values().map(|v| 8 + v.need_space()).sum::<usize>()
     }
 }
 
@@ -145,6 +423,7 @@ mod tests {
     use log::info;
 
 
+    use crate::feature::key::{FeatureKey, KeyPart};
     use crate::feature::value::{ValueKind, FeatureValue};
     use crate::init_log;
     use crate::store::Storable;
@@ -152,6 +431,8 @@ mod tests {
     use crate::store::page::Page;
     use bytes::BytesMut;
     use std::io::Cursor;
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
 
     #[test]
     pub fn test_value_serialize() {
@@ -169,8 +450,8 @@ mod tests {
         feature_value2.0.insert(1, v2);
 
         let mut page = Page::new(5, 1,0,100000);
-        page.data.insert("xxx杨".to_string(), feature_value);
-        page.data.insert("xxx杨2".to_string(), feature_value2);
+        page.data.insert(FeatureKey::new(1, &[KeyPart::Text("xxx杨".to_string())]).unwrap(), feature_value);
+        page.data.insert(FeatureKey::new(1, &[KeyPart::Text("xxx杨2".to_string())]).unwrap(), feature_value2);
 
         let mut buf = BytesMut::new();
         page.encode(&mut buf).expect("page.encode");
@@ -188,4 +469,57 @@ mod tests {
         info!("json_byte:{:?}", json_byte);
         info!("json_byte:{:?}", json_byte.len());
     }
-}
\ No newline at end of file
+
+    #[test]
+    pub fn test_concurrent_add_int() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(8)
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let page = Arc::new(RwLock::new(Page::new(0, 1, 0, u64::MAX)));
+            let key = FeatureKey::new(1, &[KeyPart::Text("same_key".to_string())]).unwrap();
+            let window_size = 1000;
+
+            let mut handles = vec![];
+            for task in 0..64u64 {
+                let page = page.clone();
+                let key = key.clone();
+                handles.push(tokio::spawn(async move {
+                    for i in 0..500u64 {
+                        let mut locked_page = page.write().await;
+                        // 同一个key，两个时间分片
+                        let time = (task + i) % 2 * window_size;
+                        match locked_page.get_mut(&key).await {
+                            None => {
+                                let mut sv = FeatureValue::new();
+                                sv.add_int(&key, time, window_size, 1).expect("add_int");
+                                locked_page.put(key.clone(), sv).await.expect("put");
+                            }
+                            Some(sv) => {
+                                sv.add_int(&key, time, window_size, 1).expect("add_int");
+                            }
+                        }
+                        drop(locked_page);
+                        tokio::task::yield_now().await;
+                    }
+                }));
+            }
+            for h in handles {
+                h.await.expect("join");
+            }
+
+            let locked_page = page.read().await;
+            let value = locked_page.get(&key).await.expect("value");
+            let mut total = 0;
+            for v in value.0.values() {
+                if let ValueKind::Int(v) = v {
+                    total += v;
+                }
+            }
+            assert_eq!(value.0.len(), 2);
+            assert_eq!(total, 64 * 500);
+        });
+    }
+}
@@ -1,7 +1,4 @@
 
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
-
 use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
 
@@ -10,10 +7,14 @@ pub mod feature;
 pub mod ds;
 pub mod store;
 pub mod config;
+pub mod cluster;
+pub mod rpc;
+pub mod client;
+pub mod metrics;
 pub mod tools;
 
 /// 时间单位
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum WindowUnit {
     SECOND,
     MINUTE,
@@ -40,18 +41,44 @@ pub fn init_log() {
     log4rs::init_file(config_path, Default::default()).unwrap();
 }
 
-/// 计算hash
-pub fn calc_hash(key: &String) -> u64 {
-    let mut hasher = DefaultHasher::new();
-    key.hash(&mut hasher);
-    hasher.finish()
+/// 使用指定的log4rs配置文件初始化日志，路径为空时使用默认配置
+pub fn init_log_with(config_path: &str) {
+    if config_path.is_empty() {
+        init_log();
+    } else {
+        log4rs::init_file(config_path, Default::default()).unwrap();
+    }
+}
+
+/// 路由使用的hash算法，会写入数据目录的元数据，修改算法或种子必须同时修改该名称
+pub const HASH_ALGORITHM: &str = "xxh64-seed0";
+const HASH_SEED: u64 = 0;
+
+/// 计算hash，参数为编码后的主键；结果决定page的位置并持久化到磁盘，必须跨版本稳定
+pub fn calc_hash(key: &[u8]) -> u64 {
+    xxhash_rust::xxh64::xxh64(key, HASH_SEED)
 }
 
+/// 计算page和索引文件的校验和，用于发现写了一半或损坏的数据
+pub fn calc_checksum(bytes: &[u8]) -> u64 {
+    xxhash_rust::xxh64::xxh64(bytes, CHECKSUM_SEED)
+}
+const CHECKSUM_SEED: u64 = 0x6665_6174;
+
 #[cfg(test)]
 mod tests {
+    use crate::calc_hash;
+
     #[test]
     fn it_works() {
         let result = 2 + 2;
         assert_eq!(result, 4);
     }
+
+    #[test]
+    fn test_calc_hash_stable() {
+        // 固定的期望值，hash结果变化意味着磁盘上的数据无法正确路由
+        assert_eq!(calc_hash(b""), 0xef46db3751d8e999);
+        assert_eq!(calc_hash(b"abc"), 0x44bc2cf5ad770999);
+    }
 }
@@ -0,0 +1,90 @@
+use lazy_static::lazy_static;
+use prometheus::{Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+
+/// 单位秒的耗时分布，从 100us 到 10s
+const LATENCY_BUCKETS: &[f64] = &[0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0];
+
+lazy_static! {
+    pub static ref REGISTRY: Registry = Registry::new_custom(Some("feature_db".to_string()), None)
+        .expect("创建metrics registry失败");
+
+    /// Node::update 耗时
+    pub static ref UPDATE_LATENCY: Histogram = register(Histogram::with_opts(
+        HistogramOpts::new("update_latency_seconds", "Node::update 耗时").buckets(LATENCY_BUCKETS.to_vec())
+    ));
+    /// Node::update 失败次数，按错误码区分
+    pub static ref UPDATE_ERRORS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("update_errors_total", "Node::update 失败次数"), &["code"]
+    ));
+
+    /// wal 队列中等待写入的日志数
+    pub static ref WAL_QUEUE_DEPTH: IntGauge = register(IntGauge::new(
+        "wal_queue_depth", "wal 队列中等待写入的日志数"
+    ));
+    /// wal 刷盘耗时
+    pub static ref WAL_FSYNC_LATENCY: Histogram = register(Histogram::with_opts(
+        HistogramOpts::new("wal_fsync_latency_seconds", "wal 刷盘耗时").buckets(LATENCY_BUCKETS.to_vec())
+    ));
+    /// 已分配但还未写入磁盘的action数量，即 WalState::stored_num 的落后量
+    pub static ref WAL_STORED_LAG: IntGauge = register(IntGauge::new(
+        "wal_stored_lag", "已分配但还未落盘的wal日志数"
+    ));
+
+    /// 每个slot的脏页数
+    pub static ref SLOT_DIRTY_PAGES: IntGaugeVec = register(IntGaugeVec::new(
+        Opts::new("slot_dirty_pages", "slot中等待刷盘的脏页数"), &["slot"]
+    ));
+    /// page分裂次数
+    pub static ref PAGE_SPLITS: IntCounter = register(IntCounter::new(
+        "page_splits_total", "page分裂次数"
+    ));
+    /// 启动时从双写副本恢复的page和索引数
+    pub static ref TORN_REPAIRS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("torn_repairs_total", "启动时从双写副本恢复的文件数"), &["kind"]
+    ));
+    /// 检查点耗时
+    pub static ref CHECKPOINT_DURATION: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new("checkpoint_duration_seconds", "检查点耗时").buckets(LATENCY_BUCKETS.to_vec()),
+        &["result"]
+    ));
+    /// 从数据源消费的事件数，result为ok/invalid/failed
+    pub static ref SOURCE_EVENTS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("source_events_total", "从数据源消费的事件数"), &["source", "result"]
+    ));
+
+    /// store中所有page编码后的总字节数，定期采样
+    pub static ref STORE_ENCODED_BYTES: IntGauge = register(IntGauge::new(
+        "store_encoded_bytes", "store中所有page编码后的总字节数"
+    ));
+}
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(collector: prometheus::Result<T>) -> T {
+    let collector = collector.expect("创建metric失败");
+    REGISTRY.register(Box::new(collector.clone())).expect("注册metric失败");
+    collector
+}
+
+/// 以 prometheus 文本格式输出所有指标
+pub fn gather_text() -> String {
+    let mut buf = vec![];
+    let encoder = TextEncoder::new();
+    if let Err(e) = encoder.encode(&REGISTRY.gather(), &mut buf) {
+        return format!("# 输出metrics失败:{}\n", e);
+    }
+    String::from_utf8(buf).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metrics::{gather_text, PAGE_SPLITS, UPDATE_ERRORS};
+
+    #[test]
+    pub fn test_gather_text() {
+        PAGE_SPLITS.inc();
+        UPDATE_ERRORS.with_label_values(&["30001"]).inc();
+
+        let text = gather_text();
+        assert!(text.contains("feature_db_page_splits_total"));
+        assert!(text.contains("feature_db_update_errors_total{code=\"30001\"}"));
+    }
+}
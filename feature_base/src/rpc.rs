@@ -0,0 +1,160 @@
+use std::future::Future;
+
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+use crate::cluster::{ClusterMap, NodeInfo};
+use crate::custom_error::{common_err, CustomError, CustomResult};
+use crate::ds::{DataSet, DsUpdateResult};
+use crate::feature::{FeatureQuery, FeatureQueryResult};
+use crate::store::backup::BackupManifest;
+
+/// 单个帧的最大长度
+const MAX_FRAME_LEN: u32 = 64 << 20;
+
+/// node和meta server之间、客户端和node之间的请求
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Request {
+    /// 更新指标，feature_ids为空时更新数据集的全部指标；forwarded表示由其它node转发，不再转发
+    Update { event: Value, feature_ids: Option<Vec<u64>>, forwarded: bool },
+    Query { query: FeatureQuery, forwarded: bool },
+    /// 把slot迁移到目标node，由当前owner执行
+    MigrateSlots { slot_ids: Vec<u16>, target: String },
+    /// 应用其它node发来的一批更新日志，见 wal::encode_updates
+    ApplyUpdates { updates: Vec<u8> },
+    /// 集群信息已变更，node收到后立即更新
+    ClusterChanged(ClusterMap),
+    /// follower从leader拉取action id不小于from的wal日志，ack为follower已应用到的位置
+    FetchWal { from: u64, ack: u64, max_items: usize, wait_ms: u64 },
+    /// 把follower提升为leader
+    Promote,
+    ReplicationStatus,
+    /// 在node上生成一致的备份
+    Snapshot { backup_dir: String },
+
+    // meta server
+    RegisterNode(NodeInfo),
+    GetClusterMap,
+    GetDataSets,
+    /// 把slot的owner从from切换到to，slot当前的owner必须是from
+    MoveSlots { slot_ids: Vec<u16>, from: String, to: String },
+    /// 按一致性hash重新分配slot，通过迁移把数据移动到新的owner
+    Rebalance,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Response {
+    Update(DsUpdateResult),
+    Query(FeatureQueryResult),
+    ClusterMap(ClusterMap),
+    DataSets(Vec<DataSet>),
+    /// 编码后的 WalLogItem，依次排列
+    WalItems(Vec<u8>),
+    ReplicationStatus(ReplicationStatus),
+    Snapshot(BackupManifest),
+    Ok,
+    Error { code: usize, message: String },
+}
+
+/// 复制状态
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReplicationStatus {
+    // follower复制的leader地址，leader为空
+    pub leader: Option<String>,
+    // follower：已应用到的leader日志位置；leader：follower确认的位置
+    pub applied_action_id: u64,
+}
+
+impl Response {
+    pub fn from_result<T>(res: CustomResult<T>, f: impl FnOnce(T) -> Response) -> Response {
+        match res {
+            Ok(v) => f(v),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl From<CustomError> for Response {
+    fn from(e: CustomError) -> Self {
+        Response::Error { code: e.code, message: e.message }
+    }
+}
+
+/// 写入一帧：长度(u32) + json
+pub async fn write_frame<W: AsyncWrite + Unpin, T: Serialize>(w: &mut W, msg: &T) -> CustomResult<()> {
+    let body = serde_json::to_vec(msg)?;
+    w.write_u32(body.len() as u32).await?;
+    w.write_all(&body).await?;
+    w.flush().await?;
+    Ok(())
+}
+
+/// 读取一帧，连接已关闭时返回None
+pub async fn read_frame<R: AsyncRead + Unpin, T: DeserializeOwned>(r: &mut R) -> CustomResult<Option<T>> {
+    let len = match r.read_u32().await {
+        Ok(len) => len,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if len > MAX_FRAME_LEN {
+        return Err(common_err(format!("帧长度超出限制:{}", len)));
+    }
+    let mut body = vec![0u8; len as usize];
+    r.read_exact(&mut body).await?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+/// 启动服务，每个连接上可以连续发送多个请求，handler处理单个请求
+pub fn spawn_server<F, Fut>(listener: TcpListener, handler: F) -> JoinHandle<()>
+    where F: Fn(Request) -> Fut + Clone + Send + Sync + 'static,
+          Fut: Future<Output=Response> + Send + 'static {
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, peer) = match listener.accept().await {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("accept失败:{:?}", e);
+                    continue;
+                }
+            };
+            let handler = handler.clone();
+            tokio::spawn(async move {
+                loop {
+                    let request = match read_frame::<_, Request>(&mut stream).await {
+                        Ok(Some(request)) => request,
+                        Ok(None) => break,
+                        Err(e) => {
+                            warn!("读取请求失败:{},{:?}", peer, e);
+                            break;
+                        }
+                    };
+                    let response = handler(request).await;
+                    if let Err(e) = write_frame(&mut stream, &response).await {
+                        warn!("写入响应失败:{},{:?}", peer, e);
+                        break;
+                    }
+                }
+            });
+        }
+    })
+}
+
+/// 发送一个请求并等待响应，服务端返回的错误转换为CustomError
+pub async fn call(addr: &str, request: &Request) -> CustomResult<Response> {
+    let mut stream = TcpStream::connect(addr).await?;
+    write_frame(&mut stream, request).await?;
+    match read_frame::<_, Response>(&mut stream).await? {
+        Some(Response::Error { code, message }) => Err(CustomError { code, message }),
+        Some(resp) => Ok(resp),
+        None => Err(common_err(format!("{} 连接已关闭", addr))),
+    }
+}
+
+pub fn unexpected_response_err(resp: &Response) -> CustomError {
+    common_err(format!("不符合预期的响应:{:?}", resp))
+}
@@ -0,0 +1,160 @@
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::custom_error::{common_err, CustomResult};
+use crate::store::meta::{get_store_meta_path, StoreMeta};
+use crate::store::recover::{CleanShutdown, write_marker};
+use crate::store::wal::get_wal_file_path;
+
+pub const MANIFEST_FILE_NAME: &str = "manifest.json";
+const WAL_FILE_NAME: &str = "redo.log";
+
+/// 备份中的一个文件
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BackupFile {
+    pub name: String,
+    pub size: u64,
+    // 是否是硬链接，硬链接的文件之后可能继续增长，只有前size个字节属于备份
+    pub linked: bool,
+}
+
+/// 备份清单
+///
+/// 备份时所有page和索引都已刷盘，wal_size之前的日志都已体现在page中，
+/// 恢复后写入停机标记，启动时不需要重放wal。
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BackupManifest {
+    pub node_id: String,
+    // 备份时间，毫秒
+    pub created_at: u64,
+    pub store_meta: StoreMeta,
+    // 备份时wal的长度
+    pub wal_size: u64,
+    // 备份时的 (事务ID, 动作ID)
+    pub next_tid: u64,
+    pub next_action_id: u64,
+    pub files: Vec<BackupFile>,
+}
+
+/// 是否是需要备份的数据文件：slot索引和page文件。slot_N_shard 和 slot_N_index_bk 是双写的副本，刷盘完成后不再需要
+fn is_data_file(name: &str) -> bool {
+    match name.strip_prefix("slot_") {
+        Some(rest) => rest.ends_with("_index") || rest.contains("_page_"),
+        None => false,
+    }
+}
+
+/// 复制数据目录中已刷盘的文件到备份目录。调用方需要保证复制期间page和索引文件不被修改，
+/// 且wal的前wal_size个字节与page文件一致
+pub async fn copy_snapshot(data_dir: &str, backup_dir: &str, node_id: &str,
+                           wal_size: u64, next_tid: u64, next_action_id: u64) -> CustomResult<BackupManifest> {
+    prepare_empty_dir(backup_dir).await?;
+
+    let mut files = vec![];
+    let mut names = vec![];
+    let mut entries = fs::read_dir(data_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if is_data_file(&name) {
+            names.push(name);
+        }
+    }
+    names.sort();
+
+    // page和索引文件原地修改，只能复制
+    for name in names {
+        let size = fs::copy(format!("{}/{}", data_dir, name), format!("{}/{}", backup_dir, name)).await?;
+        files.push(BackupFile { name, size, linked: false });
+    }
+    let store_meta = StoreMeta::load_or_init(data_dir).await?;
+    fs::write(get_store_meta_path(backup_dir), serde_json::to_vec_pretty(&store_meta)?).await?;
+
+    // wal只追加，尽量使用硬链接，恢复时只取前wal_size个字节
+    let wal_path = get_wal_file_path(data_dir.to_string());
+    let backup_wal_path = format!("{}/{}", backup_dir, WAL_FILE_NAME);
+    let linked = match fs::hard_link(&wal_path, &backup_wal_path).await {
+        Ok(_) => true,
+        Err(e) => {
+            info!("wal无法硬链接，改为复制:{}", e);
+            copy_prefix(&wal_path, &backup_wal_path, wal_size).await?;
+            false
+        }
+    };
+    files.push(BackupFile { name: WAL_FILE_NAME.to_string(), size: wal_size, linked });
+
+    let manifest = BackupManifest {
+        node_id: node_id.to_string(),
+        created_at: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64),
+        store_meta,
+        wal_size,
+        next_tid,
+        next_action_id,
+        files,
+    };
+    write_manifest(backup_dir, &manifest).await?;
+    info!("备份完成:{}，文件数:{}，wal:{}", backup_dir, manifest.files.len(), wal_size);
+    Ok(manifest)
+}
+
+/// 按备份清单重建数据目录，目标目录必须不存在或为空
+pub async fn restore(backup_dir: &str, data_dir: &str) -> CustomResult<BackupManifest> {
+    let manifest = read_manifest(backup_dir).await?;
+    if manifest.store_meta != StoreMeta::current() {
+        return Err(common_err(format!("备份的数据格式:{:?} 与当前程序不一致", manifest.store_meta)));
+    }
+    prepare_empty_dir(data_dir).await?;
+
+    for file in &manifest.files {
+        let from = format!("{}/{}", backup_dir, file.name);
+        let to = format!("{}/{}", data_dir, file.name);
+        let size = fs::metadata(&from).await?.len();
+        if size < file.size || (!file.linked && size != file.size) {
+            return Err(common_err(format!("备份文件{}大小不一致，期望:{}，实际:{}", file.name, file.size, size)));
+        }
+        copy_prefix(&from, &to, file.size).await?;
+    }
+    fs::write(get_store_meta_path(data_dir), serde_json::to_vec_pretty(&manifest.store_meta)?).await?;
+    write_marker(data_dir, CleanShutdown { tid: manifest.next_tid, action_id: manifest.next_action_id }).await?;
+    info!("从{}恢复到{}完成", backup_dir, data_dir);
+    Ok(manifest)
+}
+
+pub async fn read_manifest(backup_dir: &str) -> CustomResult<BackupManifest> {
+    let bytes = fs::read(format!("{}/{}", backup_dir, MANIFEST_FILE_NAME)).await?;
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+async fn write_manifest(backup_dir: &str, manifest: &BackupManifest) -> CustomResult<()> {
+    let path = format!("{}/{}", backup_dir, MANIFEST_FILE_NAME);
+    let tmp_path = format!("{}.tmp", path);
+    let mut f = File::create(&tmp_path).await?;
+    f.write_all(&serde_json::to_vec_pretty(manifest)?).await?;
+    f.sync_data().await?;
+    fs::rename(&tmp_path, &path).await?;
+    Ok(())
+}
+
+async fn prepare_empty_dir(dir: &str) -> CustomResult<()> {
+    if Path::new(dir).exists() && fs::read_dir(dir).await?.next_entry().await?.is_some() {
+        return Err(common_err(format!("目录{}不为空", dir)));
+    }
+    fs::create_dir_all(dir).await?;
+    Ok(())
+}
+
+/// 复制文件的前size个字节
+async fn copy_prefix(from: &str, to: &str, size: u64) -> CustomResult<()> {
+    let src = File::open(from).await?;
+    let mut dst = OpenOptions::new().write(true).create(true).truncate(true).open(to).await?;
+    let copied = tokio::io::copy(&mut src.take(size), &mut dst).await?;
+    if copied != size {
+        return Err(common_err(format!("{}长度不足，期望:{}，实际:{}", from, size, copied)));
+    }
+    dst.sync_data().await?;
+    Ok(())
+}
@@ -0,0 +1,374 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::SeekFrom;
+
+use log::info;
+use tokio::fs::{self, OpenOptions};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+use crate::calc_hash;
+use crate::custom_error::CustomResult;
+use crate::feature::key::FeatureKey;
+use crate::feature::value::ValueKind;
+use crate::store::inspect::{group_by_tid, list_slots, read_page, read_shard, read_slot_index, read_slot_index_bk, read_wal};
+use crate::store::page::Page;
+use crate::store::Storable;
+use crate::store::slot::{get_page_file_position, get_slot_index_file_path, PAGE_NUM, PAGE_SIZE, slot_id_of_hash};
+use crate::store::wal::{WalFeatureRemoveValue, WalFeatureUpdateValue, WalLogKind, WalPageBkStoreValue};
+use crate::tools::bitmap::BitMap;
+
+/// 一致性检查的结果
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    pub slots: usize,
+    pub pages: usize,
+    pub keys: usize,
+    // 与wal比对过的时间分片数
+    pub checked_updates: usize,
+    // wal末尾不完整的字节数，崩溃后存在是正常的
+    pub wal_torn_bytes: u64,
+    pub problems: Vec<String>,
+    // 已修复的问题
+    pub repaired: Vec<String>,
+}
+
+impl FsckReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// 时间分片的一次更新：(动作ID, 是否提交, redo值)，删除时redo值为None
+type SliceUpdate = (u64, bool, Option<ValueKind>);
+
+/// 已提交的page写入记录，记录时page覆盖的范围内，动作ID之前的更新都已写入磁盘
+struct PageStoreRecord {
+    action_id: u64,
+    min_pk: u64,
+    max_pk: u64,
+}
+
+impl PageStoreRecord {
+    fn contains(&self, hash: u64) -> bool {
+        in_range(hash, self.min_pk, self.max_pk)
+    }
+}
+
+/// page的范围为 [min_pk, max_pk)，最后一个page包含u64::MAX
+fn in_range(hash: u64, min_pk: u64, max_pk: u64) -> bool {
+    min_pk <= hash && (hash < max_pk || max_pk == u64::MAX)
+}
+
+/// 离线检查数据目录，node必须已停止。
+///
+/// 检查每个slot的索引、page的范围和大小、key的归属，并用wal中已提交的更新核对已刷盘的page。
+/// repair为true时，用 slot_N_index_bk 修复无法解析的索引，用 slot_N_shard 修复无法解析的page
+pub async fn check(data_dir: &str, repair: bool) -> CustomResult<FsckReport> {
+    let mut report = FsckReport::default();
+
+    let scan = read_wal(data_dir).await?;
+    report.wal_torn_bytes = scan.file_len - scan.valid_len;
+    if let Some(e) = &scan.error {
+        report.problems.push(format!("wal {}", e));
+    }
+
+    let mut store_records: HashMap<u16, Vec<PageStoreRecord>> = HashMap::new();
+    // 每个时间分片的更新
+    let mut updates: HashMap<(FeatureKey, u64), Vec<SliceUpdate>> = HashMap::new();
+    for txn in group_by_tid(scan.items) {
+        let committed = txn.is_committed();
+        for mut item in txn.items {
+            let value = match item.value.as_mut() {
+                Some(v) => v.as_mut().as_any(),
+                None => continue,
+            };
+            match item.kind {
+                WalLogKind::PageBkStore if committed => {
+                    if let Some(v) = value.downcast_mut::<WalPageBkStoreValue>() {
+                        store_records.entry(v.slot_id).or_insert(vec![]).push(PageStoreRecord {
+                            action_id: item.action_id,
+                            min_pk: v.min_pk,
+                            max_pk: v.max_pk,
+                        });
+                    }
+                }
+                WalLogKind::FeatureUpdate => {
+                    if let Some(v) = value.downcast_mut::<WalFeatureUpdateValue>() {
+                        updates.entry((v.fk.clone(), v.tk)).or_insert(vec![])
+                            .push((item.action_id, committed, Some(v.redo_v.clone())));
+                    }
+                }
+                WalLogKind::FeatureRemove => {
+                    if let Some(v) = value.downcast_mut::<WalFeatureRemoveValue>() {
+                        updates.entry((v.fk.clone(), v.tk)).or_insert(vec![])
+                            .push((item.action_id, committed, None));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let mut slots = list_slots(data_dir).await?;
+    for slot_id in store_records.keys() {
+        if !slots.contains(slot_id) {
+            report.problems.push(format!("slot:{} wal中有刷盘记录，但数据文件不存在", slot_id));
+        }
+    }
+    slots.sort();
+
+    let mut slot_pages = HashMap::new();
+    for slot_id in slots {
+        let pages = check_slot(data_dir, slot_id, repair, &mut report).await?;
+        report.slots += 1;
+        report.pages += pages.len();
+        report.keys += pages.values().map(|p| p.data.len()).sum::<usize>();
+        slot_pages.insert(slot_id, pages);
+    }
+
+    for ((fk, tk), list) in updates.iter_mut() {
+        let hash = calc_hash(fk.as_bytes());
+        let slot_id = slot_id_of_hash(hash);
+        // key所在的page最后一次刷盘的动作ID
+        let stored_action_id = match store_records.get(&slot_id)
+            .and_then(|records| records.iter().filter(|r| r.contains(hash)).map(|r| r.action_id).max()) {
+            Some(action_id) => action_id,
+            None => continue,
+        };
+        list.sort_by_key(|(action_id, _, _)| *action_id);
+        let (_, committed, expected) = match list.iter().rev().find(|(action_id, _, _)| *action_id < stored_action_id) {
+            Some(update) => update,
+            None => continue,
+        };
+        // 未提交的更新可能已经写入page，无法判断
+        if !committed {
+            continue;
+        }
+        let page = match slot_pages.get(&slot_id).and_then(|pages| pages.range(..=hash).last()) {
+            Some((_, page)) => page,
+            None => continue,
+        };
+        report.checked_updates += 1;
+        let actual = page.data.get(fk).and_then(|v| v.get(*tk));
+        if actual != expected.as_ref() {
+            report.problems.push(format!("slot:{} page:{} key:{} 分片:{} 已提交的值:{:?}，page中的值:{:?}",
+                                         slot_id, page.id, fk, tk, expected, actual));
+        }
+    }
+    Ok(report)
+}
+
+/// 检查一个slot，返回能正常读取的page，key为min_pk
+async fn check_slot(data_dir: &str, slot_id: u16, repair: bool, report: &mut FsckReport) -> CustomResult<BTreeMap<u64, Page>> {
+    let mut pages = BTreeMap::new();
+    let index = match read_slot_index(data_dir, slot_id).await {
+        Ok(index) => index,
+        Err(e) => {
+            if repair && repair_index(data_dir, slot_id).await? {
+                report.repaired.push(format!("slot:{} {}，已从索引副本恢复", slot_id, e));
+                read_slot_index(data_dir, slot_id).await?
+            } else {
+                report.problems.push(format!("slot:{} {}", slot_id, e));
+                return Ok(pages);
+            }
+        }
+    };
+    // 未发生过分裂的slot没有索引文件，只有page 0
+    let entries = index.unwrap_or_else(|| vec![(0, 0)]);
+
+    // 离线时根据索引重建page_bit_map，每个page只能被一个索引项引用
+    let mut bitmap = BitMap::new(PAGE_NUM as u64);
+    for (min_pk, page_id) in entries {
+        if page_id >= PAGE_NUM as u64 {
+            report.problems.push(format!("slot:{} 索引项:{} 的page:{} 超出范围", slot_id, min_pk, page_id));
+            continue;
+        }
+        if bitmap.get(page_id) {
+            report.problems.push(format!("slot:{} page:{} 被多个索引项引用", slot_id, page_id));
+            continue;
+        }
+        bitmap.set(page_id, true);
+
+        let res = match read_page(data_dir, slot_id, page_id).await {
+            Ok(Some(page)) => Ok(page),
+            Ok(None) => Err(format!("page:{} 未写入", page_id)),
+            Err(e) => Err(e.to_string()),
+        };
+        let page = match res {
+            Ok(page) => page,
+            Err(e) => {
+                if repair && repair_page(data_dir, slot_id, page_id).await? {
+                    report.repaired.push(format!("slot:{} {}，已从page副本恢复", slot_id, e));
+                    match read_page(data_dir, slot_id, page_id).await? {
+                        Some(page) => page,
+                        None => continue,
+                    }
+                } else {
+                    report.problems.push(format!("slot:{} {}", slot_id, e));
+                    continue;
+                }
+            }
+        };
+
+        if page.slot_id != slot_id || page.id != page_id || page.min_pk != min_pk {
+            report.problems.push(format!("slot:{} page:{} 头部与索引不一致，头部:(slot:{},page:{},min_pk:{})，索引min_pk:{}",
+                                         slot_id, page_id, page.slot_id, page.id, page.min_pk, min_pk));
+        }
+        if page.need_space() >= PAGE_SIZE as usize {
+            report.problems.push(format!("slot:{} page:{} 大小:{} 超过page上限", slot_id, page_id, page.need_space()));
+        }
+        let misplaced = page.data.keys()
+            .map(|k| calc_hash(k.as_bytes()))
+            .filter(|hash| slot_id_of_hash(*hash) != slot_id || !in_range(*hash, page.min_pk, page.max_pk))
+            .count();
+        if misplaced > 0 {
+            report.problems.push(format!("slot:{} page:{} 有{}个key不属于该page的范围", slot_id, page_id, misplaced));
+        }
+        pages.insert(min_pk, page);
+    }
+
+    // page的范围必须首尾相连，覆盖整个hash空间
+    let mut expected_min = 0;
+    for page in pages.values() {
+        if page.min_pk != expected_min {
+            let kind = if page.min_pk < expected_min { "重叠" } else { "不连续" };
+            report.problems.push(format!("slot:{} page:{} 范围{}，min_pk:{} 期望:{}", slot_id, page.id, kind, page.min_pk, expected_min));
+        }
+        if page.min_pk >= page.max_pk {
+            report.problems.push(format!("slot:{} page:{} 范围非法:[{},{})", slot_id, page.id, page.min_pk, page.max_pk));
+        }
+        expected_min = page.max_pk;
+    }
+    if !pages.is_empty() && expected_min != u64::MAX {
+        report.problems.push(format!("slot:{} 最后一个page的max_pk:{} 不是u64::MAX", slot_id, expected_min));
+    }
+    Ok(pages)
+}
+
+/// 用索引副本覆盖无法解析的索引，副本也无法解析时返回false
+pub async fn repair_index(data_dir: &str, slot_id: u16) -> CustomResult<bool> {
+    match read_slot_index_bk(data_dir, slot_id).await {
+        Ok(Some(_)) => {}
+        _ => return Ok(false),
+    }
+    let path = get_slot_index_file_path(data_dir, slot_id);
+    fs::copy(format!("{}_bk", path), &path).await?;
+    OpenOptions::new().write(true).open(&path).await?.sync_data().await?;
+    info!("slot:{} 从副本恢复索引", slot_id);
+    Ok(true)
+}
+
+/// 副本中保存的是slot最近一次写入的page，是同一个page时用副本覆盖，否则返回false
+pub async fn repair_page(data_dir: &str, slot_id: u16, page_id: u64) -> CustomResult<bool> {
+    let bytes = match read_shard(data_dir, slot_id).await {
+        // 分裂时副本中的page超过PAGE_SIZE，原page没有被覆盖，不需要恢复
+        Ok(Some((page, bytes))) if page.slot_id == slot_id && page.id == page_id
+            && bytes.len() < PAGE_SIZE as usize => bytes,
+        _ => return Ok(false),
+    };
+    write_page(data_dir, slot_id, page_id, &bytes).await?;
+    info!("slot:{} page:{} 从副本恢复", slot_id, page_id);
+    Ok(true)
+}
+
+async fn write_page(data_dir: &str, slot_id: u16, page_id: u64, bytes: &[u8]) -> CustomResult<()> {
+    let (path, seek_pos) = get_page_file_position(data_dir, slot_id, page_id);
+    // 只覆盖page所在的区域，不能截断文件中的其它page
+    let mut f = OpenOptions::new().write(true).create(true).truncate(false).open(&path).await?;
+    f.seek(SeekFrom::Start(seek_pos)).await?;
+    f.write_all(bytes).await?;
+    f.sync_data().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+
+    use crate::calc_hash;
+    use crate::config::Config;
+    use crate::feature::key::{FeatureKey, KeyPart};
+    use crate::feature::value::{FeatureValue, ValueKind};
+    use crate::store::fsck::{check, write_page};
+    use crate::store::inspect::read_page;
+    use crate::store::slot::{encode_page_index, get_slot_index_bk_file_path, get_slot_index_file_path, slot_id_of_hash};
+    use crate::store::{Storable, Store};
+    use crate::store::wal::{crate_wal, generate_tid};
+
+    #[test]
+    pub fn test_check_and_repair() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let data_dir = std::env::temp_dir().join(format!("feature_db_fsck_test_{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&data_dir);
+            std::fs::create_dir_all(&data_dir).unwrap();
+            let config = Config {
+                data_dir: data_dir.to_str().unwrap().to_string(),
+                ..Config::default()
+            };
+            let data_dir_str = config.data_dir.as_str();
+            let wal = crate_wal(config.data_dir.clone(), config.wal_sync, config.wal_queue_size, None).await.expect("创建wal失败！");
+            let store = Store::new(config.data_dir.clone()).await.expect("创建store失败！");
+
+            let key = FeatureKey::new(1, &[KeyPart::Text("fsck".to_string())]).unwrap();
+            let hash = calc_hash(key.as_bytes());
+            let slot_id = slot_id_of_hash(hash);
+            for i in 0..3 {
+                let tid = generate_tid();
+                wal.send_begin_log(tid).await.unwrap();
+                let (_, page) = store.get_page(hash).await.unwrap();
+                let mut page = page.write().await;
+                let update = match page.get_mut(&key).await {
+                    Some(value) => value.add_int(&key, i * 1000, 1000, 1).unwrap(),
+                    None => {
+                        let mut value = FeatureValue::new();
+                        let update = value.add_int(&key, i * 1000, 1000, 1).unwrap();
+                        page.put(key.clone(), value).await.unwrap();
+                        update
+                    }
+                };
+                let action_id = wal.send_feature_update_log(tid, update).await.unwrap();
+                page.after_update(action_id, &store).await;
+                drop(page);
+                wal.commit_log(tid).await.unwrap();
+            }
+            store.check_point_all(&wal).await.unwrap();
+            wal.close().await.unwrap();
+
+            let report = check(data_dir_str, false).await.unwrap();
+            assert!(report.is_ok(), "{:?}", report.problems);
+            assert_eq!((report.slots, report.pages, report.keys, report.checked_updates), (1, 1, 1, 3));
+
+            // page的值与wal不一致
+            let mut page = read_page(data_dir_str, slot_id, 0).await.unwrap().unwrap();
+            page.data.get_mut(&key).unwrap().set(0, ValueKind::Int(100));
+            let mut buf = BytesMut::new();
+            page.encode(&mut buf).unwrap();
+            write_page(data_dir_str, slot_id, 0, &buf).await.unwrap();
+            let report = check(data_dir_str, false).await.unwrap();
+            assert_eq!(report.problems.len(), 1, "{:?}", report.problems);
+
+            // page写了一半，从副本恢复
+            write_page(data_dir_str, slot_id, 0, &(buf.len() as u64 - 3).to_be_bytes()).await.unwrap();
+            let report = check(data_dir_str, false).await.unwrap();
+            assert!(!report.is_ok());
+            let report = check(data_dir_str, true).await.unwrap();
+            assert!(report.is_ok(), "{:?}", report.problems);
+            assert_eq!(report.repaired.len(), 1);
+
+            // 索引损坏，从索引副本恢复
+            let index = encode_page_index(&[(0, 0)]);
+            std::fs::write(get_slot_index_bk_file_path(data_dir_str, slot_id), &index).unwrap();
+            std::fs::write(get_slot_index_file_path(data_dir_str, slot_id), &index[..7]).unwrap();
+            assert!(!check(data_dir_str, false).await.unwrap().is_ok());
+            let report = check(data_dir_str, true).await.unwrap();
+            assert!(report.is_ok(), "{:?}", report.problems);
+            assert_eq!(report.repaired.len(), 1);
+            assert!(check(data_dir_str, false).await.unwrap().is_ok());
+
+            let _ = std::fs::remove_dir_all(&data_dir);
+        });
+    }
+}
@@ -0,0 +1,280 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io::{Cursor, SeekFrom};
+
+use serde::Serialize;
+use tokio::fs::{self, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::custom_error::{common_err, CustomResult, DECODE_FAILED_BY_INSUFFICIENT_DATA_CODE};
+use crate::store::Storable;
+use crate::store::page::{Page, PAGE_HEADER_SIZE};
+use crate::store::slot::{decode_page_index, get_page_file_position, get_shard_file_path, get_slot_index_bk_file_path, get_slot_index_file_path, PAGE_SIZE};
+use crate::store::wal::{get_wal_file_path, WalLogItem, WalLogKind};
+
+/// 离线读取wal的结果
+#[derive(Debug)]
+pub struct WalScan {
+    pub items: Vec<WalLogItem>,
+    // 能完整解析的字节数
+    pub valid_len: u64,
+    pub file_len: u64,
+    // 遇到无法解析的数据时的错误，末尾数据不完整不算错误
+    pub error: Option<String>,
+}
+
+/// 同一个事务的日志
+#[derive(Debug)]
+pub struct WalTxn {
+    pub tid: u64,
+    pub items: Vec<WalLogItem>,
+}
+
+impl WalTxn {
+    pub fn is_committed(&self) -> bool {
+        self.items.iter().any(|item| item.kind == WalLogKind::Commit)
+    }
+}
+
+/// 读取整个wal文件，解析到第一条不完整或非法的日志为止
+pub async fn read_wal(data_dir: &str) -> CustomResult<WalScan> {
+    let bytes = fs::read(get_wal_file_path(data_dir.to_string())).await?;
+    let mut cursor: Cursor<&[u8]> = Cursor::new(&bytes);
+    let mut items = vec![];
+    let mut error = None;
+    let mut valid_len = 0;
+    while (cursor.position() as usize) < bytes.len() {
+        match WalLogItem::decode(&mut cursor) {
+            Ok(item) => {
+                valid_len = cursor.position();
+                items.push(item);
+            }
+            Err(e) => {
+                if e.code != DECODE_FAILED_BY_INSUFFICIENT_DATA_CODE {
+                    error = Some(format!("偏移:{} 解析失败:{}", valid_len, e));
+                }
+                break;
+            }
+        }
+    }
+    Ok(WalScan { items, valid_len, file_len: bytes.len() as u64, error })
+}
+
+/// 按事务分组，事务的顺序为首条日志出现的顺序
+pub fn group_by_tid(items: Vec<WalLogItem>) -> Vec<WalTxn> {
+    let mut txns: Vec<WalTxn> = vec![];
+    let mut index: HashMap<u64, usize> = HashMap::new();
+    for item in items {
+        match index.get(&item.tid) {
+            Some(i) => txns[*i].items.push(item),
+            None => {
+                index.insert(item.tid, txns.len());
+                txns.push(WalTxn { tid: item.tid, items: vec![item] });
+            }
+        }
+    }
+    txns
+}
+
+/// 读取slot的page索引：(min_pk, page_id)，索引文件不存在时返回None
+pub async fn read_slot_index(data_dir: &str, slot_id: u16) -> CustomResult<Option<Vec<(u64, u64)>>> {
+    read_index_file(&get_slot_index_file_path(data_dir, slot_id)).await
+}
+
+/// 读取slot索引的双写副本
+pub async fn read_slot_index_bk(data_dir: &str, slot_id: u16) -> CustomResult<Option<Vec<(u64, u64)>>> {
+    read_index_file(&get_slot_index_bk_file_path(data_dir, slot_id)).await
+}
+
+async fn read_index_file(path: &str) -> CustomResult<Option<Vec<(u64, u64)>>> {
+    let bytes = match fs::read(path).await {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let index = decode_page_index(&bytes).map_err(|e| common_err(format!("{} {}", path, e)))?;
+    Ok(Some(index))
+}
+
+/// slot当前使用的page：有索引时以索引为准，否则只有初始的page 0
+pub async fn read_slot_pages(data_dir: &str, slot_id: u16) -> CustomResult<Vec<(u64, u64)>> {
+    Ok(read_slot_index(data_dir, slot_id).await?.unwrap_or_else(|| vec![(0, 0)]))
+}
+
+/// 从page文件中读取一个page，page从未写入时返回None
+pub async fn read_page(data_dir: &str, slot_id: u16, page_id: u64) -> CustomResult<Option<Page>> {
+    let (path, seek_pos) = get_page_file_position(data_dir, slot_id, page_id);
+    let mut f = match OpenOptions::new().read(true).open(&path).await {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if f.metadata().await?.len() < seek_pos + 8 {
+        return Ok(None);
+    }
+    f.seek(SeekFrom::Start(seek_pos)).await?;
+    let size = f.read_u64().await?;
+    if size == 0 {
+        return Ok(None);
+    }
+    if size < PAGE_HEADER_SIZE as u64 || size > PAGE_SIZE as u64 {
+        return Err(common_err(format!("{} 偏移:{} page大小:{} 非法", path, seek_pos, size)));
+    }
+    let mut buf = vec![0u8; size as usize];
+    // 读取完整的page，包括头部
+    f.seek(SeekFrom::Start(seek_pos)).await?;
+    f.read_exact(&mut buf).await
+        .map_err(|e| common_err(format!("{} 偏移:{} 读取page失败:{}", path, seek_pos, e)))?;
+    Ok(Some(decode_page(&buf).map_err(|e| common_err(format!("{} 偏移:{} {}", path, seek_pos, e)))?))
+}
+
+/// 读取slot的page双写副本，返回解析后的page和原始字节
+pub async fn read_shard(data_dir: &str, slot_id: u16) -> CustomResult<Option<(Page, Vec<u8>)>> {
+    let bytes = match fs::read(get_shard_file_path(data_dir, slot_id)).await {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if bytes.is_empty() {
+        return Ok(None);
+    }
+    let page = decode_page(&bytes)?;
+    Ok(Some((page, bytes)))
+}
+
+/// 解析一个完整的page，头部记录的大小必须与内容一致
+fn decode_page(bytes: &[u8]) -> CustomResult<Page> {
+    let mut cursor: Cursor<&[u8]> = Cursor::new(bytes);
+    let page = Page::decode(&mut cursor)?;
+    if page.need_space() != bytes.len() {
+        return Err(common_err(format!("page:{} 数据不完整，大小:{} 解析结果:{}", page.id, bytes.len(), page.need_space())));
+    }
+    Ok(page)
+}
+
+/// 数据目录中存在文件的slot
+pub async fn list_slots(data_dir: &str) -> CustomResult<Vec<u16>> {
+    let mut slots = BTreeSet::new();
+    let mut entries = fs::read_dir(data_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if let Some(slot_id) = name.strip_prefix("slot_")
+            .and_then(|rest| rest.split('_').next())
+            .and_then(|id| id.parse::<u16>().ok()) {
+            slots.insert(slot_id);
+        }
+    }
+    Ok(slots.into_iter().collect())
+}
+
+/// slot的统计信息
+#[derive(Debug, Serialize, Default)]
+pub struct SlotStats {
+    pub slot_id: u16,
+    // 索引中的page数
+    pub pages: usize,
+    // 已写入磁盘的page数
+    pub stored_pages: usize,
+    pub keys: usize,
+    // 时间分片数
+    pub buckets: usize,
+    // page编码后的总字节数
+    pub used_bytes: u64,
+}
+
+impl SlotStats {
+    /// page平均填充率
+    pub fn fill(&self) -> f64 {
+        if self.pages == 0 {
+            return 0.0;
+        }
+        self.used_bytes as f64 / (self.pages as u64 * PAGE_SIZE as u64) as f64
+    }
+}
+
+pub async fn slot_stats(data_dir: &str, slot_id: u16) -> CustomResult<SlotStats> {
+    let pages = read_slot_pages(data_dir, slot_id).await?;
+    let mut stats = SlotStats { slot_id, pages: pages.len(), ..SlotStats::default() };
+    for (_, page_id) in pages {
+        if let Some(page) = read_page(data_dir, slot_id, page_id).await? {
+            stats.stored_pages += 1;
+            stats.keys += page.data.len();
+            stats.buckets += page.data.values().map(|v| v.iter().count()).sum::<usize>();
+            stats.used_bytes += page.need_space() as u64;
+        }
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::feature::key::{FeatureKey, KeyPart};
+    use crate::feature::value::FeatureValue;
+    use crate::calc_hash;
+    use crate::store::inspect::{group_by_tid, list_slots, read_page, read_slot_index, read_wal, slot_stats};
+    use crate::store::slot::slot_id_of_hash;
+    use crate::store::Store;
+    use crate::store::wal::{crate_wal, generate_tid, WalLogKind};
+
+    #[test]
+    pub fn test_inspect() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let data_dir = std::env::temp_dir().join(format!("feature_db_inspect_test_{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&data_dir);
+            std::fs::create_dir_all(&data_dir).unwrap();
+            let config = Config {
+                data_dir: data_dir.to_str().unwrap().to_string(),
+                ..Config::default()
+            };
+            let wal = crate_wal(config.data_dir.clone(), config.wal_sync, config.wal_queue_size, None).await.expect("创建wal失败！");
+            let store = Store::new(config.data_dir.clone()).await.expect("创建store失败！");
+
+            let key = FeatureKey::new(1, &[KeyPart::Text("inspect".to_string())]).unwrap();
+            let hash = calc_hash(key.as_bytes());
+            let tid = generate_tid();
+            wal.send_begin_log(tid).await.unwrap();
+            {
+                let (_, page) = store.get_page(hash).await.unwrap();
+                let mut page = page.write().await;
+                let mut value = FeatureValue::new();
+                let update = value.add_int(&key, 1000, 1000, 3).unwrap();
+                page.put(key.clone(), value).await.unwrap();
+                let action_id = wal.send_feature_update_log(tid, update).await.unwrap();
+                page.after_update(action_id, &store).await;
+            }
+            wal.commit_log(tid).await.unwrap();
+            store.check_point_all(&wal).await.unwrap();
+            wal.close().await.unwrap();
+
+            let scan = read_wal(&config.data_dir).await.unwrap();
+            assert!(scan.error.is_none());
+            assert_eq!(scan.valid_len, scan.file_len);
+            let txns = group_by_tid(scan.items);
+            assert_eq!(txns[0].tid, tid);
+            assert!(txns[0].is_committed());
+            assert_eq!(txns[0].items.len(), 3);
+            // 检查点写page的事务
+            assert!(txns.iter().any(|t| t.is_committed() && t.items.iter().any(|i| i.kind == WalLogKind::PageBkStore)));
+            // 最后的结束日志没有提交
+            assert!(!txns.last().unwrap().is_committed());
+
+            let slot_id = slot_id_of_hash(hash);
+            assert_eq!(list_slots(&config.data_dir).await.unwrap(), vec![slot_id]);
+            assert!(read_slot_index(&config.data_dir, slot_id).await.unwrap().is_none());
+            let page = read_page(&config.data_dir, slot_id, 0).await.unwrap().expect("page");
+            assert_eq!(page.slot_id, slot_id);
+            assert_eq!(page.data.len(), 1);
+            assert!(read_page(&config.data_dir, slot_id, 1).await.unwrap().is_none());
+
+            let stats = slot_stats(&config.data_dir, slot_id).await.unwrap();
+            assert_eq!((stats.pages, stats.stored_pages, stats.keys, stats.buckets), (1, 1, 1, 1));
+            assert!(stats.fill() > 0.0);
+
+            let _ = std::fs::remove_dir_all(&data_dir);
+        });
+    }
+}
@@ -0,0 +1,120 @@
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::custom_error::{CustomResult, store_meta_mismatch_err};
+use crate::HASH_ALGORITHM;
+use crate::store::wal::get_wal_file_path;
+
+/// 数据格式版本，2: page和索引文件增加校验和，3: 文本分组字段改为转义编码
+pub const STORE_FORMAT_VERSION: u32 = 3;
+
+pub fn get_store_meta_path(data_dir: &str) -> String {
+    format!("{}/store_meta.json", data_dir)
+}
+
+/// 数据目录的元数据，打开已有数据时校验
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StoreMeta {
+    pub format_version: u32,
+    pub hash_algorithm: String,
+}
+
+impl StoreMeta {
+    pub fn current() -> StoreMeta {
+        StoreMeta {
+            format_version: STORE_FORMAT_VERSION,
+            hash_algorithm: HASH_ALGORITHM.to_string(),
+        }
+    }
+
+    /// 读取数据目录的元数据，与当前版本不一致时拒绝打开；
+    /// 不存在时只在目录中还没有数据时写入当前版本，否则无法确认已有数据的格式，同样拒绝打开
+    pub async fn load_or_init(data_dir: &str) -> CustomResult<StoreMeta> {
+        let path = get_store_meta_path(data_dir);
+        let current = StoreMeta::current();
+
+        match fs::read(&path).await {
+            Ok(bytes) => {
+                let meta: StoreMeta = serde_json::from_slice(&bytes)?;
+                if meta != current {
+                    return Err(store_meta_mismatch_err(&format!("{:?}", meta), &format!("{:?}", current)));
+                }
+                Ok(meta)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                if has_data(data_dir).await? {
+                    return Err(store_meta_mismatch_err("缺少store_meta.json，目录中已有数据", &format!("{:?}", current)));
+                }
+                let tmp_path = format!("{}.tmp", path);
+                fs::write(&tmp_path, serde_json::to_vec_pretty(&current)?).await?;
+                fs::rename(&tmp_path, &path).await?;
+                Ok(current)
+            }
+            Err(e) => Err(e.into())
+        }
+    }
+}
+
+/// 目录中是否已有数据：非空的wal或slot的文件。打开数据目录时会先创建空的wal
+async fn has_data(data_dir: &str) -> CustomResult<bool> {
+    match fs::metadata(get_wal_file_path(data_dir.to_string())).await {
+        Ok(m) if m.len() > 0 => return Ok(true),
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    let mut entries = match fs::read_dir(data_dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_name().to_string_lossy().starts_with("slot_") {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::custom_error::STORE_META_MISMATCH_CODE;
+    use crate::store::meta::{get_store_meta_path, StoreMeta};
+    use crate::store::slot::get_slot_index_file_path;
+    use crate::store::wal::get_wal_file_path;
+
+    #[test]
+    pub fn test_refuse_other_hash() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let data_dir = std::env::temp_dir().join(format!("feature_db_meta_test_{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&data_dir);
+            std::fs::create_dir_all(&data_dir).unwrap();
+            let data_dir = data_dir.to_str().unwrap().to_string();
+
+            // 首次打开写入元数据，再次打开校验通过
+            assert_eq!(StoreMeta::load_or_init(&data_dir).await.unwrap(), StoreMeta::current());
+            assert_eq!(StoreMeta::load_or_init(&data_dir).await.unwrap(), StoreMeta::current());
+
+            let other = StoreMeta {
+                format_version: StoreMeta::current().format_version,
+                hash_algorithm: "std-default-hasher".to_string(),
+            };
+            std::fs::write(get_store_meta_path(&data_dir), serde_json::to_vec(&other).unwrap()).unwrap();
+            assert_eq!(StoreMeta::load_or_init(&data_dir).await.unwrap_err().code, STORE_META_MISMATCH_CODE);
+
+            // 缺少元数据时，空的wal可以初始化，已有数据时拒绝
+            std::fs::remove_file(get_store_meta_path(&data_dir)).unwrap();
+            std::fs::write(get_wal_file_path(data_dir.clone()), b"").unwrap();
+            assert_eq!(StoreMeta::load_or_init(&data_dir).await.unwrap(), StoreMeta::current());
+            std::fs::remove_file(get_store_meta_path(&data_dir)).unwrap();
+            std::fs::write(get_slot_index_file_path(&data_dir, 1), b"").unwrap();
+            assert_eq!(StoreMeta::load_or_init(&data_dir).await.unwrap_err().code, STORE_META_MISMATCH_CODE);
+            std::fs::remove_file(get_slot_index_file_path(&data_dir, 1)).unwrap();
+            std::fs::write(get_wal_file_path(data_dir.clone()), b"x").unwrap();
+            assert_eq!(StoreMeta::load_or_init(&data_dir).await.unwrap_err().code, STORE_META_MISMATCH_CODE);
+
+            let _ = std::fs::remove_dir_all(&data_dir);
+        });
+    }
+}
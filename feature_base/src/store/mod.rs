@@ -3,18 +3,26 @@ use std::collections::HashMap;
 use std::fmt::Debug;
 use std::io::Cursor;
 use std::sync::Arc;
+use std::time::Instant;
 
 use bytes::BytesMut;
 use tokio::sync::{ RwLock};
 use serde::{Deserialize, Serialize};
 use crate::custom_error::{common_err, CustomResult};
+use crate::metrics;
+use crate::store::meta::StoreMeta;
 use crate::store::page::Page;
-use crate::store::slot::{Slot, SLOT_NUM_BY_BIT};
+use crate::store::slot::{Slot, slot_id_of_hash, SLOT_NUM_BY_BIT};
 use crate::store::wal::Wal;
 
 pub mod wal;
 pub mod page;
 pub mod slot;
+pub mod meta;
+pub mod replication;
+pub mod backup;
+pub mod inspect;
+pub mod fsck;
 mod recover;
 
 /// store-->slot--->page--->record
@@ -25,7 +33,10 @@ pub struct Store {
 
 
 impl Store {
-    pub async fn new(data_dir: String) -> Store {
+    pub async fn new(data_dir: String) -> CustomResult<Store> {
+        // 校验数据目录的版本和hash算法
+        StoreMeta::load_or_init(&data_dir).await?;
+
         let mut slot_index = HashMap::new();
         for i in 0..1 << SLOT_NUM_BY_BIT {
             slot_index.insert(i, Slot::new(i, data_dir.clone()).await);
@@ -34,14 +45,13 @@ impl Store {
             data_dir,
             slot_index,
         };
-        recover::recover(&mut store).await.expect("恢复失败！");
-        store
+        recover::recover(&mut store).await?;
+        Ok(store)
     }
 
     /// 计算slot的值
     pub fn get_slot(&self, key_hash: u64) -> CustomResult<&Slot> {
-        // let slot_id = (key_hash >> (64 - SLOT_NUM_BY_BIT)) as u16;
-        let slot_id = 0;
+        let slot_id = slot_id_of_hash(key_hash);
         self.slot_index.get(&slot_id).ok_or(common_err(format!("获取slot失败！")))
     }
 
@@ -51,6 +61,14 @@ impl Store {
     }
 
     pub async fn check_point(&self, wal: &Wal) -> CustomResult<()> {
+        let start = Instant::now();
+        let res = self.do_check_point(wal).await;
+        let result = if res.is_ok() { "ok" } else { "err" };
+        metrics::CHECKPOINT_DURATION.with_label_values(&[result]).observe(start.elapsed().as_secs_f64());
+        res
+    }
+
+    async fn do_check_point(&self, wal: &Wal) -> CustomResult<()> {
         for (_, slot) in &self.slot_index {
             slot.store_page(wal).await?;
             slot.store_page_index(wal).await?;
@@ -58,6 +76,41 @@ impl Store {
 
         Ok(())
     }
+
+    /// 是否还有未刷盘的page或索引
+    pub async fn has_dirty(&self) -> bool {
+        for slot in self.slot_index.values() {
+            if !slot.dirty_pages.lock().await.is_empty() || slot.index_dirty.is_dirty().await {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// 刷盘所有的脏页和索引，停机前调用
+    pub async fn check_point_all(&self, wal: &Wal) -> CustomResult<()> {
+        while self.has_dirty().await {
+            self.check_point(wal).await?;
+        }
+        Ok(())
+    }
+
+    /// 写入正常停机标记，必须在 check_point_all 和 Wal::close 之后调用
+    pub async fn mark_clean_shutdown(&self) -> CustomResult<()> {
+        recover::write_clean_shutdown_marker(&self.data_dir).await
+    }
+
+    /// 所有page编码后的总字节数，不是实际占用的内存；需要逐个获取page读锁，只用于低频采样
+    pub async fn encoded_bytes(&self) -> usize {
+        let mut total = 0;
+        for slot in self.slot_index.values() {
+            let pages: Vec<_> = slot.page_tree.read().await.values().cloned().collect();
+            for page in pages {
+                total += page.read().await.need_space();
+            }
+        }
+        total
+    }
 }
 
 /// 可存储的接口定义
@@ -5,18 +5,24 @@ use bytes::{Buf, BufMut, BytesMut};
 use log::info;
 use serde::Serialize;
 
-use crate::calc_hash;
-use crate::custom_error::{common_err, CustomResult};
+use crate::{calc_checksum, calc_hash};
+use crate::custom_error::{common_err, CustomResult, decode_failed_by_insufficient_data_err};
+use crate::feature::key::FeatureKey;
 use crate::feature::value::FeatureValue;
+use crate::store::wal::{WalFeatureUpdateValue, WalRedo};
+use crate::metrics;
 use crate::store::{Dirty, Storable, Store};
 use crate::store::slot::{PAGE_SIZE, Slot};
 
+/// page头部的大小：size + checksum + slot_id + page_id + min_pk + max_pk
+pub const PAGE_HEADER_SIZE: usize = 8 + 8 + 2 + 8 + 8 + 8;
+
 /// 页
 #[derive(Debug, Serialize)]
 pub struct Page {
     pub slot_id: u16,
     pub id: u64,
-    pub data: BTreeMap<String, FeatureValue>,
+    pub data: BTreeMap<FeatureKey, FeatureValue>,
     pub min_pk: u64,
     pub max_pk: u64,
     /// 是否是脏页
@@ -36,15 +42,50 @@ impl Page {
         }
     }
 
-    pub async fn get(&self, key: &String) -> Option<&FeatureValue> {
+    pub async fn get(&self, key: &FeatureKey) -> Option<&FeatureValue> {
         self.data.get(key)
     }
-    pub async fn put(&mut self, key: String, value: FeatureValue) -> CustomResult<()> {
+
+    /// 获取可变的值，调用方必须持有page的写锁
+    pub async fn get_mut(&mut self, key: &FeatureKey) -> Option<&mut FeatureValue> {
+        self.data.get_mut(key)
+    }
+    pub async fn put(&mut self, key: FeatureKey, value: FeatureValue) -> CustomResult<()> {
        // info!("page[{},{}] key len:{},insert key:{}", self.slot_id,self.id,self.data.keys().len(),&key);
         self.data.insert(key, value);
         Ok(())
     }
 
+    /// 按日志覆盖或删除对应分片，重复应用结果不变；分片全部删除后去掉key
+    pub async fn redo(&mut self, redo: &WalRedo) {
+        match redo {
+            WalRedo::Update(update) => {
+                self.data.entry(update.fk.clone())
+                    .or_insert_with(FeatureValue::new)
+                    .set(update.tk, update.redo_v.clone());
+            }
+            WalRedo::Remove(remove) => {
+                if let Some(value) = self.data.get_mut(&remove.fk) {
+                    value.remove(remove.tk);
+                    if value.is_empty() {
+                        self.data.remove(&remove.fk);
+                    }
+                }
+            }
+        }
+    }
+
+    /// 把page中的数据转换为更新日志，按顺序redo后得到相同的数据
+    pub fn snapshot(&self) -> Vec<WalFeatureUpdateValue> {
+        let mut updates = vec![];
+        for (key, value) in &self.data {
+            for (tk, v) in value.iter() {
+                updates.push(WalFeatureUpdateValue { fk: key.clone(), tk: *tk, undo_v: None, redo_v: v.clone() });
+            }
+        }
+        updates
+    }
+
     /// 更新page后调用，参数为数据变更的大小，可为负值
     pub async fn after_update(&mut self, action_id: u64, store: &Store) {
         if self.dirty.update(action_id).await {
@@ -52,6 +93,7 @@ impl Page {
             if let Some(slot) = store.slot_index.get(&self.slot_id) {
                 let mut dp = slot.dirty_pages.lock().await;
                 dp.push(self.min_pk);
+                metrics::SLOT_DIRTY_PAGES.with_label_values(&[&self.slot_id.to_string()]).set(dp.len() as i64);
                 info!("加入 dirty 列表:{}", self.id);
             }
         }
@@ -62,7 +104,7 @@ impl Page {
 
         let mut data = BTreeMap::new();
         for (k, v) in &self.data {
-            let hash = calc_hash(k);
+            let hash = calc_hash(k.as_bytes());
             let entry = data.entry(hash).or_insert(vec![]);
             entry.push((k.clone(), v.clone()));
         }
@@ -87,38 +129,50 @@ impl Page {
 
 impl Storable for Page {
     fn encode(&self, buf: &mut BytesMut) -> CustomResult<()> {
+        let start = buf.len();
         let size = self.need_space() as u64;
         buf.put_u64(size);
+        // 校验和覆盖之后的所有内容，最后回填
+        buf.put_u64(0);
         buf.put_u16(self.slot_id);
         buf.put_u64(self.id as u64);
         buf.put_u64(self.min_pk);
         buf.put_u64(self.max_pk);
 
         for (k, v) in &self.data {
-            buf.put_u16(k.len() as u16);
-            buf.put(k.as_bytes());
+            k.encode(buf)?;
             v.encode(buf)?;
         }
+        let checksum = calc_checksum(&buf[start + 16..]);
+        buf[start + 8..start + 16].copy_from_slice(&checksum.to_be_bytes());
         Ok(())
     }
 
     fn decode(buf: &mut Cursor<&[u8]>) -> CustomResult<Self> where Self: Sized {
-        if buf.remaining() < 8 {
+        if buf.remaining() < PAGE_HEADER_SIZE {
             return Err(common_err(format!("page数据格式非法，解析失败！")));
         }
-        let size = buf.get_u64();
+        let start = buf.position() as usize;
+        let size = buf.get_u64() as usize;
+        let checksum = buf.get_u64();
+        if size < PAGE_HEADER_SIZE {
+            return Err(common_err(format!("page大小:{} 非法，解析失败！", size)));
+        }
+        if buf.remaining() < size - 16 {
+            return Err(decode_failed_by_insufficient_data_err());
+        }
+        let end = start + size;
+        if calc_checksum(&buf.get_ref()[start + 16..end]) != checksum {
+            return Err(common_err("page校验和不一致，数据已损坏".to_string()));
+        }
         let slot_id = buf.get_u16();
         let page_id = buf.get_u64();
         let min_key = buf.get_u64();
         let max_key = buf.get_u64();
         let mut page = Page::new(slot_id, page_id, min_key, max_key);
 
-        while buf.remaining() > 0 {
-            let key_len = buf.get_u16();
-
-            // buf.read
-            let bytes = buf.copy_to_bytes(key_len as usize);
-            let key = String::from_utf8(bytes.to_vec())?;
+        while (buf.position() as usize) < end {
+            let key = FeatureKey::decode(buf)?;
 
             let value = FeatureValue::decode(buf)?;
             page.data.insert(key, value);
@@ -128,9 +182,9 @@ impl Storable for Page {
     }
 
     fn need_space(&self) -> usize {
-        let mut space = 8 + 2 + 8 + 8 + 8;
+        let mut space = PAGE_HEADER_SIZE;
         for (k, v) in &self.data {
-            space = space + 2 + k.len() + v.need_space();
+            space = space + k.need_space() + v.need_space();
         }
         space
     }
@@ -1,24 +1,78 @@
 
+use std::collections::{HashMap, HashSet};
 use std::io::{Cursor, SeekFrom};
 use std::sync::Arc;
 
 use bytes::{ BytesMut};
 use log::{info, warn};
-use tokio::fs::OpenOptions;
-use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use serde::{Deserialize, Serialize};
+use tokio::fs::{self, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 use tokio::sync::RwLock;
 
-use crate::custom_error::{ CustomResult, DECODE_FAILED_BY_INSUFFICIENT_DATA_CODE};
+use crate::custom_error::{common_err, CustomResult, DECODE_FAILED_BY_INSUFFICIENT_DATA_CODE};
+use crate::metrics;
 use crate::store::{Storable, Store};
-use crate::store::wal::{get_wal_file_path, WalLogItem};
+use crate::store::fsck::{repair_index, repair_page};
+use crate::store::inspect::{read_page, read_slot_index, read_slot_pages};
+use crate::store::page::Page;
+use crate::store::wal::{current_ids, get_wal_file_path, restore_ids, WalLogItem, WalLogKind, WalPageBkStoreValue, WalPageIndexStoreValue};
+
+pub fn get_clean_shutdown_path(data_dir: &str) -> String {
+    format!("{}/clean_shutdown", data_dir)
+}
+
+/// 正常停机的标记，存在时说明停机前已完成检查点且wal全部落盘，启动时可以跳过重放
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CleanShutdown {
+    pub tid: u64,
+    pub action_id: u64,
+}
+
+pub async fn write_clean_shutdown_marker(data_dir: &str) -> CustomResult<()> {
+    let (tid, action_id) = current_ids();
+    write_marker(data_dir, CleanShutdown { tid, action_id }).await
+}
+
+/// 写入指定ID的停机标记，用于从备份恢复的数据目录
+pub async fn write_marker(data_dir: &str, marker: CleanShutdown) -> CustomResult<()> {
+    let path = get_clean_shutdown_path(data_dir);
+    let tmp_path = format!("{}.tmp", path);
+
+    let mut f = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&tmp_path)
+        .await?;
+    f.write_all(&serde_json::to_vec(&marker)?).await?;
+    f.sync_data().await?;
+    fs::rename(&tmp_path, &path).await?;
+    info!("写入停机标记:{:?}", marker);
+    Ok(())
+}
+
+/// 读取并删除停机标记，删除后再次崩溃时会正常重放
+async fn take_clean_shutdown_marker(data_dir: &str) -> CustomResult<Option<CleanShutdown>> {
+    let path = get_clean_shutdown_path(data_dir);
+    match fs::read(&path).await {
+        Ok(bytes) => {
+            let marker: CleanShutdown = serde_json::from_slice(&bytes)?;
+            fs::remove_file(&path).await?;
+            Ok(Some(marker))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into())
+    }
+}
 
 pub async fn recover(store: &mut Store) -> CustomResult<()> {
-    // 初始化
-    for (slot_id, slot) in &store.slot_index {
-        let page = slot.new_page(0,1<<64-1).await?;
-        slot.page_tree.write().await
-            .insert(0, Arc::new(RwLock::new(page)));
+    if let Some(marker) = take_clean_shutdown_marker(&store.data_dir).await? {
+        info!("上次正常停机，跳过wal重放:{:?}", marker);
+        restore_ids(marker.tid, marker.action_id);
+        return load_pages(store).await;
     }
+
     // 从磁盘恢复
     let wal_log_path = get_wal_file_path(store.data_dir.clone());
 
@@ -29,20 +83,42 @@ pub async fn recover(store: &mut Store) -> CustomResult<()> {
 
     let mut buf = BytesMut::with_capacity(1024);
     let mut before_pos = 0;
-    let mut pos = 0;
+    let mut next_tid = 0;
+    let mut next_action_id = 0;
+    // 每个slot最后一次写page和索引的记录：slot -> (事务ID, page_id) / 事务ID
+    let mut last_page_store: HashMap<u16, (u64, u64)> = HashMap::new();
+    let mut last_index_store: HashMap<u16, u64> = HashMap::new();
+    let mut committed = HashSet::new();
 
     loop {
-        let res = {
+        let (res, pos) = {
             let mut cursor: Cursor<&[u8]> = Cursor::new(&*buf);
             cursor.seek(SeekFrom::Start(before_pos)).await?;
             let res = WalLogItem::decode(&mut cursor);
-            pos = cursor.position();
-            res
+            (res, cursor.position())
         };
         match res {
-            Ok(item) => {
+            Ok(mut item) => {
                 before_pos = pos;
+                next_tid = next_tid.max(item.tid + 1);
+                next_action_id = next_action_id.max(item.action_id + 1);
                 info!("item:{:?}", item);
+                match item.kind {
+                    WalLogKind::Commit => {
+                        committed.insert(item.tid);
+                    }
+                    WalLogKind::PageBkStore => {
+                        if let Some(v) = item.value.as_mut().and_then(|v| v.as_mut().as_any().downcast_mut::<WalPageBkStoreValue>()) {
+                            last_page_store.insert(v.slot_id, (item.tid, v.page_id));
+                        }
+                    }
+                    WalLogKind::PageIndexStore => {
+                        if let Some(v) = item.value.as_mut().and_then(|v| v.as_mut().as_any().downcast_mut::<WalPageIndexStoreValue>()) {
+                            last_index_store.insert(v.slot_id, item.tid);
+                        }
+                    }
+                    _ => {}
+                }
             }
             Err(e) => {
                 if e.code == DECODE_FAILED_BY_INSUFFICIENT_DATA_CODE {
@@ -54,34 +130,235 @@ pub async fn recover(store: &mut Store) -> CustomResult<()> {
                     }
                 } else {
                     warn!("解析出现错误:buf={},{}", buf.len(), e);
+                    break;
                 }
             }
         }
     }
+    restore_ids(next_tid, next_action_id);
+    repair_torn_files(&store.data_dir, last_page_store, last_index_store, &committed).await?;
+
+    load_pages(store).await
+}
+
+/// 按slot索引加载page，没有索引的slot只有初始的page 0；从未写入磁盘的page为空
+async fn load_pages(store: &Store) -> CustomResult<()> {
+    for (slot_id, slot) in &store.slot_index {
+        let entries = read_slot_pages(&store.data_dir, *slot_id).await?;
+        let mut page_tree = slot.page_tree.write().await;
+        let mut bitmap = slot.page_bit_map.lock().await;
+        for (i, (min_pk, page_id)) in entries.iter().enumerate() {
+            let page = match read_page(&store.data_dir, *slot_id, *page_id).await? {
+                Some(page) => page,
+                None => {
+                    let max_pk = entries.get(i + 1).map_or(u64::MAX, |(next_min_pk, _)| *next_min_pk);
+                    Page::new(*slot_id, *page_id, *min_pk, max_pk)
+                }
+            };
+            bitmap.set(*page_id, true);
+            page_tree.insert(*min_pk, Arc::new(RwLock::new(page)));
+        }
+    }
+    Ok(())
+}
 
+/// 写page和索引时先写双写副本并记录日志，再原地覆盖文件并刷盘，最后提交事务。
+/// 检查每个slot最后一次写入的page和索引：事务已提交说明覆盖已完成；没有提交时覆盖可能还没开始或只写了一半，
+/// 副本中是更新的数据，用副本覆盖（repair_page 会校验副本中是同一个page）；已提交但无法解析时同样从副本恢复
+async fn repair_torn_files(data_dir: &str, last_page_store: HashMap<u16, (u64, u64)>,
+                           last_index_store: HashMap<u16, u64>, committed: &HashSet<u64>) -> CustomResult<()> {
+    for (slot_id, tid) in last_index_store {
+        match read_slot_index(data_dir, slot_id).await {
+            Ok(_) if committed.contains(&tid) => {}
+            Ok(_) => {
+                if repair_index(data_dir, slot_id).await? {
+                    info!("slot:{} 索引从副本写入，事务:{} 未提交", slot_id, tid);
+                    metrics::TORN_REPAIRS.with_label_values(&["index"]).inc();
+                }
+            }
+            Err(e) => {
+                warn!("slot:{} 索引损坏，最后写入的事务:{} 已提交:{}，{}", slot_id, tid, committed.contains(&tid), e);
+                if !repair_index(data_dir, slot_id).await? {
+                    return Err(common_err(format!("slot:{} 索引损坏且无法从副本恢复，请使用fsck检查:{}", slot_id, e)));
+                }
+                metrics::TORN_REPAIRS.with_label_values(&["index"]).inc();
+            }
+        }
+    }
+    for (slot_id, (tid, page_id)) in last_page_store {
+        match read_page(data_dir, slot_id, page_id).await {
+            Ok(Some(_)) if committed.contains(&tid) => {}
+            Ok(_) => {
+                if repair_page(data_dir, slot_id, page_id).await? {
+                    info!("slot:{} page:{} 从副本写入，事务:{} 未提交", slot_id, page_id, tid);
+                    metrics::TORN_REPAIRS.with_label_values(&["page"]).inc();
+                }
+            }
+            Err(e) => {
+                warn!("slot:{} page:{} 损坏，最后写入的事务:{} 已提交:{}，{}", slot_id, page_id, tid, committed.contains(&tid), e);
+                if !repair_page(data_dir, slot_id, page_id).await? {
+                    return Err(common_err(format!("slot:{} page:{} 损坏且无法从副本恢复，请使用fsck检查:{}", slot_id, page_id, e)));
+                }
+                metrics::TORN_REPAIRS.with_label_values(&["page"]).inc();
+            }
+        }
+    }
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
+    use bytes::BytesMut;
+
+    use crate::calc_hash;
     use crate::config::Config;
-    use crate::store::{Store};
+    use crate::feature::key::{FeatureKey, KeyPart};
+    use crate::feature::value::{FeatureValue, ValueKind};
+    use crate::store::{Storable, Store};
+    use crate::store::inspect::{read_page, read_slot_index};
+    use crate::store::slot::{encode_page_index, get_page_file_position, get_shard_file_path, get_slot_index_bk_file_path, get_slot_index_file_path, slot_id_of_hash};
+    use crate::store::wal::{crate_wal, generate_tid, WalPageBkStoreValue, WalPageIndexStoreValue};
 
 
     #[test]
     pub fn test_recover() {
-        crate::init_log();
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let data_dir = std::env::temp_dir().join(format!("feature_db_recover_test_{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&data_dir);
+            std::fs::create_dir_all(&data_dir).unwrap();
+            let config = Config {
+                data_dir: data_dir.to_str().unwrap().to_string(),
+                ..Config::default()
+            };
+
+            crate_wal(config.data_dir.clone(), config.wal_sync, config.wal_queue_size, None).await.expect("创建wal失败！");
+            Store::new(config.data_dir.clone()).await.expect("恢复失败！");
+
+            let _ = std::fs::remove_dir_all(&data_dir);
+        });
+    }
 
+    #[test]
+    pub fn test_repair_torn_files() {
         let rt = tokio::runtime::Builder::new_multi_thread()
             .enable_all()
             .build()
             .unwrap();
         rt.block_on(async {
+            let data_dir = std::env::temp_dir().join(format!("feature_db_torn_test_{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&data_dir);
+            std::fs::create_dir_all(&data_dir).unwrap();
             let config = Config {
-                data_dir: "/Users/yang/feature_db".to_string()
+                data_dir: data_dir.to_str().unwrap().to_string(),
+                ..Config::default()
             };
+            let data_dir_str = config.data_dir.as_str();
+            let wal = crate_wal(config.data_dir.clone(), config.wal_sync, config.wal_queue_size, None).await.expect("创建wal失败！");
+            let store = Store::new(config.data_dir.clone()).await.expect("创建store失败！");
+
+            let key = FeatureKey::new(1, &[KeyPart::Text("torn".to_string())]).unwrap();
+            let hash = calc_hash(key.as_bytes());
+            let slot_id = slot_id_of_hash(hash);
+            let tid = generate_tid();
+            wal.send_begin_log(tid).await.unwrap();
+            {
+                let (_, page) = store.get_page(hash).await.unwrap();
+                let mut page = page.write().await;
+                let mut value = FeatureValue::new();
+                let update = value.add_int(&key, 1000, 1000, 1).unwrap();
+                page.put(key.clone(), value).await.unwrap();
+                let action_id = wal.send_feature_update_log(tid, update).await.unwrap();
+                page.after_update(action_id, &store).await;
+            }
+            wal.commit_log(tid).await.unwrap();
+            store.check_point_all(&wal).await.unwrap();
+
+            // 写索引时崩溃：副本已写完并记录日志，索引只写了一部分
+            let index = encode_page_index(&[(0, 0)]);
+            std::fs::write(get_slot_index_bk_file_path(data_dir_str, slot_id), &index).unwrap();
+            std::fs::write(get_slot_index_file_path(data_dir_str, slot_id), &index[..10]).unwrap();
+            let tid = generate_tid();
+            wal.send_begin_log(tid).await.unwrap();
+            wal.send_page_index_store_log(tid, WalPageIndexStoreValue::new(slot_id)).await.unwrap();
+            wal.close().await.unwrap();
 
-            Store::new(config.data_dir.clone()).await;
+            // page的最后一个字节损坏
+            let (path, _) = get_page_file_position(data_dir_str, slot_id, 0);
+            let mut bytes = std::fs::read(&path).unwrap();
+            let page_size = read_page(data_dir_str, slot_id, 0).await.unwrap().unwrap().need_space();
+            bytes[page_size - 1] ^= 0xff;
+            std::fs::write(&path, &bytes).unwrap();
+            assert!(read_page(data_dir_str, slot_id, 0).await.is_err());
+
+            Store::new(config.data_dir.clone()).await.expect("恢复失败！");
+            let page = read_page(data_dir_str, slot_id, 0).await.unwrap().expect("page");
+            assert_eq!(page.data.get(&key).and_then(|v| v.get(1000)), Some(&ValueKind::Int(1)));
+            assert_eq!(read_slot_index(data_dir_str, slot_id).await.unwrap(), Some(vec![(0, 0)]));
+
+            let _ = std::fs::remove_dir_all(&data_dir);
+        });
+    }
+
+    /// 副本已写完并记录日志，覆盖page之前崩溃：原page仍能解析，但副本中的数据更新
+    #[test]
+    pub fn test_repair_uncommitted_page() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let data_dir = std::env::temp_dir().join(format!("feature_db_uncommitted_page_test_{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&data_dir);
+            std::fs::create_dir_all(&data_dir).unwrap();
+            let config = Config {
+                data_dir: data_dir.to_str().unwrap().to_string(),
+                ..Config::default()
+            };
+            let data_dir_str = config.data_dir.as_str();
+            let wal = crate_wal(config.data_dir.clone(), config.wal_sync, config.wal_queue_size, None).await.expect("创建wal失败！");
+            let store = Store::new(config.data_dir.clone()).await.expect("创建store失败！");
+
+            let key = FeatureKey::new(1, &[KeyPart::Text("uncommitted".to_string())]).unwrap();
+            let hash = calc_hash(key.as_bytes());
+            let slot_id = slot_id_of_hash(hash);
+            let tid = generate_tid();
+            wal.send_begin_log(tid).await.unwrap();
+            let (_, page) = store.get_page(hash).await.unwrap();
+            {
+                let mut page = page.write().await;
+                let mut value = FeatureValue::new();
+                let update = value.add_int(&key, 1000, 1000, 1).unwrap();
+                page.put(key.clone(), value).await.unwrap();
+                let action_id = wal.send_feature_update_log(tid, update).await.unwrap();
+                page.after_update(action_id, &store).await;
+            }
+            wal.commit_log(tid).await.unwrap();
+            store.check_point_all(&wal).await.unwrap();
+
+            // 新的值只写入了副本
+            let mut buf = BytesMut::new();
+            {
+                let mut page = page.write().await;
+                page.data.get_mut(&key).unwrap().add_int(&key, 1000, 1000, 1).unwrap();
+                page.encode(&mut buf).unwrap();
+            }
+            std::fs::write(get_shard_file_path(data_dir_str, slot_id), &buf).unwrap();
+            let tid = generate_tid();
+            wal.send_begin_log(tid).await.unwrap();
+            wal.send_page_bk_store_log(tid, WalPageBkStoreValue { slot_id, page_id: 0, min_pk: 0, max_pk: u64::MAX }).await.unwrap();
+            wal.close().await.unwrap();
+            let page = read_page(data_dir_str, slot_id, 0).await.unwrap().expect("page");
+            assert_eq!(page.data.get(&key).and_then(|v| v.get(1000)), Some(&ValueKind::Int(1)));
+
+            Store::new(config.data_dir.clone()).await.expect("恢复失败！");
+            let page = read_page(data_dir_str, slot_id, 0).await.unwrap().expect("page");
+            assert_eq!(page.data.get(&key).and_then(|v| v.get(1000)), Some(&ValueKind::Int(2)));
+
+            let _ = std::fs::remove_dir_all(&data_dir);
         });
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,186 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+use tokio::sync::{Notify, watch};
+use tokio::time;
+
+use crate::custom_error::{CustomResult, replication_gap_err};
+
+/// leader的复制缓冲区：保存最近写入wal的日志，供follower拉取
+///
+/// 日志按action id递增的顺序加入，follower用下一条需要的action id拉取，
+/// 同时报告已应用到的位置，即小于该值的日志都已应用。
+pub struct ReplicationLog {
+    // (action id, 编码后的 WalLogItem)
+    items: Mutex<VecDeque<(u64, Vec<u8>)>>,
+    capacity: usize,
+    // 小于该值的日志已不在缓冲区中
+    floor: AtomicU64,
+    // 有新的日志
+    notify: Notify,
+    // follower已应用到的位置
+    acked: watch::Sender<u64>,
+    // 是否有follower在拉取，没有时提交不等待确认
+    attached: AtomicBool,
+    // 提交时是否等待follower确认，以及最长等待时间
+    sync_ack: bool,
+    ack_timeout: Duration,
+}
+
+impl ReplicationLog {
+    pub fn new(capacity: usize, sync_ack: bool, ack_timeout: Duration) -> ReplicationLog {
+        let (acked, _) = watch::channel(0);
+        ReplicationLog {
+            items: Mutex::new(VecDeque::new()),
+            capacity,
+            floor: AtomicU64::new(0),
+            notify: Notify::new(),
+            acked,
+            attached: AtomicBool::new(false),
+            sync_ack,
+            ack_timeout,
+        }
+    }
+
+    /// 恢复完成后调用，之前的日志不在缓冲区中
+    pub fn set_floor(&self, next_action_id: u64) {
+        self.floor.store(next_action_id, Ordering::Release);
+    }
+
+    /// 日志写入wal后加入缓冲区
+    pub fn push(&self, action_id: u64, bytes: Vec<u8>) {
+        {
+            let mut items = self.items.lock().unwrap();
+            items.push_back((action_id, bytes));
+            while items.len() > self.capacity {
+                if let Some((id, _)) = items.pop_front() {
+                    self.floor.fetch_max(id + 1, Ordering::AcqRel);
+                }
+            }
+        }
+        self.notify.notify_waiters();
+    }
+
+    /// follower拉取action id不小于from的日志，最多max_items条，没有新日志时最多等待wait
+    pub async fn fetch(&self, from: u64, max_items: usize, wait: Duration) -> CustomResult<Vec<u8>> {
+        self.attached.store(true, Ordering::Release);
+        self.read(from, max_items, wait).await
+    }
+
+    /// 缓冲区中最早的日志位置
+    pub fn floor(&self) -> u64 {
+        self.floor.load(Ordering::Acquire)
+    }
+
+    /// 与fetch相同，但不作为follower，提交时不会等待确认，用于订阅变更
+    pub async fn read(&self, from: u64, max_items: usize, wait: Duration) -> CustomResult<Vec<u8>> {
+        let deadline = time::Instant::now() + wait;
+        loop {
+            let notified = self.notify.notified();
+            {
+                let floor = self.floor.load(Ordering::Acquire);
+                if from < floor {
+                    return Err(replication_gap_err(from, floor));
+                }
+                let items = self.items.lock().unwrap();
+                let start = items.partition_point(|(id, _)| *id < from);
+                if start < items.len() {
+                    let mut res = vec![];
+                    for (_, bytes) in items.range(start..).take(max_items) {
+                        res.extend_from_slice(bytes);
+                    }
+                    return Ok(res);
+                }
+            }
+            if time::timeout_at(deadline, notified).await.is_err() {
+                return Ok(vec![]);
+            }
+        }
+    }
+
+    /// follower报告已应用到的位置
+    pub fn ack(&self, next_action_id: u64) {
+        self.attached.store(true, Ordering::Release);
+        // 只前进不后退；并发确认时如果覆盖了更大的值，再写回去
+        if next_action_id > *self.acked.borrow() {
+            let prev = self.acked.send_replace(next_action_id);
+            if prev > next_action_id {
+                self.acked.send_replace(prev);
+            }
+        }
+    }
+
+    pub fn acked(&self) -> u64 {
+        *self.acked.borrow()
+    }
+
+    /// 开启同步确认且有follower时，等待follower应用到action_id；超时后视为follower已断开，之后不再等待
+    pub async fn wait_ack(&self, action_id: u64) -> bool {
+        if !self.sync_ack || !self.attached.load(Ordering::Acquire) {
+            return true;
+        }
+        let mut rx = self.acked.subscribe();
+        let res = time::timeout(self.ack_timeout, async {
+            while *rx.borrow_and_update() <= action_id {
+                if rx.changed().await.is_err() {
+                    return;
+                }
+            }
+        }).await;
+        if res.is_err() {
+            self.attached.store(false, Ordering::Release);
+            return false;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::custom_error::REPLICATION_GAP_CODE;
+    use crate::store::replication::ReplicationLog;
+
+    #[test]
+    pub fn test_fetch_and_ack() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let log = Arc::new(ReplicationLog::new(3, true, Duration::from_millis(200)));
+            for id in 0..3u64 {
+                log.push(id, vec![id as u8]);
+            }
+            let wait = Duration::from_millis(10);
+            assert_eq!(log.fetch(1, 10, wait).await.unwrap(), vec![1, 2]);
+            assert_eq!(log.fetch(0, 1, wait).await.unwrap(), vec![0]);
+            assert!(log.fetch(3, 10, wait).await.unwrap().is_empty());
+
+            // 超出容量后最早的日志被丢弃
+            log.push(3, vec![3]);
+            assert_eq!(log.fetch(0, 10, wait).await.unwrap_err().code, REPLICATION_GAP_CODE);
+
+            // 等待新日志
+            let log2 = log.clone();
+            let handle = tokio::spawn(async move { log2.fetch(4, 10, Duration::from_secs(5)).await });
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            log.push(4, vec![4]);
+            assert_eq!(handle.await.unwrap().unwrap(), vec![4]);
+
+            // 同步确认
+            let log2 = log.clone();
+            let handle = tokio::spawn(async move { log2.wait_ack(4).await });
+            log.ack(5);
+            assert!(handle.await.unwrap());
+            assert_eq!(log.acked(), 5);
+            // follower不再确认时超时，之后不再等待
+            assert!(!log.wait_ack(10).await);
+            assert!(log.wait_ack(10).await);
+        });
+    }
+}
@@ -3,29 +3,89 @@ use std::collections::BTreeMap;
 use std::io::SeekFrom;
 use std::sync::Arc;
 
-use bytes::{BufMut, BytesMut};
+use std::io::Cursor;
+
+use bytes::{Buf, BufMut, BytesMut};
 use log::info;
 use tokio::fs::{File, OpenOptions};
 use tokio::io::{AsyncSeekExt, AsyncWriteExt};
 use tokio::sync::{Mutex, RwLock};
 
+use crate::calc_checksum;
 use crate::custom_error::{common_err, CustomResult};
+use crate::metrics;
 use crate::store::{Dirty, Storable};
 use crate::store::page::Page;
 use crate::store::wal::{generate_tid, Wal, WalPageBkStoreValue, WalPageIndexStoreValue};
 use crate::tools::bitmap::BitMap;
 
 /// 每个slot最多拥有的page数量，乘以 PAGE_SIZE = slot最多存储的数据量
-const PAGE_NUM: u32 = 1 << 18;
+pub const PAGE_NUM: u32 = 1 << 18;
 /// slot数量的bit表示法，即 2^12
 pub const SLOT_NUM_BY_BIT: u16 = 12;
 
+/// 根据key的hash计算所属的slot，取hash的高位
+pub fn slot_id_of_hash(key_hash: u64) -> u16 {
+    (key_hash >> (64 - SLOT_NUM_BY_BIT)) as u16
+}
+
 /// 页的大小 64K
 pub const PAGE_SIZE: u32 = 1 << 16;
 
 /// 文件大小 1G
 pub const FILE_SIZE: u32 = 1 << 30;
 
+pub fn get_slot_index_file_path(data_dir: &str, slot_id: u16) -> String {
+    format!("{}/slot_{}_index", data_dir, slot_id)
+}
+
+/// 编码slot索引：(min_pk, page_id) 列表，最后8个字节为校验和
+pub fn encode_page_index(entries: &[(u64, u64)]) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(entries.len() * 16 + 8);
+    for (min_pk, page_id) in entries {
+        buf.put_u64(*min_pk);
+        buf.put_u64(*page_id);
+    }
+    let checksum = calc_checksum(&buf);
+    buf.put_u64(checksum);
+    buf
+}
+
+/// 解析slot索引，长度或校验和不对时说明文件写了一半或已损坏
+pub fn decode_page_index(bytes: &[u8]) -> CustomResult<Vec<(u64, u64)>> {
+    if bytes.len() < 8 || !(bytes.len() - 8).is_multiple_of(16) {
+        return Err(common_err(format!("索引文件长度:{} 非法", bytes.len())));
+    }
+    let (data, checksum) = bytes.split_at(bytes.len() - 8);
+    if calc_checksum(data) != Cursor::new(checksum).get_u64() {
+        return Err(common_err("索引文件校验和不一致，数据已损坏".to_string()));
+    }
+    let mut buf = Cursor::new(data);
+    let mut entries = vec![];
+    while buf.has_remaining() {
+        entries.push((buf.get_u64(), buf.get_u64()));
+    }
+    Ok(entries)
+}
+
+/// 索引的双写副本
+pub fn get_slot_index_bk_file_path(data_dir: &str, slot_id: u16) -> String {
+    format!("{}/slot_{}_index_bk", data_dir, slot_id)
+}
+
+/// page的双写副本，保存slot最近一次写入的page
+pub fn get_shard_file_path(data_dir: &str, slot_id: u16) -> String {
+    format!("{}/slot_{}_shard", data_dir, slot_id)
+}
+
+/// page所在的文件和文件内的偏移，每个文件存放 FILE_SIZE / PAGE_SIZE 个page
+pub fn get_page_file_position(data_dir: &str, slot_id: u16, page_id: u64) -> (String, u64) {
+    let page_id = page_id as u32;
+    let file_index = PAGE_SIZE * page_id / FILE_SIZE;
+    let seek_pos = PAGE_SIZE * page_id % FILE_SIZE;
+    (format!("{}/slot_{}_page_{}", data_dir, slot_id, file_index), seek_pos as u64)
+}
+
 /// 分片
 #[derive(Debug)]
 pub struct Slot {
@@ -74,7 +134,7 @@ impl Slot {
 
     pub async fn get_page(&self, key_hash: u64) -> CustomResult<(u64, Arc<RwLock<Page>>)> {
         let page_tree = self.page_tree.read().await;
-        let (mk, page) = page_tree.range(..key_hash).last()
+        let (mk, page) = page_tree.range(..=key_hash).last()
             .ok_or(common_err(format!("找不到对应的page:{}", key_hash)))?;
         Ok((mk.clone(), page.clone()))
     }
@@ -87,13 +147,22 @@ impl Slot {
 
         let tid = generate_tid();
         wal.send_begin_log(tid).await?;
+        if let Err(e) = self.store_index(wal, tid).await {
+            wal.abort_log(tid).await;
+            return Err(e);
+        }
+        // 写完成，提交事务
+        wal.commit_log(tid).await?;
+        Ok(())
+    }
 
-        let mut buf = BytesMut::new();
+    async fn store_index(&self, wal: &Wal, tid: u64) -> CustomResult<()> {
         let page_tree = self.page_tree.read().await;
+        let mut entries = vec![];
         for (k, v) in page_tree.iter() {
-            buf.put_u64(k.clone());
-            buf.put_u64(v.read().await.id);
+            entries.push((*k, v.read().await.id));
         }
+        let mut buf = encode_page_index(&entries);
 
         let mut cp_buf = buf.clone();
 
@@ -109,8 +178,6 @@ impl Slot {
         f.sync_data().await?;
 
         self.index_dirty.reset().await;
-        // 写完成，提交事务
-        wal.commit_log(tid).await?;
         Ok(())
     }
 
@@ -126,6 +193,7 @@ impl Slot {
                 pages.push(page.clone());
             }
         }
+        metrics::SLOT_DIRTY_PAGES.with_label_values(&[&self.id.to_string()]).set(dp.len() as i64);
         pages
     }
 
@@ -137,54 +205,69 @@ impl Slot {
 
             let tid = generate_tid();
             wal.send_begin_log(tid).await?;
+            if let Err(e) = self.store_one_page(wal, tid, &page).await {
+                // 事务不会再提交，follower和变更订阅丢弃它的日志；page仍是脏页，放回列表等下次检查点重试
+                wal.abort_log(tid).await;
+                self.dirty_pages.lock().await.push(page.min_pk);
+                return Err(e);
+            }
+            wal.commit_log(tid).await?;
+        }
+
+        Ok(())
+    }
 
-            let mut buf = BytesMut::new();
-            page.encode(&mut buf)?;
-            let mut buf_bk = buf.clone();
-            info!("待写入page:{},size:{},need_space:{}", page.id, buf.len(), page.need_space());
-
-            // 写入备份
-            let mut shard_f = self.get_shard_page_store_file().await?;
-            shard_f.write_buf(&mut buf).await;
-            shard_f.sync_data().await?;
-            let bk_action_id = wal.send_page_bk_store_log(tid, WalPageBkStoreValue {
-                slot_id: page.slot_id,
-                page_id: page.id,
-                min_pk: page.min_pk,
-                max_pk: page.max_pk,
-            }).await?;
-
-            if (page.need_space() as u32) < PAGE_SIZE {
-                let mut page_file = self.get_page_store_file(page.id).await?;
-                page_file.write_buf(&mut buf_bk).await?;
-                page_file.sync_data().await?;
-                page.dirty.reset().await;
-                info!("保存page:{}成功！", page.id);
-            } else {
-                let slit_page = page.split(&self).await?;
-
-                let slit_page_ids: Vec<u64> = slit_page.iter().map(|p| p.id).collect();
-                info!("page分裂:old={},new:{:?}", page.id, slit_page_ids);
-
-                let mut page_tree = self.page_tree.write().await;
-                for p in slit_page {
-                    let mut buf = BytesMut::new();
-                    p.encode(&mut buf)?;
-                    let mut pf = self.get_page_store_file(p.id).await?;
-                    pf.write_buf(&mut buf).await?;
-                    pf.sync_data().await?;
-
-                    info!("插入page:{}:{}", p.min_pk, p.id);
-                    page_tree.insert(p.min_pk, Arc::new(RwLock::new(p)));
+    /// 先写副本并记录日志，再覆盖page；超过PAGE_SIZE时分裂后写入新的page
+    async fn store_one_page(&self, wal: &Wal, tid: u64, page: &Page) -> CustomResult<()> {
+        let mut buf = BytesMut::new();
+        page.encode(&mut buf)?;
+        let mut buf_bk = buf.clone();
+        info!("待写入page:{},size:{},need_space:{}", page.id, buf.len(), page.need_space());
+
+        // 写入备份
+        let mut shard_f = self.get_shard_page_store_file().await?;
+        shard_f.write_buf(&mut buf).await?;
+        shard_f.sync_data().await?;
+        let bk_action_id = wal.send_page_bk_store_log(tid, WalPageBkStoreValue {
+            slot_id: page.slot_id,
+            page_id: page.id,
+            min_pk: page.min_pk,
+            max_pk: page.max_pk,
+        }).await?;
+
+        if (page.need_space() as u32) < PAGE_SIZE {
+            let mut page_file = self.get_page_store_file(page.id).await?;
+            page_file.write_buf(&mut buf_bk).await?;
+            page_file.sync_data().await?;
+            page.dirty.reset().await;
+            info!("保存page:{}成功！", page.id);
+        } else {
+            let slit_page = page.split(self).await?;
+
+            let slit_page_ids: Vec<u64> = slit_page.iter().map(|p| p.id).collect();
+            info!("page分裂:old={},new:{:?}", page.id, slit_page_ids);
+            metrics::PAGE_SPLITS.inc();
+
+            let mut page_tree = self.page_tree.write().await;
+            for p in slit_page {
+                let mut buf = BytesMut::new();
+                p.encode(&mut buf)?;
+                // 同一个key不能拆分，超过上限时写入会覆盖相邻的page
+                if buf.len() > PAGE_SIZE as usize {
+                    return Err(common_err(format!("page:{} 拆分后大小:{} 超过上限:{}", p.id, buf.len(), PAGE_SIZE)));
                 }
+                let mut pf = self.get_page_store_file(p.id).await?;
+                pf.write_buf(&mut buf).await?;
+                pf.sync_data().await?;
 
-                // 释放之前的page
-                self.freed_page(page.id).await;
-                self.index_dirty.update(bk_action_id).await;
+                info!("插入page:{}:{}", p.min_pk, p.id);
+                page_tree.insert(p.min_pk, Arc::new(RwLock::new(p)));
             }
-            wal.commit_log(tid).await?;
-        }
 
+            // 释放之前的page
+            self.freed_page(page.id).await;
+            self.index_dirty.update(bk_action_id).await;
+        }
         Ok(())
     }
 
@@ -194,7 +277,7 @@ impl Slot {
             .write(true)
             .create(true)
             .truncate(true)
-            .open(format!("{}/slot_{}_index", self.data_dir, self.id))
+            .open(get_slot_index_file_path(&self.data_dir, self.id))
             .await?)
     }
 
@@ -204,7 +287,7 @@ impl Slot {
             .write(true)
             .create(true)
             .truncate(true)
-            .open(format!("{}/slot_{}_index_bk", self.data_dir, self.id))
+            .open(get_slot_index_bk_file_path(&self.data_dir, self.id))
             .await?)
     }
 
@@ -215,24 +298,22 @@ impl Slot {
             .write(true)
             .create(true)
             .truncate(true)
-            .open(format!("{}/slot_{}_shard", self.data_dir, self.id))
+            .open(get_shard_file_path(&self.data_dir, self.id))
             .await?)
     }
 
     /// 共享的page写文件，dubbo write的第一次写入文件
     async fn get_page_store_file(&self, page_id: u64) -> CustomResult<File> {
-        let page_id = page_id as u32;
-        let file_index = PAGE_SIZE * page_id / FILE_SIZE;
-        let seek_pos = PAGE_SIZE * page_id % FILE_SIZE;
+        let (path, seek_pos) = get_page_file_position(&self.data_dir, self.id, page_id);
         info!("page file:{},seek:{}", page_id, seek_pos);
 
         let mut page_file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(format!("{}/slot_{}_page_{}", self.data_dir, self.id, file_index))
+            .open(path)
             .await?;
-        page_file.seek(SeekFrom::Start(seek_pos as u64)).await?;
+        page_file.seek(SeekFrom::Start(seek_pos)).await?;
         Ok(page_file)
     }
 }
@@ -1,3 +1,4 @@
+use std::collections::{BTreeMap, BTreeSet};
 use std::convert::TryFrom;
 use std::fmt::Debug;
 use std::io::{Cursor, Error};
@@ -10,14 +11,19 @@ use log::{info, warn};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 use tokio::fs::{File, OpenOptions};
 use tokio::io::AsyncWriteExt;
-use tokio::sync::{mpsc, Mutex, oneshot, RwLock};
+use tokio::sync::{mpsc, Mutex, Notify, oneshot, RwLock};
 use tokio::sync::mpsc::{Receiver, Sender};
 
-use crate::custom_error::{ CustomResult, decode_failed_by_insufficient_data_err};
+use crate::config::WalSyncPolicy;
+use crate::custom_error::{common_err, CustomResult, decode_failed_by_insufficient_data_err};
+use crate::feature::key::FeatureKey;
 use crate::feature::value::ValueKind;
+use crate::metrics;
 use crate::store::Storable;
+use crate::store::replication::ReplicationLog;
 
-pub type Callback = oneshot::Sender<u64>;
+/// 日志落盘后回调事务ID；写入或刷盘失败时返回错误
+pub type Callback = oneshot::Sender<CustomResult<u64>>;
 
 pub fn get_wal_file_path(data_dir: String) -> String {
     format!("{}/redo.log", data_dir)
@@ -38,16 +44,68 @@ pub fn generate_action_id() -> u64 {
     ACTION_ID.fetch_add(1, Ordering::AcqRel)
 }
 
+/// 当前的 (事务ID, 动作ID)，即下一个将要分配的值
+pub fn current_ids() -> (u64, u64) {
+    (T_ID.load(Ordering::Acquire), ACTION_ID.load(Ordering::Acquire))
+}
+
+/// 重启后从已有的日志恢复ID，保证ID持续递增
+pub fn restore_ids(tid: u64, action_id: u64) {
+    T_ID.fetch_max(tid, Ordering::AcqRel);
+    ACTION_ID.fetch_max(action_id, Ordering::AcqRel);
+}
+
+/// 已分配但还没有写入的动作ID，写入线程只写小于其中最小值的日志
+struct Reservations {
+    ids: std::sync::Mutex<BTreeSet<u64>>,
+    // 有动作ID被放弃，写入线程需要重新检查
+    released: Notify,
+}
+
+/// 预先分配的动作ID，用于在持有page锁时确定日志顺序、释放锁后再发送；没有发送就被丢弃时释放
+pub struct ReservedActionId {
+    pub action_id: u64,
+    reservations: Arc<Reservations>,
+    sent: bool,
+}
+
+impl Drop for ReservedActionId {
+    fn drop(&mut self) {
+        if !self.sent {
+            self.reservations.ids.lock().unwrap().remove(&self.action_id);
+            self.reservations.released.notify_one();
+        }
+    }
+}
+
 /// 预写日志
+///
+/// 日志按动作ID的顺序写入，与进入队列的顺序无关：更新日志的动作ID在持有page锁时分配，
+/// 同一个key的更新日志顺序与更新顺序一致
 pub struct Wal {
     pub send: Mutex<Sender<WalLogItem>>,
     pub state: Arc<RwLock<WalState>>,
+    pub sync_policy: WalSyncPolicy,
+    // 写入后的日志同时加入复制缓冲区，供follower拉取
+    pub replication: Option<Arc<ReplicationLog>>,
+    reservations: Arc<Reservations>,
 }
 
 impl Wal {
-    pub async fn send_log(&self, tid: u64, kind: WalLogKind, value: Option<Box<dyn Storable>>, callback: Option<Callback>) -> CustomResult<u64> {
-        let send = self.send.lock().await;
+    /// 分配动作ID，不会等待
+    pub fn reserve(&self) -> ReservedActionId {
+        let mut ids = self.reservations.ids.lock().unwrap();
+        // 在锁内生成，写入线程看到的已分配ID不会漏掉更小的值
         let action_id = generate_action_id();
+        ids.insert(action_id);
+        ReservedActionId { action_id, reservations: self.reservations.clone(), sent: false }
+    }
+
+    /// 用预先分配的动作ID发送日志
+    pub async fn send_reserved(&self, mut reserved: ReservedActionId, tid: u64, kind: WalLogKind,
+                               value: Option<Box<dyn Storable>>, callback: Option<Callback>) -> CustomResult<u64> {
+        let send = self.send.lock().await;
+        let action_id = reserved.action_id;
         send.send(WalLogItem {
             tid,
             kind,
@@ -55,10 +113,17 @@ impl Wal {
             value,
             callback,
         }).await?;
+        reserved.sent = true;
+        metrics::WAL_QUEUE_DEPTH.inc();
 
         Ok(action_id)
     }
 
+    pub async fn send_log(&self, tid: u64, kind: WalLogKind, value: Option<Box<dyn Storable>>, callback: Option<Callback>) -> CustomResult<u64> {
+        let reserved = self.reserve();
+        self.send_reserved(reserved, tid, kind, value, callback).await
+    }
+
     pub async fn send_begin_log(&self, tid: u64) -> CustomResult<u64> {
         self.send_log(tid, WalLogKind::Begin, None, None).await
     }
@@ -75,53 +140,146 @@ impl Wal {
         self.send_log(tid, WalLogKind::PageBkStore, Some(Box::new(value)), None).await
     }
 
+    /// 放弃已写入Begin的事务，follower和变更订阅丢弃它未提交的日志；尽力发送，失败时只记录日志
+    pub async fn abort_log(&self, tid: u64) {
+        if let Err(e) = self.send_log(tid, WalLogKind::Abort, None, None).await {
+            warn!("事务:{} 放弃日志发送失败:{:?}", tid, e);
+        }
+    }
+
     pub async fn commit_log(&self, tid: u64) -> CustomResult<()> {
         let (tx, rx) = oneshot::channel();
         let action_id = self.send_log(tid, WalLogKind::Commit, None, Some(tx)).await?;
 
-        let tid: u64 = rx.await?;
+        let tid: u64 = rx.await??;
         //info!("commit_log:{}", tid);
+        if let Some(replication) = &self.replication {
+            if !replication.wait_ack(action_id).await {
+                warn!("等待follower确认超时，事务:{} 只在本地提交", tid);
+            }
+        }
+        Ok(())
+    }
+
+    /// wal队列是否已满，满时新的事务应当被拒绝而不是排队等待
+    pub async fn is_full(&self) -> bool {
+        self.send.lock().await.capacity() == 0
+    }
+
+    /// 写入结束日志并等待之前的日志全部落盘，之后写入线程退出，不能再发送日志
+    pub async fn close(&self) -> CustomResult<()> {
+        let (tx, rx) = oneshot::channel();
+        self.send_log(generate_tid(), WalLogKind::End, None, Some(tx)).await?;
+        rx.await??;
         Ok(())
     }
 
 
     pub fn start_write(&self, mut f: File, mut rx: Receiver<WalLogItem>) {
         let state = self.state.clone();
+        let sync_policy = self.sync_policy;
+        let replication = self.replication.clone();
+        let reservations = self.reservations.clone();
 
         tokio::spawn(async move {
+            // 已收到但前面还有动作ID没有收到的日志
+            let mut pending: BTreeMap<u64, WalLogItem> = BTreeMap::new();
+            // 写入或刷盘失败后文件状态未知，之后的提交都返回这个错误
+            let mut failure: Option<String> = None;
             loop {
-                if let Some(message) = rx.recv().await {
-                    let mut buf = BytesMut::new();
-                    match message.encode(&mut buf) {
-                        Ok(_) => {
-                           match  f.write_buf(&mut buf).await{
-                               Ok(_) => {}
-                               Err(e) => {
-                                   warn!("wal写入失败!,{:?}",e);
-                               }
-                           }
+                tokio::select! {
+                    message = rx.recv() => match message {
+                        Some(message) => {
+                            metrics::WAL_QUEUE_DEPTH.dec();
+                            pending.insert(message.action_id, message);
                         }
-                        Err(e) => {
-                            warn!("序列化失败:{:?},{:?}", message,e);
+                        None => {
+                            info!("recv none");
+                            break;
                         }
+                    },
+                    _ = reservations.released.notified() => {}
+                }
+                while let Some(message) = next_in_order(&mut pending, &reservations) {
+                    let is_end = message.kind == WalLogKind::End;
+                    write_item(&mut f, message, sync_policy, &replication, &state, &mut failure).await;
+                    if is_end {
+                        info!("wal写入线程退出");
+                        return;
                     }
-                    // if message.action_id % 100 == 0 {
-                    f.sync_data().await;
-                    let mut lock = state.write().await;
-                    (*lock).stored_num = message.action_id;
-                    // }
-
-                    if let Some(callback) = message.callback {
-                        callback.send(message.tid);
-                    }
-                } else {
-                    info!("recv none");
                 }
             }
         });
     }
 }
 
+/// 取出动作ID最小的日志，比它小的已分配ID都已收到或已放弃时才能写入
+fn next_in_order(pending: &mut BTreeMap<u64, WalLogItem>, reservations: &Reservations) -> Option<WalLogItem> {
+    let action_id = *pending.keys().next()?;
+    let mut ids = reservations.ids.lock().unwrap();
+    if ids.range(..action_id).next().is_some() {
+        return None;
+    }
+    ids.remove(&action_id);
+    pending.remove(&action_id)
+}
+
+async fn write_item(f: &mut File, message: WalLogItem, sync_policy: WalSyncPolicy,
+                    replication: &Option<Arc<ReplicationLog>>, state: &RwLock<WalState>, failure: &mut Option<String>) {
+    let mut buf = BytesMut::new();
+    let mut encoded = None;
+    let mut item_error = None;
+    match message.encode(&mut buf) {
+        Ok(_) => {
+           if replication.is_some() {
+               encoded = Some(buf.to_vec());
+           }
+           match  f.write_buf(&mut buf).await{
+               Ok(_) => {}
+               Err(e) => {
+                   warn!("wal写入失败!,{:?}",e);
+                   failure.get_or_insert(format!("wal写入失败:{}", e));
+               }
+           }
+        }
+        Err(e) => {
+            warn!("序列化失败:{:?},{:?}", message,e);
+            item_error = Some(format!("wal日志序列化失败:{}", e));
+        }
+    }
+    let need_sync = message.kind == WalLogKind::End || match sync_policy {
+        WalSyncPolicy::Always => true,
+        // page备份相关的日志和提交日志一样需要落盘
+        WalSyncPolicy::Commit => matches!(message.kind,
+            WalLogKind::Commit | WalLogKind::PageBkStore | WalLogKind::PageIndexStore),
+        WalSyncPolicy::Never => false,
+    };
+    if need_sync {
+        let timer = metrics::WAL_FSYNC_LATENCY.start_timer();
+        if let Err(e) = f.sync_data().await {
+            warn!("wal刷盘失败!,{:?}", e);
+            failure.get_or_insert(format!("wal刷盘失败:{}", e));
+        }
+        timer.observe_duration();
+    }
+    // 没有落盘的日志不能让follower应用
+    if let (Some(replication), Some(bytes), None) = (replication, encoded, &failure) {
+        replication.push(message.action_id, bytes);
+    }
+    let mut lock = state.write().await;
+    lock.stored_num = message.action_id;
+    let lag = ACTION_ID.load(Ordering::Acquire).saturating_sub(message.action_id + 1);
+    metrics::WAL_STORED_LAG.set(lag as i64);
+
+    if let Some(callback) = message.callback {
+        let res = match item_error.as_ref().or(failure.as_ref()) {
+            Some(msg) => Err(common_err(msg.clone())),
+            None => Ok(message.tid),
+        };
+        let _ = callback.send(res);
+    }
+}
+
 pub struct WalState {
     pub stored_num: u64,
 }
@@ -132,18 +290,20 @@ impl WalState {
     }
 }
 
-pub async fn crate_wal(data_dir: String) -> CustomResult<Wal> {
+pub async fn crate_wal(data_dir: String, sync_policy: WalSyncPolicy, queue_size: usize,
+                       replication: Option<Arc<ReplicationLog>>) -> CustomResult<Wal> {
     let f = OpenOptions::new()
         .read(true)
-        .write(true)
+        .append(true)
         .create(true)
         .open(get_wal_file_path(data_dir))
         .await?;
 
-    let (tx, rx): (Sender<WalLogItem>, Receiver<WalLogItem>) = mpsc::channel(100);
+    let (tx, rx): (Sender<WalLogItem>, Receiver<WalLogItem>) = mpsc::channel(queue_size);
     let state = WalState::new();
 
-    let wal = Wal { send: Mutex::new(tx), state: Arc::new(RwLock::new(state)) };
+    let reservations = Arc::new(Reservations { ids: std::sync::Mutex::new(BTreeSet::new()), released: Notify::new() });
+    let wal = Wal { send: Mutex::new(tx), state: Arc::new(RwLock::new(state)), sync_policy, replication, reservations };
     wal.start_write(f, rx);
     Ok(wal)
 }
@@ -163,6 +323,12 @@ pub enum WalLogKind {
     PageBkStore = 5,
 
     PageIndexStore = 8,
+
+    // 事务放弃，之前的日志作废，不会再提交
+    Abort = 6,
+
+    // 指标分片删除，如slot迁出后清理数据
+    FeatureRemove = 7,
 }
 
 #[derive(Debug)]
@@ -206,6 +372,9 @@ impl Storable for WalLogItem {
             WalLogKind::FeatureUpdate => {
                 Some(Box::new(WalFeatureUpdateValue::decode(buf)?))
             }
+            WalLogKind::FeatureRemove => {
+                Some(Box::new(WalFeatureRemoveValue::decode(buf)?))
+            }
             WalLogKind::PageIndexStore => {
                 Some(Box::new(WalPageIndexStoreValue::decode(buf)?))
             }
@@ -232,10 +401,10 @@ impl Storable for WalLogItem {
 }
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct WalFeatureUpdateValue {
     // feature key
-    pub fk: String,
+    pub fk: FeatureKey,
     // 时间分片key
     pub tk: u64,
     pub undo_v: Option<ValueKind>,
@@ -244,8 +413,7 @@ pub struct WalFeatureUpdateValue {
 
 impl Storable for WalFeatureUpdateValue {
     fn encode(&self, buf: &mut BytesMut) -> CustomResult<()> {
-        buf.put_u32(self.fk.len() as u32);
-        buf.put(self.fk.as_bytes());
+        self.fk.encode(buf)?;
         buf.put_u64(self.tk);
         match &self.undo_v {
             None => {
@@ -260,8 +428,7 @@ impl Storable for WalFeatureUpdateValue {
     }
 
     fn decode(buf: &mut Cursor<&[u8]>) -> CustomResult<Self> where Self: Sized {
-        let fk_len = buf.get_u32();
-        let fk = String::from_utf8(buf.copy_to_bytes(fk_len as usize).to_vec())?;
+        let fk = FeatureKey::decode(buf)?;
         let tk = buf.get_u64();
         let undo_v_flag = buf.get_u8();
         let undo_v = if undo_v_flag == 0 {
@@ -279,7 +446,7 @@ impl Storable for WalFeatureUpdateValue {
     }
 
     fn need_space(&self) -> usize {
-        4 + self.fk.len() + 8 + match &self.undo_v {
+        self.fk.need_space() + 8 + match &self.undo_v {
             None => 1,
             Some(v) => {
                 1 + v.need_space()
@@ -288,6 +455,99 @@ impl Storable for WalFeatureUpdateValue {
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct WalFeatureRemoveValue {
+    // feature key
+    pub fk: FeatureKey,
+    // 时间分片key
+    pub tk: u64,
+    // 删除前的值
+    pub undo_v: ValueKind,
+}
+
+impl Storable for WalFeatureRemoveValue {
+    fn encode(&self, buf: &mut BytesMut) -> CustomResult<()> {
+        self.fk.encode(buf)?;
+        buf.put_u64(self.tk);
+        self.undo_v.encode(buf)
+    }
+
+    fn decode(buf: &mut Cursor<&[u8]>) -> CustomResult<Self> where Self: Sized {
+        let fk = FeatureKey::decode(buf)?;
+        let tk = buf.get_u64();
+        let undo_v = ValueKind::decode(buf)?;
+        Ok(WalFeatureRemoveValue { fk, tk, undo_v })
+    }
+
+    fn need_space(&self) -> usize {
+        self.fk.need_space() + 8 + self.undo_v.need_space()
+    }
+}
+
+/// 可以按顺序重做的数据变更：覆盖或删除一个分片
+#[derive(Debug, Clone)]
+pub enum WalRedo {
+    Update(WalFeatureUpdateValue),
+    Remove(WalFeatureRemoveValue),
+}
+
+impl WalRedo {
+    /// 从FeatureUpdate或FeatureRemove日志中取出变更
+    pub fn from_item(item: &mut WalLogItem) -> CustomResult<WalRedo> {
+        let value = item.value.as_mut().map(|v| v.as_mut().as_any());
+        let redo = match (&item.kind, value) {
+            (WalLogKind::FeatureUpdate, Some(v)) => v.downcast_mut::<WalFeatureUpdateValue>().cloned().map(WalRedo::Update),
+            (WalLogKind::FeatureRemove, Some(v)) => v.downcast_mut::<WalFeatureRemoveValue>().cloned().map(WalRedo::Remove),
+            _ => None,
+        };
+        redo.ok_or(common_err(format!("wal日志内容错误:{:?}", item)))
+    }
+
+    pub fn fk(&self) -> &FeatureKey {
+        match self {
+            WalRedo::Update(v) => &v.fk,
+            WalRedo::Remove(v) => &v.fk,
+        }
+    }
+
+    /// 转换为写入wal的日志类型和内容
+    pub fn into_log(self) -> (WalLogKind, Box<dyn Storable>) {
+        match self {
+            WalRedo::Update(v) => (WalLogKind::FeatureUpdate, Box::new(v)),
+            WalRedo::Remove(v) => (WalLogKind::FeatureRemove, Box::new(v)),
+        }
+    }
+}
+
+/// 把多条更新日志编码为一个批次：每条为 长度(u32) + 内容，用于在node之间传输
+pub fn encode_updates(updates: &[WalFeatureUpdateValue]) -> CustomResult<Vec<u8>> {
+    let mut buf = BytesMut::new();
+    for update in updates {
+        buf.put_u32(update.need_space() as u32);
+        update.encode(&mut buf)?;
+    }
+    Ok(buf.to_vec())
+}
+
+pub fn decode_updates(bytes: &[u8]) -> CustomResult<Vec<WalFeatureUpdateValue>> {
+    let mut buf = Cursor::new(bytes);
+    let mut updates = vec![];
+    while buf.has_remaining() {
+        if buf.remaining() < 4 {
+            return Err(decode_failed_by_insufficient_data_err());
+        }
+        let len = buf.get_u32() as usize;
+        if buf.remaining() < len {
+            return Err(decode_failed_by_insufficient_data_err());
+        }
+        let start = buf.position() as usize;
+        let mut item = Cursor::new(&bytes[start..start + len]);
+        updates.push(WalFeatureUpdateValue::decode(&mut item)?);
+        buf.set_position((start + len) as u64);
+    }
+    Ok(updates)
+}
+
 #[derive(Debug)]
 pub struct WalPageIndexStoreValue {
     pub slot_id: u16,
@@ -349,4 +609,88 @@ impl Storable for WalPageBkStoreValue {
     fn need_space(&self) -> usize {
         2 + 8 + 8 + 8
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::config::WalSyncPolicy;
+    use crate::store::replication::ReplicationLog;
+    use crate::store::Storable;
+    use crate::store::wal::{crate_wal, generate_tid, get_wal_file_path, Reservations, Wal, WalLogItem, WalLogKind, WalState};
+
+    #[test]
+    pub fn test_write_in_action_id_order() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let data_dir = std::env::temp_dir().join(format!("feature_db_wal_test_{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&data_dir);
+            std::fs::create_dir_all(&data_dir).unwrap();
+            let replication = Arc::new(ReplicationLog::new(100, false, Duration::from_secs(1)));
+            let wal = crate_wal(data_dir.to_str().unwrap().to_string(), WalSyncPolicy::Commit, 10, Some(replication.clone())).await.unwrap();
+
+            let tid = generate_tid();
+            let first = wal.reserve();
+            let dropped = wal.reserve();
+            let second = wal.reserve();
+            let ids = (first.action_id, second.action_id);
+            // 后分配的先发送，放弃的ID不会阻塞写入
+            wal.send_reserved(second, tid, WalLogKind::Begin, None, None).await.unwrap();
+            drop(dropped);
+            wal.send_reserved(first, tid, WalLogKind::Begin, None, None).await.unwrap();
+            wal.commit_log(tid).await.unwrap();
+
+            let bytes = replication.read(0, 10, Duration::from_millis(10)).await.unwrap();
+            let mut buf = Cursor::new(&bytes[..]);
+            let mut action_ids = vec![];
+            while (buf.position() as usize) < bytes.len() {
+                action_ids.push(WalLogItem::decode(&mut buf).unwrap().action_id);
+            }
+            assert_eq!(&action_ids[..2], &[ids.0, ids.1]);
+            assert_eq!(action_ids.len(), 3);
+            wal.close().await.unwrap();
+            let _ = std::fs::remove_dir_all(&data_dir);
+        });
+    }
+
+    #[test]
+    pub fn test_commit_fails_after_write_error() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let data_dir = std::env::temp_dir().join(format!("feature_db_wal_failure_test_{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&data_dir);
+            std::fs::create_dir_all(&data_dir).unwrap();
+            let path = get_wal_file_path(data_dir.to_str().unwrap().to_string());
+            std::fs::write(&path, b"").unwrap();
+            // 只读打开，写入失败
+            let f = tokio::fs::OpenOptions::new().read(true).open(&path).await.unwrap();
+            let (tx, rx) = tokio::sync::mpsc::channel(10);
+            let reservations = Arc::new(Reservations { ids: std::sync::Mutex::new(Default::default()), released: Default::default() });
+            let wal = Wal {
+                send: tokio::sync::Mutex::new(tx),
+                state: Arc::new(tokio::sync::RwLock::new(WalState::new())),
+                sync_policy: WalSyncPolicy::Commit,
+                replication: None,
+                reservations,
+            };
+            wal.start_write(f, rx);
+
+            let tid = generate_tid();
+            wal.send_begin_log(tid).await.unwrap();
+            assert!(wal.commit_log(tid).await.is_err());
+            // 之后的提交同样失败
+            let tid = generate_tid();
+            assert!(wal.commit_log(tid).await.is_err());
+            assert!(wal.close().await.is_err());
+            let _ = std::fs::remove_dir_all(&data_dir);
+        });
+    }
+}
@@ -0,0 +1,252 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use log::info;
+use tokio::fs;
+use tokio::net::TcpListener;
+use tokio::sync::{Mutex, RwLock};
+
+use feature_base::cluster::{ClusterMap, NodeInfo};
+use feature_base::custom_error::{common_err, CustomResult, not_owner_err};
+use feature_base::ds::DataSet;
+use feature_base::rpc::{call, Request, Response, spawn_server, unexpected_response_err};
+
+/// 元数据服务：维护数据集定义和集群的slot分配
+pub struct MetaServer {
+    pub data_dir: String,
+    pub cluster: RwLock<ClusterMap>,
+    pub datasets: Vec<DataSet>,
+    // 同一时间只执行一次rebalance
+    rebalance_lock: Mutex<()>,
+}
+
+fn get_cluster_path(data_dir: &str) -> String {
+    format!("{}/cluster.json", data_dir)
+}
+
+fn get_datasets_path(data_dir: &str) -> String {
+    format!("{}/datasets.json", data_dir)
+}
+
+impl MetaServer {
+    /// 从数据目录加载集群信息和数据集，不存在时使用默认值
+    pub async fn open(data_dir: &str) -> CustomResult<MetaServer> {
+        fs::create_dir_all(data_dir).await?;
+
+        let cluster = match fs::read(get_cluster_path(data_dir)).await {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => ClusterMap::assign(0, Default::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let datasets: Vec<DataSet> = match fs::read(get_datasets_path(data_dir)).await {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => default_datasets()?,
+            Err(e) => return Err(e.into()),
+        };
+        for ds in &datasets {
+            ds.validate()?;
+        }
+
+        Ok(MetaServer {
+            data_dir: data_dir.to_string(),
+            cluster: RwLock::new(cluster),
+            datasets,
+            rebalance_lock: Mutex::new(()),
+        })
+    }
+
+    pub async fn handle(&self, request: Request) -> Response {
+        match request {
+            Request::RegisterNode(node) => Response::from_result(self.register_node(node).await, Response::ClusterMap),
+            Request::GetClusterMap => Response::ClusterMap(self.cluster.read().await.clone()),
+            Request::GetDataSets => Response::DataSets(self.datasets.clone()),
+            Request::MoveSlots { slot_ids, from, to } =>
+                Response::from_result(self.move_slots(&slot_ids, &from, &to).await, Response::ClusterMap),
+            Request::Rebalance => Response::from_result(self.rebalance().await, Response::ClusterMap),
+            _ => Response::Error { code: 10000, message: "meta server不支持的请求".to_string() },
+        }
+    }
+
+    /// 注册node。集群还没有分配slot时按一致性hash分配；之后加入的node不拥有slot，通过rebalance迁移数据后才接管
+    pub async fn register_node(&self, node: NodeInfo) -> CustomResult<ClusterMap> {
+        let mut cluster = self.cluster.write().await;
+        if cluster.addr_of(&node.id) == Some(&node.addr) {
+            return Ok(cluster.clone());
+        }
+
+        let mut nodes = cluster.nodes.clone();
+        nodes.insert(node.id.clone(), node.addr.clone());
+        let new_cluster = if cluster.is_unassigned() {
+            ClusterMap::assign(cluster.version + 1, nodes)
+        } else {
+            ClusterMap { version: cluster.version + 1, nodes, slots: cluster.slots.clone() }
+        };
+        self.store_cluster(&new_cluster).await?;
+        info!("node注册:{:?}，集群版本:{}", node, new_cluster.version);
+
+        *cluster = new_cluster;
+        Ok(cluster.clone())
+    }
+
+    /// 切换slot的owner，由迁移的源node在数据追平后调用
+    pub async fn move_slots(&self, slot_ids: &[u16], from: &str, to: &str) -> CustomResult<ClusterMap> {
+        let mut cluster = self.cluster.write().await;
+        if cluster.addr_of(to).is_none() {
+            return Err(common_err(format!("目标node:{} 不存在", to)));
+        }
+        for slot_id in slot_ids {
+            if cluster.owner_of_slot(*slot_id).map(|s| s.as_str()) != Some(from) {
+                return Err(not_owner_err(*slot_id, cluster.owner_of_slot(*slot_id)));
+            }
+        }
+
+        let mut new_cluster = cluster.clone();
+        new_cluster.version += 1;
+        for slot_id in slot_ids {
+            new_cluster.slots[*slot_id as usize] = to.to_string();
+        }
+        self.store_cluster(&new_cluster).await?;
+        info!("{}个slot从{}迁移到{}，集群版本:{}", slot_ids.len(), from, to, new_cluster.version);
+
+        *cluster = new_cluster;
+        Ok(cluster.clone())
+    }
+
+    /// 按一致性hash重新分配slot，依次让源node把slot迁移到新的owner
+    pub async fn rebalance(&self) -> CustomResult<ClusterMap> {
+        let _guard = self.rebalance_lock.lock().await;
+        let (plan, nodes) = {
+            let cluster = self.cluster.read().await;
+            (cluster.rebalance_plan(), cluster.nodes.clone())
+        };
+        for ((from, to), slot_ids) in plan {
+            let addr = nodes.get(&from).ok_or(common_err(format!("node:{} 不存在", from)))?;
+            info!("开始迁移{}个slot:{} -> {}", slot_ids.len(), from, to);
+            match call(addr, &Request::MigrateSlots { slot_ids, target: to }).await? {
+                Response::ClusterMap(_) => {}
+                resp => return Err(unexpected_response_err(&resp)),
+            }
+        }
+        Ok(self.cluster.read().await.clone())
+    }
+
+    async fn store_cluster(&self, cluster: &ClusterMap) -> CustomResult<()> {
+        let path = get_cluster_path(&self.data_dir);
+        let tmp_path = format!("{}.tmp", path);
+        fs::write(&tmp_path, serde_json::to_vec_pretty(cluster)?).await?;
+        fs::rename(&tmp_path, &path).await?;
+        Ok(())
+    }
+}
+
+/// 启动meta server，返回实际监听的地址
+pub async fn start(data_dir: &str, listen_addr: &str) -> CustomResult<(Arc<MetaServer>, SocketAddr)> {
+    let server = Arc::new(MetaServer::open(data_dir).await?);
+    let listener = TcpListener::bind(listen_addr).await?;
+    let local_addr = listener.local_addr()?;
+    info!("meta server启动:{}", local_addr);
+
+    let s = server.clone();
+    spawn_server(listener, move |request| {
+        let s = s.clone();
+        async move { s.handle(request).await }
+    });
+    Ok((server, local_addr))
+}
+
+/// 没有配置数据集文件时使用的默认数据集
+pub fn default_datasets() -> CustomResult<Vec<DataSet>> {
+    let data = r#"
+    [
+     {
+        "id":101,
+        "name":"ds_user_order",
+        "desc":"用户订单数据集",
+        "column_type_map":{
+          "user_id":"INT",
+          "amount":"FLOAT",
+          "ts":"DATETIME"
+        },
+        "features":[
+          {
+            "id":10001,
+            "name":"用户最近30天订单数量",
+            "template":{
+              "COUNT":{
+                  "group_keys":["user_id"],
+                  "time_key":"ts",
+                  "window_unit":"DAY",
+                  "window_size":30
+              }
+            }
+          }
+        ]
+     }
+    ]
+        "#;
+    serde_json::from_str(data).map_err(|e|e.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use feature_base::cluster::NodeInfo;
+    use feature_base::custom_error::FEATURE_INVALID_CODE;
+
+    use crate::{default_datasets, MetaServer};
+
+    #[test]
+    pub fn register_node_test() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let data_dir = std::env::temp_dir().join(format!("feature_meta_test_{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&data_dir);
+            let data_dir = data_dir.to_str().unwrap().to_string();
+
+            let server = MetaServer::open(&data_dir).await.unwrap();
+            let n1 = NodeInfo { id: "n1".to_string(), addr: "127.0.0.1:7001".to_string() };
+            let n2 = NodeInfo { id: "n2".to_string(), addr: "127.0.0.1:7002".to_string() };
+            assert_eq!(server.register_node(n1.clone()).await.unwrap().version, 1);
+            // 之后加入的node不拥有slot
+            let map = server.register_node(n2).await.unwrap();
+            assert_eq!(map.version, 2);
+            assert!(map.slots_of("n2").is_empty());
+            // 重复注册不改变分配
+            assert_eq!(server.register_node(n1).await.unwrap(), map);
+
+            let map = server.move_slots(&[1, 2], "n1", "n2").await.unwrap();
+            assert_eq!(map.version, 3);
+            assert_eq!(map.slots_of("n2"), vec![1, 2]);
+            // slot已经不属于n1
+            assert!(server.move_slots(&[1], "n1", "n2").await.is_err());
+
+            // 重启后从文件恢复
+            let server = MetaServer::open(&data_dir).await.unwrap();
+            assert_eq!(*server.cluster.read().await, map);
+            assert_eq!(server.datasets.len(), 1);
+
+            let _ = std::fs::remove_dir_all(&data_dir);
+        });
+    }
+
+    /// 数据集定义在加载时校验，过滤条件中的字段必须存在且类型一致
+    #[test]
+    pub fn invalid_dataset_test() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let data_dir = std::env::temp_dir().join(format!("feature_meta_invalid_dataset_test_{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&data_dir);
+            std::fs::create_dir_all(&data_dir).unwrap();
+            let mut datasets = default_datasets().unwrap();
+            let feature = serde_json::json!({"id":10002,"name":"大额订单数","template":{"COUNT":{
+                "group_keys":["user_id"],"time_key":"ts","window_unit":"DAY","window_size":30,
+                "filter":{"GT":["amount","100"]}}}});
+            datasets[0].features.push(serde_json::from_value(feature).unwrap());
+            std::fs::write(data_dir.join("datasets.json"), serde_json::to_vec(&datasets).unwrap()).unwrap();
+
+            let e = MetaServer::open(data_dir.to_str().unwrap()).await.err().expect("应当校验失败");
+            assert_eq!(e.code, FEATURE_INVALID_CODE);
+
+            let _ = std::fs::remove_dir_all(&data_dir);
+        });
+    }
+}
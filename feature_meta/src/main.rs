@@ -1,3 +1,23 @@
-fn main() {
-    println!("Hello, world!");
+use std::collections::HashMap;
+
+use feature_base::config::parse_args;
+
+/// 启动: feature_meta --listen-addr 127.0.0.1:6500 --data-dir ./meta_data
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: HashMap<String, String> = match parse_args(&args) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let listen_addr = args.get("listen_addr").map(|s| s.as_str()).unwrap_or("127.0.0.1:6500");
+    let data_dir = args.get("data_dir").map(|s| s.as_str()).unwrap_or("./meta_data");
+    let log_config = args.get("log_config").map(|s| s.as_str()).unwrap_or("");
+    feature_base::init_log_with(log_config);
+
+    feature_meta::start(data_dir, listen_addr).await.expect("启动meta server失败！");
+    tokio::signal::ctrl_c().await.expect("监听退出信号失败");
 }
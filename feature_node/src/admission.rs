@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use feature_base::config::Config;
+use feature_base::custom_error::{overloaded_err, CustomResult};
+
+/// 令牌桶，容量为每秒的速率，即最多允许1秒的突发
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> TokenBucket {
+        TokenBucket {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+
+    fn try_take(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// 接入控制：限制同时处理的事件数和每个数据集的速率，超出时直接拒绝，由调用方稍后重试
+pub struct Admission {
+    in_flight: Arc<Semaphore>,
+    max_in_flight: usize,
+    default_rate: u64,
+    dataset_rates: HashMap<i64, u64>,
+    buckets: Mutex<HashMap<i64, TokenBucket>>,
+}
+
+impl Admission {
+    pub fn new(config: &Config) -> Admission {
+        let dataset_rates = config.dataset_rate_limits.iter()
+            .filter_map(|(k, v)| k.parse::<i64>().ok().map(|k| (k, *v)))
+            .collect();
+        Admission {
+            in_flight: Arc::new(Semaphore::new(config.max_in_flight)),
+            max_in_flight: config.max_in_flight,
+            default_rate: config.dataset_rate_limit,
+            dataset_rates,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 申请处理一个事件，返回的permit释放后才允许新的事件进入
+    pub fn admit(&self, ds_id: i64) -> CustomResult<OwnedSemaphorePermit> {
+        let permit = self.in_flight.clone().try_acquire_owned()
+            .map_err(|_| overloaded_err(format!("处理中的事件数已达上限:{}", self.max_in_flight)))?;
+
+        let rate = *self.dataset_rates.get(&ds_id).unwrap_or(&self.default_rate);
+        if rate > 0 {
+            let mut buckets = self.buckets.lock().unwrap();
+            let bucket = buckets.entry(ds_id).or_insert_with(|| TokenBucket::new(rate));
+            if !bucket.try_take(Instant::now()) {
+                return Err(overloaded_err(format!("数据集:{} 超过速率限制:{}/s", ds_id, rate)));
+            }
+        }
+        Ok(permit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    use feature_base::config::Config;
+
+    use crate::admission::{Admission, TokenBucket};
+
+    #[test]
+    pub fn in_flight_limit_test() {
+        let config = Config {
+            max_in_flight: 2,
+            ..Config::default()
+        };
+        let admission = Admission::new(&config);
+
+        let p1 = admission.admit(101).unwrap();
+        let _p2 = admission.admit(101).unwrap();
+        let e = admission.admit(101).unwrap_err();
+        assert!(e.is_retriable());
+
+        drop(p1);
+        assert!(admission.admit(101).is_ok());
+    }
+
+    #[test]
+    pub fn rate_limit_test() {
+        let mut rates = HashMap::new();
+        rates.insert("102".to_string(), 3);
+        let config = Config {
+            dataset_rate_limit: 5,
+            dataset_rate_limits: rates,
+            ..Config::default()
+        };
+        let admission = Admission::new(&config);
+
+        let passed = (0..10).filter(|_| admission.admit(101).is_ok()).count();
+        assert_eq!(passed, 5);
+        let passed = (0..10).filter(|_| admission.admit(102).is_ok()).count();
+        assert_eq!(passed, 3);
+    }
+
+    #[test]
+    pub fn token_bucket_refill_test() {
+        let mut bucket = TokenBucket::new(10);
+        let start = Instant::now();
+        for _ in 0..10 {
+            assert!(bucket.try_take(start));
+        }
+        assert!(!bucket.try_take(start));
+        assert!(bucket.try_take(start + Duration::from_millis(150)));
+        // 桶容量不超过1秒的量
+        let later = start + Duration::from_secs(10);
+        assert_eq!((0..20).filter(|_| bucket.try_take(later)).count(), 10);
+    }
+}
@@ -0,0 +1,425 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use log::{debug, info, warn};
+use serde_json::{json, Map, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+
+use feature_base::custom_error::{common_err, CustomResult, replication_gap_err, REPLICATION_GAP_CODE};
+use feature_base::feature::Feature;
+use feature_base::feature::key::KeyPart;
+use feature_base::feature::value::ValueKind;
+use feature_base::store::replication::ReplicationLog;
+use feature_base::store::Storable;
+use feature_base::store::wal::{current_ids, WalLogItem, WalLogKind, WalRedo};
+
+use crate::node::Node;
+
+/// 每次从复制缓冲区读取的最多日志条数
+const READ_MAX_ITEMS: usize = 1000;
+/// 没有新的变更时发送心跳的间隔
+const KEEPALIVE_INTERVAL: time::Duration = time::Duration::from_secs(15);
+/// 请求头的最大长度
+const MAX_REQUEST_LEN: usize = 8192;
+
+/// 启动变更订阅服务，返回实际监听的地址。
+///
+/// GET /changes?from=<action id>&ds=<数据集id>&feature=<指标id,指标id> 以SSE推送已提交事务中的指标更新，
+/// 每条事件是一个key在一个时间桶上的更新（slot迁出时的删除new为null），事务的最后一条事件带 id:<commit的action id>。
+/// from 为最早推送的commit action id，不指定时只推送之后提交的事务；
+/// 带 Last-Event-ID 请求头时从该事务之后继续，优先于from。断开时可能收到不完整的事务，重连后会重新推送，即至少一次。
+/// 变更来自leader的复制缓冲区，位置早于缓冲区时返回410，follower返回503
+pub async fn start(node: Arc<Node>, addr: &str) -> CustomResult<SocketAddr> {
+    let listener = TcpListener::bind(addr).await?;
+    let local_addr = listener.local_addr()?;
+    info!("变更订阅服务启动:{}", local_addr);
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let node = node.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle(node, stream).await {
+                            debug!("变更订阅连接结束:{:?}", e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    warn!("变更订阅服务accept失败:{:?}", e);
+                }
+            }
+        }
+    });
+    Ok(local_addr)
+}
+
+/// 订阅条件
+struct Subscription {
+    // 最早推送的commit action id，None表示从当前位置开始
+    from: Option<u64>,
+    ds: Option<i64>,
+    features: Option<HashSet<u64>>,
+}
+
+async fn handle(node: Arc<Node>, mut stream: TcpStream) -> CustomResult<()> {
+    let request = read_request(&mut stream).await?;
+    let sub = match parse_request(&request) {
+        Ok(sub) => sub,
+        Err((status, message)) => return respond(&mut stream, status, &message).await,
+    };
+    let replication = match &node.wal.replication {
+        Some(replication) => replication.clone(),
+        None => return respond(&mut stream, "503 Service Unavailable", "follower不提供变更订阅").await,
+    };
+    let cursor = sub.from.unwrap_or_else(|| current_ids().1);
+    let floor = replication.floor();
+    if cursor < floor {
+        return respond(&mut stream, "410 Gone",
+                       &format!("位置:{} 早于复制缓冲区中最早的日志:{}", cursor, floor)).await;
+    }
+
+    // 指标id -> (数据集id, 指标)
+    let mut features = HashMap::new();
+    for ds in node.datasets.values() {
+        if matches!(sub.ds, Some(id) if id != ds.id) {
+            continue;
+        }
+        for feature in &ds.features {
+            if !matches!(&sub.features, Some(ids) if !ids.contains(&feature.id)) {
+                features.insert(feature.id, (ds.id, feature));
+            }
+        }
+    }
+
+    stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n").await?;
+    let res = stream_changes(&node, &replication, cursor, floor, &features, &mut stream).await;
+    if let Err(e) = &res {
+        if e.code == REPLICATION_GAP_CODE {
+            let data = json!({"error": e.message});
+            let _ = stream.write_all(format!("event: error\ndata: {}\n\n", data).as_bytes()).await;
+        }
+    }
+    let _ = stream.shutdown().await;
+    res
+}
+
+/// 未提交的事务：(是否读到了Begin, 第一条日志的action id, (action id, 更新或删除日志))
+type PendingTxn = (bool, u64, Vec<(u64, WalRedo)>);
+
+/// 从复制缓冲区的起点扫描，只推送commit action id不小于cursor的事务；停机时正常返回
+async fn stream_changes(node: &Node, replication: &ReplicationLog, cursor: u64, floor: u64,
+                        features: &HashMap<u64, (i64, &Feature)>, stream: &mut TcpStream) -> CustomResult<()> {
+    let mut shutdown_rx = node.shutdown_signal();
+    // 未提交的事务：tid -> 事务
+    let mut pending: BTreeMap<u64, PendingTxn> = BTreeMap::new();
+    let mut next = floor;
+    loop {
+        if *shutdown_rx.borrow() {
+            return Ok(());
+        }
+        let bytes = tokio::select! {
+            res = replication.read(next, READ_MAX_ITEMS, KEEPALIVE_INTERVAL) => res?,
+            _ = shutdown_rx.changed() => return Ok(()),
+        };
+        if bytes.is_empty() {
+            stream.write_all(b": keepalive\n\n").await?;
+            continue;
+        }
+
+        let mut out = String::new();
+        let mut buf = Cursor::new(&bytes[..]);
+        while (buf.position() as usize) < bytes.len() {
+            let mut item = WalLogItem::decode(&mut buf)?;
+            next = item.action_id + 1;
+            match item.kind {
+                WalLogKind::Begin => {
+                    pending.entry(item.tid).or_insert((true, item.action_id, vec![]));
+                }
+                WalLogKind::FeatureUpdate | WalLogKind::FeatureRemove => {
+                    let redo = WalRedo::from_item(&mut item)?;
+                    pending.entry(item.tid).or_insert((false, item.action_id, vec![])).2.push((item.action_id, redo));
+                }
+                WalLogKind::Commit => {
+                    let txn = pending.remove(&item.tid);
+                    if item.action_id < cursor {
+                        continue;
+                    }
+                    match txn {
+                        Some((true, _, updates)) => format_txn(&mut out, item.tid, item.action_id, &updates, features)?,
+                        // 事务开头的日志已被淘汰，无法推送完整的事务
+                        _ => return Err(replication_gap_err(item.action_id, replication.floor())),
+                    }
+                }
+                WalLogKind::Abort => {
+                    pending.remove(&item.tid);
+                }
+                // leader停机，未提交的事务不会再提交
+                WalLogKind::End => pending.clear(),
+                _ => {}
+            }
+        }
+        // 开头已被淘汰的事务提交时会报错，不需要继续保留
+        let floor = replication.floor();
+        pending.retain(|_, (_, first, _)| *first >= floor);
+        if !out.is_empty() {
+            stream.write_all(out.as_bytes()).await?;
+        }
+    }
+}
+
+/// 把一个事务中符合条件的更新写成SSE事件，删除的分片new为null，最后一条带事件id
+fn format_txn(out: &mut String, tid: u64, commit_id: u64, updates: &[(u64, WalRedo)],
+              features: &HashMap<u64, (i64, &Feature)>) -> CustomResult<()> {
+    let matched: Vec<_> = updates.iter()
+        .filter_map(|(action_id, redo)| features.get(&redo.fk().feature_id()).map(|f| (action_id, redo, f)))
+        .collect();
+    for (i, (action_id, redo, (ds_id, feature))) in matched.iter().enumerate() {
+        let (fk, tk, old, new) = match redo {
+            WalRedo::Update(u) => (&u.fk, u.tk, u.undo_v.as_ref(), Some(&u.redo_v)),
+            WalRedo::Remove(r) => (&r.fk, r.tk, Some(&r.undo_v), None),
+        };
+        let mut keys = Map::new();
+        for (name, part) in feature.group_keys().iter().zip(fk.parts()?) {
+            let value = match part {
+                KeyPart::Text(v) => json!(v),
+                KeyPart::Int(v) => json!(v),
+                KeyPart::Float(v) => json!(v),
+                KeyPart::DateTime(v) => json!(v),
+            };
+            keys.insert(name.clone(), value);
+        }
+        let data = json!({
+            "action_id": action_id,
+            "commit_id": commit_id,
+            "tid": tid,
+            "ds": ds_id,
+            "feature_id": feature.id,
+            "key": fk.to_string(),
+            "keys": keys,
+            "bucket": tk,
+            "old": value_json(old),
+            "new": value_json(new),
+        });
+        if i + 1 == matched.len() {
+            out.push_str(&format!("id: {}\n", commit_id));
+        }
+        out.push_str(&format!("data: {}\n\n", data));
+    }
+    Ok(())
+}
+
+fn value_json(value: Option<&ValueKind>) -> Value {
+    match value {
+        None => Value::Null,
+        Some(ValueKind::Int(v)) => json!(v),
+        Some(ValueKind::Float(v)) => json!(v),
+        Some(ValueKind::List(items)) => json!(items),
+        Some(ValueKind::Seen { first, last }) => json!({"first": first, "last": last}),
+        Some(ValueKind::Stats { count, mean, m2 }) => json!({"count": count, "mean": mean, "m2": m2}),
+    }
+}
+
+/// 读取请求行和请求头
+async fn read_request(stream: &mut TcpStream) -> CustomResult<String> {
+    let mut buf = vec![];
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() > MAX_REQUEST_LEN {
+            return Err(common_err("请求头过长".to_string()));
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    Ok(String::from_utf8_lossy(&buf).to_string())
+}
+
+fn parse_request(request: &str) -> Result<Subscription, (&'static str, String)> {
+    let mut lines = request.lines();
+    let request_line = lines.next().unwrap_or("");
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    if method != "GET" || path != "/changes" {
+        return Err(("404 Not Found", "not found".to_string()));
+    }
+
+    let invalid = |name: &str, value: &str| ("400 Bad Request", format!("参数{}的值:{} 非法", name, value));
+    let mut sub = Subscription { from: None, ds: None, features: None };
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        match name {
+            "from" => sub.from = Some(value.parse().map_err(|_| invalid(name, value))?),
+            "ds" => sub.ds = Some(value.parse().map_err(|_| invalid(name, value))?),
+            "feature" => {
+                let ids = value.replace("%2C", ",").replace("%2c", ",").split(',')
+                    .map(|id| id.trim().parse::<u64>())
+                    .collect::<Result<HashSet<_>, _>>()
+                    .map_err(|_| invalid(name, value))?;
+                sub.features = Some(ids);
+            }
+            _ => return Err(("400 Bad Request", format!("未知的参数:{}", name))),
+        }
+    }
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("last-event-id") {
+                let value = value.trim();
+                let id: u64 = value.parse().map_err(|_| invalid("Last-Event-ID", value))?;
+                sub.from = Some(id + 1);
+            }
+        }
+    }
+    Ok(sub)
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, message: &str) -> CustomResult<()> {
+    let body = format!("{}\n", message);
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body);
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::Value;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpStream;
+
+    use feature_base::config::Config;
+    use feature_base::ds::DataSet;
+
+    use crate::changes::start;
+    use crate::node::create_node;
+
+    /// 读取SSE事件直到收到n条数据，返回(事件id, 数据)
+    async fn read_events(reader: &mut BufReader<TcpStream>, n: usize) -> Vec<(Option<u64>, Value)> {
+        let mut events = vec![];
+        let mut id = None;
+        let mut line = String::new();
+        while events.len() < n {
+            line.clear();
+            let read = tokio::time::timeout(std::time::Duration::from_secs(5), reader.read_line(&mut line))
+                .await.expect("等待事件超时").unwrap();
+            assert!(read > 0, "连接已关闭");
+            if let Some(v) = line.strip_prefix("id: ") {
+                id = Some(v.trim().parse().unwrap());
+            } else if let Some(v) = line.strip_prefix("data: ") {
+                events.push((id.take(), serde_json::from_str(v.trim()).unwrap()));
+            }
+        }
+        events
+    }
+
+    async fn subscribe(addr: std::net::SocketAddr, request: &str) -> BufReader<TcpStream> {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        assert!(line.starts_with("HTTP/1.1 200"), "{}", line);
+        while line != "\r\n" {
+            line.clear();
+            reader.read_line(&mut line).await.unwrap();
+        }
+        reader
+    }
+
+    #[test]
+    pub fn test_changes() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let dir = std::env::temp_dir().join(format!("feature_db_changes_test_{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            let config = Config {
+                data_dir: dir.to_str().unwrap().to_string(),
+                replication_log_size: 20,
+                ..Config::default()
+            };
+            let ds: DataSet = serde_json::from_str(r#"{
+                "id":101,
+                "name":"ds_user_order",
+                "desc":"",
+                "column_type_map":{"user_id":"INT","merchant_id":"INT","ts":"DATETIME"},
+                "features":[
+                  {"id":10001,"name":"用户订单数","template":{"COUNT":{
+                    "group_keys":["user_id"],"time_key":"ts","window_unit":"DAY","window_size":30}}},
+                  {"id":10002,"name":"商户订单数","template":{"COUNT":{
+                    "group_keys":["merchant_id"],"time_key":"ts","window_unit":"DAY","window_size":30}}}
+                ]
+            }"#).unwrap();
+            let node = create_node(config, HashMap::from([(ds.id, ds)])).await.expect("创建node失败！");
+            let addr = start(node.clone(), "127.0.0.1:0").await.expect("启动变更订阅服务失败");
+
+            let ts = 1650000000000u64;
+            for _ in 0..2 {
+                node.update_features(serde_json::json!({"ds": 101, "user_id": 7, "merchant_id": 3, "ts": ts}), None, false)
+                    .await.expect("更新失败");
+            }
+
+            let mut reader = subscribe(addr, "GET /changes?from=0 HTTP/1.1\r\n\r\n").await;
+            let events = read_events(&mut reader, 4).await;
+            // 每个事务更新两个指标，只有最后一条带事件id
+            assert_eq!(events[0].0, None);
+            let first_commit = events[1].0.expect("缺少事件id");
+            assert_eq!(events[1].1["commit_id"], first_commit);
+            let user = events.iter().find(|(_, e)| e["feature_id"] == 10001).unwrap();
+            assert_eq!(user.1["ds"], 101);
+            assert_eq!(user.1["keys"]["user_id"], 7);
+            assert_eq!(user.1["old"], Value::Null);
+            assert_eq!(user.1["new"], 1);
+            assert!(user.1["bucket"].as_u64().unwrap() <= ts);
+            let second = events.iter().filter(|(_, e)| e["feature_id"] == 10001).nth(1).unwrap();
+            assert_eq!(second.1["old"], 1);
+            assert_eq!(second.1["new"], 2);
+
+            // 新的更新实时推送
+            node.update_features(serde_json::json!({"ds": 101, "user_id": 8, "merchant_id": 3, "ts": ts}), None, false)
+                .await.expect("更新失败");
+            let events = read_events(&mut reader, 2).await;
+            assert!(events.iter().any(|(_, e)| e["keys"]["user_id"] == 8));
+
+            // 断开后带Last-Event-ID重连，只推送之后的事务，并按指标过滤
+            let request = format!("GET /changes?feature=10002 HTTP/1.1\r\nLast-Event-ID: {}\r\n\r\n", first_commit);
+            let mut reader = subscribe(addr, &request).await;
+            let events = read_events(&mut reader, 2).await;
+            assert!(events.iter().all(|(id, e)| e["feature_id"] == 10002 && id.is_some() && e["commit_id"].as_u64().unwrap() > first_commit));
+            assert_eq!(events.iter().map(|(_, e)| e["new"].as_u64().unwrap()).collect::<Vec<_>>(), vec![2, 3]);
+
+            // 缓冲区淘汰后，过早的位置返回410
+            for i in 0..10 {
+                node.update_features(serde_json::json!({"ds": 101, "user_id": i, "merchant_id": 3, "ts": ts}), None, false)
+                    .await.expect("更新失败");
+            }
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(b"GET /changes?from=0 HTTP/1.1\r\n\r\n").await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 410"), "{}", response);
+
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(b"GET /changes?from=x HTTP/1.1\r\n\r\n").await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+
+            node.shutdown().await.expect("停机失败");
+            let _ = std::fs::remove_dir_all(&dir);
+        });
+    }
+}
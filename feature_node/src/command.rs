@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+
+use feature_base::config::{Config, parse_args, WalSyncPolicy};
+use feature_base::custom_error::{config_invalid_err, CustomResult};
+use feature_base::ds::DataSet;
+use feature_base::rpc::{call, Request, Response, unexpected_response_err};
+use feature_base::store::backup;
+
+use crate::meta_client;
+use crate::node::create_node;
+
+/// 运维命令：
+///   feature_node snapshot --addr 127.0.0.1:6600 --backup-dir /backup/20220501
+///   feature_node restore --backup-dir /backup/20220501 --data-dir /data/feature_db
+///   feature_node load --config node.toml --ds 101 --input a.jsonl,b.csv --reject reject.jsonl
+///       [--batch-size 10000] [--datasets datasets.json]
+/// 不是运维命令时返回None，按正常方式启动node
+pub async fn run(args: &[String]) -> Option<CustomResult<String>> {
+    let (command, rest) = args.split_first()?;
+    match command.as_str() {
+        "snapshot" => Some(snapshot(rest).await),
+        "restore" => Some(restore(rest).await),
+        "load" => Some(load(rest).await),
+        _ => None,
+    }
+}
+
+async fn snapshot(args: &[String]) -> CustomResult<String> {
+    let args = parse_args(args)?;
+    let addr = args.get("addr").ok_or(config_invalid_err("缺少参数--addr".to_string()))?;
+    let backup_dir = args.get("backup_dir").ok_or(config_invalid_err("缺少参数--backup-dir".to_string()))?;
+    match call(addr, &Request::Snapshot { backup_dir: backup_dir.clone() }).await? {
+        Response::Snapshot(manifest) => Ok(serde_json::to_string_pretty(&manifest)?),
+        resp => Err(unexpected_response_err(&resp)),
+    }
+}
+
+async fn restore(args: &[String]) -> CustomResult<String> {
+    let args = parse_args(args)?;
+    let backup_dir = args.get("backup_dir").ok_or(config_invalid_err("缺少参数--backup-dir".to_string()))?;
+    let data_dir = args.get("data_dir").ok_or(config_invalid_err("缺少参数--data-dir".to_string()))?;
+    let manifest = backup::restore(backup_dir, data_dir).await?;
+    Ok(serde_json::to_string_pretty(&manifest)?)
+}
+
+/// 批量导入历史数据，node停止时使用。其余参数与启动node相同，wal固定为不刷盘，结束时正常停机。
+///
+/// 指定 --datasets 文件时不连接meta server，所有key都导入当前node；
+/// 否则从meta server获取数据集和集群信息，只导入属于当前node的key
+async fn load(args: &[String]) -> CustomResult<String> {
+    let mut args = parse_args(args)?;
+    let ds_id: i64 = match args.remove("ds") {
+        Some(v) => v.parse().map_err(|_| config_invalid_err(format!("参数--ds的值:{} 非法", v)))?,
+        None => return Err(config_invalid_err("缺少参数--ds".to_string())),
+    };
+    let inputs: Vec<String> = args.remove("input").ok_or(config_invalid_err("缺少参数--input".to_string()))?
+        .split(',').map(|s| s.trim().to_string()).collect();
+    let reject_path = args.remove("reject").ok_or(config_invalid_err("缺少参数--reject".to_string()))?;
+    let batch_size: usize = match args.remove("batch_size") {
+        Some(v) => v.parse().map_err(|_| config_invalid_err(format!("参数--batch-size的值:{} 非法", v)))?,
+        None => 10000,
+    };
+    let datasets_path = args.remove("datasets");
+
+    let config_args: Vec<String> = args.iter().map(|(k, v)| format!("--{}={}", k, v)).collect();
+    let envs: HashMap<String, String> = std::env::vars().collect();
+    let mut config = Config::load(&config_args, &envs)?;
+    config.wal_sync = WalSyncPolicy::Never;
+
+    let datasets = match &datasets_path {
+        Some(path) => {
+            let datasets: Vec<DataSet> = serde_json::from_slice(&std::fs::read(path)?)?;
+            datasets.into_iter().map(|ds| (ds.id, ds)).collect()
+        }
+        None => meta_client::fetch_datasets(&config.meta_addr).await?,
+    };
+    let node = create_node(config, datasets).await?;
+    if datasets_path.is_none() {
+        node.refresh_cluster().await?;
+    }
+    let res = node.bulk_load(ds_id, &inputs, &reject_path, batch_size).await;
+    node.shutdown().await?;
+    let summary = res?;
+    Ok(format!("行数:{} 导入:{} 拒绝:{} 指标更新:{} 跳过其它node的key:{} 事务:{} 耗时:{:.1}s 速度:{:.0}行/s",
+               summary.rows, summary.loaded, summary.rejected, summary.updates, summary.skipped_keys,
+               summary.transactions, summary.elapsed.as_secs_f64(), summary.rows_per_sec()))
+}
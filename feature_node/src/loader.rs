@@ -0,0 +1,304 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use serde_json::{Map, Value};
+
+use feature_base::calc_hash;
+use feature_base::custom_error::{common_err, config_invalid_err, CustomResult, read_only_err};
+use feature_base::ds::column::{ColumnType, parse_column_value};
+use feature_base::ds::DataSet;
+use feature_base::feature::Feature;
+use feature_base::feature::key::FeatureKey;
+use feature_base::feature::value::FeatureValue;
+use feature_base::store::wal::generate_tid;
+
+use crate::node::{KEY_DS, Node};
+
+/// 批量导入的结果统计
+#[derive(Debug, Default)]
+pub struct LoadSummary {
+    pub rows: u64,
+    pub loaded: u64,
+    // 写入拒绝文件的行数
+    pub rejected: u64,
+    // 指标更新数
+    pub updates: u64,
+    // 属于其它node，没有导入的key数
+    pub skipped_keys: u64,
+    pub transactions: u64,
+    pub elapsed: Duration,
+}
+
+impl LoadSummary {
+    /// 每秒处理的行数
+    pub fn rows_per_sec(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 { self.rows as f64 / secs } else { 0.0 }
+    }
+}
+
+/// 输入文件中的一行，解析失败时原样写入拒绝文件
+struct Row {
+    line: u64,
+    raw: Value,
+    event: CustomResult<Value>,
+}
+
+impl Node {
+    /// 批量导入历史数据：按文件顺序读取，与update相同的方式计算指标，每batch_size行一个事务，提交后刷盘脏页。
+    ///
+    /// 无法解析或任一指标计算失败的行整行不导入，连同原因写入拒绝文件，修正后可以直接重新导入；
+    /// 属于其它node的key跳过
+    pub async fn bulk_load(&self, ds_id: i64, inputs: &[String], reject_path: &str, batch_size: usize) -> CustomResult<LoadSummary> {
+        if self.read_only.load(Ordering::Acquire) {
+            return Err(read_only_err());
+        }
+        let ds = self.datasets.get(&ds_id)
+            .ok_or(config_invalid_err(format!("找不到数据集:{}", ds_id)))?;
+        let start = Instant::now();
+        let mut summary = LoadSummary::default();
+        let mut rejects = BufWriter::new(File::create(reject_path)?);
+        let mut batch = vec![];
+        for input in inputs {
+            for row in read_rows(input, ds)? {
+                summary.rows += 1;
+                match row.event.and_then(|event| build_row_keys(event, ds)) {
+                    Ok((event, keys)) => {
+                        let (local_map, remote_map, _) = self.split_by_owner(keys).await;
+                        summary.skipped_keys += remote_map.values().map(|v| v.len() as u64).sum::<u64>();
+                        summary.loaded += 1;
+                        batch.push((event, local_map));
+                    }
+                    Err(e) => {
+                        summary.rejected += 1;
+                        let reject = serde_json::json!({"file": input, "line": row.line, "error": e.to_string(), "row": row.raw});
+                        writeln!(rejects, "{}", reject)?;
+                    }
+                }
+                if batch.len() >= batch_size.max(1) {
+                    self.load_batch(ds, std::mem::take(&mut batch), &mut summary).await?;
+                }
+            }
+        }
+        self.load_batch(ds, batch, &mut summary).await?;
+        rejects.flush()?;
+        summary.elapsed = start.elapsed();
+        Ok(summary)
+    }
+
+    /// 在一个事务中应用一批数据，提交后刷盘脏页，避免导入过程中内存持续增长
+    async fn load_batch(&self, ds: &DataSet, batch: Vec<(Value, HashMap<FeatureKey, &Feature>)>, summary: &mut LoadSummary) -> CustomResult<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let tid = generate_tid();
+        self.wal.send_begin_log(tid).await?;
+        // 数据已在build_row_keys中校验过；其它错误时也先提交已经应用的更新，不留下未提交的事务
+        let res = self.apply_batch(tid, ds, &batch, summary).await;
+        self.wal.commit_log(tid).await?;
+        summary.transactions += 1;
+        res?;
+
+        let _guard = self.check_point_lock.lock().await;
+        self.store.check_point(&self.wal).await
+    }
+
+    async fn apply_batch(&self, tid: u64, ds: &DataSet, batch: &[(Value, HashMap<FeatureKey, &Feature>)], summary: &mut LoadSummary) -> CustomResult<()> {
+        for (event, key_feature_map) in batch {
+            for (key, feature) in key_feature_map {
+                let (_, page) = self.store.get_page(calc_hash(key.as_bytes())).await?;
+                let mut page = page.write().await;
+                if let Some(update) = feature.calc_and_update(event, &ds.column_type_map, key, &mut page, &self.wal).await? {
+                    let action_id = self.wal.send_feature_update_log(tid, update).await?;
+                    page.after_update(action_id, &self.store).await;
+                    summary.updates += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 校验一行数据并构建所有指标的key，按空值试算每个指标，任一指标失败时整行拒绝；没有ds字段时补上导入的数据集
+fn build_row_keys(mut event: Value, ds: &DataSet) -> CustomResult<(Value, HashMap<FeatureKey, &Feature>)> {
+    let map = event.as_object_mut().ok_or(common_err("数据不是json对象".to_string()))?;
+    match map.get(KEY_DS) {
+        None => {
+            map.insert(KEY_DS.to_string(), Value::from(ds.id));
+        }
+        Some(v) if v.as_i64() == Some(ds.id) => {}
+        Some(v) => return Err(common_err(format!("数据的ds:{} 与导入的数据集:{} 不一致", v, ds.id))),
+    }
+    let mut key_feature_map = HashMap::new();
+    for feature in ds.features.iter().filter(|f| f.derived().is_none()) {
+        let key = feature.build_key(&event, &ds.column_type_map)
+            .and_then(|key| feature.calc(&event, &ds.column_type_map, &key, &mut FeatureValue::new()).map(|_| key))
+            .map_err(|e| common_err(format!("指标:{} {}", feature.id, e)))?;
+        key_feature_map.insert(key, feature);
+    }
+    Ok((event, key_feature_map))
+}
+
+/// 按扩展名读取JSONL或带表头的CSV文件
+fn read_rows(path: &str, ds: &DataSet) -> CustomResult<Box<dyn Iterator<Item=Row>>> {
+    if path.ends_with(".jsonl") || path.ends_with(".json") {
+        read_jsonl(path)
+    } else if path.ends_with(".csv") {
+        read_csv(path, ds)
+    } else {
+        Err(config_invalid_err(format!("无法识别的文件格式:{}，可选.jsonl/.csv", path)))
+    }
+}
+
+fn read_jsonl(path: &str) -> CustomResult<Box<dyn Iterator<Item=Row>>> {
+    let lines = BufReader::new(File::open(path)?).lines();
+    Ok(Box::new(lines.enumerate().filter_map(|(i, line)| {
+        let line_no = i as u64 + 1;
+        match line {
+            Ok(line) if line.trim().is_empty() => None,
+            Ok(line) => {
+                let event = serde_json::from_str(&line).map_err(|e| common_err(format!("json解析失败:{}", e)));
+                Some(Row { line: line_no, raw: Value::from(line), event })
+            }
+            Err(e) => Some(Row { line: line_no, raw: Value::Null, event: Err(e.into()) }),
+        }
+    })))
+}
+
+/// 数据集中定义的字段按字段类型解析，非文本字段为空时视为缺少该字段，其它字段作为文本
+fn read_csv(path: &str, ds: &DataSet) -> CustomResult<Box<dyn Iterator<Item=Row>>> {
+    let mut reader = csv::Reader::from_path(path).map_err(|e| common_err(e.to_string()))?;
+    let headers: Vec<String> = reader.headers().map_err(|e| common_err(e.to_string()))?
+        .iter().map(|h| h.to_string()).collect();
+    let column_types: Vec<Option<ColumnType>> = headers.iter()
+        .map(|h| ds.column_type_map.get(h).cloned())
+        .collect();
+    Ok(Box::new(reader.into_records().map(move |record| {
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                let line = e.position().map_or(0, |p| p.line());
+                return Row { line, raw: Value::Null, event: Err(common_err(e.to_string())) };
+            }
+        };
+        let line = record.position().map_or(0, |p| p.line());
+        let mut raw = Map::new();
+        let mut event = Map::new();
+        let mut error = None;
+        for ((name, column_type), text) in headers.iter().zip(&column_types).zip(record.iter()) {
+            raw.insert(name.clone(), Value::from(text));
+            let value = match column_type {
+                Some(ColumnType::TEXT) | None => Ok(Value::from(text)),
+                Some(_) if text.trim().is_empty() => continue,
+                Some(column_type) => parse_column_value(text, name, column_type),
+            };
+            match value {
+                Ok(value) => {
+                    event.insert(name.clone(), value);
+                }
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        let event = match error {
+            Some(e) => Err(e),
+            None => Ok(Value::Object(event)),
+        };
+        Row { line, raw: Value::Object(raw), event }
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::Value;
+
+    use feature_base::config::{Config, WalSyncPolicy};
+    use feature_base::ds::DataSet;
+    use feature_base::feature::FeatureQuery;
+    use feature_base::feature::value::ValueKind;
+    use feature_base::store::fsck;
+
+    use crate::node::create_node;
+
+    #[test]
+    pub fn test_bulk_load() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let dir = std::env::temp_dir().join(format!("feature_db_bulk_load_test_{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            let config = Config {
+                data_dir: dir.join("data").to_str().unwrap().to_string(),
+                wal_sync: WalSyncPolicy::Never,
+                ..Config::default()
+            };
+            let ds: DataSet = serde_json::from_str(r#"{
+                "id":101,
+                "name":"ds_user_order",
+                "desc":"",
+                "column_type_map":{"user_id":"INT","merchant_id":"INT","ts":"DATETIME"},
+                "features":[
+                  {"id":10001,"name":"用户订单数","template":{"COUNT":{
+                    "group_keys":["user_id"],"time_key":"ts","window_unit":"DAY","window_size":30}}},
+                  {"id":10002,"name":"商户订单数","template":{"COUNT":{
+                    "group_keys":["merchant_id"],"time_key":"ts","window_unit":"DAY","window_size":30}}}
+                ]
+            }"#).unwrap();
+            let datasets = HashMap::from([(ds.id, ds)]);
+
+            let ts = 1650000000000u64;
+            let mut jsonl = String::new();
+            for i in 0..10 {
+                jsonl.push_str(&format!("{{\"user_id\":{},\"merchant_id\":1,\"ts\":{}}}\n", i % 3, ts));
+            }
+            jsonl.push_str("{\"user_id\":1,\n");
+            jsonl.push_str(&format!("{{\"ds\":102,\"user_id\":1,\"merchant_id\":1,\"ts\":{}}}\n", ts));
+            std::fs::write(dir.join("events.jsonl"), jsonl).unwrap();
+            // 第3行缺少merchant_id，第4行user_id类型错误
+            std::fs::write(dir.join("events.csv"), format!("user_id,merchant_id,ts,note\n1,2,{ts},a\n2,2,{ts},b\n3,,{ts},c\nx,2,{ts},d\n")).unwrap();
+
+            let node = create_node(config.clone(), datasets.clone()).await.expect("创建node失败！");
+            let inputs = vec![dir.join("events.jsonl").to_str().unwrap().to_string(), dir.join("events.csv").to_str().unwrap().to_string()];
+            let reject_path = dir.join("rejects.jsonl");
+            let summary = node.bulk_load(101, &inputs, reject_path.to_str().unwrap(), 4).await.unwrap();
+            assert_eq!((summary.rows, summary.loaded, summary.rejected), (16, 12, 4));
+            assert_eq!((summary.updates, summary.skipped_keys, summary.transactions), (24, 0, 3));
+
+            let rejects: Vec<Value> = std::fs::read_to_string(&reject_path).unwrap().lines()
+                .map(|l| serde_json::from_str(l).unwrap())
+                .collect();
+            let lines: Vec<(bool, u64)> = rejects.iter()
+                .map(|r| (r["file"].as_str().unwrap().ends_with(".csv"), r["line"].as_u64().unwrap()))
+                .collect();
+            assert_eq!(lines, vec![(false, 11), (false, 12), (true, 4), (true, 5)]);
+            assert_eq!(rejects[3]["row"]["user_id"], "x");
+
+            let query = |feature_id: u64, keys: Value| FeatureQuery { ds: 101, feature_id, keys, time: ts };
+            let count = |feature_id: u64, keys: Value| {
+                let node = node.clone();
+                async move { node.query(query(feature_id, keys), true).await.expect("查询失败").value }
+            };
+            assert_eq!(count(10001, serde_json::json!({"user_id": 0})).await, Some(ValueKind::Int(4)));
+            assert_eq!(count(10001, serde_json::json!({"user_id": 1})).await, Some(ValueKind::Int(4)));
+            assert_eq!(count(10002, serde_json::json!({"merchant_id": 1})).await, Some(ValueKind::Int(10)));
+            assert_eq!(count(10002, serde_json::json!({"merchant_id": 2})).await, Some(ValueKind::Int(2)));
+            node.shutdown().await.unwrap();
+
+            // 停机后数据已全部刷盘，与wal一致
+            let report = fsck::check(&config.data_dir, false).await.unwrap();
+            assert!(report.is_ok(), "{:?}", report.problems);
+            assert_eq!(report.keys, 5);
+
+            let _ = std::fs::remove_dir_all(&dir);
+        });
+    }
+}
@@ -1,6 +1,57 @@
+use std::collections::HashMap;
+
+use log::{info, warn};
+
+use feature_base::config::Config;
+
 pub mod node;
+pub mod admission;
+pub mod changes;
+pub mod command;
+pub mod loader;
 pub mod meta_client;
+pub mod metrics_server;
+pub mod migration;
+pub mod replication;
+pub mod server;
+pub mod source;
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(res) = command::run(&args).await {
+        match res {
+            Ok(output) => println!("{}", output),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    let envs: HashMap<String, String> = std::env::vars().collect();
+    let config = match Config::load(&args, &envs) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    feature_base::init_log_with(&config.log_config);
+    for key in Config::ignored_env_keys(&envs) {
+        warn!("忽略未知的环境变量:{}", key);
+    }
+    info!("启动配置:{:?}", config);
+
+    metrics_server::start(&config.metrics_addr).await.expect("启动metrics服务失败！");
+    let node = node::create_and_init(config).await.expect("创建node失败！");
+    if !node.config.changes_addr.is_empty() {
+        changes::start(node.clone(), &node.config.changes_addr).await.expect("启动变更订阅服务失败！");
+    }
 
-fn main() {
-    println!("Hello, world!");
+    tokio::signal::ctrl_c().await.expect("监听退出信号失败");
+    if let Err(e) = node.shutdown().await {
+        eprintln!("停机失败:{}", e);
+        std::process::exit(1);
+    }
 }
@@ -1,36 +1,21 @@
+use feature_base::cluster::{ClusterMap, NodeInfo};
+use feature_base::custom_error::CustomResult;
+use feature_base::rpc::{call, Request, Response, unexpected_response_err};
 
+pub use feature_base::client::{fetch_cluster_map, fetch_datasets};
 
-use feature_base::custom_error::CustomResult;
-use feature_base::ds::DataSet;
+/// 注册到meta server，返回注册后的集群信息
+pub async fn register_node(meta_addr: &str, node: NodeInfo) -> CustomResult<ClusterMap> {
+    match call(meta_addr, &Request::RegisterNode(node)).await? {
+        Response::ClusterMap(map) => Ok(map),
+        resp => Err(unexpected_response_err(&resp)),
+    }
+}
 
-pub fn fetch_all_dataset() -> CustomResult<Vec<DataSet>> {
-    let data = r#"
-    [
-     {
-        "id":101,
-        "name":"ds_user_order",
-        "desc":"用户订单数据集",
-        "column_type_map":{
-          "user_id":"INT",
-          "amount":"FLOAT",
-          "ts":"DATETIME"
-        },
-        "features":[
-          {
-            "id":10001,
-            "name":"用户最近30天订单数量",
-            "template":{
-              "COUNT":{
-                  "group_keys":["user_id"],
-                  "time_key":"ts",
-                  "window_unit":"DAY",
-                  "window_size":30
-              }
-            }
-          }
-        ]
-     }
-    ]
-        "#;
-    serde_json::from_str(data).map_err(|e|e.into())
-}
\ No newline at end of file
+/// 在meta server切换slot的owner
+pub async fn move_slots(meta_addr: &str, slot_ids: Vec<u16>, from: String, to: String) -> CustomResult<ClusterMap> {
+    match call(meta_addr, &Request::MoveSlots { slot_ids, from, to }).await? {
+        Response::ClusterMap(map) => Ok(map),
+        resp => Err(unexpected_response_err(&resp)),
+    }
+}
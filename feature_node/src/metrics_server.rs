@@ -0,0 +1,83 @@
+use log::{info, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use feature_base::custom_error::CustomResult;
+use feature_base::metrics;
+
+/// 启动metrics服务，GET /metrics 返回 prometheus 文本格式，返回实际监听的地址
+pub async fn start(addr: &str) -> CustomResult<std::net::SocketAddr> {
+    let listener = TcpListener::bind(addr).await?;
+    let local_addr = listener.local_addr()?;
+    info!("metrics服务启动:{}", local_addr);
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(async move {
+                        if let Err(e) = handle(stream).await {
+                            warn!("metrics请求处理失败:{:?}", e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    warn!("metrics服务accept失败:{:?}", e);
+                }
+            }
+        }
+    });
+    Ok(local_addr)
+}
+
+async fn handle(mut stream: TcpStream) -> CustomResult<()> {
+    // 只需要请求行，不解析header和body
+    let mut buf = vec![0u8; 1024];
+    let n = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..n]);
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+
+    let (status, body) = if request.starts_with("GET ") && path == "/metrics" {
+        ("200 OK", metrics::gather_text())
+    } else {
+        ("404 Not Found", "not found\n".to_string())
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body);
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use feature_base::metrics;
+
+    use crate::metrics_server::start;
+
+    #[test]
+    pub fn metrics_endpoint_test() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            metrics::PAGE_SPLITS.inc();
+            let addr = start("127.0.0.1:0").await.expect("启动metrics服务失败");
+
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 200 OK"));
+            assert!(response.contains("feature_db_page_splits_total"));
+
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(b"GET /other HTTP/1.1\r\n\r\n").await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 404"));
+        });
+    }
+}
@@ -0,0 +1,219 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use log::{info, warn};
+use tokio::sync::{Mutex, RwLock};
+use tokio::time;
+
+use feature_base::cluster::ClusterMap;
+use feature_base::custom_error::{common_err, CustomError, CustomResult, not_owner_err};
+use feature_base::rpc::{call, Request, Response, unexpected_response_err};
+use feature_base::store::page::Page;
+use feature_base::store::wal::{encode_updates, generate_tid, WalFeatureRemoveValue, WalFeatureUpdateValue, WalRedo};
+
+use crate::meta_client;
+use crate::node::Node;
+
+/// 每次发送给目标node的更新日志条数
+const APPLY_BATCH_SIZE: usize = 1000;
+/// 追赶阶段最多进行的轮数
+const MAX_CATCH_UP_ROUNDS: usize = 10;
+/// 缓冲区中的日志少于该值时进入切换阶段
+const CUTOVER_THRESHOLD: usize = 100;
+/// 切换owner失败后从meta server确认结果的最多次数
+const RECONCILE_ATTEMPTS: usize = 5;
+const RECONCILE_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+static MIGRATION_ID: AtomicU64 = AtomicU64::new(0);
+
+/// 正在进行的slot迁移
+///
+/// 迁移开始后，这些slot的更新日志除了写入wal，同时进入缓冲区，由迁移任务转发给目标node。
+/// 更新日志按redo值覆盖分片，快照和缓冲区中的日志即使重叠，按顺序应用后结果也一致。
+pub struct SlotMigration {
+    // 同时持有多个迁移的读锁时按id排序，避免死锁
+    pub id: u64,
+    pub target: String,
+    pub target_addr: String,
+    // 涉及这些slot的更新持有读锁直到更新完成；切换时迁移任务获取写锁，值为true表示owner已切换到目标node
+    pub gate: Arc<RwLock<bool>>,
+    // 迁移开始后这些slot产生的更新日志
+    pub buffer: Mutex<Vec<WalFeatureUpdateValue>>,
+}
+
+impl SlotMigration {
+    fn new(target: String, target_addr: String) -> SlotMigration {
+        SlotMigration {
+            id: MIGRATION_ID.fetch_add(1, Ordering::AcqRel),
+            target,
+            target_addr,
+            gate: Arc::new(RwLock::new(false)),
+            buffer: Mutex::new(vec![]),
+        }
+    }
+
+    async fn take_buffer(&self) -> Vec<WalFeatureUpdateValue> {
+        std::mem::take(&mut *self.buffer.lock().await)
+    }
+}
+
+impl Node {
+    /// 把slot迁移到目标node：发送快照，追赶之后的更新，短暂阻止这些slot的更新后在meta server切换owner
+    pub async fn migrate_slots(&self, slot_ids: Vec<u16>, target: String) -> CustomResult<ClusterMap> {
+        // 目标node可能刚注册，先获取最新的集群信息
+        self.refresh_cluster().await?;
+        let target_addr = {
+            let cluster = self.cluster.read().await;
+            if target == self.config.node_id {
+                return Err(common_err(format!("不能迁移到自己:{}", target)));
+            }
+            for slot_id in &slot_ids {
+                let owner = cluster.owner_of_slot(*slot_id);
+                if owner != Some(&self.config.node_id) {
+                    return Err(not_owner_err(*slot_id, owner));
+                }
+            }
+            cluster.addr_of(&target).cloned()
+                .ok_or(common_err(format!("目标node:{} 不存在", target)))?
+        };
+
+        let migration = Arc::new(SlotMigration::new(target, target_addr));
+        {
+            let mut migrations = self.migrations.write().await;
+            if let Some(slot_id) = slot_ids.iter().find(|id| migrations.contains_key(id)) {
+                return Err(common_err(format!("slot:{} 正在迁移", slot_id)));
+            }
+            for slot_id in &slot_ids {
+                migrations.insert(*slot_id, migration.clone());
+            }
+        }
+        // 注册之前已经判断过owner的update看不到迁移，不会写入缓冲区，等待它们完成后再做快照
+        let mut sorted_slot_ids = slot_ids.clone();
+        sorted_slot_ids.sort_unstable();
+        sorted_slot_ids.dedup();
+        for slot_id in sorted_slot_ids {
+            drop(self.slot_gates[slot_id as usize].write().await);
+        }
+
+        let res = self.do_migrate(&slot_ids, &migration).await;
+        if let Err(e) = &res {
+            warn!("slot迁移失败，继续由当前node负责:{:?}", e);
+        }
+
+        // 切换成功时集群信息已更新，之后的更新直接转发给新的owner
+        let mut migrations = self.migrations.write().await;
+        for slot_id in &slot_ids {
+            migrations.remove(slot_id);
+        }
+        res
+    }
+
+    async fn do_migrate(&self, slot_ids: &[u16], migration: &SlotMigration) -> CustomResult<ClusterMap> {
+        // 快照：逐个page在读锁下转换为更新日志，迁移状态注册之后的更新都在缓冲区中
+        for slot_id in slot_ids {
+            let slot = self.store.slot_index.get(slot_id)
+                .ok_or(common_err(format!("slot:{} 不存在", slot_id)))?;
+            let pages: Vec<_> = slot.page_tree.read().await.values().cloned().collect();
+            for page in pages {
+                let updates = page.read().await.snapshot();
+                send_updates(&migration.target_addr, updates).await?;
+            }
+        }
+
+        // 追赶：转发缓冲区中的更新，直到剩余的足够少
+        for _ in 0..MAX_CATCH_UP_ROUNDS {
+            let updates = migration.take_buffer().await;
+            let n = updates.len();
+            send_updates(&migration.target_addr, updates).await?;
+            if n <= CUTOVER_THRESHOLD {
+                break;
+            }
+        }
+
+        // 切换：等待进行中的更新完成并阻止新的更新，转发剩余的日志后切换owner
+        let mut moved = migration.gate.write().await;
+        send_updates(&migration.target_addr, migration.take_buffer().await).await?;
+        let cluster = match meta_client::move_slots(&self.config.meta_addr, slot_ids.to_vec(),
+                                                    self.config.node_id.clone(), migration.target.clone()).await {
+            Ok(cluster) => cluster,
+            Err(e) => self.reconcile_move(slot_ids, &migration.target, e).await?,
+        };
+        self.set_cluster(cluster.clone()).await;
+        // 先通知目标node，否则被阻止的更新转发过去时会因为集群信息过期被拒绝
+        if let Err(e) = call(&migration.target_addr, &Request::ClusterChanged(cluster.clone())).await {
+            warn!("通知目标node集群信息变更失败，等待其定期刷新:{:?}", e);
+        }
+        *moved = true;
+        drop(moved);
+        info!("{}个slot已迁移到{}", slot_ids.len(), migration.target);
+
+        // 清理已迁出的数据，owner已经切换，失败时不影响迁移结果
+        for slot_id in slot_ids {
+            if let Some(slot) = self.store.slot_index.get(slot_id) {
+                let pages: Vec<_> = slot.page_tree.read().await.values().cloned().collect();
+                for page in pages {
+                    if let Err(e) = self.clear_page(&page).await {
+                        // page已经清空且锁已释放，无法撤销
+                        self.mark_failed(&e);
+                        return Ok(cluster);
+                    }
+                }
+            }
+        }
+        Ok(cluster)
+    }
+
+    /// 切换owner的请求失败时，meta server上可能已经切换而只是响应丢失，此时返回错误会让两个node同时负责这些slot。
+    /// 重新读取集群信息：已切换到目标node时按成功继续，否则返回原来的错误
+    async fn reconcile_move(&self, slot_ids: &[u16], target: &String, e: CustomError) -> CustomResult<ClusterMap> {
+        warn!("切换slot的owner失败，从meta server确认结果:{:?}", e);
+        for _ in 0..RECONCILE_ATTEMPTS {
+            match meta_client::fetch_cluster_map(&self.config.meta_addr).await {
+                Ok(cluster) if slot_ids.iter().all(|id| cluster.owner_of_slot(*id) == Some(target)) => {
+                    info!("meta server上已切换到{}，继续完成迁移", target);
+                    return Ok(cluster);
+                }
+                Ok(_) => return Err(e),
+                Err(err) => {
+                    warn!("获取集群信息失败:{:?}", err);
+                    time::sleep(RECONCILE_INTERVAL).await;
+                }
+            }
+        }
+        Err(e)
+    }
+
+    /// 在一个事务中删除page的所有数据，删除日志同样复制给follower和变更订阅
+    async fn clear_page(&self, page: &RwLock<Page>) -> CustomResult<()> {
+        let tid = generate_tid();
+        // 和更新一样，动作ID在锁内分配，日志在释放锁后发送
+        let (begin, removes) = {
+            let mut page = page.write().await;
+            if page.data.is_empty() {
+                return Ok(());
+            }
+            let begin = self.wal.reserve();
+            let mut removes = vec![];
+            for (fk, value) in std::mem::take(&mut page.data) {
+                for (tk, v) in value.iter() {
+                    let reserved = self.wal.reserve();
+                    page.after_update(reserved.action_id, &self.store).await;
+                    removes.push((reserved, WalRedo::Remove(WalFeatureRemoveValue { fk: fk.clone(), tk: *tk, undo_v: v.clone() })));
+                }
+            }
+            (begin, removes)
+        };
+        self.log_redo(begin, tid, removes).await
+    }
+}
+
+async fn send_updates(addr: &str, updates: Vec<WalFeatureUpdateValue>) -> CustomResult<()> {
+    for batch in updates.chunks(APPLY_BATCH_SIZE) {
+        let request = Request::ApplyUpdates { updates: encode_updates(batch)? };
+        match call(addr, &request).await? {
+            Response::Ok => {}
+            resp => return Err(unexpected_response_err(&resp)),
+        }
+    }
+    Ok(())
+}
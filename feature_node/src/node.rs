@@ -1,94 +1,449 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 use log::{info, warn};
 use serde_json::Value;
+use tokio::sync::{Mutex, RwLock, watch};
 use tokio::time;
 
 use feature_base::calc_hash;
+use feature_base::cluster::{ClusterMap, NodeInfo};
 use feature_base::config::Config;
-use feature_base::custom_error::{common_err, CustomError, CustomResult};
+use feature_base::custom_error::{common_err, CustomError, CustomResult, forward_failed_err, node_failed_err, node_shutting_down_err, not_owner_err, overloaded_err, page_lock_timeout_err, read_only_err};
 use feature_base::ds::{DataSet, DsUpdateResult, FeatureUpdateResult};
 use feature_base::ds::column::get_value_as_int;
-use feature_base::feature::Feature;
+use feature_base::feature::{Feature, FeatureQuery, FeatureQueryResult};
+use feature_base::feature::key::FeatureKey;
+use feature_base::metrics;
+use feature_base::rpc::{call, Request, Response, unexpected_response_err};
 use feature_base::store::Store;
-use feature_base::store::wal::{crate_wal, generate_tid, Wal, WalState};
+use feature_base::store::slot::{slot_id_of_hash, SLOT_NUM_BY_BIT};
+use feature_base::store::backup::{BackupManifest, copy_snapshot};
+use feature_base::store::replication::ReplicationLog;
+use feature_base::store::wal::{crate_wal, current_ids, decode_updates, generate_tid, get_wal_file_path, ReservedActionId, Wal, WalLogKind, WalRedo};
 
-use crate::meta_client;
+use crate::admission::Admission;
+use crate::migration::SlotMigration;
+use crate::replication::Follower;
+use crate::{meta_client, server, source};
 
 pub struct Node {
     pub config: Config,
     pub datasets: HashMap<i64, DataSet>,
     pub wal: Wal,
     pub store: Store,
+    // 集群的slot分配，定期从meta server刷新
+    pub cluster: RwLock<ClusterMap>,
+    // 正在迁出的slot
+    pub(crate) migrations: RwLock<HashMap<u16, Arc<SlotMigration>>>,
+    // 每个slot一个；update从判断owner之前到本地更新完成持有读锁，迁移注册后获取写锁等待没有看到迁移的update完成
+    pub(crate) slot_gates: Vec<RwLock<()>>,
+    // 实际监听的地址
+    pub addr: RwLock<String>,
+    // 作为follower时的复制状态，提升后为空
+    pub(crate) follower: Mutex<Option<Follower>>,
+    // 是否是follower，follower不接收直接写入
+    pub(crate) read_only: AtomicBool,
+    // 修改page后wal发送或提交失败，内存中有没有记录日志的更新；之后拒绝写入且不再做检查点，重启后从上次检查点恢复
+    pub(crate) failed: AtomicBool,
+    // follower已应用到的leader日志位置
+    pub(crate) applied_action_id: AtomicU64,
+    // 接入控制
+    admission: Admission,
+    // 是否已停止接收数据；update持有读锁，停机时获取写锁等待进行中的update完成
+    closed: RwLock<bool>,
+    // 通知后台任务退出
+    shutdown_tx: watch::Sender<bool>,
+    shutdown_rx: watch::Receiver<bool>,
+    // 检查点串行执行
+    pub(crate) check_point_lock: Mutex<()>,
 }
 
-const KEY_DS: &str = "ds";
+pub(crate) const KEY_DS: &str = "ds";
+// 采样store数据大小的间隔，秒；需要逐个获取page读锁，不随检查点执行
+const STORE_SIZE_SAMPLE_SECS: u64 = 60;
 
 
 impl Node {
     /// 根据数据，更新关联的所有指标
+    #[cfg(test)]
     async fn update(&self, event: Value) -> CustomResult<DsUpdateResult> {
+        self.update_features(event, None, false).await
+    }
+
+    /// 更新指定的指标，feature_ids为空时更新数据集的全部指标；不属于当前node的key转发给owner
+    pub async fn update_features(&self, event: Value, feature_ids: Option<Vec<u64>>, forwarded: bool) -> CustomResult<DsUpdateResult> {
+        let timer = metrics::UPDATE_LATENCY.start_timer();
+        let res = self.do_update(event, feature_ids, forwarded).await;
+        timer.observe_duration();
+        if let Err(e) = &res {
+            metrics::UPDATE_ERRORS.with_label_values(&[&e.code.to_string()]).inc();
+        }
+        res
+    }
+
+    async fn do_update(&self, event: Value, feature_ids: Option<Vec<u64>>, forwarded: bool) -> CustomResult<DsUpdateResult> {
+        let closed = self.closed.read().await;
+        if *closed {
+            return Err(node_shutting_down_err());
+        }
+        if self.read_only.load(Ordering::Acquire) {
+            return Err(read_only_err());
+        }
+        if self.failed.load(Ordering::Acquire) {
+            return Err(node_failed_err());
+        }
+
         let ds_value = get_value_as_int(&event, KEY_DS)?;
         let ds = self.datasets.get(&ds_value)
             .ok_or(common_err(format!("找不到对应的ds:{}", ds_value)))?.clone();
 
-        let mut result_map = HashMap::new();
-
-        let tid = generate_tid();
-        let action_id = self.wal.send_begin_log(tid).await?;
+        // 超出处理能力时直接拒绝，不排队
+        let _permit = self.admission.admit(ds.id)?;
+        if self.wal.is_full().await {
+            return Err(overloaded_err("wal队列已满".to_string()));
+        }
 
         // 先根据feature构建所有的key
-        let (key_feature_map, key_error_map) = build_feature_keys(&event, &ds);
+        let (key_feature_map, key_error_map) = build_feature_keys(&event, &ds, feature_ids.as_deref());
 
         // 这里如果把result_map的可变引用传入build_feature_keys，会导致下面有问题，所以现在只能复制一份
+        let mut result_map = HashMap::new();
         for (k, v) in key_error_map {
             result_map.insert(k, v);
         }
 
-        // 根据这些key，找到page，并锁定
-        let mut locks = vec![];
-        let mut page_map = HashMap::new();
+        // 按slot id排序获取slot的读锁，迁移在注册之后会等待这些update完成再做快照
+        let slot_ids: BTreeSet<u16> = key_feature_map.keys()
+            .map(|key| slot_id_of_hash(calc_hash(key.as_bytes())))
+            .collect();
+        let mut slot_guards = vec![];
+        for slot_id in slot_ids {
+            slot_guards.push(self.slot_gates[slot_id as usize].read().await);
+        }
+
+        // 按owner拆分，只有属于当前node的key在本地更新
+        let (mut key_feature_map, mut remote_map, migrations) = self.split_by_owner(key_feature_map).await;
+
+        // 正在迁移的slot持有读锁直到更新完成，按迁移id排序获取；已切换owner的key改为转发给新的owner
+        let sorted_migrations: BTreeMap<u64, &Arc<SlotMigration>> = migrations.values().map(|m| (m.id, m)).collect();
+        let mut migration_guards = BTreeMap::new();
+        for (id, migration) in sorted_migrations {
+            migration_guards.insert(id, migration.gate.clone().read_owned().await);
+        }
+        let moved_keys: Vec<FeatureKey> = key_feature_map.keys()
+            .filter(|key| {
+                let slot_id = slot_id_of_hash(calc_hash(key.as_bytes()));
+                matches!(migrations.get(&slot_id), Some(m) if *migration_guards[&m.id])
+            })
+            .cloned()
+            .collect();
+        for key in moved_keys {
+            let slot_id = slot_id_of_hash(calc_hash(key.as_bytes()));
+            if let (Some(feature), Some(migration)) = (key_feature_map.remove(&key), migrations.get(&slot_id)) {
+                remote_map.entry(migration.target_addr.clone()).or_insert(vec![])
+                    .push((feature.id, slot_id, migration.target.clone()));
+            }
+        }
+
+        // 本地没有需要更新的key时直接转发；否则本地提交之后再转发，本地锁超时返回错误时其它owner还没有更新，调用方可以重试整个事件
+        if key_feature_map.is_empty() {
+            drop(slot_guards);
+            drop(migration_guards);
+            self.forward_updates(&event, remote_map, forwarded, &mut result_map).await;
+            return Ok(DsUpdateResult { id: ds.id, feature_result_map: result_map });
+        }
+
+        // 根据这些key，找到page，按 (slot id, page min key) 排序后依次锁定，避免死锁
+        let mut page_map = BTreeMap::new();
         let mut feature_mk_map = HashMap::new();
         for (key, _) in &key_feature_map {
-            let hash = calc_hash(&key);
-            let (mk, page) = self.store.get_page(hash).await?;
-            locks.push((mk, page));
-            feature_mk_map.insert(key, mk);
+            let hash = calc_hash(key.as_bytes());
+            let slot = self.store.get_slot(hash)?;
+            let (mk, page) = slot.get_page(hash).await?;
+            page_map.entry((slot.id, mk)).or_insert(page);
+            feature_mk_map.insert(key, (slot.id, mk));
         }
-        for (mk, page) in &locks {
-            if !page_map.contains_key(mk) {
-                let l = page.write().await;
-                page_map.insert(mk, l);
-            }
+        let lock_timeout = time::Duration::from_millis(self.config.page_lock_timeout_ms);
+        let mut locked_page_map = BTreeMap::new();
+        for ((slot_id, mk), page) in &page_map {
+            // 超时后已获取的锁随 locked_page_map 一起释放，由调用方重试；此时还没有写wal
+            let l = time::timeout(lock_timeout, page.write()).await
+                .map_err(|_| page_lock_timeout_err(*slot_id, *mk))?;
+            locked_page_map.insert((*slot_id, *mk), l);
         }
 
-        // 锁定后，计算feature新值，并更新
+        // 锁定后，计算feature新值，并更新；动作ID在锁内分配，日志在释放锁后发送
+        let tid = generate_tid();
+        let begin = self.wal.reserve();
+        let mut updates = vec![];
         for (key, feature) in &key_feature_map {
-            if let Some(mk) = feature_mk_map.get(key) {
-                if let Some(locked_page) = page_map.get_mut(mk) {
+            if let Some(lock_key) = feature_mk_map.get(key) {
+                if let Some(locked_page) = locked_page_map.get_mut(lock_key) {
                     match feature.calc_and_update(&event, &ds.column_type_map, key, locked_page, &self.wal).await {
-                        Ok(res) => {
-                            let action_id = self.wal.send_feature_update_log(tid, res).await?;
-                            locked_page.after_update(action_id, &self.store).await;
+                        // 不满足过滤条件
+                        Ok(None) => {}
+                        Ok(Some(res)) => {
+                            // 迁移中的slot，更新日志同时转发给目标node
+                            if let Some(migration) = migrations.get(&lock_key.0) {
+                                migration.buffer.lock().await.push(res.clone());
+                            }
+                            let reserved = self.wal.reserve();
+                            locked_page.after_update(reserved.action_id, &self.store).await;
+                            updates.push((reserved, WalRedo::Update(res)));
                         }
                         Err(e) => {
-                            result_map.insert(feature.id, FeatureUpdateResult::failed(e.to_string()));
+                            result_map.insert(feature.id, FeatureUpdateResult::failed(&e));
                         }
                     }
                 }
             }
         }
-        self.wal.commit_log(tid).await;
+        // wal按动作ID的顺序写入，同一个key的更新日志顺序与更新顺序一致，且先于之后的page刷盘日志落盘，因此无需持锁发送
+        drop(locked_page_map);
+        drop(migration_guards);
+        drop(slot_guards);
+        if let Err(e) = self.log_redo(begin, tid, updates).await {
+            // page已经修改且锁已释放，无法撤销
+            self.mark_failed(&e);
+            return Err(e);
+        }
 
+        self.forward_updates(&event, remote_map, forwarded, &mut result_map).await;
         Ok(DsUpdateResult { id: ds.id, feature_result_map: result_map })
     }
 
+    /// 按预先分配的动作ID发送事务的日志并提交
+    pub(crate) async fn log_redo(&self, begin: ReservedActionId, tid: u64, redos: Vec<(ReservedActionId, WalRedo)>) -> CustomResult<()> {
+        self.wal.send_reserved(begin, tid, WalLogKind::Begin, None, None).await?;
+        for (reserved, redo) in redos {
+            let (kind, value) = redo.into_log();
+            self.wal.send_reserved(reserved, tid, kind, Some(value), None).await?;
+        }
+        self.wal.commit_log(tid).await
+    }
+
+    /// wal失败后停止接收写入
+    pub(crate) fn mark_failed(&self, e: &CustomError) {
+        if !self.failed.swap(true, Ordering::AcqRel) {
+            warn!("wal写入失败，node停止接收写入，重启后从上次检查点恢复:{:?}", e);
+        }
+    }
+
+    /// 把不属于当前node的指标转发给owner，失败的指标记录在结果中
+    async fn forward_updates(&self, event: &Value, remote_map: BTreeMap<String, Vec<(u64, u16, String)>>,
+                             forwarded: bool, result_map: &mut HashMap<u64, FeatureUpdateResult>) {
+        for (addr, features) in remote_map {
+            if forwarded {
+                // 已经是转发过来的请求，说明双方的集群信息不一致，不再继续转发
+                for (feature_id, slot_id, owner) in features {
+                    let e = not_owner_err(slot_id, Some(&owner));
+                    result_map.insert(feature_id, FeatureUpdateResult::failed(&e));
+                }
+                continue;
+            }
+            let feature_ids: Vec<u64> = features.iter().map(|(feature_id, _, _)| *feature_id).collect();
+            let request = Request::Update { event: event.clone(), feature_ids: Some(feature_ids.clone()), forwarded: true };
+            // 整个请求失败时owner上的指标都没有更新，记录为可以重试的错误
+            let e = match call(&addr, &request).await {
+                Ok(Response::Update(res)) => {
+                    result_map.extend(res.feature_result_map);
+                    continue;
+                }
+                Ok(resp) => forward_failed_err(&addr, unexpected_response_err(&resp).message),
+                Err(e) if e.is_retriable() => e,
+                Err(e) => forward_failed_err(&addr, e.message),
+            };
+            warn!("转发更新到{}失败:{:?}", addr, e);
+            feature_ids.iter().for_each(|id| { result_map.insert(*id, FeatureUpdateResult::failed(&e)); });
+        }
+    }
+
+    /// 把key按owner拆分，返回本地的key，按owner地址分组的 (feature id, slot id, owner id)，以及本地key所在slot正在进行的迁移
+    ///
+    /// 迁移状态在集群信息的读锁内获取：迁移切换owner时先更新集群信息再移除迁移状态，
+    /// 因此按旧的集群信息判断为本地的key一定能看到对应的迁移
+    pub(crate) async fn split_by_owner<'a>(&self, key_feature_map: HashMap<FeatureKey, &'a Feature>)
+                                -> (HashMap<FeatureKey, &'a Feature>, BTreeMap<String, Vec<(u64, u16, String)>>, HashMap<u16, Arc<SlotMigration>>) {
+        let cluster = self.cluster.read().await;
+        let all_migrations = self.migrations.read().await;
+        let mut local_map = HashMap::new();
+        let mut remote_map: BTreeMap<String, Vec<(u64, u16, String)>> = BTreeMap::new();
+        let mut migrations = HashMap::new();
+        for (key, feature) in key_feature_map {
+            let slot_id = slot_id_of_hash(calc_hash(key.as_bytes()));
+            match cluster.owner_of_slot(slot_id) {
+                Some(owner) if owner != &self.config.node_id => {
+                    let addr = cluster.addr_of(owner).cloned().unwrap_or_default();
+                    remote_map.entry(addr).or_insert(vec![]).push((feature.id, slot_id, owner.clone()));
+                }
+                _ => {
+                    if let Some(migration) = all_migrations.get(&slot_id) {
+                        migrations.insert(slot_id, migration.clone());
+                    }
+                    local_map.insert(key, feature);
+                }
+            }
+        }
+        (local_map, remote_map, migrations)
+    }
+
+    /// 查询指标，key不属于当前node时转发给owner
+    pub async fn query(&self, query: FeatureQuery, forwarded: bool) -> CustomResult<FeatureQueryResult> {
+        let ds = self.datasets.get(&query.ds)
+            .ok_or(common_err(format!("找不到对应的ds:{}", query.ds)))?;
+        let feature = ds.features.iter().find(|f| f.id == query.feature_id)
+            .ok_or(common_err(format!("找不到对应的feature:{}", query.feature_id)))?;
+        let key = feature.build_key(&query.keys, &ds.column_type_map)?;
+        if let Some(derived) = feature.derived() {
+            // 派生指标没有存储，分别查询引用的指标后计算，引用的key可能属于其它node
+            let mut values = HashMap::new();
+            for feature_id in derived.feature_ids() {
+                let query = FeatureQuery { ds: query.ds, feature_id, keys: query.keys.clone(), time: query.time };
+                let res = Box::pin(self.query(query, false)).await?;
+                values.insert(feature_id, res.value);
+            }
+            return Ok(FeatureQueryResult { feature_id: feature.id, value: derived.eval(&values) });
+        }
+        let hash = calc_hash(key.as_bytes());
+
+        let owner = {
+            let cluster = self.cluster.read().await;
+            match cluster.owner_of_hash(hash) {
+                Some(owner) if owner != &self.config.node_id => Some((owner.clone(), cluster.addr_of(owner).cloned())),
+                _ => None,
+            }
+        };
+        if let Some((owner, addr)) = owner {
+            let slot_id = slot_id_of_hash(hash);
+            if forwarded {
+                return Err(not_owner_err(slot_id, Some(&owner)));
+            }
+            let addr = addr.ok_or(not_owner_err(slot_id, Some(&owner)))?;
+            return match call(&addr, &Request::Query { query, forwarded: true }).await? {
+                Response::Query(res) => Ok(res),
+                resp => Err(unexpected_response_err(&resp)),
+            };
+        }
+
+        let (_, page) = self.store.get_page(hash).await?;
+        let page = page.read().await;
+        let value = feature.query(page.get(&key).await, query.time)?;
+        Ok(FeatureQueryResult { feature_id: feature.id, value })
+    }
+
+    /// 应用其它node发来的更新日志（如迁入的slot），按redo值覆盖，重复应用结果不变
+    pub async fn apply_updates(&self, updates: &[u8]) -> CustomResult<()> {
+        self.apply_redo(decode_updates(updates)?.into_iter().map(WalRedo::Update).collect()).await
+    }
+
+    /// 在一个事务中按顺序应用更新和删除日志
+    pub async fn apply_redo(&self, redos: Vec<WalRedo>) -> CustomResult<()> {
+        let closed = self.closed.read().await;
+        if *closed {
+            return Err(node_shutting_down_err());
+        }
+        if redos.is_empty() {
+            return Ok(());
+        }
+
+        let tid = generate_tid();
+        self.wal.send_begin_log(tid).await?;
+        for redo in redos {
+            let (_, page) = self.store.get_page(calc_hash(redo.fk().as_bytes())).await?;
+            let mut page = page.write().await;
+            page.redo(&redo).await;
+            let (kind, value) = redo.into_log();
+            let action_id = self.wal.send_log(tid, kind, Some(value), None).await?;
+            page.after_update(action_id, &self.store).await;
+        }
+        self.wal.commit_log(tid).await
+    }
+
+    /// 处理一个rpc请求
+    pub async fn handle(&self, request: Request) -> Response {
+        match request {
+            Request::Update { event, feature_ids, forwarded } =>
+                Response::from_result(self.update_features(event, feature_ids, forwarded).await, Response::Update),
+            Request::Query { query, forwarded } =>
+                Response::from_result(self.query(query, forwarded).await, Response::Query),
+            Request::MigrateSlots { slot_ids, target } =>
+                Response::from_result(self.migrate_slots(slot_ids, target).await, Response::ClusterMap),
+            Request::ApplyUpdates { updates } =>
+                Response::from_result(self.apply_updates(&updates).await, |_| Response::Ok),
+            Request::FetchWal { from, ack, max_items, wait_ms } =>
+                Response::from_result(self.fetch_wal(from, ack, max_items, wait_ms).await, Response::WalItems),
+            Request::Promote => Response::from_result(self.promote().await, Response::ClusterMap),
+            Request::ReplicationStatus => Response::ReplicationStatus(self.replication_status()),
+            Request::Snapshot { backup_dir } => Response::from_result(self.snapshot(&backup_dir).await, Response::Snapshot),
+            Request::ClusterChanged(cluster) => {
+                self.set_cluster(cluster).await;
+                Response::Ok
+            }
+            _ => Response::Error { code: 10000, message: "node不支持的请求".to_string() },
+        }
+    }
+
+    /// 更新集群信息，忽略比当前旧的版本
+    pub async fn set_cluster(&self, cluster: ClusterMap) {
+        let mut current = self.cluster.write().await;
+        if cluster.version >= current.version {
+            if cluster.version > current.version {
+                info!("集群信息更新，版本:{} -> {}", current.version, cluster.version);
+            }
+            *current = cluster;
+        }
+    }
+
+    /// 从meta server刷新集群信息
+    pub async fn refresh_cluster(&self) -> CustomResult<()> {
+        let cluster = meta_client::fetch_cluster_map(&self.config.meta_addr).await?;
+        self.set_cluster(cluster).await;
+        Ok(())
+    }
+
+    /// 定期刷新集群信息
+    pub async fn cluster_refresh(&self) {
+        let mut interval = time::interval(time::Duration::from_secs(self.config.cluster_refresh_secs));
+        let mut shutdown_rx = self.shutdown_rx.clone();
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown_rx.changed() => {
+                    info!("集群信息刷新任务退出");
+                    return;
+                }
+            }
+            if let Err(e) = self.refresh_cluster().await {
+                warn!("刷新集群信息失败:{:?}", e);
+            }
+        }
+    }
+
+    /// 停机信号，停机时值变为true
+    pub fn shutdown_signal(&self) -> watch::Receiver<bool> {
+        self.shutdown_rx.clone()
+    }
+
     pub async fn check_point(&self) {
-        let mut interval = time::interval(time::Duration::from_secs(5));
+        let mut interval = time::interval(time::Duration::from_secs(self.config.checkpoint_interval_secs));
+        let mut shutdown_rx = self.shutdown_rx.clone();
         loop {
-            interval.tick().await;
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown_rx.changed() => {
+                    info!("检查点任务退出");
+                    return;
+                }
+            }
+            // 内存中有没有记录日志的更新，不能写入磁盘
+            if self.failed.load(Ordering::Acquire) {
+                warn!("wal写入失败，跳过检查点");
+                continue;
+            }
             info!("check_point start...");
+            let _guard = self.check_point_lock.lock().await;
 
             match self.store.check_point(&self.wal).await {
                 Ok(_) => {}
@@ -98,19 +453,99 @@ impl Node {
             }
         }
     }
+
+    /// 定期采样store中page编码后的总大小
+    pub async fn sample_store_size(&self) {
+        let mut interval = time::interval(time::Duration::from_secs(STORE_SIZE_SAMPLE_SECS));
+        let mut shutdown_rx = self.shutdown_rx.clone();
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown_rx.changed() => {
+                    info!("store大小采样任务退出");
+                    return;
+                }
+            }
+            metrics::STORE_ENCODED_BYTES.set(self.store.encoded_bytes().await as i64);
+        }
+    }
+}
+
+impl Node {
+    /// 在线备份：先正常刷盘，再短暂阻塞写入，刷完剩余的脏页并记录wal位置；
+    /// 之后恢复写入，持有检查点锁复制文件，复制期间page和索引文件不会被修改
+    pub async fn snapshot(&self, backup_dir: &str) -> CustomResult<BackupManifest> {
+        if self.failed.load(Ordering::Acquire) {
+            return Err(node_failed_err());
+        }
+        let _guard = self.check_point_lock.lock().await;
+        self.store.check_point(&self.wal).await?;
+
+        let (wal_size, next_tid, next_action_id) = {
+            let closed = self.closed.write().await;
+            if *closed {
+                return Err(node_shutting_down_err());
+            }
+            self.store.check_point_all(&self.wal).await?;
+            // 空事务提交后，之前的日志都已写入wal文件
+            let tid = generate_tid();
+            self.wal.send_begin_log(tid).await?;
+            self.wal.commit_log(tid).await?;
+            let wal_size = tokio::fs::metadata(get_wal_file_path(self.config.data_dir.clone())).await?.len();
+            let (next_tid, next_action_id) = current_ids();
+            (wal_size, next_tid, next_action_id)
+        };
+        info!("备份开始，wal位置:{}", wal_size);
+
+        copy_snapshot(&self.config.data_dir, backup_dir, &self.config.node_id, wal_size, next_tid, next_action_id).await
+    }
+
+    /// 停机：停止接收数据，等待进行中的update完成，刷盘所有数据，关闭wal，写入停机标记
+    pub async fn shutdown(&self) -> CustomResult<()> {
+        info!("node停机开始...");
+        {
+            let mut closed = self.closed.write().await;
+            if *closed {
+                return Ok(());
+            }
+            *closed = true;
+        }
+        info!("已停止接收数据，进行中的update已完成");
+
+        let _ = self.shutdown_tx.send(true);
+        let _guard = self.check_point_lock.lock().await;
+
+        // 不做检查点也不写停机标记，重启后从上次检查点恢复
+        if self.failed.load(Ordering::Acquire) {
+            return Err(node_failed_err());
+        }
+        self.store.check_point_all(&self.wal).await?;
+        info!("最终检查点完成");
+
+        self.wal.close().await?;
+        self.store.mark_clean_shutdown().await?;
+        info!("node停机完成");
+        Ok(())
+    }
 }
 
 /// 根据feature构建所有的key
-fn build_feature_keys<'a>(data: &'a Value, ds: &'a DataSet) -> (HashMap<String, &'a Feature>, HashMap<u64, FeatureUpdateResult>) {
+fn build_feature_keys<'a>(data: &'a Value, ds: &'a DataSet, feature_ids: Option<&[u64]>) -> (HashMap<FeatureKey, &'a Feature>, HashMap<u64, FeatureUpdateResult>) {
     let mut key_feature_map = HashMap::new();
     let mut key_error_map = HashMap::new();
-    for feature in &ds.features {
+    // 派生指标没有存储，不需要更新
+    for feature in ds.features.iter().filter(|f| f.derived().is_none()) {
+        if let Some(ids) = feature_ids {
+            if !ids.contains(&feature.id) {
+                continue;
+            }
+        }
         match feature.build_key(&data, &ds.column_type_map) {
             Ok(key) => {
                 key_feature_map.insert(key, feature);
             }
             Err(e) => {
-                key_error_map.insert(feature.id, FeatureUpdateResult::failed(e.to_string()));
+                key_error_map.insert(feature.id, FeatureUpdateResult::failed(&e));
             }
         }
     }
@@ -118,50 +553,119 @@ fn build_feature_keys<'a>(data: &'a Value, ds: &'a DataSet) -> (HashMap<String,
 }
 
 /// 创建和初始化node
-pub async fn create_and_init() -> CustomResult<Arc<Node>> {
-    let ds_vec = meta_client::fetch_all_dataset().expect("创建node失败");
-    let mut datasets = HashMap::new();
-    for ds in ds_vec {
-        datasets.insert(ds.id, ds);
-    }
-
-    let config = Config {
-        data_dir: "/Users/yang/feature_db".to_string()
-    };
+pub async fn create_and_init(config: Config) -> CustomResult<Arc<Node>> {
+    let datasets = meta_client::fetch_datasets(&config.meta_addr).await?;
+    let node = create_node(config, datasets).await?;
 
-    // 初始化redo log
-    let wal = crate_wal(config.data_dir.clone()).await?;
-    let store = Store::new(config.data_dir.clone()).await;
-
-    let node = Arc::new(Node {
-        config,
-        datasets,
-        wal,
-        store,
-    });
+    // 先启动服务，再用实际监听的地址注册到meta server；follower提升后才注册
+    let addr = server::start(node.clone(), &node.config.listen_addr).await?;
+    *node.addr.write().await = addr.to_string();
+    if node.config.replicate_from.is_empty() {
+        let node_info = NodeInfo { id: node.config.node_id.clone(), addr: addr.to_string() };
+        let cluster = meta_client::register_node(&node.config.meta_addr, node_info).await?;
+        node.set_cluster(cluster).await;
+    } else {
+        node.refresh_cluster().await?;
+        let node1 = node.clone();
+        tokio::spawn(async move {
+            node1.replicate().await
+        });
+    }
 
     let node2 = node.clone();
     tokio::spawn(async move {
         node2.check_point().await
     });
+    let node3 = node.clone();
+    tokio::spawn(async move {
+        node3.cluster_refresh().await
+    });
+    let node4 = node.clone();
+    tokio::spawn(async move {
+        node4.sample_store_size().await
+    });
+    source::start_all(&node);
 
     Ok(node)
 }
 
+/// 根据配置和数据集创建node，不启动后台任务
+pub async fn create_node(config: Config, datasets: HashMap<i64, DataSet>) -> CustomResult<Arc<Node>> {
+    // 初始化redo log
+    tokio::fs::create_dir_all(&config.data_dir).await?;
+    // leader保留最近的wal日志供follower拉取；wal为空时follower不会缺少任何日志
+    let wal_is_empty = tokio::fs::metadata(get_wal_file_path(config.data_dir.clone())).await
+        .map_or(true, |m| m.len() == 0);
+    let replication = if config.replicate_from.is_empty() {
+        let ack_timeout = time::Duration::from_millis(config.replication_ack_timeout_ms);
+        Some(Arc::new(ReplicationLog::new(config.replication_log_size, config.replication_sync_ack, ack_timeout)))
+    } else {
+        None
+    };
+    let wal = crate_wal(config.data_dir.clone(), config.wal_sync, config.wal_queue_size, replication.clone()).await?;
+    let store = Store::new(config.data_dir.clone()).await?;
+    if let Some(replication) = &replication {
+        replication.set_floor(if wal_is_empty { 0 } else { current_ids().1 });
+    }
+    let follower = if config.replicate_from.is_empty() {
+        None
+    } else {
+        Some(Follower::load(config.replicate_from.clone(), &config.data_dir, config.replication_log_size as u64).await?)
+    };
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let admission = Admission::new(&config);
+    // 注册到meta server之前，认为所有slot属于自己
+    let cluster = ClusterMap::single(&NodeInfo { id: config.node_id.clone(), addr: config.listen_addr.clone() });
+    let addr = config.listen_addr.clone();
+    Ok(Arc::new(Node {
+        config,
+        datasets,
+        wal,
+        store,
+        cluster: RwLock::new(cluster),
+        migrations: RwLock::new(HashMap::new()),
+        slot_gates: (0..1 << SLOT_NUM_BY_BIT).map(|_| RwLock::new(())).collect(),
+        addr: RwLock::new(addr),
+        read_only: AtomicBool::new(follower.is_some()),
+        failed: AtomicBool::new(false),
+        follower: Mutex::new(follower),
+        applied_action_id: AtomicU64::new(0),
+        admission,
+        closed: RwLock::new(false),
+        shutdown_tx,
+        shutdown_rx,
+        check_point_lock: Mutex::new(()),
+    }))
+}
 
 #[cfg(test)]
 mod tests {
     use std::sync::Arc;
-    use std::time::{Duration, SystemTime};
+    use std::time::Duration;
 
     use chrono::Local;
-    use log::info;
     use rand::{Rng, SeedableRng};
     use serde::{Deserialize, Serialize};
     use serde_json::Value;
     use tokio::sync::Semaphore;
 
-    use crate::node::create_and_init;
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    use feature_base::config::Config;
+    use feature_base::custom_error::{NODE_FAILED_CODE, NODE_SHUTTING_DOWN_CODE, READ_ONLY_CODE};
+    use feature_base::ds::DataSet;
+    use tokio::sync::RwLock;
+
+    use feature_base::calc_hash;
+    use feature_base::client::{FeatureClient, rebalance};
+    use feature_base::feature::FeatureQuery;
+    use feature_base::feature::value::{Scalar, ValueKind};
+    use feature_base::store::{backup, fsck, inspect};
+    use feature_base::store::wal::{WalLogKind, WalRedo};
+
+    use crate::node::{create_and_init, create_node, Node};
 
     #[derive(Serialize, Deserialize, Debug)]
     struct Event {
@@ -180,7 +684,11 @@ mod tests {
             .build()
             .unwrap();
         rt.block_on(async {
-            let node_bs = create_and_init().await.expect("创建node失败！");
+            let config = Config {
+                data_dir: "/Users/yang/feature_db".to_string(),
+                ..Config::default()
+            };
+            let node_bs = create_and_init(config).await.expect("创建node失败！");
             let dt = Local::now();
             let semaphore = Arc::new(Semaphore::new(1000));
 
@@ -211,4 +719,806 @@ mod tests {
 
         std::thread::sleep(std::time::Duration::from_secs(10));
     }
-}
\ No newline at end of file
+
+    /// 测试用的数据目录，每次都是空目录
+    fn test_data_dir(name: &str) -> PathBuf {
+        let data_dir = std::env::temp_dir().join(format!("feature_db_{}_test_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&data_dir);
+        std::fs::create_dir_all(&data_dir).expect("创建目录失败");
+        data_dir
+    }
+
+    fn test_datasets() -> HashMap<i64, DataSet> {
+        let ds: DataSet = serde_json::from_str(r#"{
+            "id":101,
+            "name":"ds_user_order",
+            "desc":"用户订单数据集",
+            "column_type_map":{"user_id":"INT","merchant_id":"INT","ts":"DATETIME"},
+            "features":[
+              {"id":10001,"name":"用户订单数","template":{"COUNT":{
+                "group_keys":["user_id"],"time_key":"ts","window_unit":"DAY","window_size":30}}},
+              {"id":10002,"name":"商户订单数","template":{"COUNT":{
+                "group_keys":["merchant_id"],"time_key":"ts","window_unit":"DAY","window_size":30}}}
+            ]
+        }"#).expect("解析数据集失败");
+        let mut datasets = HashMap::new();
+        datasets.insert(ds.id, ds);
+        datasets
+    }
+
+    /// 两个指标的key分布在不同page上，并发更新时加锁顺序不一致会死锁
+    #[test]
+    pub fn overlapping_keys_update_test() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(8)
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let data_dir = test_data_dir("lock");
+            let config = Config {
+                data_dir: data_dir.to_str().unwrap().to_string(),
+                ..Config::default()
+            };
+            let node = create_node(config, test_datasets()).await.expect("创建node失败！");
+
+            // 把slot切分成多个page，让不同的key落到不同的page上
+            for slot in node.store.slot_index.values() {
+                let mut page_tree = slot.page_tree.write().await;
+                for i in 1..16u64 {
+                    let min_pk = (u64::MAX / 16) * i;
+                    let page = slot.new_page(min_pk, min_pk + u64::MAX / 16).await.expect("new_page");
+                    page_tree.insert(min_pk, Arc::new(RwLock::new(page)));
+                }
+            }
+
+            let mut handles = vec![];
+            for task in 0..32i64 {
+                let node = node.clone();
+                handles.push(tokio::spawn(async move {
+                    for i in 0..100i64 {
+                        // user 和 merchant 交叉组合，保证两个任务以相反的顺序访问同一组page
+                        let (user_id, merchant_id) = if task % 2 == 0 { (i % 4, (i + 1) % 4) } else { ((i + 1) % 4, i % 4) };
+                        let v: Value = serde_json::json!({
+                            "ds": 101,
+                            "user_id": user_id,
+                            "merchant_id": merchant_id,
+                            "ts": 1650000000000u64 + i as u64,
+                        });
+                        loop {
+                            match node.update(v.clone()).await {
+                                Ok(res) => {
+                                    assert!(res.feature_result_map.is_empty(), "{:?}", res);
+                                    break;
+                                }
+                                Err(e) if e.is_retriable() => continue,
+                                Err(e) => panic!("更新失败:{:?}", e),
+                            }
+                        }
+                    }
+                }));
+            }
+
+            let all = async {
+                for h in handles {
+                    h.await.expect("join");
+                }
+            };
+            tokio::time::timeout(Duration::from_secs(60), all).await.expect("并发更新超时，可能发生死锁");
+
+            let ds = node.datasets.get(&101).unwrap();
+            let mut totals = HashMap::new();
+            for slot in node.store.slot_index.values() {
+                for page in slot.page_tree.read().await.values() {
+                    for (key, value) in &page.read().await.data {
+                        let feature_id = key.feature_id();
+                        let json = serde_json::to_value(value).unwrap();
+                        let sum: u64 = json.as_object().unwrap().values()
+                            .map(|v| v["Int"].as_u64().unwrap())
+                            .sum();
+                        *totals.entry(feature_id).or_insert(0) += sum;
+                    }
+                }
+            }
+            for feature in &ds.features {
+                assert_eq!(totals.get(&feature.id), Some(&(32 * 100)));
+            }
+
+            let _ = std::fs::remove_dir_all(&data_dir);
+        });
+    }
+
+    #[test]
+    pub fn shutdown_test() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let data_dir = test_data_dir("shutdown");
+            let config = Config {
+                data_dir: data_dir.to_str().unwrap().to_string(),
+                ..Config::default()
+            };
+            let node = create_node(config.clone(), test_datasets()).await.expect("创建node失败！");
+
+            let mut handles = vec![];
+            for i in 0..200i64 {
+                let node = node.clone();
+                handles.push(tokio::spawn(async move {
+                    let v: Value = serde_json::json!({"ds": 101, "user_id": i, "merchant_id": i % 7, "ts": 1650000000000u64});
+                    node.update(v).await
+                }));
+            }
+            node.shutdown().await.expect("停机失败");
+            // 停机前已经开始的update全部成功，之后的被拒绝（或因负载过高被拒绝）
+            for h in handles {
+                if let Err(e) = h.await.expect("join") {
+                    assert!(e.code == NODE_SHUTTING_DOWN_CODE || e.is_retriable(), "{:?}", e);
+                }
+            }
+            let v: Value = serde_json::json!({"ds": 101, "user_id": 1, "merchant_id": 1, "ts": 1650000000000u64});
+            assert_eq!(node.update(v).await.unwrap_err().code, NODE_SHUTTING_DOWN_CODE);
+
+            assert!(!node.store.has_dirty().await);
+            let marker = data_dir.join("clean_shutdown");
+            assert!(marker.exists());
+            drop(node);
+
+            // 重启后消费停机标记
+            let node = create_node(config, test_datasets()).await.expect("重启node失败！");
+            assert!(!marker.exists());
+            node.shutdown().await.expect("停机失败");
+
+            let _ = std::fs::remove_dir_all(&data_dir);
+        });
+    }
+
+    /// 修改page后wal失败，node停止接收写入，不再把没有日志的更新写入磁盘
+    #[test]
+    pub fn wal_failure_test() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let data_dir = test_data_dir("wal_failure");
+            let config = Config {
+                data_dir: data_dir.to_str().unwrap().to_string(),
+                ..Config::default()
+            };
+            let node = create_node(config.clone(), test_datasets()).await.expect("创建node失败！");
+            let v: Value = serde_json::json!({"ds": 101, "user_id": 1, "merchant_id": 1, "ts": 1650000000000u64});
+            node.update(v.clone()).await.expect("更新失败");
+            node.store.check_point_all(&node.wal).await.expect("检查点失败");
+
+            // wal写入线程退出后发送失败
+            node.wal.close().await.expect("关闭wal失败");
+            assert!(node.update(v.clone()).await.is_err());
+            assert_eq!(node.update(v).await.unwrap_err().code, NODE_FAILED_CODE);
+            assert_eq!(node.shutdown().await.unwrap_err().code, NODE_FAILED_CODE);
+            assert!(!data_dir.join("clean_shutdown").exists());
+            drop(node);
+
+            let node = create_node(config, test_datasets()).await.expect("重启node失败！");
+            let query = FeatureQuery { ds: 101, feature_id: 10001, keys: serde_json::json!({"user_id": 1}), time: 1650000000000u64 };
+            assert_eq!(node.query(query, false).await.expect("查询失败").value, Some(ValueKind::Int(1)));
+            node.shutdown().await.expect("停机失败");
+            let _ = std::fs::remove_dir_all(&data_dir);
+        });
+    }
+
+    /// 带过滤条件的指标只累加满足条件的事件，字段类型错误时该指标更新失败
+    #[test]
+    pub fn filter_test() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let data_dir = test_data_dir("filter");
+            let config = Config {
+                data_dir: data_dir.to_str().unwrap().to_string(),
+                ..Config::default()
+            };
+            let ds: DataSet = serde_json::from_str(r#"{
+                "id":101,
+                "name":"ds_user_order",
+                "desc":"用户订单数据集",
+                "column_type_map":{"user_id":"INT","amount":"FLOAT","status":"TEXT","ts":"DATETIME"},
+                "features":[
+                  {"id":10001,"name":"用户订单数","template":{"COUNT":{
+                    "group_keys":["user_id"],"time_key":"ts","window_unit":"DAY","window_size":30}}},
+                  {"id":10003,"name":"用户大额成功订单数","template":{"COUNT":{
+                    "group_keys":["user_id"],"time_key":"ts","window_unit":"DAY","window_size":30,
+                    "filter":{"AND":[{"GT":["amount",100]},{"NOT":{"IN":["status",["failed","canceled"]]}}]}}}}
+                ]
+            }"#).expect("解析数据集失败");
+            ds.validate().expect("校验数据集失败");
+            let node = create_node(config, HashMap::from([(ds.id, ds)])).await.expect("创建node失败！");
+
+            let ts = 1650000000000u64;
+            for (amount, status) in [(50.0, "paid"), (150.0, "paid"), (200.0, "failed"), (300.0, "paid")] {
+                let v: Value = serde_json::json!({"ds": 101, "user_id": 1, "amount": amount, "status": status, "ts": ts});
+                let res = node.update(v).await.expect("更新失败");
+                assert!(res.feature_result_map.is_empty(), "{:?}", res);
+            }
+            let v: Value = serde_json::json!({"ds": 101, "user_id": 1, "amount": "x", "ts": ts});
+            let res = node.update(v).await.expect("更新失败");
+            assert!(!res.feature_result_map[&10003].success);
+
+            let query = |feature_id: u64| FeatureQuery { ds: 101, feature_id, keys: serde_json::json!({"user_id": 1}), time: ts };
+            assert_eq!(node.query(query(10001), false).await.expect("查询失败").value, Some(ValueKind::Int(5)));
+            assert_eq!(node.query(query(10003), false).await.expect("查询失败").value, Some(ValueKind::Int(2)));
+
+            node.shutdown().await.expect("停机失败");
+            let _ = std::fs::remove_dir_all(&data_dir);
+        });
+    }
+
+    /// 派生指标在查询时由引用的指标计算，更新时跳过
+    #[test]
+    pub fn derived_test() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let data_dir = test_data_dir("derived");
+            let config = Config {
+                data_dir: data_dir.to_str().unwrap().to_string(),
+                ..Config::default()
+            };
+            let ds: DataSet = serde_json::from_str(r#"{
+                "id":101,
+                "name":"ds_user_order",
+                "desc":"用户订单数据集",
+                "column_type_map":{"user_id":"INT","status":"TEXT","ts":"DATETIME"},
+                "features":[
+                  {"id":10001,"name":"用户订单数","template":{"COUNT":{
+                    "group_keys":["user_id"],"time_key":"ts","window_unit":"DAY","window_size":30}}},
+                  {"id":10002,"name":"用户退款数","template":{"COUNT":{
+                    "group_keys":["user_id"],"time_key":"ts","window_unit":"DAY","window_size":30,
+                    "filter":{"EQ":["status","refund"]}}}},
+                  {"id":10003,"name":"用户退款率","template":{"DERIVED":{
+                    "group_keys":["user_id"],"expr":{"DIV":[{"FEATURE":10002},{"FEATURE":10001}]}}}},
+                  {"id":10004,"name":"用户退款率百分比","template":{"DERIVED":{
+                    "group_keys":["user_id"],"expr":{"MUL":[{"FEATURE":10003},{"CONST":100}]}}}}
+                ]
+            }"#).expect("解析数据集失败");
+            ds.validate().expect("校验数据集失败");
+            let node = create_node(config, HashMap::from([(ds.id, ds)])).await.expect("创建node失败！");
+
+            let ts = 1650000000000u64;
+            for status in ["paid", "refund", "paid", "paid"] {
+                let v: Value = serde_json::json!({"ds": 101, "user_id": 1, "status": status, "ts": ts});
+                let res = node.update(v).await.expect("更新失败");
+                assert!(res.feature_result_map.is_empty(), "{:?}", res);
+            }
+
+            let query = |feature_id: u64, user_id: i64| FeatureQuery { ds: 101, feature_id, keys: serde_json::json!({"user_id": user_id}), time: ts };
+            assert_eq!(node.query(query(10003, 1), false).await.expect("查询失败").value, Some(ValueKind::Float(0.25)));
+            assert_eq!(node.query(query(10004, 1), false).await.expect("查询失败").value, Some(ValueKind::Float(25.0)));
+            // 没有订单时除数为0
+            assert_eq!(node.query(query(10003, 2), false).await.expect("查询失败").value, None);
+            assert!(node.query(FeatureQuery { ds: 101, feature_id: 10003, keys: serde_json::json!({}), time: ts }, false).await.is_err());
+
+            node.shutdown().await.expect("停机失败");
+            let _ = std::fs::remove_dir_all(&data_dir);
+        });
+    }
+
+    /// LAST_N保留每个key最近的n个值，查询返回查询时间之前的列表，wal重放后一致
+    #[test]
+    pub fn last_n_test() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let data_dir = test_data_dir("last_n");
+            let config = Config {
+                data_dir: data_dir.to_str().unwrap().to_string(),
+                ..Config::default()
+            };
+            let ds: DataSet = serde_json::from_str(r#"{
+                "id":101,
+                "name":"ds_user_order",
+                "desc":"用户订单数据集",
+                "column_type_map":{"user_id":"INT","amount":"FLOAT","ts":"DATETIME"},
+                "features":[
+                  {"id":10001,"name":"用户最近3笔订单金额","template":{"LAST_N":{
+                    "group_keys":["user_id"],"time_key":"ts","value_key":"amount","n":3}}}
+                ]
+            }"#).expect("解析数据集失败");
+            ds.validate().expect("校验数据集失败");
+            let node = create_node(config, HashMap::from([(ds.id, ds)])).await.expect("创建node失败！");
+
+            let ts = 1650000000000u64;
+            for (i, amount) in [10.0, 20.0, 30.0, 40.0].iter().enumerate() {
+                let v: Value = serde_json::json!({"ds": 101, "user_id": 1, "amount": amount, "ts": ts + i as u64});
+                let res = node.update(v).await.expect("更新失败");
+                assert!(res.feature_result_map.is_empty(), "{:?}", res);
+            }
+            let amounts = |value: Option<ValueKind>| match value {
+                Some(ValueKind::List(items)) => items.into_iter().map(|i| match i.value {
+                    Scalar::Float(v) => v,
+                    v => panic!("{:?}", v),
+                }).collect::<Vec<_>>(),
+                v => panic!("{:?}", v),
+            };
+            let query = |time: u64| FeatureQuery { ds: 101, feature_id: 10001, keys: serde_json::json!({"user_id": 1}), time };
+            assert_eq!(amounts(node.query(query(ts + 10), false).await.expect("查询失败").value), vec![20.0, 30.0, 40.0]);
+            assert_eq!(amounts(node.query(query(ts + 2), false).await.expect("查询失败").value), vec![20.0, 30.0]);
+
+            node.shutdown().await.expect("停机失败");
+            // 列表按自己的编码刷盘后可以完整读出
+            let report = fsck::check(data_dir.to_str().unwrap(), false).await.expect("检查失败");
+            assert!(report.is_ok(), "{:?}", report.problems);
+            assert_eq!(report.keys, 1);
+            let _ = std::fs::remove_dir_all(&data_dir);
+        });
+    }
+
+    #[test]
+    pub fn seen_test() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let data_dir = test_data_dir("seen");
+            let config = Config {
+                data_dir: data_dir.to_str().unwrap().to_string(),
+                ..Config::default()
+            };
+            let ds: DataSet = serde_json::from_str(r#"{
+                "id":101,
+                "name":"ds_user_login",
+                "desc":"用户登录数据集",
+                "column_type_map":{"user_id":"INT","ts":"DATETIME"},
+                "features":[
+                  {"id":10001,"name":"用户首次登录距今天数","template":{"SEEN":{
+                    "group_keys":["user_id"],"time_key":"ts","output":"SINCE_FIRST","unit":"DAY"}}},
+                  {"id":10002,"name":"用户距上次登录秒数","template":{"SEEN":{
+                    "group_keys":["user_id"],"time_key":"ts","output":"SINCE_LAST","unit":"SECOND"}}}
+                ]
+            }"#).expect("解析数据集失败");
+            ds.validate().expect("校验数据集失败");
+            let node = create_node(config, HashMap::from([(ds.id, ds)])).await.expect("创建node失败！");
+
+            let day = 24 * 3600 * 1000u64;
+            let ts = 1650000000000u64;
+            for t in [ts + day, ts + 3 * day, ts] {
+                let v: Value = serde_json::json!({"ds": 101, "user_id": 1, "ts": t});
+                let res = node.update(v).await.expect("更新失败");
+                assert!(res.feature_result_map.is_empty(), "{:?}", res);
+            }
+            let query = |feature_id: u64, time: u64| FeatureQuery { ds: 101, feature_id, keys: serde_json::json!({"user_id": 1}), time };
+            let now = ts + 5 * day;
+            assert_eq!(node.query(query(10001, now), false).await.expect("查询失败").value, Some(ValueKind::Int(5)));
+            assert_eq!(node.query(query(10002, now), false).await.expect("查询失败").value, Some(ValueKind::Int(2 * 24 * 3600)));
+            assert_eq!(node.query(query(10001, ts - 1), false).await.expect("查询失败").value, None);
+
+            node.shutdown().await.expect("停机失败");
+            let report = fsck::check(data_dir.to_str().unwrap(), false).await.expect("检查失败");
+            assert!(report.is_ok(), "{:?}", report.problems);
+            assert_eq!(report.keys, 2);
+            let _ = std::fs::remove_dir_all(&data_dir);
+        });
+    }
+
+    /// 标准差合并窗口内按天的分片
+    #[test]
+    pub fn stddev_test() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let data_dir = test_data_dir("stddev");
+            let config = Config {
+                data_dir: data_dir.to_str().unwrap().to_string(),
+                ..Config::default()
+            };
+            let ds: DataSet = serde_json::from_str(r#"{
+                "id":101,
+                "name":"ds_user_order",
+                "desc":"用户订单数据集",
+                "column_type_map":{"user_id":"INT","amount":"FLOAT","ts":"DATETIME"},
+                "features":[
+                  {"id":10001,"name":"用户30天交易金额标准差","template":{"STDDEV":{
+                    "group_keys":["user_id"],"time_key":"ts","value_key":"amount",
+                    "window_unit":"DAY","window_size":30,"output":"STDDEV"}}}
+                ]
+            }"#).expect("解析数据集失败");
+            ds.validate().expect("校验数据集失败");
+            let node = create_node(config, HashMap::from([(ds.id, ds)])).await.expect("创建node失败！");
+
+            let day = 24 * 3600 * 1000u64;
+            let ts = 1650000000000u64;
+            for (i, amount) in [2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0].iter().enumerate() {
+                let v: Value = serde_json::json!({"ds": 101, "user_id": 1, "amount": amount, "ts": ts + i as u64 * day});
+                let res = node.update(v).await.expect("更新失败");
+                assert!(res.feature_result_map.is_empty(), "{:?}", res);
+            }
+            let stddev = |value: Option<ValueKind>| match value {
+                Some(ValueKind::Float(v)) => v,
+                v => panic!("{:?}", v),
+            };
+            let query = |time: u64| FeatureQuery { ds: 101, feature_id: 10001, keys: serde_json::json!({"user_id": 1}), time };
+            // 2,4,4,4,5,5,7,9
+            assert!((stddev(node.query(query(ts + 10 * day), false).await.expect("查询失败").value) - 2.0).abs() < 1e-9);
+            // 窗口只剩最后两天：7,9
+            assert!((stddev(node.query(query(ts + 35 * day), false).await.expect("查询失败").value) - 1.0).abs() < 1e-9);
+
+            node.shutdown().await.expect("停机失败");
+            let report = fsck::check(data_dir.to_str().unwrap(), false).await.expect("检查失败");
+            assert!(report.is_ok(), "{:?}", report.problems);
+            assert_eq!(report.keys, 1);
+            let _ = std::fs::remove_dir_all(&data_dir);
+        });
+    }
+
+    /// 三个node分片，客户端按key路由，直接发给某个node的事件由它转发给owner
+    #[test]
+    pub fn cluster_test() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let meta_dir = test_data_dir("cluster_meta");
+            let datasets: Vec<DataSet> = test_datasets().into_values().collect();
+            std::fs::write(meta_dir.join("datasets.json"), serde_json::to_vec(&datasets).unwrap()).unwrap();
+            let (_meta, meta_addr) = feature_meta::start(meta_dir.to_str().unwrap(), "127.0.0.1:0").await
+                .expect("启动meta server失败");
+
+            let mut nodes = vec![];
+            let mut data_dirs = vec![];
+            for i in 0..3 {
+                let data_dir = test_data_dir(&format!("cluster_node_{}", i));
+                let config = Config {
+                    node_id: format!("node_{}", i),
+                    data_dir: data_dir.to_str().unwrap().to_string(),
+                    listen_addr: "127.0.0.1:0".to_string(),
+                    meta_addr: meta_addr.to_string(),
+                    ..Config::default()
+                };
+                nodes.push(create_and_init(config).await.expect("创建node失败！"));
+                data_dirs.push(data_dir);
+            }
+            // 第一个node拥有全部slot，rebalance后分布到三个node
+            let cluster = rebalance(&meta_addr.to_string()).await.expect("rebalance失败");
+            for node in &nodes {
+                node.refresh_cluster().await.expect("刷新集群信息失败");
+                assert_eq!(*node.cluster.read().await, cluster);
+                assert!(!cluster.slots_of(&node.config.node_id).is_empty());
+            }
+
+            let client = FeatureClient::connect(&meta_addr.to_string()).await.expect("连接失败");
+            for i in 0..100i64 {
+                let v: Value = serde_json::json!({"ds": 101, "user_id": i, "merchant_id": i % 5, "ts": 1650000000000u64});
+                let res = client.update(v).await.expect("更新失败");
+                assert!(res.feature_result_map.is_empty(), "{:?}", res);
+            }
+            // 直接发给node_0，不属于它的key由它转发
+            for i in 0..50i64 {
+                let v: Value = serde_json::json!({"ds": 101, "user_id": i, "merchant_id": i % 5, "ts": 1650000000000u64});
+                let res = nodes[0].update(v).await.expect("更新失败");
+                assert!(res.feature_result_map.is_empty(), "{:?}", res);
+            }
+
+            // 每个node只保存属于自己的key
+            for node in &nodes {
+                let cluster = node.cluster.read().await;
+                for slot in node.store.slot_index.values() {
+                    for page in slot.page_tree.read().await.values() {
+                        for key in page.read().await.data.keys() {
+                            let owner = cluster.owner_of_hash(calc_hash(key.as_bytes()));
+                            assert_eq!(owner, Some(&node.config.node_id));
+                        }
+                    }
+                }
+            }
+
+            let query = |feature_id: u64, keys: Value| FeatureQuery { ds: 101, feature_id, keys, time: 1650000000000u64 };
+            for i in 0..100i64 {
+                let res = client.query(query(10001, serde_json::json!({"user_id": i}))).await.expect("查询失败");
+                assert_eq!(res.value, Some(ValueKind::Int(if i < 50 { 2 } else { 1 })));
+            }
+            for i in 0..5i64 {
+                let res = client.query(query(10002, serde_json::json!({"merchant_id": i}))).await.expect("查询失败");
+                assert_eq!(res.value, Some(ValueKind::Int(30)));
+            }
+            // 经node转发的查询结果一致
+            let res = nodes[1].query(query(10001, serde_json::json!({"user_id": 7})), false).await.expect("查询失败");
+            assert_eq!(res.value, Some(ValueKind::Int(2)));
+
+            for node in &nodes {
+                node.shutdown().await.expect("停机失败");
+            }
+            for data_dir in data_dirs {
+                let _ = std::fs::remove_dir_all(&data_dir);
+            }
+            let _ = std::fs::remove_dir_all(&meta_dir);
+        });
+    }
+
+    /// 持续写入的同时把slot迁移到新加入的node，数据不丢失也不重复
+    #[test]
+    pub fn migration_test() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let meta_dir = test_data_dir("migration_meta");
+            let datasets: Vec<DataSet> = test_datasets().into_values().collect();
+            std::fs::write(meta_dir.join("datasets.json"), serde_json::to_vec(&datasets).unwrap()).unwrap();
+            let (_meta, meta_addr) = feature_meta::start(meta_dir.to_str().unwrap(), "127.0.0.1:0").await
+                .expect("启动meta server失败");
+            let meta_addr = meta_addr.to_string();
+
+            let mut nodes = vec![];
+            let mut data_dirs = vec![];
+            for i in 0..2 {
+                let data_dir = test_data_dir(&format!("migration_node_{}", i));
+                let config = Config {
+                    node_id: format!("node_{}", i),
+                    data_dir: data_dir.to_str().unwrap().to_string(),
+                    listen_addr: "127.0.0.1:0".to_string(),
+                    meta_addr: meta_addr.clone(),
+                    ..Config::default()
+                };
+                nodes.push(create_and_init(config).await.expect("创建node失败！"));
+                data_dirs.push(data_dir);
+            }
+            // node_1 后加入，不拥有slot
+            assert!(nodes[1].cluster.read().await.slots_of("node_1").is_empty());
+
+            let client = Arc::new(FeatureClient::connect(&meta_addr).await.expect("连接失败"));
+            let mut handles = vec![];
+            for task in 0..4i64 {
+                let client = client.clone();
+                handles.push(tokio::spawn(async move {
+                    for i in 0..300i64 {
+                        let v: Value = serde_json::json!({"ds": 101, "user_id": (task * 300 + i) % 50, "merchant_id": i % 5, "ts": 1650000000000u64});
+                        loop {
+                            match client.update(v.clone()).await {
+                                Ok(res) => {
+                                    assert!(res.feature_result_map.is_empty(), "{:?}", res);
+                                    break;
+                                }
+                                Err(e) if e.is_retriable() => continue,
+                                Err(e) => panic!("更新失败:{:?}", e),
+                            }
+                        }
+                    }
+                }));
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let cluster = rebalance(&meta_addr).await.expect("rebalance失败");
+            assert!(!cluster.slots_of("node_1").is_empty());
+            for h in handles {
+                h.await.expect("join");
+            }
+
+            // 迁出的数据已清理，每个node只保存属于自己的key
+            for node in &nodes {
+                for slot in node.store.slot_index.values() {
+                    for page in slot.page_tree.read().await.values() {
+                        for key in page.read().await.data.keys() {
+                            assert_eq!(cluster.owner_of_hash(calc_hash(key.as_bytes())), Some(&node.config.node_id));
+                        }
+                    }
+                }
+            }
+
+            let client = FeatureClient::connect(&meta_addr).await.expect("连接失败");
+            let query = |feature_id: u64, keys: Value| FeatureQuery { ds: 101, feature_id, keys, time: 1650000000000u64 };
+            for i in 0..50i64 {
+                let res = client.query(query(10001, serde_json::json!({"user_id": i}))).await.expect("查询失败");
+                assert_eq!(res.value, Some(ValueKind::Int(24)), "user_id:{}", i);
+            }
+            for i in 0..5i64 {
+                let res = client.query(query(10002, serde_json::json!({"merchant_id": i}))).await.expect("查询失败");
+                assert_eq!(res.value, Some(ValueKind::Int(240)), "merchant_id:{}", i);
+            }
+
+            for node in &nodes {
+                node.shutdown().await.expect("停机失败");
+            }
+
+            // 清理作为已提交的事务写入了wal，删除的都是迁出的key
+            let data_dir = data_dirs[0].to_str().unwrap();
+            let scan = inspect::read_wal(data_dir).await.expect("读取wal失败");
+            let mut removed = 0;
+            for txn in inspect::group_by_tid(scan.items) {
+                let committed = txn.is_committed();
+                for mut item in txn.items.into_iter().filter(|item| item.kind == WalLogKind::FeatureRemove) {
+                    let fk = WalRedo::from_item(&mut item).expect("删除日志错误").fk().clone();
+                    assert!(committed, "删除日志未提交");
+                    assert_eq!(cluster.owner_of_hash(calc_hash(fk.as_bytes())), Some(&"node_1".to_string()));
+                    removed += 1;
+                }
+            }
+            assert!(removed > 0);
+            let report = fsck::check(data_dir, false).await.expect("检查失败");
+            assert!(report.problems.is_empty(), "{:?}", report.problems);
+
+            for data_dir in data_dirs {
+                let _ = std::fs::remove_dir_all(&data_dir);
+            }
+            let _ = std::fs::remove_dir_all(&meta_dir);
+        });
+    }
+
+    /// follower复制leader的数据，leader停止后提升follower继续服务
+    #[test]
+    pub fn replication_test() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let meta_dir = test_data_dir("replication_meta");
+            let datasets: Vec<DataSet> = test_datasets().into_values().collect();
+            std::fs::write(meta_dir.join("datasets.json"), serde_json::to_vec(&datasets).unwrap()).unwrap();
+            let (_meta, meta_addr) = feature_meta::start(meta_dir.to_str().unwrap(), "127.0.0.1:0").await
+                .expect("启动meta server失败");
+            let meta_addr = meta_addr.to_string();
+
+            let leader_dir = test_data_dir("replication_leader");
+            let leader = create_and_init(Config {
+                node_id: "node_0".to_string(),
+                data_dir: leader_dir.to_str().unwrap().to_string(),
+                listen_addr: "127.0.0.1:0".to_string(),
+                meta_addr: meta_addr.clone(),
+                replication_sync_ack: true,
+                ..Config::default()
+            }).await.expect("创建leader失败！");
+            let follower_dir = test_data_dir("replication_follower");
+            let follower = create_and_init(Config {
+                node_id: "node_0".to_string(),
+                data_dir: follower_dir.to_str().unwrap().to_string(),
+                listen_addr: "127.0.0.1:0".to_string(),
+                meta_addr: meta_addr.clone(),
+                replicate_from: leader.addr.read().await.clone(),
+                ..Config::default()
+            }).await.expect("创建follower失败！");
+
+            let event = |i: i64| serde_json::json!({"ds": 101, "user_id": i % 10, "merchant_id": i % 3, "ts": 1650000000000u64});
+            // follower不接收直接写入
+            assert_eq!(follower.update(event(0)).await.unwrap_err().code, READ_ONLY_CODE);
+
+            // 等待follower开始拉取，之后每次提交都等待follower确认
+            let attach = async {
+                while leader.wal.replication.as_ref().unwrap().acked() == 0 {
+                    leader.update(event(0)).await.expect("更新失败");
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            };
+            tokio::time::timeout(Duration::from_secs(30), attach).await.expect("follower没有开始复制");
+            for i in 0..100i64 {
+                leader.update(event(i)).await.expect("更新失败");
+            }
+
+            let query = |feature_id: u64, keys: Value| FeatureQuery { ds: 101, feature_id, keys, time: 1650000000000u64 };
+            let count = |node: Arc<Node>, feature_id: u64, keys: Value| async move {
+                match node.query(query(feature_id, keys), true).await.expect("查询失败").value {
+                    Some(ValueKind::Int(n)) => n,
+                    v => panic!("查询结果错误:{:?}", v),
+                }
+            };
+            for i in 0..10i64 {
+                let keys = serde_json::json!({"user_id": i});
+                assert_eq!(count(follower.clone(), 10001, keys.clone()).await, count(leader.clone(), 10001, keys).await);
+            }
+            let status = follower.replication_status();
+            assert_eq!(status.leader.as_deref(), Some(follower.config.replicate_from.as_str()));
+            assert!(status.applied_action_id > 0);
+
+            // leader停止后提升follower，meta server上node_0指向follower
+            let before = count(leader.clone(), 10002, serde_json::json!({"merchant_id": 1})).await;
+            leader.shutdown().await.expect("停机失败");
+            let cluster = follower.promote().await.expect("提升失败");
+            assert_eq!(cluster.addr_of("node_0"), Some(&*follower.addr.read().await));
+
+            let client = FeatureClient::connect(&meta_addr).await.expect("连接失败");
+            client.update(event(1)).await.expect("更新失败");
+            let res = client.query(query(10002, serde_json::json!({"merchant_id": 1}))).await.expect("查询失败");
+            assert_eq!(res.value, Some(ValueKind::Int(before + 1)));
+
+            follower.shutdown().await.expect("停机失败");
+            for dir in [leader_dir, follower_dir, meta_dir] {
+                let _ = std::fs::remove_dir_all(&dir);
+            }
+        });
+    }
+
+    /// 写入过程中备份，再从备份恢复出新的数据目录
+    #[test]
+    pub fn snapshot_test() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let data_dir = test_data_dir("snapshot");
+            let backup_dir = std::env::temp_dir().join(format!("feature_db_snapshot_backup_test_{}", std::process::id()));
+            let restore_dir = std::env::temp_dir().join(format!("feature_db_snapshot_restore_test_{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&backup_dir);
+            let _ = std::fs::remove_dir_all(&restore_dir);
+            let config = Config {
+                data_dir: data_dir.to_str().unwrap().to_string(),
+                ..Config::default()
+            };
+            let node = create_node(config.clone(), test_datasets()).await.expect("创建node失败！");
+            let ts = 1650000000000u64;
+            for _ in 0..3 {
+                node.update(serde_json::json!({"ds": 101, "user_id": -1, "merchant_id": -1, "ts": ts})).await.expect("更新失败");
+            }
+
+            let mut handles = vec![];
+            for task in 0..4i64 {
+                let node = node.clone();
+                handles.push(tokio::spawn(async move {
+                    for i in 0..200i64 {
+                        let v: Value = serde_json::json!({"ds": 101, "user_id": task * 1000 + i, "merchant_id": i % 7, "ts": 1650000000000u64});
+                        loop {
+                            match node.update(v.clone()).await {
+                                Ok(_) => break,
+                                Err(e) if e.is_retriable() => continue,
+                                Err(e) => panic!("更新失败:{:?}", e),
+                            }
+                        }
+                    }
+                }));
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let manifest = node.snapshot(backup_dir.to_str().unwrap()).await.expect("备份失败");
+            for h in handles {
+                h.await.expect("join");
+            }
+
+            assert!(manifest.files.iter().any(|f| f.name.contains("_page_")));
+            let wal = manifest.files.iter().find(|f| f.name == "redo.log").expect("缺少wal");
+            assert_eq!(wal.size, manifest.wal_size);
+            // 备份之后没有执行检查点，page和索引文件与数据目录一致
+            for file in manifest.files.iter().filter(|f| !f.linked) {
+                assert_eq!(std::fs::read(backup_dir.join(&file.name)).unwrap(),
+                           std::fs::read(data_dir.join(&file.name)).unwrap(), "{}", file.name);
+            }
+
+            let restored = backup::restore(backup_dir.to_str().unwrap(), restore_dir.to_str().unwrap()).await.expect("恢复失败");
+            assert_eq!(restored.files, manifest.files);
+            assert_eq!(std::fs::metadata(restore_dir.join("redo.log")).unwrap().len(), manifest.wal_size);
+            assert!(restore_dir.join("clean_shutdown").exists());
+            // 目标目录不为空时拒绝恢复
+            assert!(backup::restore(backup_dir.to_str().unwrap(), restore_dir.to_str().unwrap()).await.is_err());
+
+            node.shutdown().await.expect("停机失败");
+            let restored_node = create_node(Config {
+                data_dir: restore_dir.to_str().unwrap().to_string(),
+                ..Config::default()
+            }, test_datasets()).await.expect("打开恢复的数据目录失败");
+            assert!(!restore_dir.join("clean_shutdown").exists());
+            // 备份之前的更新可以查询，恢复的node执行检查点并重新打开后不变
+            let query = FeatureQuery { ds: 101, feature_id: 10001, keys: serde_json::json!({"user_id": -1}), time: ts };
+            assert_eq!(restored_node.query(query.clone(), false).await.expect("查询失败").value, Some(ValueKind::Int(3)));
+            restored_node.update(serde_json::json!({"ds": 101, "user_id": -1, "merchant_id": -1, "ts": ts})).await.expect("更新失败");
+            restored_node.shutdown().await.expect("停机失败");
+            let reopened = create_node(Config {
+                data_dir: restore_dir.to_str().unwrap().to_string(),
+                ..Config::default()
+            }, test_datasets()).await.expect("打开恢复的数据目录失败");
+            assert_eq!(reopened.query(query, false).await.expect("查询失败").value, Some(ValueKind::Int(4)));
+            reopened.shutdown().await.expect("停机失败");
+
+            for dir in [data_dir, backup_dir, restore_dir] {
+                let _ = std::fs::remove_dir_all(&dir);
+            }
+        });
+    }
+}
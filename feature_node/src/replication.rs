@@ -0,0 +1,358 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Cursor;
+use std::sync::atomic::Ordering;
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::time;
+
+use feature_base::cluster::{ClusterMap, NodeInfo};
+use feature_base::custom_error::{common_err, CustomResult};
+use feature_base::rpc::{call, ReplicationStatus, Request, Response, unexpected_response_err};
+use feature_base::store::Storable;
+use feature_base::store::wal::{WalLogItem, WalLogKind, WalRedo};
+
+use crate::meta_client;
+use crate::node::Node;
+
+/// 每次拉取的最多日志条数
+const FETCH_MAX_ITEMS: usize = 1000;
+/// 没有新日志时leader最多等待的时间，毫秒
+const FETCH_WAIT_MS: u64 = 500;
+/// 拉取失败后重试的间隔
+const RETRY_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+pub fn get_replication_state_path(data_dir: &str) -> String {
+    format!("{}/replication.json", data_dir)
+}
+
+/// follower的复制进度，小于next_action_id的leader日志都已应用，重启后从这里继续拉取
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ReplicationState {
+    pub next_action_id: u64,
+}
+
+/// 记录的已丢弃事务数上限，超过时去掉tid最小的
+const DISCARDED_MAX: usize = 10000;
+
+/// follower的复制状态
+pub struct Follower {
+    pub leader: String,
+    // 下一次拉取的位置
+    fetch_from: u64,
+    // 事务开始后超过这么多条日志仍未提交时丢弃，如leader崩溃前没有提交的事务
+    horizon: u64,
+    // 还未提交的事务：tid -> (第一条日志的action id, (action id, 更新或删除日志))
+    pending: BTreeMap<u64, (u64, Vec<(u64, WalRedo)>)>,
+    // 已丢弃的事务，之后再收到它的日志时忽略，不能只应用一部分
+    discarded: BTreeSet<u64>,
+    // 已提交还未应用的更新：action id -> 更新或删除日志。
+    // leader在提交前释放page锁，同一个key的两个事务提交顺序可能与更新顺序相反，因此按action id的顺序应用
+    committed: BTreeMap<u64, WalRedo>,
+}
+
+impl Follower {
+    pub async fn load(leader: String, data_dir: &str, horizon: u64) -> CustomResult<Follower> {
+        let state = match tokio::fs::read(get_replication_state_path(data_dir)).await {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => ReplicationState::default(),
+            Err(e) => return Err(e.into()),
+        };
+        info!("从{}复制，位置:{}", leader, state.next_action_id);
+        Ok(Follower::new(leader, state.next_action_id, horizon))
+    }
+
+    fn new(leader: String, fetch_from: u64, horizon: u64) -> Follower {
+        Follower { leader, fetch_from, horizon, pending: BTreeMap::new(), discarded: BTreeSet::new(), committed: BTreeMap::new() }
+    }
+
+    /// 已应用到的位置：未提交的事务需要重新拉取，小于该位置的已提交更新都可以应用
+    fn applied(&self) -> u64 {
+        self.pending.values()
+            .map(|(first, _)| *first)
+            .min()
+            .unwrap_or(self.fetch_from)
+            .min(self.fetch_from)
+    }
+}
+
+impl Follower {
+    /// 处理一条拉取到的日志
+    fn receive(&mut self, mut item: WalLogItem) -> CustomResult<()> {
+        self.fetch_from = item.action_id + 1;
+        if self.discarded.contains(&item.tid) {
+            return Ok(());
+        }
+        match item.kind {
+            WalLogKind::Begin => {
+                self.pending.entry(item.tid).or_insert((item.action_id, vec![]));
+            }
+            WalLogKind::FeatureUpdate | WalLogKind::FeatureRemove => {
+                let redo = WalRedo::from_item(&mut item)?;
+                self.pending.entry(item.tid).or_insert((item.action_id, vec![])).1.push((item.action_id, redo));
+            }
+            WalLogKind::Commit => {
+                if let Some((_, updates)) = self.pending.remove(&item.tid) {
+                    self.committed.extend(updates);
+                }
+            }
+            WalLogKind::Abort => self.discard(item.tid),
+            // leader停机前已等待所有更新完成，未提交的事务不会再提交
+            WalLogKind::End => {
+                let tids: Vec<u64> = self.pending.keys().cloned().collect();
+                tids.into_iter().for_each(|tid| self.discard(tid));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// 丢弃开始得太早仍未提交的事务，leader崩溃时不会写入Abort或End
+    fn discard_stale(&mut self) {
+        let stale: Vec<u64> = self.pending.iter()
+            .filter(|(_, (first, _))| first + self.horizon < self.fetch_from)
+            .map(|(tid, _)| *tid)
+            .collect();
+        for tid in stale {
+            warn!("事务:{} 超过{}条日志仍未提交，丢弃", tid, self.horizon);
+            self.discard(tid);
+        }
+    }
+
+    fn discard(&mut self, tid: u64) {
+        if self.pending.remove(&tid).is_some() {
+            info!("丢弃未提交的事务:{}", tid);
+        }
+        self.discarded.insert(tid);
+        if self.discarded.len() > DISCARDED_MAX {
+            self.discarded.pop_first();
+        }
+    }
+
+    /// 取出可以应用的更新，按action id排序：更早的事务都已提交或不存在，之后不会再有更小action id的更新
+    fn take_ready(&mut self) -> (u64, Vec<WalRedo>) {
+        let applied = self.applied();
+        let rest = self.committed.split_off(&applied);
+        let ready = std::mem::replace(&mut self.committed, rest);
+        (applied, ready.into_values().collect())
+    }
+}
+
+impl Node {
+    /// follower持续拉取leader的wal并应用，直到被提升或停机
+    pub async fn replicate(&self) {
+        let mut shutdown_rx = self.shutdown_signal();
+        loop {
+            let res = tokio::select! {
+                res = self.replicate_once() => res,
+                _ = shutdown_rx.changed() => {
+                    info!("复制任务退出");
+                    return;
+                }
+            };
+            match res {
+                Ok(true) => {}
+                Ok(false) => {
+                    info!("已提升为leader，复制任务退出");
+                    return;
+                }
+                Err(e) => {
+                    warn!("从leader复制失败:{:?}", e);
+                    tokio::select! {
+                        _ = time::sleep(RETRY_INTERVAL) => {}
+                        _ = shutdown_rx.changed() => return,
+                    }
+                }
+            }
+        }
+    }
+
+    /// 拉取并应用一批日志，已不是follower时返回false
+    async fn replicate_once(&self) -> CustomResult<bool> {
+        let mut guard = self.follower.lock().await;
+        let follower = match guard.as_mut() {
+            Some(follower) => follower,
+            None => return Ok(false),
+        };
+
+        let request = Request::FetchWal {
+            from: follower.fetch_from,
+            ack: follower.applied(),
+            max_items: FETCH_MAX_ITEMS,
+            wait_ms: FETCH_WAIT_MS,
+        };
+        let bytes = match call(&follower.leader, &request).await? {
+            Response::WalItems(bytes) => bytes,
+            resp => return Err(unexpected_response_err(&resp)),
+        };
+        if bytes.is_empty() {
+            return Ok(true);
+        }
+
+        // 只应用已提交的事务
+        let mut buf = Cursor::new(&bytes[..]);
+        while (buf.position() as usize) < bytes.len() {
+            follower.receive(WalLogItem::decode(&mut buf)?)?;
+        }
+        follower.discard_stale();
+        let (applied, updates) = follower.take_ready();
+        self.apply_redo(updates).await?;
+
+        store_replication_state(&self.config.data_dir, applied).await?;
+        self.applied_action_id.store(applied, Ordering::Release);
+        Ok(true)
+    }
+
+    /// 把follower提升为leader：停止复制，用自己的地址在meta server上接替同一个node id
+    ///
+    /// 调用方需要确认原leader已经停止，否则两者会同时接收写入
+    pub async fn promote(&self) -> CustomResult<ClusterMap> {
+        {
+            let mut follower = self.follower.lock().await;
+            if let Some(f) = follower.take() {
+                info!("停止从{}复制，未提交的事务:{}", f.leader, f.pending.len());
+            }
+            self.read_only.store(false, Ordering::Release);
+        }
+
+        let node_info = NodeInfo { id: self.config.node_id.clone(), addr: self.addr.read().await.clone() };
+        let cluster = meta_client::register_node(&self.config.meta_addr, node_info).await?;
+        self.set_cluster(cluster.clone()).await;
+        info!("已提升为leader:{}", self.config.node_id);
+        Ok(cluster)
+    }
+
+    /// leader处理follower的拉取请求
+    pub async fn fetch_wal(&self, from: u64, ack: u64, max_items: usize, wait_ms: u64) -> CustomResult<Vec<u8>> {
+        let replication = self.wal.replication.as_ref()
+            .ok_or(common_err("当前node没有开启复制".to_string()))?;
+        replication.ack(ack);
+        replication.fetch(from, max_items, time::Duration::from_millis(wait_ms)).await
+    }
+
+    pub fn replication_status(&self) -> ReplicationStatus {
+        if self.read_only.load(Ordering::Acquire) {
+            ReplicationStatus {
+                leader: Some(self.config.replicate_from.clone()),
+                applied_action_id: self.applied_action_id.load(Ordering::Acquire),
+            }
+        } else {
+            ReplicationStatus {
+                leader: None,
+                applied_action_id: self.wal.replication.as_ref().map_or(0, |r| r.acked()),
+            }
+        }
+    }
+}
+
+async fn store_replication_state(data_dir: &str, next_action_id: u64) -> CustomResult<()> {
+    let path = get_replication_state_path(data_dir);
+    let tmp_path = format!("{}.tmp", path);
+    tokio::fs::write(&tmp_path, serde_json::to_vec(&ReplicationState { next_action_id })?).await?;
+    tokio::fs::rename(&tmp_path, &path).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use feature_base::feature::key::{FeatureKey, KeyPart};
+    use feature_base::feature::value::ValueKind;
+    use feature_base::store::wal::{WalFeatureRemoveValue, WalFeatureUpdateValue, WalLogItem, WalLogKind, WalRedo};
+
+    use crate::replication::Follower;
+
+    /// 更新取redo值，删除为None
+    fn values(ready: Vec<WalRedo>) -> Vec<Option<ValueKind>> {
+        ready.into_iter().map(|redo| match redo {
+            WalRedo::Update(u) => Some(u.redo_v),
+            WalRedo::Remove(_) => None,
+        }).collect()
+    }
+
+    #[test]
+    pub fn follower_order_test() {
+        let mut follower = Follower::new(String::new(), 0, 100);
+        let fk = FeatureKey::new(1, &[KeyPart::Int(1)]).unwrap();
+        let item = |tid: u64, action_id: u64, kind: WalLogKind, v: Option<u64>| WalLogItem {
+            tid,
+            kind,
+            action_id,
+            value: v.map(|v| Box::new(WalFeatureUpdateValue { fk: fk.clone(), tk: 0, undo_v: None, redo_v: ValueKind::Int(v) }) as _),
+            callback: None,
+        };
+        // 两个事务先后更新同一个key，后更新的先提交
+        follower.receive(item(1, 0, WalLogKind::Begin, None)).unwrap();
+        follower.receive(item(1, 1, WalLogKind::FeatureUpdate, Some(1))).unwrap();
+        follower.receive(item(2, 2, WalLogKind::Begin, None)).unwrap();
+        follower.receive(item(2, 3, WalLogKind::FeatureUpdate, Some(2))).unwrap();
+        follower.receive(item(2, 4, WalLogKind::Commit, None)).unwrap();
+        let (applied, ready) = follower.take_ready();
+        assert_eq!(applied, 0);
+        assert!(ready.is_empty());
+
+        follower.receive(item(1, 5, WalLogKind::Commit, None)).unwrap();
+        let (applied, ready) = follower.take_ready();
+        assert_eq!((applied, values(ready)), (6, vec![Some(ValueKind::Int(1)), Some(ValueKind::Int(2))]));
+
+        // 删除日志和更新一样在提交后按顺序应用
+        follower.receive(item(3, 6, WalLogKind::Begin, None)).unwrap();
+        follower.receive(WalLogItem {
+            tid: 3,
+            kind: WalLogKind::FeatureRemove,
+            action_id: 7,
+            value: Some(Box::new(WalFeatureRemoveValue { fk: fk.clone(), tk: 0, undo_v: ValueKind::Int(2) })),
+            callback: None,
+        }).unwrap();
+        assert!(follower.take_ready().1.is_empty());
+        follower.receive(item(3, 8, WalLogKind::Commit, None)).unwrap();
+        let (applied, ready) = follower.take_ready();
+        assert_eq!((applied, values(ready)), (9, vec![None]));
+    }
+
+    #[test]
+    pub fn follower_discard_test() {
+        let fk = FeatureKey::new(1, &[KeyPart::Int(1)]).unwrap();
+        let item = |tid: u64, action_id: u64, kind: WalLogKind, v: Option<u64>| WalLogItem {
+            tid,
+            kind,
+            action_id,
+            value: v.map(|v| Box::new(WalFeatureUpdateValue { fk: fk.clone(), tk: 0, undo_v: None, redo_v: ValueKind::Int(v) }) as _),
+            callback: None,
+        };
+
+        // 只有Begin没有Commit的事务超过范围后丢弃，之后提交的事务可以应用
+        let mut follower = Follower::new(String::new(), 0, 10);
+        follower.receive(item(1, 0, WalLogKind::Begin, None)).unwrap();
+        follower.receive(item(2, 1, WalLogKind::Begin, None)).unwrap();
+        follower.receive(item(2, 2, WalLogKind::FeatureUpdate, Some(2))).unwrap();
+        follower.receive(item(2, 3, WalLogKind::Commit, None)).unwrap();
+        follower.discard_stale();
+        let (applied, ready) = follower.take_ready();
+        assert_eq!((applied, ready.len()), (0, 0));
+        for action_id in 4..12 {
+            follower.receive(item(3, action_id, WalLogKind::PageBkStore, None)).unwrap();
+        }
+        follower.discard_stale();
+        let (applied, ready) = follower.take_ready();
+        assert_eq!((applied, values(ready)), (12, vec![Some(ValueKind::Int(2))]));
+        // 丢弃后收到的日志忽略
+        follower.receive(item(1, 12, WalLogKind::FeatureUpdate, Some(1))).unwrap();
+        follower.receive(item(1, 13, WalLogKind::Commit, None)).unwrap();
+        assert!(follower.take_ready().1.is_empty());
+
+        // Abort和leader停机的End
+        let mut follower = Follower::new(String::new(), 0, 1000);
+        follower.receive(item(1, 0, WalLogKind::Begin, None)).unwrap();
+        follower.receive(item(1, 1, WalLogKind::Abort, None)).unwrap();
+        follower.receive(item(2, 2, WalLogKind::Begin, None)).unwrap();
+        follower.receive(item(2, 3, WalLogKind::FeatureUpdate, Some(2))).unwrap();
+        follower.receive(item(3, 4, WalLogKind::End, None)).unwrap();
+        follower.receive(item(4, 5, WalLogKind::Begin, None)).unwrap();
+        follower.receive(item(4, 6, WalLogKind::FeatureUpdate, Some(4))).unwrap();
+        follower.receive(item(4, 7, WalLogKind::Commit, None)).unwrap();
+        let (applied, ready) = follower.take_ready();
+        assert_eq!((applied, values(ready)), (8, vec![Some(ValueKind::Int(4))]));
+    }
+}
@@ -0,0 +1,29 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use log::info;
+use tokio::net::TcpListener;
+
+use feature_base::custom_error::CustomResult;
+use feature_base::rpc::spawn_server;
+
+use crate::node::Node;
+
+/// 启动node的rpc服务，返回实际监听的地址；node停机时停止服务
+pub async fn start(node: Arc<Node>, addr: &str) -> CustomResult<SocketAddr> {
+    let listener = TcpListener::bind(addr).await?;
+    let local_addr = listener.local_addr()?;
+    info!("node服务启动:{}", local_addr);
+
+    let mut shutdown_rx = node.shutdown_signal();
+    let handle = spawn_server(listener, move |request| {
+        let node = node.clone();
+        async move { node.handle(request).await }
+    });
+    tokio::spawn(async move {
+        let _ = shutdown_rx.changed().await;
+        handle.abort();
+        info!("node服务停止:{}", local_addr);
+    });
+    Ok(local_addr)
+}
@@ -0,0 +1,170 @@
+use std::collections::BTreeMap;
+use std::io::SeekFrom;
+
+use log::{info, warn};
+use tokio::fs;
+use tokio::io::{AsyncBufReadExt, AsyncSeekExt, AsyncWriteExt, BufReader};
+use tokio::time;
+
+use feature_base::config::FileSourceConfig;
+use feature_base::custom_error::{common_err, CustomResult};
+
+use crate::source::{Source, SourceRecord};
+
+/// 跟踪追加写入的JSONL文件，位置为已处理的字节数，提交时写入位置文件。
+/// 文件变短时认为被截断或轮转，从头开始读；末尾没有换行的行等写完后再读取
+pub struct FileSource {
+    config: FileSourceConfig,
+    offset_path: String,
+    // 已读取到的位置，None表示还没有加载已提交的位置
+    position: Option<u64>,
+}
+
+impl FileSource {
+    pub fn new(config: FileSourceConfig) -> FileSource {
+        let offset_path = if config.offset_path.is_empty() {
+            format!("{}.offset", config.path)
+        } else {
+            config.offset_path.clone()
+        };
+        FileSource { config, offset_path, position: None }
+    }
+
+    async fn load_position(&self) -> CustomResult<u64> {
+        match fs::read_to_string(&self.offset_path).await {
+            Ok(text) => text.trim().parse()
+                .map_err(|_| common_err(format!("位置文件:{} 内容非法:{}", self.offset_path, text))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn read_lines(&self, position: u64) -> CustomResult<(Vec<SourceRecord>, u64)> {
+        let mut f = fs::File::open(&self.config.path).await?;
+        f.seek(SeekFrom::Start(position)).await?;
+        let mut reader = BufReader::new(f);
+        let mut records = vec![];
+        let mut next = position;
+        let mut line = vec![];
+        for _ in 0..self.config.max_lines {
+            line.clear();
+            let n = reader.read_until(b'\n', &mut line).await?;
+            if n == 0 || line.last() != Some(&b'\n') {
+                break;
+            }
+            next += n as u64;
+            let payload = line.trim_ascii();
+            if !payload.is_empty() {
+                records.push(SourceRecord { partition: 0, next_offset: next, payload: payload.to_vec() });
+            }
+        }
+        Ok((records, next))
+    }
+}
+
+impl Source for FileSource {
+    fn name(&self) -> String {
+        format!("file:{}", self.config.path)
+    }
+
+    async fn poll(&mut self) -> CustomResult<Vec<SourceRecord>> {
+        let mut position = match self.position {
+            Some(position) => position,
+            None => {
+                let position = self.load_position().await?;
+                info!("文件:{} 从位置:{} 开始读取", self.config.path, position);
+                position
+            }
+        };
+        let len = match fs::metadata(&self.config.path).await {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        if len < position {
+            warn!("文件:{} 长度:{} 小于已读取的位置:{}，从头开始读取", self.config.path, len, position);
+            position = 0;
+        }
+        let (records, next) = if len > position {
+            self.read_lines(position).await?
+        } else {
+            (vec![], position)
+        };
+        self.position = Some(next);
+        if records.is_empty() {
+            time::sleep(time::Duration::from_millis(self.config.poll_interval_ms)).await;
+        }
+        Ok(records)
+    }
+
+    async fn commit(&mut self, offsets: &BTreeMap<i32, u64>) -> CustomResult<()> {
+        let offset = match offsets.get(&0) {
+            Some(offset) => *offset,
+            None => return Ok(()),
+        };
+        let tmp_path = format!("{}.tmp", self.offset_path);
+        let mut f = fs::File::create(&tmp_path).await?;
+        f.write_all(offset.to_string().as_bytes()).await?;
+        f.sync_data().await?;
+        fs::rename(&tmp_path, &self.offset_path).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::io::Write;
+
+    use feature_base::config::FileSourceConfig;
+
+    use crate::source::file::FileSource;
+    use crate::source::Source;
+
+    #[test]
+    pub fn test_file_source() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let dir = std::env::temp_dir().join(format!("feature_db_file_source_test_{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("events.jsonl");
+            std::fs::write(&path, "{\"a\":1}\n\n{\"a\":2}\n{\"a\":").unwrap();
+            let config = FileSourceConfig {
+                path: path.to_str().unwrap().to_string(),
+                poll_interval_ms: 10,
+                max_lines: 2,
+                ..FileSourceConfig::default()
+            };
+
+            let mut source = FileSource::new(config.clone());
+            let records = source.poll().await.unwrap();
+            // 空行计入行数但不产生事件
+            assert_eq!(records.iter().map(|r| (r.payload.as_slice(), r.next_offset)).collect::<Vec<_>>(), vec![(&b"{\"a\":1}"[..], 8)]);
+            let records = source.poll().await.unwrap();
+            assert_eq!(records.iter().map(|r| (r.payload.as_slice(), r.next_offset)).collect::<Vec<_>>(), vec![(&b"{\"a\":2}"[..], 17)]);
+            // 最后一行还没写完
+            assert!(source.poll().await.unwrap().is_empty());
+            source.commit(&BTreeMap::from([(0, 8)])).await.unwrap();
+            assert_eq!(std::fs::read_to_string(dir.join("events.jsonl.offset")).unwrap(), "8");
+
+            // 重启后从已提交的位置开始，未提交的事件重新读取
+            let mut source = FileSource::new(config.clone());
+            std::fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(b"3}\n").unwrap();
+            let records = source.poll().await.unwrap();
+            assert_eq!(records.iter().map(|r| r.payload.as_slice()).collect::<Vec<_>>(), vec![&b"{\"a\":2}"[..]]);
+            let records = source.poll().await.unwrap();
+            assert_eq!(records.iter().map(|r| (r.payload.as_slice(), r.next_offset)).collect::<Vec<_>>(), vec![(&b"{\"a\":3}"[..], 25)]);
+
+            // 文件被截断后从头读取
+            std::fs::write(&path, "{\"b\":1}\n").unwrap();
+            let records = source.poll().await.unwrap();
+            assert_eq!(records.iter().map(|r| (r.payload.as_slice(), r.next_offset)).collect::<Vec<_>>(), vec![(&b"{\"b\":1}"[..], 8)]);
+
+            let _ = std::fs::remove_dir_all(&dir);
+        });
+    }
+}
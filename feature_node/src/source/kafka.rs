@@ -0,0 +1,731 @@
+use std::collections::BTreeMap;
+
+use bytes::{Buf, BufMut};
+use log::{info, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time;
+
+use feature_base::config::KafkaSourceConfig;
+use feature_base::custom_error::{common_err, CustomResult};
+
+use crate::source::{Source, SourceRecord};
+
+// 只用到以下请求，版本选择各broker版本都支持的最低版本
+const API_FETCH: i16 = 1;
+const API_LIST_OFFSETS: i16 = 2;
+const API_OFFSET_COMMIT: i16 = 8;
+const API_OFFSET_FETCH: i16 = 9;
+const FETCH_VERSION: i16 = 4;
+const LIST_OFFSETS_VERSION: i16 = 1;
+const OFFSET_COMMIT_VERSION: i16 = 2;
+const OFFSET_FETCH_VERSION: i16 = 1;
+
+const ERROR_NONE: i16 = 0;
+const ERROR_OFFSET_OUT_OF_RANGE: i16 = 1;
+
+/// ListOffsets中表示最早、最新位置的时间戳
+const EARLIEST_TIMESTAMP: i64 = -2;
+const LATEST_TIMESTAMP: i64 = -1;
+
+/// 响应的最大长度，避免错误的长度字段导致分配过多内存
+const MAX_RESPONSE_SIZE: usize = 256 << 20;
+/// 请求超时时间，不包括Fetch在broker端等待的时间
+const REQUEST_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+
+/// Kafka协议的数据源，只实现了消费需要的请求：
+/// 分区由配置指定，不加入消费组的成员管理，只用消费组保存位置；不解析集群元数据，broker需要是所有分区的leader和消费组的coordinator；
+/// 读取未提交的事务消息，不支持压缩的消息
+pub struct KafkaSource {
+    config: KafkaSourceConfig,
+    conn: Option<Connection>,
+    // 每个分区下一次拉取的位置，None表示需要从消费组加载
+    positions: Option<BTreeMap<i32, i64>>,
+}
+
+impl KafkaSource {
+    pub fn new(config: KafkaSourceConfig) -> KafkaSource {
+        KafkaSource { config, conn: None, positions: None }
+    }
+
+    async fn call(&mut self, api_key: i16, api_version: i16, body: &[u8]) -> CustomResult<Vec<u8>> {
+        let timeout = REQUEST_TIMEOUT + time::Duration::from_millis(self.config.max_wait_ms);
+        let res = time::timeout(timeout, async {
+            if self.conn.is_none() {
+                let stream = TcpStream::connect(&self.config.broker).await?;
+                stream.set_nodelay(true)?;
+                self.conn = Some(Connection { stream, correlation_id: 0 });
+            }
+            match self.conn.as_mut() {
+                Some(conn) => conn.request(&self.config.client_id, api_key, api_version, body).await,
+                None => Err(common_err("连接kafka失败".to_string())),
+            }
+        }).await.unwrap_or_else(|_| Err(common_err(format!("kafka请求超时:{}", api_key))));
+        // 出错后连接的状态未知，下次重新连接
+        if res.is_err() {
+            self.conn = None;
+        }
+        res
+    }
+
+    fn start_timestamp(&self) -> i64 {
+        if self.config.start_from == "latest" { LATEST_TIMESTAMP } else { EARLIEST_TIMESTAMP }
+    }
+
+    /// 读取消费组已提交的位置，没有提交过的分区按start_from从最早或最新的位置开始
+    async fn load_positions(&mut self) -> CustomResult<BTreeMap<i32, i64>> {
+        let mut body = vec![];
+        put_string(&mut body, &self.config.group);
+        body.put_i32(1);
+        put_string(&mut body, &self.config.topic);
+        body.put_i32(self.config.partitions.len() as i32);
+        for partition in &self.config.partitions {
+            body.put_i32(*partition);
+        }
+        let resp = self.call(API_OFFSET_FETCH, OFFSET_FETCH_VERSION, &body).await?;
+        let mut d = Decoder::new(&resp);
+        let mut positions = BTreeMap::new();
+        for _ in 0..d.array_len()? {
+            d.string()?;
+            for _ in 0..d.array_len()? {
+                let partition = d.i32()?;
+                let offset = d.i64()?;
+                d.string()?;
+                check_error("OffsetFetch", partition, d.i16()?)?;
+                if offset >= 0 {
+                    positions.insert(partition, offset);
+                }
+            }
+        }
+
+        let missing: Vec<i32> = self.config.partitions.iter().filter(|p| !positions.contains_key(p)).cloned().collect();
+        if !missing.is_empty() {
+            positions.extend(self.list_offsets(&missing, self.start_timestamp()).await?);
+        }
+        info!("kafka topic:{} 消费组:{} 开始位置:{:?}", self.config.topic, self.config.group, positions);
+        Ok(positions)
+    }
+
+    async fn list_offsets(&mut self, partitions: &[i32], timestamp: i64) -> CustomResult<BTreeMap<i32, i64>> {
+        let mut body = vec![];
+        body.put_i32(-1);
+        body.put_i32(1);
+        put_string(&mut body, &self.config.topic);
+        body.put_i32(partitions.len() as i32);
+        for partition in partitions {
+            body.put_i32(*partition);
+            body.put_i64(timestamp);
+        }
+        let resp = self.call(API_LIST_OFFSETS, LIST_OFFSETS_VERSION, &body).await?;
+        let mut d = Decoder::new(&resp);
+        let mut offsets = BTreeMap::new();
+        for _ in 0..d.array_len()? {
+            d.string()?;
+            for _ in 0..d.array_len()? {
+                let partition = d.i32()?;
+                check_error("ListOffsets", partition, d.i16()?)?;
+                d.i64()?;
+                offsets.insert(partition, d.i64()?);
+            }
+        }
+        Ok(offsets)
+    }
+}
+
+impl Source for KafkaSource {
+    fn name(&self) -> String {
+        format!("kafka:{}", self.config.topic)
+    }
+
+    async fn poll(&mut self) -> CustomResult<Vec<SourceRecord>> {
+        let mut positions = match self.positions.take() {
+            Some(positions) => positions,
+            None => self.load_positions().await?,
+        };
+        let mut body = vec![];
+        body.put_i32(-1);
+        body.put_i32(self.config.max_wait_ms as i32);
+        body.put_i32(1);
+        body.put_i32(self.config.max_bytes as i32);
+        // read_uncommitted
+        body.put_i8(0);
+        body.put_i32(1);
+        put_string(&mut body, &self.config.topic);
+        body.put_i32(positions.len() as i32);
+        for (partition, offset) in &positions {
+            body.put_i32(*partition);
+            body.put_i64(*offset);
+            body.put_i32(self.config.max_bytes as i32);
+        }
+        let resp = match self.call(API_FETCH, FETCH_VERSION, &body).await {
+            Ok(resp) => resp,
+            Err(e) => {
+                self.positions = Some(positions);
+                return Err(e);
+            }
+        };
+
+        let mut d = Decoder::new(&resp);
+        let mut records = vec![];
+        let mut out_of_range = vec![];
+        d.i32()?;
+        for _ in 0..d.array_len()? {
+            d.string()?;
+            for _ in 0..d.array_len()? {
+                let partition = d.i32()?;
+                let error = d.i16()?;
+                d.i64()?;
+                d.i64()?;
+                for _ in 0..d.array_len()? {
+                    d.i64()?;
+                    d.i64()?;
+                }
+                let record_set = d.nullable_bytes()?.unwrap_or_default();
+                if error == ERROR_OFFSET_OUT_OF_RANGE {
+                    out_of_range.push(partition);
+                    continue;
+                }
+                check_error("Fetch", partition, error)?;
+                if let Some(offset) = positions.get_mut(&partition) {
+                    *offset = decode_record_batches(record_set, partition, *offset, &mut records)?;
+                }
+            }
+        }
+        if !out_of_range.is_empty() {
+            warn!("kafka topic:{} 分区:{:?} 的位置已失效，按{}重新开始", self.config.topic, out_of_range, self.config.start_from);
+            positions.extend(self.list_offsets(&out_of_range, self.start_timestamp()).await?);
+        }
+        self.positions = Some(positions);
+        Ok(records)
+    }
+
+    async fn commit(&mut self, offsets: &BTreeMap<i32, u64>) -> CustomResult<()> {
+        let mut body = vec![];
+        put_string(&mut body, &self.config.group);
+        // 不是消费组成员，generation为-1，member为空
+        body.put_i32(-1);
+        put_string(&mut body, "");
+        body.put_i64(-1);
+        body.put_i32(1);
+        put_string(&mut body, &self.config.topic);
+        body.put_i32(offsets.len() as i32);
+        for (partition, offset) in offsets {
+            body.put_i32(*partition);
+            body.put_i64(*offset as i64);
+            body.put_i16(-1);
+        }
+        let resp = self.call(API_OFFSET_COMMIT, OFFSET_COMMIT_VERSION, &body).await?;
+        let mut d = Decoder::new(&resp);
+        for _ in 0..d.array_len()? {
+            d.string()?;
+            for _ in 0..d.array_len()? {
+                let partition = d.i32()?;
+                check_error("OffsetCommit", partition, d.i16()?)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn check_error(api: &str, partition: i32, error: i16) -> CustomResult<()> {
+    if error == ERROR_NONE {
+        Ok(())
+    } else {
+        Err(common_err(format!("kafka {} 分区:{} 返回错误码:{}", api, partition, error)))
+    }
+}
+
+struct Connection {
+    stream: TcpStream,
+    correlation_id: i32,
+}
+
+impl Connection {
+    /// 发送请求（header v1）并读取响应（header v0），返回响应体
+    async fn request(&mut self, client_id: &str, api_key: i16, api_version: i16, body: &[u8]) -> CustomResult<Vec<u8>> {
+        self.correlation_id = self.correlation_id.wrapping_add(1);
+        let mut buf = Vec::with_capacity(body.len() + 64);
+        buf.put_i32(0);
+        buf.put_i16(api_key);
+        buf.put_i16(api_version);
+        buf.put_i32(self.correlation_id);
+        put_string(&mut buf, client_id);
+        buf.put_slice(body);
+        let len = (buf.len() - 4) as i32;
+        buf[..4].copy_from_slice(&len.to_be_bytes());
+        self.stream.write_all(&buf).await?;
+
+        let len = self.stream.read_i32().await?;
+        if len < 4 || len as usize > MAX_RESPONSE_SIZE {
+            return Err(common_err(format!("kafka响应长度非法:{}", len)));
+        }
+        let mut resp = vec![0; len as usize];
+        self.stream.read_exact(&mut resp).await?;
+        let correlation_id = (&resp[..4]).get_i32();
+        if correlation_id != self.correlation_id {
+            return Err(common_err(format!("kafka响应不匹配，请求:{} 响应:{}", self.correlation_id, correlation_id)));
+        }
+        resp.drain(..4);
+        Ok(resp)
+    }
+}
+
+/// 解析v2格式的record batch，跳过fetch_offset之前的消息和事务控制消息，返回下一次拉取的位置。
+/// 末尾不完整的batch是因为超过了max_bytes，下次从该batch重新拉取
+fn decode_record_batches(mut buf: &[u8], partition: i32, fetch_offset: i64, records: &mut Vec<SourceRecord>) -> CustomResult<i64> {
+    let mut next = fetch_offset;
+    while buf.len() >= 12 {
+        let base_offset = (&buf[..8]).get_i64();
+        let batch_len = (&buf[8..12]).get_i32();
+        if batch_len < 0 {
+            return Err(common_err(format!("kafka分区:{} 的batch长度非法:{}", partition, batch_len)));
+        }
+        if buf.len() - 12 < batch_len as usize {
+            break;
+        }
+        let (batch, rest) = buf[12..].split_at(batch_len as usize);
+        buf = rest;
+
+        let mut d = Decoder::new(batch);
+        d.i32()?;
+        let magic = d.i8()?;
+        if magic != 2 {
+            return Err(common_err(format!("kafka分区:{} 位置:{} 的消息格式:{} 不支持", partition, base_offset, magic)));
+        }
+        let crc = d.u32()?;
+        if crc32c(d.buf) != crc {
+            return Err(common_err(format!("kafka分区:{} 位置:{} 的消息校验失败", partition, base_offset)));
+        }
+        let attributes = d.i16()?;
+        let last_offset_delta = d.i32()?;
+        // base_timestamp, max_timestamp, producer_id, producer_epoch, base_sequence
+        d.bytes(8 + 8 + 8 + 2 + 4)?;
+        let count = d.i32()?;
+        next = next.max(base_offset + last_offset_delta as i64 + 1);
+        if attributes & 0x07 != 0 {
+            return Err(common_err(format!("kafka分区:{} 位置:{} 的消息是压缩的，不支持", partition, base_offset)));
+        }
+        if attributes & 0x20 != 0 {
+            continue;
+        }
+        for _ in 0..count {
+            let len = d.len_varint()?;
+            let mut r = Decoder::new(d.bytes(len)?);
+            r.i8()?;
+            r.varint()?;
+            let offset = base_offset + r.varint()?;
+            let key_len = r.varint()?;
+            if key_len > 0 {
+                r.bytes(key_len as usize)?;
+            }
+            let value_len = r.varint()?;
+            if offset < fetch_offset || value_len < 0 {
+                continue;
+            }
+            let value = r.bytes(value_len as usize)?;
+            records.push(SourceRecord { partition, next_offset: (offset + 1) as u64, payload: value.to_vec() });
+        }
+    }
+    Ok(next)
+}
+
+fn put_string(buf: &mut Vec<u8>, s: &str) {
+    buf.put_i16(s.len() as i16);
+    buf.put_slice(s.as_bytes());
+}
+
+/// 按Kafka协议读取响应，数据不足时返回错误
+struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn new(buf: &'a [u8]) -> Decoder<'a> {
+        Decoder { buf }
+    }
+
+    fn need(&self, n: usize) -> CustomResult<()> {
+        if self.buf.len() < n {
+            return Err(common_err(format!("kafka响应数据不完整，需要:{} 剩余:{}", n, self.buf.len())));
+        }
+        Ok(())
+    }
+
+    fn i8(&mut self) -> CustomResult<i8> {
+        self.need(1)?;
+        Ok(self.buf.get_i8())
+    }
+
+    fn i16(&mut self) -> CustomResult<i16> {
+        self.need(2)?;
+        Ok(self.buf.get_i16())
+    }
+
+    fn i32(&mut self) -> CustomResult<i32> {
+        self.need(4)?;
+        Ok(self.buf.get_i32())
+    }
+
+    fn u32(&mut self) -> CustomResult<u32> {
+        self.need(4)?;
+        Ok(self.buf.get_u32())
+    }
+
+    fn i64(&mut self) -> CustomResult<i64> {
+        self.need(8)?;
+        Ok(self.buf.get_i64())
+    }
+
+    fn bytes(&mut self, n: usize) -> CustomResult<&'a [u8]> {
+        self.need(n)?;
+        let (bytes, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(bytes)
+    }
+
+    /// 长度为-1表示null，按空字符串处理
+    fn string(&mut self) -> CustomResult<String> {
+        let len = self.i16()?;
+        if len < 0 {
+            return Ok(String::new());
+        }
+        Ok(String::from_utf8_lossy(self.bytes(len as usize)?).to_string())
+    }
+
+    fn nullable_bytes(&mut self) -> CustomResult<Option<&'a [u8]>> {
+        let len = self.i32()?;
+        if len < 0 {
+            return Ok(None);
+        }
+        Ok(Some(self.bytes(len as usize)?))
+    }
+
+    /// 数组长度，-1表示null
+    fn array_len(&mut self) -> CustomResult<usize> {
+        Ok(self.i32()?.max(0) as usize)
+    }
+
+    /// zigzag编码的变长整数
+    fn varint(&mut self) -> CustomResult<i64> {
+        let mut value = 0u64;
+        for i in 0..10 {
+            let b = self.i8()? as u8;
+            value |= ((b & 0x7f) as u64) << (i * 7);
+            if b & 0x80 == 0 {
+                return Ok((value >> 1) as i64 ^ -((value & 1) as i64));
+            }
+        }
+        Err(common_err("kafka变长整数过长".to_string()))
+    }
+
+    fn len_varint(&mut self) -> CustomResult<usize> {
+        let len = self.varint()?;
+        if len < 0 {
+            return Err(common_err(format!("kafka消息长度非法:{}", len)));
+        }
+        Ok(len as usize)
+    }
+}
+
+const CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0x82F6_3B78 } else { crc >> 1 };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// record batch使用的CRC-32C（Castagnoli）
+fn crc32c(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in bytes {
+        crc = CRC32C_TABLE[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use bytes::BufMut;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use feature_base::config::{Config, KafkaSourceConfig};
+    use feature_base::ds::DataSet;
+    use feature_base::feature::FeatureQuery;
+    use feature_base::feature::value::ValueKind;
+
+    use crate::node::{create_node, Node};
+    use crate::source::kafka::{API_FETCH, API_LIST_OFFSETS, API_OFFSET_COMMIT, API_OFFSET_FETCH, crc32c, Decoder, KafkaSource, put_string};
+    use crate::source::run;
+
+    /// 内存中的broker，实现KafkaSource用到的请求，每次Fetch最多返回两条消息
+    #[derive(Default)]
+    struct MockBroker {
+        partitions: HashMap<i32, Vec<Vec<u8>>>,
+        committed: HashMap<i32, i64>,
+    }
+
+    fn put_varint(buf: &mut Vec<u8>, v: i64) {
+        let mut v = ((v << 1) ^ (v >> 63)) as u64;
+        while v >= 0x80 {
+            buf.put_u8((v as u8) | 0x80);
+            v >>= 7;
+        }
+        buf.put_u8(v as u8);
+    }
+
+    fn encode_batch(base_offset: i64, values: &[Vec<u8>]) -> Vec<u8> {
+        let mut body = vec![];
+        body.put_i16(0);
+        body.put_i32(values.len() as i32 - 1);
+        body.put_i64(0);
+        body.put_i64(0);
+        body.put_i64(-1);
+        body.put_i16(-1);
+        body.put_i32(-1);
+        body.put_i32(values.len() as i32);
+        for (i, value) in values.iter().enumerate() {
+            let mut record = vec![0u8];
+            put_varint(&mut record, 0);
+            put_varint(&mut record, i as i64);
+            put_varint(&mut record, -1);
+            put_varint(&mut record, value.len() as i64);
+            record.put_slice(value);
+            put_varint(&mut record, 0);
+            put_varint(&mut body, record.len() as i64);
+            body.put_slice(&record);
+        }
+        let mut batch = vec![];
+        batch.put_i64(base_offset);
+        batch.put_i32((4 + 1 + 4 + body.len()) as i32);
+        batch.put_i32(0);
+        batch.put_i8(2);
+        batch.put_u32(crc32c(&body));
+        batch.put_slice(&body);
+        batch
+    }
+
+    impl MockBroker {
+        fn handle(&mut self, api_key: i16, d: &mut Decoder) -> Vec<u8> {
+            let mut resp = vec![];
+            match api_key {
+                API_OFFSET_FETCH => {
+                    d.string().unwrap();
+                    d.array_len().unwrap();
+                    let topic = d.string().unwrap();
+                    resp.put_i32(1);
+                    put_string(&mut resp, &topic);
+                    let n = d.array_len().unwrap();
+                    resp.put_i32(n as i32);
+                    for _ in 0..n {
+                        let partition = d.i32().unwrap();
+                        resp.put_i32(partition);
+                        resp.put_i64(*self.committed.get(&partition).unwrap_or(&-1));
+                        resp.put_i16(-1);
+                        resp.put_i16(0);
+                    }
+                }
+                API_LIST_OFFSETS => {
+                    d.i32().unwrap();
+                    d.array_len().unwrap();
+                    let topic = d.string().unwrap();
+                    resp.put_i32(1);
+                    put_string(&mut resp, &topic);
+                    let n = d.array_len().unwrap();
+                    resp.put_i32(n as i32);
+                    for _ in 0..n {
+                        let partition = d.i32().unwrap();
+                        let timestamp = d.i64().unwrap();
+                        resp.put_i32(partition);
+                        resp.put_i16(0);
+                        resp.put_i64(-1);
+                        let len = self.partitions.get(&partition).map_or(0, |p| p.len() as i64);
+                        resp.put_i64(if timestamp == -2 { 0 } else { len });
+                    }
+                }
+                API_FETCH => {
+                    d.bytes(4 + 4 + 4 + 4 + 1).unwrap();
+                    d.array_len().unwrap();
+                    let topic = d.string().unwrap();
+                    resp.put_i32(0);
+                    resp.put_i32(1);
+                    put_string(&mut resp, &topic);
+                    let n = d.array_len().unwrap();
+                    resp.put_i32(n as i32);
+                    for _ in 0..n {
+                        let partition = d.i32().unwrap();
+                        let offset = d.i64().unwrap();
+                        d.i32().unwrap();
+                        let messages = self.partitions.get(&partition).cloned().unwrap_or_default();
+                        resp.put_i32(partition);
+                        resp.put_i16(if offset as usize > messages.len() { 1 } else { 0 });
+                        resp.put_i64(messages.len() as i64);
+                        resp.put_i64(messages.len() as i64);
+                        resp.put_i32(-1);
+                        // 从偶数位置开始按两条一个batch返回，可能包含拉取位置之前的消息
+                        let start = (offset.max(0) as usize / 2 * 2).min(messages.len());
+                        let end = (start + 2).min(messages.len());
+                        let batch = if start < end { encode_batch(start as i64, &messages[start..end]) } else { vec![] };
+                        resp.put_i32(batch.len() as i32);
+                        resp.put_slice(&batch);
+                    }
+                }
+                API_OFFSET_COMMIT => {
+                    d.string().unwrap();
+                    d.i32().unwrap();
+                    d.string().unwrap();
+                    d.i64().unwrap();
+                    d.array_len().unwrap();
+                    let topic = d.string().unwrap();
+                    resp.put_i32(1);
+                    put_string(&mut resp, &topic);
+                    let n = d.array_len().unwrap();
+                    resp.put_i32(n as i32);
+                    for _ in 0..n {
+                        let partition = d.i32().unwrap();
+                        let offset = d.i64().unwrap();
+                        d.i16().unwrap();
+                        self.committed.insert(partition, offset);
+                        resp.put_i32(partition);
+                        resp.put_i16(0);
+                    }
+                }
+                _ => panic!("不支持的请求:{}", api_key),
+            }
+            resp
+        }
+    }
+
+    async fn serve(broker: Arc<Mutex<MockBroker>>, mut stream: TcpStream) {
+        while let Ok(len) = stream.read_i32().await {
+            let mut req = vec![0; len as usize];
+            stream.read_exact(&mut req).await.unwrap();
+            let mut d = Decoder::new(&req);
+            let api_key = d.i16().unwrap();
+            d.i16().unwrap();
+            let correlation_id = d.i32().unwrap();
+            d.string().unwrap();
+            let body = broker.lock().unwrap().handle(api_key, &mut d);
+            if api_key == API_FETCH && body.len() < 64 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            let mut resp = vec![];
+            resp.put_i32(body.len() as i32 + 4);
+            resp.put_i32(correlation_id);
+            resp.put_slice(&body);
+            stream.write_all(&resp).await.unwrap();
+        }
+    }
+
+    fn test_datasets() -> HashMap<i64, DataSet> {
+        let ds: DataSet = serde_json::from_str(r#"{
+            "id":101,
+            "name":"ds_user_order",
+            "desc":"",
+            "column_type_map":{"user_id":"INT","ts":"DATETIME"},
+            "features":[{"id":10001,"name":"用户订单数","template":{"COUNT":{
+                "group_keys":["user_id"],"time_key":"ts","window_unit":"DAY","window_size":30}}}]
+        }"#).unwrap();
+        HashMap::from([(ds.id, ds)])
+    }
+
+    fn event(user_id: i64) -> Vec<u8> {
+        format!("{{\"ds\":101,\"user_id\":{},\"ts\":1650000000000}}", user_id).into_bytes()
+    }
+
+    async fn count(node: &Node, user_id: i64) -> Option<ValueKind> {
+        let query = FeatureQuery { ds: 101, feature_id: 10001, keys: serde_json::json!({"user_id": user_id}), time: 1650000000000 };
+        node.query(query, true).await.unwrap().value
+    }
+
+    async fn wait_committed(broker: &Arc<Mutex<MockBroker>>, expected: &[(i32, i64)]) {
+        for _ in 0..500 {
+            let committed: BTreeMap<i32, i64> = broker.lock().unwrap().committed.iter().map(|(k, v)| (*k, *v)).collect();
+            if committed == expected.iter().cloned().collect() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("等待提交位置超时:{:?}", broker.lock().unwrap().committed);
+    }
+
+    #[test]
+    pub fn test_crc32c() {
+        assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+    }
+
+    #[test]
+    pub fn test_kafka_source() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let dir = std::env::temp_dir().join(format!("feature_db_kafka_source_test_{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+
+            let broker = Arc::new(Mutex::new(MockBroker::default()));
+            broker.lock().unwrap().partitions.insert(0, vec![event(1), event(2), b"not json".to_vec(), event(1), event(2)]);
+            broker.lock().unwrap().partitions.insert(1, vec![event(3)]);
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap().to_string();
+            let broker1 = broker.clone();
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(serve(broker1.clone(), stream));
+                }
+            });
+            let source_config = KafkaSourceConfig {
+                broker: addr,
+                topic: "orders".to_string(),
+                partitions: vec![0, 1],
+                max_wait_ms: 10,
+                ..KafkaSourceConfig::default()
+            };
+
+            let config = Config {
+                data_dir: dir.join("node_1").to_str().unwrap().to_string(),
+                ..Config::default()
+            };
+            let node = create_node(config, test_datasets()).await.expect("创建node失败！");
+            let task = tokio::spawn(run(node.clone(), KafkaSource::new(source_config.clone())));
+            wait_committed(&broker, &[(0, 5), (1, 1)]).await;
+            assert_eq!(count(&node, 1).await, Some(ValueKind::Int(2)));
+            assert_eq!(count(&node, 2).await, Some(ValueKind::Int(2)));
+            assert_eq!(count(&node, 3).await, Some(ValueKind::Int(1)));
+            node.shutdown().await.unwrap();
+            task.await.unwrap();
+
+            // 重启后从已提交的位置继续消费
+            broker.lock().unwrap().partitions.get_mut(&0).unwrap().push(event(4));
+            let config = Config {
+                data_dir: dir.join("node_2").to_str().unwrap().to_string(),
+                ..Config::default()
+            };
+            let node = create_node(config, test_datasets()).await.expect("创建node失败！");
+            let task = tokio::spawn(run(node.clone(), KafkaSource::new(source_config)));
+            wait_committed(&broker, &[(0, 6), (1, 1)]).await;
+            assert_eq!(count(&node, 1).await, None);
+            assert_eq!(count(&node, 4).await, Some(ValueKind::Int(1)));
+            node.shutdown().await.unwrap();
+            task.await.unwrap();
+
+            let _ = std::fs::remove_dir_all(&dir);
+        });
+    }
+}
@@ -0,0 +1,241 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+use log::{info, warn};
+use serde_json::Value;
+use tokio::time;
+
+use feature_base::config::SourceConfig;
+use feature_base::custom_error::{common_err, CustomResult, NODE_SHUTTING_DOWN_CODE};
+use feature_base::metrics;
+
+use crate::node::Node;
+
+pub mod file;
+pub mod kafka;
+
+/// 拉取失败、更新可重试失败后的等待时间
+const RETRY_INTERVAL: time::Duration = time::Duration::from_millis(200);
+
+/// 数据源中的一条事件
+#[derive(Debug, Clone)]
+pub struct SourceRecord {
+    pub partition: i32,
+    // 处理完这条事件后应提交的位置
+    pub next_offset: u64,
+    pub payload: Vec<u8>,
+}
+
+/// 数据源：按顺序拉取事件，事件写入wal并提交后再提交位置，重启后从已提交的位置继续
+pub trait Source: Send {
+    fn name(&self) -> String;
+
+    /// 拉取下一批事件，没有新事件时等待一段时间后返回空
+    fn poll(&mut self) -> impl Future<Output=CustomResult<Vec<SourceRecord>>> + Send;
+
+    /// 提交每个分区的位置，之前的事件都已处理完
+    fn commit(&mut self, offsets: &BTreeMap<i32, u64>) -> impl Future<Output=CustomResult<()>> + Send;
+}
+
+/// 按配置启动所有数据源，follower只在提升后开始消费
+pub fn start_all(node: &Arc<Node>) {
+    for config in node.config.sources.clone() {
+        let node = node.clone();
+        match config {
+            SourceConfig::Kafka(config) => {
+                tokio::spawn(async move { run(node, kafka::KafkaSource::new(config)).await });
+            }
+            SourceConfig::File(config) => {
+                tokio::spawn(async move { run(node, file::FileSource::new(config)).await });
+            }
+        }
+    }
+}
+
+/// 持续从数据源拉取事件写入node。一批事件全部更新完成（wal已提交）后才提交位置，
+/// 中途崩溃或停机时未提交的事件重启后重新消费，保证至少一次
+pub async fn run<S: Source>(node: Arc<Node>, mut source: S) {
+    let name = source.name();
+    let mut shutdown_rx = node.shutdown_signal();
+    info!("数据源:{} 启动", name);
+    loop {
+        if *shutdown_rx.borrow() {
+            break;
+        }
+        let records = if node.read_only.load(Ordering::Acquire) {
+            Ok(vec![])
+        } else {
+            tokio::select! {
+                res = source.poll() => res,
+                _ = shutdown_rx.changed() => break,
+            }
+        };
+        let records = match records {
+            Ok(records) if !records.is_empty() => records,
+            res => {
+                if let Err(e) = &res {
+                    warn!("数据源:{} 拉取失败:{:?}", name, e);
+                }
+                // follower或拉取失败时等待后重试，poll返回空时已经等待过
+                if res.is_err() || node.read_only.load(Ordering::Acquire) {
+                    tokio::select! {
+                        _ = time::sleep(RETRY_INTERVAL) => {}
+                        _ = shutdown_rx.changed() => break,
+                    }
+                }
+                continue;
+            }
+        };
+
+        if let Err(e) = process(&node, &name, &records).await {
+            info!("数据源:{} 停止，未提交的事件重启后重新消费:{}", name, e);
+            break;
+        }
+        let mut offsets = BTreeMap::new();
+        for record in &records {
+            let offset = offsets.entry(record.partition).or_insert(record.next_offset);
+            *offset = record.next_offset.max(*offset);
+        }
+        // 提交失败不影响正确性，之后的提交会覆盖
+        if let Err(e) = source.commit(&offsets).await {
+            warn!("数据源:{} 提交位置失败:{:?}", name, e);
+        }
+    }
+    info!("数据源:{} 退出", name);
+}
+
+/// 并发更新一批事件，全部完成后返回；无法解析或不可重试的失败跳过，停机时返回错误。
+/// 部分指标转发失败或owner变化时只重试这些指标，全部指标更新或确定失败后才算处理完
+async fn process(node: &Arc<Node>, name: &str, records: &[SourceRecord]) -> CustomResult<()> {
+    let mut tasks = Vec::with_capacity(records.len());
+    for record in records {
+        let event: Value = match serde_json::from_slice(&record.payload) {
+            Ok(event) => event,
+            Err(e) => {
+                warn!("数据源:{} 分区:{} 位置:{} 的事件解析失败:{}", name, record.partition, record.next_offset, e);
+                metrics::SOURCE_EVENTS.with_label_values(&[name, "invalid"]).inc();
+                continue;
+            }
+        };
+        let node = node.clone();
+        let name = name.to_string();
+        tasks.push(tokio::spawn(async move {
+            let mut feature_ids = None;
+            let mut failed = false;
+            loop {
+                match node.update_features(event.clone(), feature_ids.clone(), false).await {
+                    Ok(res) => {
+                        let mut retry = vec![];
+                        for (feature_id, result) in &res.feature_result_map {
+                            if result.is_retriable() {
+                                retry.push(*feature_id);
+                            } else if !result.success {
+                                warn!("数据源:{} 的事件更新指标:{} 失败:{} {}", name, feature_id, result.msg, event);
+                                failed = true;
+                            }
+                        }
+                        if retry.is_empty() {
+                            let result = if failed { "failed" } else { "ok" };
+                            metrics::SOURCE_EVENTS.with_label_values(&[&name, result]).inc();
+                            return Ok(());
+                        }
+                        feature_ids = Some(retry);
+                        time::sleep(RETRY_INTERVAL).await;
+                    }
+                    Err(e) if e.code == NODE_SHUTTING_DOWN_CODE => return Err(e),
+                    Err(e) if e.is_retriable() => time::sleep(RETRY_INTERVAL).await,
+                    Err(e) => {
+                        warn!("数据源:{} 的事件更新失败:{:?} {}", name, e, event);
+                        metrics::SOURCE_EVENTS.with_label_values(&[&name, "failed"]).inc();
+                        return Ok(());
+                    }
+                }
+            }
+        }));
+    }
+    let mut res = Ok(());
+    for task in tasks {
+        match task.await {
+            Ok(Err(e)) => res = Err(e),
+            Err(e) => res = Err(common_err(format!("更新任务异常退出:{}", e))),
+            Ok(Ok(())) => {}
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    use tokio::net::TcpListener;
+
+    use feature_base::cluster::ClusterMap;
+    use feature_base::config::Config;
+    use feature_base::ds::DataSet;
+    use feature_base::feature::FeatureQuery;
+    use feature_base::feature::value::ValueKind;
+
+    use crate::node::create_node;
+    use crate::source::{process, SourceRecord};
+
+    /// owner暂时不可达时转发失败，等owner恢复后重试，之后才算处理完
+    #[test]
+    pub fn test_retry_failed_forward() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let dir = std::env::temp_dir().join(format!("feature_db_source_retry_test_{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            let ds: DataSet = serde_json::from_str(r#"{
+                "id":101,
+                "name":"ds_user_order",
+                "desc":"",
+                "column_type_map":{"user_id":"INT","ts":"DATETIME"},
+                "features":[{"id":10001,"name":"用户订单数","template":{"COUNT":{
+                    "group_keys":["user_id"],"time_key":"ts","window_unit":"DAY","window_size":30}}}]
+            }"#).unwrap();
+            let config = Config {
+                data_dir: dir.to_str().unwrap().to_string(),
+                ..Config::default()
+            };
+            let node = create_node(config, HashMap::from([(ds.id, ds)])).await.expect("创建node失败！");
+
+            // 所有slot属于一个没有监听的地址
+            let unreachable = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().to_string();
+            let mut cluster = ClusterMap::assign(1, BTreeMap::from([("node_x".to_string(), unreachable)]));
+            node.set_cluster(cluster.clone()).await;
+
+            let records = vec![SourceRecord { partition: 0, next_offset: 1, payload: br#"{"ds":101,"user_id":1,"ts":1650000000000}"#.to_vec() }];
+            let node1 = node.clone();
+            let done = Arc::new(AtomicBool::new(false));
+            let done1 = done.clone();
+            let task = tokio::spawn(async move {
+                let res = process(&node1, "test", &records).await;
+                done1.store(true, Ordering::Release);
+                res
+            });
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            assert!(!done.load(Ordering::Acquire));
+
+            // slot迁回本地后重试成功
+            cluster.version = 2;
+            cluster.nodes.insert(node.config.node_id.clone(), node.config.listen_addr.clone());
+            cluster.slots.iter_mut().for_each(|owner| *owner = node.config.node_id.clone());
+            node.set_cluster(cluster).await;
+            task.await.unwrap().unwrap();
+
+            let query = FeatureQuery { ds: 101, feature_id: 10001, keys: serde_json::json!({"user_id": 1}), time: 1650000000000 };
+            assert_eq!(node.query(query, true).await.unwrap().value, Some(ValueKind::Int(1)));
+            node.shutdown().await.unwrap();
+            let _ = std::fs::remove_dir_all(&dir);
+        });
+    }
+}
@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+
+use feature_base::custom_error::CustomResult;
+use feature_base::ds::DataSet;
+use feature_base::feature::Feature;
+use feature_base::feature::key::KeyPart;
+use feature_base::feature::value::ValueKind;
+use feature_base::store::inspect::{list_slots, read_page, read_slot_pages};
+
+use crate::table::{Cell, CellKind, Column, create_writer, Format, TableWriter};
+
+/// 当前指标状态导出的结果统计
+#[derive(Debug, Default)]
+pub struct ExportSummary {
+    pub slots: usize,
+    pub pages: usize,
+    // 每个指标导出的行数
+    pub rows: HashMap<u64, usize>,
+    // 不属于任何数据集的key
+    pub unknown_keys: usize,
+}
+
+struct FeatureWriter<'a> {
+    feature: &'a Feature,
+    writer: Box<dyn TableWriter>,
+}
+
+/// 遍历数据目录中所有slot已刷盘的page，每个指标输出一个文件：{ds}_{feature}.csv|parquet。
+///
+/// 每行为一个key：分组字段、time时刻的窗口值，以及最近一次更新所在时间分片的起始时间。
+/// 未刷盘的更新不会导出，需要在node停止后或对备份目录执行
+pub async fn export(data_dir: &str, datasets: &[DataSet], time: u64, output_dir: &str, format: Format) -> CustomResult<ExportSummary> {
+    std::fs::create_dir_all(output_dir)?;
+    let extension = match format {
+        Format::Csv => "csv",
+        Format::Parquet => "parquet",
+    };
+    let mut writers = HashMap::new();
+    for ds in datasets {
+        // 派生指标没有存储，不导出
+        for feature in ds.features.iter().filter(|f| f.derived().is_none()) {
+            let mut columns: Vec<Column> = feature.group_keys().iter()
+                .map(|k| Column::new(k, ds.column_type_map.get(k).map_or(CellKind::Text, CellKind::from)))
+                .collect();
+            columns.push(Column::new("value", CellKind::from(&feature.value_type())));
+            columns.push(Column::new("last_update_time", CellKind::Int));
+            let path = format!("{}/{}_{}.{}", output_dir, ds.id, feature.id, extension);
+            writers.insert(feature.id, FeatureWriter { feature, writer: create_writer(&path, format, columns)? });
+        }
+    }
+
+    let mut summary = ExportSummary::default();
+    for slot_id in list_slots(data_dir).await? {
+        summary.slots += 1;
+        for (_, page_id) in read_slot_pages(data_dir, slot_id).await? {
+            let page = match read_page(data_dir, slot_id, page_id).await? {
+                Some(page) => page,
+                None => continue,
+            };
+            summary.pages += 1;
+            for (key, value) in &page.data {
+                let w = match writers.get_mut(&key.feature_id()) {
+                    Some(w) => w,
+                    None => {
+                        summary.unknown_keys += 1;
+                        continue;
+                    }
+                };
+                let mut row: Vec<Cell> = key.parts()?.into_iter().map(|part| match part {
+                    KeyPart::Text(v) => Cell::Text(v),
+                    KeyPart::Int(v) => Cell::Int(v),
+                    KeyPart::Float(v) => Cell::Float(v),
+                    KeyPart::DateTime(v) => Cell::Int(v as i64),
+                }).collect();
+                row.push(Cell::from(w.feature.query(Some(value), time)?));
+                // 列表和出现时间保存在一个分片中，取最近一次的时间
+                let last_update_time = match value.iter().last() {
+                    Some((_, ValueKind::List(items))) => items.last().map(|i| i.time),
+                    Some((_, ValueKind::Seen { last, .. })) => Some(*last),
+                    Some((tk, _)) => Some(*tk),
+                    None => None,
+                };
+                row.push(last_update_time.map_or(Cell::Null, |t| Cell::Int(t as i64)));
+                w.writer.write_row(row)?;
+                *summary.rows.entry(w.feature.id).or_insert(0) += 1;
+            }
+        }
+    }
+    for (_, w) in writers {
+        w.writer.finish()?;
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use feature_base::calc_hash;
+    use feature_base::config::Config;
+    use feature_base::feature::value::FeatureValue;
+    use feature_base::store::Store;
+    use feature_base::store::wal::{crate_wal, generate_tid};
+
+    use crate::export::export;
+    use crate::pit::tests::test_dataset;
+    use crate::table::Format;
+
+    #[test]
+    pub fn test_export_state() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let dir = std::env::temp_dir().join(format!("feature_db_export_test_{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            let data_dir = dir.join("data");
+            std::fs::create_dir_all(&data_dir).unwrap();
+            let config = Config {
+                data_dir: data_dir.to_str().unwrap().to_string(),
+                ..Config::default()
+            };
+            let wal = crate_wal(config.data_dir.clone(), config.wal_sync, config.wal_queue_size, None).await.expect("创建wal失败！");
+            let store = Store::new(config.data_dir.clone()).await.expect("创建store失败！");
+
+            let ds = test_dataset();
+            let feature = &ds.features[0];
+            let hour = 3600000u64;
+            let t0 = 1650000000000u64 - 1650000000000u64 % hour;
+            for (user_id, ts) in [(1, t0), (1, t0 + 1), (2, t0 - hour)] {
+                let event = serde_json::json!({"ds": 101, "user_id": user_id, "ts": ts});
+                let key = feature.build_key(&event, &ds.column_type_map).unwrap();
+                let tid = generate_tid();
+                wal.send_begin_log(tid).await.unwrap();
+                let (_, page) = store.get_page(calc_hash(key.as_bytes())).await.unwrap();
+                let mut page = page.write().await;
+                let mut value = page.get(&key).await.cloned().unwrap_or_else(FeatureValue::new);
+                let update = feature.calc(&event, &ds.column_type_map, &key, &mut value).unwrap().unwrap();
+                page.put(key, value).await.unwrap();
+                let action_id = wal.send_feature_update_log(tid, update).await.unwrap();
+                page.after_update(action_id, &store).await;
+                drop(page);
+                wal.commit_log(tid).await.unwrap();
+            }
+            store.check_point_all(&wal).await.unwrap();
+            wal.close().await.unwrap();
+
+            let output_dir = dir.join("out");
+            let summary = export(&config.data_dir, std::slice::from_ref(&ds), t0 + 1, output_dir.to_str().unwrap(), Format::Csv).await.unwrap();
+            assert_eq!(summary.rows.get(&1), Some(&2));
+            assert_eq!(summary.unknown_keys, 0);
+            let mut lines: Vec<String> = std::fs::read_to_string(output_dir.join("101_1.csv")).unwrap()
+                .lines().map(|l| l.to_string()).collect();
+            lines[1..].sort();
+            // user 2 的数据已经不在窗口内
+            assert_eq!(lines, vec![
+                "user_id,value,last_update_time".to_string(),
+                format!("1,2,{}", t0),
+                format!("2,,{}", t0 - hour),
+            ]);
+
+            let _ = std::fs::remove_dir_all(&dir);
+        });
+    }
+}
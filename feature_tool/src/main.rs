@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use feature_base::client::fetch_datasets;
+use feature_base::config::parse_args;
+use feature_base::custom_error::{common_err, CONFIG_INVALID_CODE, config_invalid_err, CustomResult};
+use feature_base::ds::DataSet;
+use feature_base::store::{fsck, inspect};
+use feature_base::store::slot::PAGE_SIZE;
+
+use crate::table::Format;
+
+pub mod export;
+pub mod pit;
+pub mod table;
+
+const USAGE: &str = "离线查看数据目录，node停止时使用：
+  feature_tool wal --data-dir ./data [--tid 12]
+  feature_tool index --data-dir ./data --slot 100
+  feature_tool page --data-dir ./data --slot 100 --page 0
+  feature_tool stats --data-dir ./data [--slot 100]
+  feature_tool fsck --data-dir ./data [--repair true]
+离线生成训练样本，数据集从文件或meta server读取：
+  feature_tool pit-export --datasets ./datasets.json|--meta-addr 127.0.0.1:6500 --ds 101 [--features 1,2]
+      --labels labels.csv [--time-column ts] --events events.jsonl --output train.parquet [--format csv|parquet]
+导出当前指标状态，每个指标一个文件，node停止时或对备份目录使用：
+  feature_tool export --data-dir ./data --datasets ./datasets.json|--meta-addr 127.0.0.1:6500 --output-dir ./out
+      [--format csv|parquet] [--time 1650000000000]";
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(&args).await {
+        Ok(output) => println!("{}", output),
+        Err(e) => {
+            if e.code == CONFIG_INVALID_CODE {
+                eprintln!("{}\n{}", e, USAGE);
+            } else {
+                eprintln!("{}", e);
+            }
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn run(args: &[String]) -> CustomResult<String> {
+    let (command, rest) = args.split_first()
+        .ok_or(config_invalid_err("缺少命令".to_string()))?;
+    let args = parse_args(rest)?;
+    match command.as_str() {
+        "wal" => wal(get_arg(&args, "data_dir")?, &args).await,
+        "index" => index(get_arg(&args, "data_dir")?, get_num(&args, "slot")?).await,
+        "page" => page(get_arg(&args, "data_dir")?, get_num(&args, "slot")?, get_num(&args, "page")?).await,
+        "stats" => stats(get_arg(&args, "data_dir")?, &args).await,
+        "fsck" => fsck(get_arg(&args, "data_dir")?, &args).await,
+        "pit-export" => pit_export(&args).await,
+        "export" => export(get_arg(&args, "data_dir")?, &args).await,
+        _ => Err(config_invalid_err(format!("无法识别的命令:{}", command))),
+    }
+}
+
+fn get_arg<'a>(args: &'a HashMap<String, String>, key: &str) -> CustomResult<&'a str> {
+    args.get(key).map(|v| v.as_str())
+        .ok_or(config_invalid_err(format!("缺少参数--{}", key.replace('_', "-"))))
+}
+
+fn get_num<T: std::str::FromStr>(args: &HashMap<String, String>, key: &str) -> CustomResult<T> {
+    let v = get_arg(args, key)?;
+    v.parse().map_err(|_| config_invalid_err(format!("参数--{}的值:{} 非法", key, v)))
+}
+
+/// 按事务分组输出wal，未提交的事务单独标记
+async fn wal(data_dir: &str, args: &HashMap<String, String>) -> CustomResult<String> {
+    let tid: Option<u64> = match args.get("tid") {
+        Some(_) => Some(get_num(args, "tid")?),
+        None => None,
+    };
+    let scan = inspect::read_wal(data_dir).await?;
+    let item_num = scan.items.len();
+    let txns = inspect::group_by_tid(scan.items);
+
+    let mut out = String::new();
+    for txn in txns.iter().filter(|t| tid.is_none() || tid == Some(t.tid)) {
+        writeln!(out, "事务:{} {}", txn.tid, if txn.is_committed() { "已提交" } else { "未提交" }).unwrap();
+        for item in &txn.items {
+            match &item.value {
+                Some(v) => writeln!(out, "  {} {:?} {:?}", item.action_id, item.kind, v).unwrap(),
+                None => writeln!(out, "  {} {:?}", item.action_id, item.kind).unwrap(),
+            }
+        }
+    }
+    writeln!(out, "日志:{} 事务:{} 未提交:{} 有效长度:{}/{}", item_num, txns.len(),
+             txns.iter().filter(|t| !t.is_committed()).count(), scan.valid_len, scan.file_len).unwrap();
+    if let Some(e) = scan.error {
+        writeln!(out, "错误:{}", e).unwrap();
+    }
+    Ok(out)
+}
+
+async fn index(data_dir: &str, slot_id: u16) -> CustomResult<String> {
+    let mut out = String::new();
+    match inspect::read_slot_index(data_dir, slot_id).await? {
+        Some(index) => {
+            for (min_pk, page_id) in index {
+                writeln!(out, "min_pk:{:#018x} page:{}", min_pk, page_id).unwrap();
+            }
+        }
+        // 未发生过分裂的slot只有page 0
+        None => writeln!(out, "slot:{} 没有索引文件，只有page 0", slot_id).unwrap(),
+    }
+    Ok(out)
+}
+
+async fn page(data_dir: &str, slot_id: u16, page_id: u64) -> CustomResult<String> {
+    match inspect::read_page(data_dir, slot_id, page_id).await? {
+        Some(page) => Ok(serde_json::to_string_pretty(&page)?),
+        None => Ok(format!("slot:{} page:{} 未写入", slot_id, page_id)),
+    }
+}
+
+async fn stats(data_dir: &str, args: &HashMap<String, String>) -> CustomResult<String> {
+    let slots = match args.get("slot") {
+        Some(_) => vec![get_num(args, "slot")?],
+        None => inspect::list_slots(data_dir).await?,
+    };
+    let mut out = String::new();
+    writeln!(out, "slot\tpages\tstored\tkeys\tbuckets\tbytes\tfill").unwrap();
+    let mut total = inspect::SlotStats::default();
+    for slot_id in slots {
+        let stats = inspect::slot_stats(data_dir, slot_id).await?;
+        writeln!(out, "{}\t{}\t{}\t{}\t{}\t{}\t{:.2}%", stats.slot_id, stats.pages, stats.stored_pages,
+                 stats.keys, stats.buckets, stats.used_bytes, stats.fill() * 100.0).unwrap();
+        total.pages += stats.pages;
+        total.stored_pages += stats.stored_pages;
+        total.keys += stats.keys;
+        total.buckets += stats.buckets;
+        total.used_bytes += stats.used_bytes;
+    }
+    writeln!(out, "total\t{}\t{}\t{}\t{}\t{}\t{:.2}%", total.pages, total.stored_pages, total.keys,
+             total.buckets, total.used_bytes, total.fill() * 100.0).unwrap();
+    writeln!(out, "page大小:{}", PAGE_SIZE).unwrap();
+    Ok(out)
+}
+
+/// 一致性检查，发现未修复的问题时返回错误
+async fn fsck(data_dir: &str, args: &HashMap<String, String>) -> CustomResult<String> {
+    let repair = match args.get("repair") {
+        Some(_) => get_num(args, "repair")?,
+        None => false,
+    };
+    let report = fsck::check(data_dir, repair).await?;
+    let mut out = String::new();
+    for repaired in &report.repaired {
+        writeln!(out, "已修复:{}", repaired).unwrap();
+    }
+    for problem in &report.problems {
+        writeln!(out, "问题:{}", problem).unwrap();
+    }
+    writeln!(out, "slot:{} page:{} key:{} 核对wal分片:{} wal末尾不完整字节:{}", report.slots, report.pages,
+             report.keys, report.checked_updates, report.wal_torn_bytes).unwrap();
+    if report.is_ok() {
+        Ok(out)
+    } else {
+        Err(common_err(format!("{}发现{}个问题", out, report.problems.len())))
+    }
+}
+
+/// 读取所有数据集，优先使用 --datasets 文件，否则从 --meta-addr 获取
+async fn load_datasets(args: &HashMap<String, String>) -> CustomResult<Vec<DataSet>> {
+    match args.get("datasets") {
+        Some(path) => Ok(serde_json::from_slice(&std::fs::read(path)?)?),
+        None => Ok(fetch_datasets(get_arg(args, "meta_addr")?).await?.into_values().collect()),
+    }
+}
+
+/// 读取 --ds 指定的数据集
+async fn load_dataset(args: &HashMap<String, String>) -> CustomResult<DataSet> {
+    let ds_id: i64 = get_num(args, "ds")?;
+    let mut datasets = load_datasets(args).await?;
+    let index = datasets.iter().position(|ds| ds.id == ds_id)
+        .ok_or(config_invalid_err(format!("找不到数据集:{}", ds_id)))?;
+    Ok(datasets.swap_remove(index))
+}
+
+async fn pit_export(args: &HashMap<String, String>) -> CustomResult<String> {
+    let ds = load_dataset(args).await?;
+    let feature_ids = match args.get("features") {
+        Some(ids) => ids.split(',')
+            .map(|id| id.trim().parse().map_err(|_| config_invalid_err(format!("指标id:{} 非法", id))))
+            .collect::<CustomResult<Vec<u64>>>()?,
+        None => vec![],
+    };
+    let format: Format = args.get("format").map(|f| f.as_str()).unwrap_or("csv").parse()?;
+    let summary = pit::export(&ds, &feature_ids, get_arg(args, "labels")?,
+                              args.get("time_column").map(|t| t.as_str()).unwrap_or("ts"),
+                              get_arg(args, "events")?, get_arg(args, "output")?, format)?;
+    Ok(format!("样本:{} 事件:{} 重放:{} 缺少分组字段:{}", summary.labels, summary.events, summary.replayed, summary.key_errors))
+}
+
+async fn export(data_dir: &str, args: &HashMap<String, String>) -> CustomResult<String> {
+    let datasets = load_datasets(args).await?;
+    let format: Format = args.get("format").map(|f| f.as_str()).unwrap_or("csv").parse()?;
+    let time = match args.get("time") {
+        Some(_) => get_num(args, "time")?,
+        None => SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64),
+    };
+    let summary = export::export(data_dir, &datasets, time, get_arg(args, "output_dir")?, format).await?;
+    let mut out = String::new();
+    for ds in &datasets {
+        for feature in ds.features.iter().filter(|f| f.derived().is_none()) {
+            writeln!(out, "数据集:{} 指标:{} 行数:{}", ds.id, feature.id, summary.rows.get(&feature.id).unwrap_or(&0)).unwrap();
+        }
+    }
+    writeln!(out, "slot:{} page:{} 未知指标的key:{}", summary.slots, summary.pages, summary.unknown_keys).unwrap();
+    Ok(out)
+}
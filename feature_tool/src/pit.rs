@@ -0,0 +1,237 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use serde_json::{Map, Value};
+
+use feature_base::custom_error::{common_err, config_invalid_err, CustomResult};
+use feature_base::ds::column::{ColumnType, parse_column_value};
+use feature_base::ds::DataSet;
+use feature_base::feature::Feature;
+use feature_base::feature::key::FeatureKey;
+use feature_base::feature::value::FeatureValue;
+
+use crate::table::{Cell, CellKind, Column, create_writer, Format};
+
+/// 训练样本导出的结果统计
+#[derive(Debug, Default)]
+pub struct PitSummary {
+    pub labels: usize,
+    // 属于该数据集的事件数
+    pub events: usize,
+    // 实际参与计算的 (事件, 指标) 数
+    pub replayed: usize,
+    // 样本缺少分组字段，无法计算的 (样本, 指标) 数
+    pub key_errors: usize,
+}
+
+/// 样本文件中的一行
+struct Label {
+    cells: Vec<Cell>,
+    event: Value,
+    time: u64,
+}
+
+/// 时间点正确的训练样本导出。
+///
+/// 样本文件为带表头的CSV，包含指标的分组字段和时间字段；事件文件为JSONL，与写入node的事件格式相同。
+/// 按事件时间重放事件，在每个样本的时间点查询指标，与该时刻在线查询的结果一致：
+/// 时间等于样本时间的事件计入，之后的事件不计入。输出样本的所有列，再加上每个指标一列
+pub fn export(ds: &DataSet, feature_ids: &[u64], labels_path: &str, time_column: &str,
+              events_path: &str, output_path: &str, format: Format) -> CustomResult<PitSummary> {
+    let features = select_features(ds, feature_ids)?;
+    let (mut columns, labels) = read_labels(ds, labels_path, time_column)?;
+    let mut summary = PitSummary { labels: labels.len(), ..PitSummary::default() };
+
+    // 每个指标下每个样本的key，只重放样本中出现过的key
+    let label_keys: Vec<Vec<Option<FeatureKey>>> = features.iter()
+        .map(|f| labels.iter().map(|l| f.build_key(&l.event, &ds.column_type_map).ok()).collect())
+        .collect();
+    summary.key_errors = label_keys.iter().flatten().filter(|k| k.is_none()).count();
+    let wanted: Vec<HashSet<&FeatureKey>> = label_keys.iter()
+        .map(|keys| keys.iter().flatten().collect())
+        .collect();
+
+    // 每个指标需要重放的事件：(事件时间, 事件序号, key)
+    let mut events = vec![];
+    let mut feature_events: Vec<Vec<(u64, usize, FeatureKey)>> = vec![vec![]; features.len()];
+    for (line_no, line) in BufReader::new(File::open(events_path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event: Value = serde_json::from_str(&line)
+            .map_err(|e| common_err(format!("事件文件第{}行解析失败:{}", line_no + 1, e)))?;
+        if event.get("ds").and_then(|v| v.as_i64()) != Some(ds.id) {
+            continue;
+        }
+        summary.events += 1;
+        let mut used = false;
+        for (i, feature) in features.iter().enumerate() {
+            // 与在线更新一样，缺少字段或不满足过滤条件的事件不参与该指标的计算
+            let key = match feature.build_key(&event, &ds.column_type_map) {
+                Ok(key) if wanted[i].contains(&key) => key,
+                _ => continue,
+            };
+            if !feature.matches(&event, &ds.column_type_map).unwrap_or(false) {
+                continue;
+            }
+            if let Ok(time) = feature.event_time(&event) {
+                feature_events[i].push((time, events.len(), key));
+                used = true;
+            }
+        }
+        if used {
+            events.push(event);
+        }
+    }
+
+    // 样本按时间排序，依次重放到样本时间为止的事件后查询
+    let mut label_order: Vec<usize> = (0..labels.len()).collect();
+    label_order.sort_by_key(|i| labels[*i].time);
+    let mut results = vec![vec![Cell::Null; features.len()]; labels.len()];
+    for (i, feature) in features.iter().enumerate() {
+        let feature_events = &mut feature_events[i];
+        // 稳定排序，相同时间的事件保持文件中的顺序
+        feature_events.sort_by_key(|(time, _, _)| *time);
+        let mut values: HashMap<FeatureKey, FeatureValue> = HashMap::new();
+        let mut next = 0;
+        for label_index in &label_order {
+            let label = &labels[*label_index];
+            while next < feature_events.len() && feature_events[next].0 <= label.time {
+                let (_, event_index, key) = &feature_events[next];
+                let value = values.entry(key.clone()).or_insert_with(FeatureValue::new);
+                feature.calc(&events[*event_index], &ds.column_type_map, key, value)?;
+                summary.replayed += 1;
+                next += 1;
+            }
+            if let Some(key) = &label_keys[i][*label_index] {
+                results[*label_index][i] = Cell::from(feature.query(values.get(key), label.time)?);
+            }
+        }
+    }
+
+    for feature in &features {
+        columns.push(Column::new(&feature.name, CellKind::from(&feature.value_type())));
+    }
+    let mut writer = create_writer(output_path, format, columns)?;
+    for (label, values) in labels.into_iter().zip(results) {
+        let mut row = label.cells;
+        row.extend(values);
+        writer.write_row(row)?;
+    }
+    writer.finish()?;
+    Ok(summary)
+}
+
+/// 不指定时选择所有有存储的指标；派生指标不支持重放
+fn select_features<'a>(ds: &'a DataSet, feature_ids: &[u64]) -> CustomResult<Vec<&'a Feature>> {
+    if feature_ids.is_empty() {
+        return Ok(ds.features.iter().filter(|f| f.derived().is_none()).collect());
+    }
+    feature_ids.iter().map(|id| {
+        match ds.features.iter().find(|f| f.id == *id) {
+            Some(f) if f.derived().is_some() => Err(config_invalid_err(format!("指标:{} 是派生指标，不支持重放", id))),
+            Some(f) => Ok(f),
+            None => Err(config_invalid_err(format!("数据集:{} 中没有指标:{}", ds.id, id))),
+        }
+    }).collect()
+}
+
+/// 读取样本文件，数据集中定义的字段按字段类型解析，其它字段作为文本原样输出
+fn read_labels(ds: &DataSet, path: &str, time_column: &str) -> CustomResult<(Vec<Column>, Vec<Label>)> {
+    let mut reader = csv::Reader::from_path(path).map_err(|e| common_err(e.to_string()))?;
+    let headers: Vec<String> = reader.headers().map_err(|e| common_err(e.to_string()))?
+        .iter().map(|h| h.to_string()).collect();
+    if !headers.iter().any(|h| h == time_column) {
+        return Err(config_invalid_err(format!("样本文件中没有时间字段:{}", time_column)));
+    }
+    let column_types: Vec<Option<ColumnType>> = headers.iter().map(|h| {
+        if h == time_column {
+            Some(ColumnType::DATETIME)
+        } else {
+            ds.column_type_map.get(h).cloned()
+        }
+    }).collect();
+    let columns = headers.iter().zip(&column_types)
+        .map(|(h, t)| Column::new(h, t.as_ref().map_or(CellKind::Text, CellKind::from)))
+        .collect();
+
+    let mut labels = vec![];
+    for (row_no, record) in reader.records().enumerate() {
+        let record = record.map_err(|e| common_err(e.to_string()))?;
+        let mut event = Map::new();
+        let mut cells = vec![];
+        let mut time = 0;
+        for ((name, column_type), text) in headers.iter().zip(&column_types).zip(record.iter()) {
+            let value = match column_type {
+                Some(column_type) => parse_column_value(text, name, column_type)
+                    .map_err(|e| common_err(format!("样本文件第{}行:{}", row_no + 2, e)))?,
+                None => Value::from(text),
+            };
+            if name == time_column {
+                time = value.as_u64().unwrap_or_default();
+            }
+            cells.push(match &value {
+                Value::Number(n) if n.is_f64() => Cell::Float(n.as_f64().unwrap_or_default()),
+                Value::Number(n) => Cell::Int(n.as_i64().unwrap_or(n.as_u64().unwrap_or_default() as i64)),
+                _ => Cell::Text(text.to_string()),
+            });
+            event.insert(name.clone(), value);
+        }
+        labels.push(Label { cells, event: Value::Object(event), time });
+    }
+    Ok((columns, labels))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use feature_base::ds::DataSet;
+
+    use crate::pit::export;
+    use crate::table::Format;
+
+    pub fn test_dataset() -> DataSet {
+        serde_json::from_str(r#"{
+            "id": 101,
+            "name": "ds_user_order",
+            "desc": "",
+            "column_type_map": {"user_id": "INT", "amount": "FLOAT", "ts": "DATETIME"},
+            "features": [{
+                "id": 1,
+                "name": "user_order_1h",
+                "template": {"COUNT": {"group_keys": ["user_id"], "time_key": "ts", "window_unit": "HOUR", "window_size": 1}}
+            }]
+        }"#).unwrap()
+    }
+
+    #[test]
+    pub fn test_point_in_time_export() {
+        let dir = std::env::temp_dir().join(format!("feature_db_pit_test_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let t0 = 1650000000000u64 - 1650000000000u64 % 3600000;
+        let minute = 60000;
+
+        let mut events = String::new();
+        for t in [t0, t0 + 10 * minute, t0 + 50 * minute] {
+            events.push_str(&format!("{{\"ds\":101,\"user_id\":1,\"amount\":1.0,\"ts\":{}}}\n", t));
+        }
+        // 其它数据集的事件不参与计算
+        events.push_str(&format!("{{\"ds\":102,\"user_id\":1,\"ts\":{}}}\n", t0));
+        std::fs::write(dir.join("events.jsonl"), events).unwrap();
+        std::fs::write(dir.join("labels.csv"), format!("user_id,ts,label\n1,{},a\n1,{},b\n1,{},c\n2,{},d\n",
+                                                       t0 + 50 * minute, t0 + 20 * minute, t0 - 1, t0)).unwrap();
+
+        let output = dir.join("out.csv");
+        let summary = export(&test_dataset(), &[], dir.join("labels.csv").to_str().unwrap(), "ts",
+                             dir.join("events.jsonl").to_str().unwrap(), output.to_str().unwrap(), Format::Csv).unwrap();
+        assert_eq!((summary.labels, summary.events, summary.replayed, summary.key_errors), (4, 3, 3, 0));
+        // 样本时间之后的事件不计入，即使在同一个时间分片中
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), format!(
+            "user_id,ts,label,user_order_1h\n1,{},a,3\n1,{},b,2\n1,{},c,\n2,{},d,\n",
+            t0 + 50 * minute, t0 + 20 * minute, t0 - 1, t0));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,298 @@
+use std::fs::File;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use parquet::basic::{LogicalType, Repetition, Type as PhysicalType};
+use parquet::column::writer::ColumnWriter;
+use parquet::data_type::ByteArray;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::types::Type;
+
+use feature_base::custom_error::{common_err, config_invalid_err, CustomError, CustomResult};
+use feature_base::ds::column::ColumnType;
+use feature_base::feature::value::ValueKind;
+
+/// 每个row group的行数
+const ROW_GROUP_SIZE: usize = 8192;
+
+/// 导出文件的格式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Csv,
+    Parquet,
+}
+
+impl FromStr for Format {
+    type Err = CustomError;
+
+    fn from_str(s: &str) -> CustomResult<Format> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "parquet" => Ok(Format::Parquet),
+            _ => Err(config_invalid_err(format!("不支持的格式:{}，可选csv/parquet", s))),
+        }
+    }
+}
+
+/// 列的类型
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CellKind {
+    Text,
+    Int,
+    Float,
+}
+
+impl From<&ColumnType> for CellKind {
+    fn from(column_type: &ColumnType) -> CellKind {
+        match column_type {
+            ColumnType::TEXT => CellKind::Text,
+            ColumnType::INT | ColumnType::DATETIME => CellKind::Int,
+            ColumnType::FLOAT => CellKind::Float,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Column {
+    pub name: String,
+    pub kind: CellKind,
+}
+
+impl Column {
+    pub fn new(name: &str, kind: CellKind) -> Column {
+        Column { name: name.to_string(), kind }
+    }
+}
+
+/// 单元格的值，类型与列不一致时写入parquet会失败
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cell {
+    Null,
+    Text(String),
+    Int(i64),
+    Float(f64),
+}
+
+impl From<Option<ValueKind>> for Cell {
+    /// 指标值转为单元格，列表按JSON文本输出
+    fn from(value: Option<ValueKind>) -> Cell {
+        match value {
+            None => Cell::Null,
+            Some(ValueKind::Int(v)) => Cell::Int(v as i64),
+            Some(ValueKind::Float(v)) => Cell::Float(v),
+            Some(ValueKind::List(items)) => Cell::Text(serde_json::to_string(&items).unwrap_or_default()),
+            Some(ValueKind::Seen { first, last }) => Cell::Text(serde_json::json!({"first": first, "last": last}).to_string()),
+            Some(ValueKind::Stats { count, mean, m2 }) => Cell::Text(serde_json::json!({"count": count, "mean": mean, "m2": m2}).to_string()),
+        }
+    }
+}
+
+/// 按行写入表格文件
+pub trait TableWriter {
+    fn write_row(&mut self, row: Vec<Cell>) -> CustomResult<()>;
+
+    /// 写入剩余的数据并关闭文件
+    fn finish(self: Box<Self>) -> CustomResult<()>;
+}
+
+pub fn create_writer(path: &str, format: Format, columns: Vec<Column>) -> CustomResult<Box<dyn TableWriter>> {
+    match format {
+        Format::Csv => Ok(Box::new(CsvTableWriter::new(path, &columns)?)),
+        Format::Parquet => Ok(Box::new(ParquetTableWriter::new(path, columns)?)),
+    }
+}
+
+fn to_err<E: std::fmt::Display>(e: E) -> CustomError {
+    common_err(e.to_string())
+}
+
+struct CsvTableWriter {
+    writer: csv::Writer<File>,
+}
+
+impl CsvTableWriter {
+    fn new(path: &str, columns: &[Column]) -> CustomResult<CsvTableWriter> {
+        let mut writer = csv::Writer::from_path(path).map_err(to_err)?;
+        writer.write_record(columns.iter().map(|c| c.name.as_str())).map_err(to_err)?;
+        Ok(CsvTableWriter { writer })
+    }
+}
+
+impl TableWriter for CsvTableWriter {
+    fn write_row(&mut self, row: Vec<Cell>) -> CustomResult<()> {
+        let fields = row.into_iter().map(|cell| match cell {
+            Cell::Null => String::new(),
+            Cell::Text(v) => v,
+            Cell::Int(v) => v.to_string(),
+            Cell::Float(v) => v.to_string(),
+        });
+        self.writer.write_record(fields).map_err(to_err)
+    }
+
+    fn finish(mut self: Box<Self>) -> CustomResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// 缓存一个row group的数据后按列写入，所有列都可以为空
+struct ParquetTableWriter {
+    columns: Vec<Column>,
+    rows: Vec<Vec<Cell>>,
+    writer: SerializedFileWriter<File>,
+}
+
+impl ParquetTableWriter {
+    fn new(path: &str, columns: Vec<Column>) -> CustomResult<ParquetTableWriter> {
+        let mut fields = vec![];
+        for column in &columns {
+            let (physical_type, logical_type) = match column.kind {
+                CellKind::Text => (PhysicalType::BYTE_ARRAY, Some(LogicalType::String)),
+                CellKind::Int => (PhysicalType::INT64, None),
+                CellKind::Float => (PhysicalType::DOUBLE, None),
+            };
+            let field = Type::primitive_type_builder(&column.name, physical_type)
+                .with_repetition(Repetition::OPTIONAL)
+                .with_logical_type(logical_type)
+                .build().map_err(to_err)?;
+            fields.push(Arc::new(field));
+        }
+        let schema = Type::group_type_builder("schema").with_fields(fields).build().map_err(to_err)?;
+        let props = WriterProperties::builder().build();
+        let writer = SerializedFileWriter::new(File::create(path)?, Arc::new(schema), Arc::new(props)).map_err(to_err)?;
+        Ok(ParquetTableWriter { columns, rows: vec![], writer })
+    }
+
+    fn flush_row_group(&mut self) -> CustomResult<()> {
+        if self.rows.is_empty() {
+            return Ok(());
+        }
+        let mut row_group = self.writer.next_row_group().map_err(to_err)?;
+        let mut index = 0;
+        while let Some(mut column_writer) = row_group.next_column().map_err(to_err)? {
+            let column = &self.columns[index];
+            let mut def_levels = Vec::with_capacity(self.rows.len());
+            let cells = self.rows.iter().map(|row| row.get(index).unwrap_or(&Cell::Null));
+            match column_writer.untyped() {
+                ColumnWriter::ByteArrayColumnWriter(w) => {
+                    let mut values = vec![];
+                    for cell in cells {
+                        match cell {
+                            Cell::Null => def_levels.push(0),
+                            Cell::Text(v) => {
+                                def_levels.push(1);
+                                values.push(ByteArray::from(v.as_str()));
+                            }
+                            _ => return Err(cell_type_err(column, cell)),
+                        }
+                    }
+                    w.write_batch(&values, Some(&def_levels), None).map_err(to_err)?;
+                }
+                ColumnWriter::Int64ColumnWriter(w) => {
+                    let mut values = vec![];
+                    for cell in cells {
+                        match cell {
+                            Cell::Null => def_levels.push(0),
+                            Cell::Int(v) => {
+                                def_levels.push(1);
+                                values.push(*v);
+                            }
+                            _ => return Err(cell_type_err(column, cell)),
+                        }
+                    }
+                    w.write_batch(&values, Some(&def_levels), None).map_err(to_err)?;
+                }
+                ColumnWriter::DoubleColumnWriter(w) => {
+                    let mut values = vec![];
+                    for cell in cells {
+                        match cell {
+                            Cell::Null => def_levels.push(0),
+                            Cell::Float(v) => {
+                                def_levels.push(1);
+                                values.push(*v);
+                            }
+                            // 整数可以无损写入浮点列
+                            Cell::Int(v) => {
+                                def_levels.push(1);
+                                values.push(*v as f64);
+                            }
+                            _ => return Err(cell_type_err(column, cell)),
+                        }
+                    }
+                    w.write_batch(&values, Some(&def_levels), None).map_err(to_err)?;
+                }
+                _ => return Err(common_err(format!("列:{} 的类型不支持", column.name))),
+            }
+            column_writer.close().map_err(to_err)?;
+            index += 1;
+        }
+        row_group.close().map_err(to_err)?;
+        self.rows.clear();
+        Ok(())
+    }
+}
+
+fn cell_type_err(column: &Column, cell: &Cell) -> CustomError {
+    common_err(format!("列:{} 的类型为{:?}，值:{:?} 类型不匹配", column.name, column.kind, cell))
+}
+
+impl TableWriter for ParquetTableWriter {
+    fn write_row(&mut self, row: Vec<Cell>) -> CustomResult<()> {
+        self.rows.push(row);
+        if self.rows.len() >= ROW_GROUP_SIZE {
+            self.flush_row_group()?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> CustomResult<()> {
+        self.flush_row_group()?;
+        self.writer.close().map_err(to_err)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::RowAccessor;
+
+    use crate::table::{Cell, CellKind, Column, create_writer, Format};
+
+    #[test]
+    pub fn test_write_csv_and_parquet() {
+        let dir = std::env::temp_dir().join(format!("feature_db_table_test_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let columns = vec![Column::new("name", CellKind::Text), Column::new("count", CellKind::Int), Column::new("avg", CellKind::Float)];
+        let rows = vec![
+            vec![Cell::Text("a,b".to_string()), Cell::Int(3), Cell::Float(1.5)],
+            vec![Cell::Text("c".to_string()), Cell::Null, Cell::Null],
+        ];
+
+        let csv_path = dir.join("out.csv");
+        let mut writer = create_writer(csv_path.to_str().unwrap(), Format::Csv, columns.clone()).unwrap();
+        for row in rows.clone() {
+            writer.write_row(row).unwrap();
+        }
+        writer.finish().unwrap();
+        assert_eq!(std::fs::read_to_string(&csv_path).unwrap(), "name,count,avg\n\"a,b\",3,1.5\nc,,\n");
+
+        let parquet_path = dir.join("out.parquet");
+        let mut writer = create_writer(parquet_path.to_str().unwrap(), Format::Parquet, columns).unwrap();
+        for row in rows {
+            writer.write_row(row).unwrap();
+        }
+        writer.finish().unwrap();
+        let reader = SerializedFileReader::new(std::fs::File::open(&parquet_path).unwrap()).unwrap();
+        let rows: Vec<_> = reader.get_row_iter(None).unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].get_string(0).unwrap(), "a,b");
+        assert_eq!(rows[0].get_long(1).unwrap(), 3);
+        assert_eq!(rows[0].get_double(2).unwrap(), 1.5);
+        assert!(rows[1].get_long(1).is_err());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}